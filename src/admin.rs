@@ -0,0 +1,309 @@
+use crate::{
+    alert::{Alert, AlertKind},
+    api::{self, storage_error},
+    db::{self, DB},
+    error::ApiError,
+    http::{ContentType, HttpMethod, HttpStatus, Response},
+    json::{Json, JsonNumber, JsonObject},
+    root::Root,
+    router::{Context, Router},
+};
+
+pub fn register(router: &mut Router) {
+    router.add(HttpMethod::GET, "/admin/stats", stats);
+    router.add(HttpMethod::GET, "/admin/connections", connections);
+    router.add(HttpMethod::GET, "/admin/dbs", list_databases);
+    router
+        .add(HttpMethod::POST, "/admin/dbs/:name", create_database)
+        .accepts(ContentType::ApplicationJson);
+    router.add(HttpMethod::DELETE, "/admin/dbs/:name", drop_database);
+    router.add(HttpMethod::POST, "/admin/dbs/:name/open", open_database);
+    router.add(HttpMethod::POST, "/admin/dbs/:name/close", close_database);
+    router
+        .add(
+            HttpMethod::POST,
+            "/admin/dbs/:name/password",
+            change_password,
+        )
+        .accepts(ContentType::ApplicationJson);
+    router.add(HttpMethod::GET, "/admin/dbs/:name/stats", database_stats);
+    router.add(HttpMethod::POST, "/admin/dbs/:name/compact", compact);
+    router
+        .add(HttpMethod::POST, "/admin/dbs/:name/backup", backup)
+        .query_param("incremental");
+}
+
+fn stats(context: &Context) -> Response {
+    let mut databases = Vec::new();
+    let names = match db::list(&context.server.root) {
+        Ok(names) => names,
+        Err(err) => {
+            return storage_error(err);
+        }
+    };
+    let mut closed = Vec::new();
+    for name in names {
+        match DB::open(&context.server.root, &name).and_then(|db| match db {
+            Some(db) if db.is_closed() => {
+                closed.push(Json::String(name.clone()));
+                Ok(None)
+            }
+            Some(db) => db.stats().map(Some),
+            None => Ok(None),
+        }) {
+            Ok(Some(stats)) => databases.push(stats.to_json()),
+            Ok(None) => {}
+            Err(err) => {
+                return storage_error(err);
+            }
+        }
+    }
+    let mut obj = JsonObject::new();
+    obj["databases".to_string()] = Json::List(databases);
+    obj["closed".to_string()] = Json::List(closed);
+    obj["connections".to_string()] =
+        Json::Number(JsonNumber::Int(context.server.open_connections() as i64));
+    Response::json(HttpStatus::Ok, Json::Object(obj))
+}
+
+fn connections(context: &Context) -> Response {
+    let connections = context.server.connections();
+    let mut obj = JsonObject::new();
+    obj["count".to_string()] = Json::Number(JsonNumber::Int(connections.len() as i64));
+    obj["connections".to_string()] =
+        Json::List(connections.iter().map(|info| info.to_json()).collect());
+    Response::json(HttpStatus::Ok, Json::Object(obj))
+}
+
+fn list_databases(context: &Context) -> Response {
+    let names = match db::list(&context.server.root) {
+        Ok(names) => names,
+        Err(err) => {
+            return storage_error(err);
+        }
+    };
+    let mut databases = Vec::new();
+    for name in names {
+        match DB::open(&context.server.root, &name) {
+            Ok(Some(db)) => databases.push(database_status(&db)),
+            Ok(None) => {}
+            Err(err) => {
+                return storage_error(err);
+            }
+        }
+    }
+    let mut obj = JsonObject::new();
+    obj["databases".to_string()] = Json::List(databases);
+    Response::json(HttpStatus::Ok, Json::Object(obj))
+}
+
+fn database_status(db: &DB) -> Json {
+    let status = match db.is_closed() {
+        true => "closed",
+        false => "open",
+    };
+    let mut obj = JsonObject::new();
+    obj["name".to_string()] = Json::String(db.name().to_string());
+    obj["status".to_string()] = Json::String(status.to_string());
+    Json::Object(obj)
+}
+
+// Opening and closing are idempotent, and respond with the status of the database either way.
+// Opening a database loads its engine, which replays its log, so errors in its files show up
+// here rather than in the first request to it.
+fn open_database(context: &Context) -> Response {
+    let db = match api::database_files(context, "name") {
+        Ok(db) => db,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    match db.reopen().and_then(|_| db.engine()) {
+        Ok(_) => Response::json(HttpStatus::Ok, database_status(&db)),
+        Err(err) => storage_error(err),
+    }
+}
+
+fn close_database(context: &Context) -> Response {
+    let db = match api::database_files(context, "name") {
+        Ok(db) => db,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    db.close();
+    Response::json(HttpStatus::Ok, database_status(&db))
+}
+
+fn create_database(context: &Context) -> Response {
+    let name = match context.param::<String>("name") {
+        Ok(name) => name,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let password = match context.request.json() {
+        None => String::new(),
+        Some(Json::Object(obj)) => match obj.get("password") {
+            None => String::new(),
+            Some(Json::String(password)) => password.clone(),
+            Some(_) => {
+                return ApiError::bad_request("The password should be a string".to_string())
+                    .to_response();
+            }
+        },
+        Some(_) => {
+            return ApiError::bad_request(
+                "Expected a JSON object with an optional password field".to_string(),
+            )
+            .to_response();
+        }
+    };
+    if !db::is_valid_name(&name) {
+        return ApiError::bad_request(format!("The database name {} is invalid", name))
+            .to_response();
+    }
+    let root = Root::new(&context.server.root);
+    if let Err(err) = root.check_available(&name) {
+        return ApiError::conflict(err).to_response();
+    }
+    match root.create(&name, password) {
+        Ok(db) => {
+            let mut obj = JsonObject::new();
+            obj["name".to_string()] = Json::String(db.name().to_string());
+            obj["status".to_string()] = Json::String("created".to_string());
+            Response::json(HttpStatus::Created, Json::Object(obj))
+        }
+        Err(err) => storage_error(err),
+    }
+}
+
+fn change_password(context: &Context) -> Response {
+    let db = match api::database(context, "name") {
+        Ok(db) => db,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let field = |obj: &JsonObject, name: &str| match obj.get(name) {
+        Some(Json::String(value)) => Some(value.clone()),
+        _ => None,
+    };
+    let (current, password) = match context.request.json() {
+        Some(Json::Object(obj)) => match (field(obj, "current_password"), field(obj, "password")) {
+            (Some(current), Some(password)) => (current, password),
+            _ => {
+                return ApiError::bad_request(
+                    "Expected the current_password and password fields as strings".to_string(),
+                )
+                .to_response();
+            }
+        },
+        _ => {
+            return ApiError::bad_request(
+                "Expected a JSON object with the current_password and password fields".to_string(),
+            )
+            .to_response();
+        }
+    };
+    if db.manifest().encryption.is_none() {
+        return ApiError::conflict(format!(
+            "The database {} was created without a password",
+            db.name()
+        ))
+        .to_response();
+    }
+    if password.is_empty() {
+        return ApiError::bad_request("The new password should not be empty".to_string())
+            .to_response();
+    }
+    match db.change_password(&current, &password) {
+        Ok(true) => {
+            let mut obj = JsonObject::new();
+            obj["name".to_string()] = Json::String(db.name().to_string());
+            obj["status".to_string()] = Json::String("updated".to_string());
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Ok(false) => ApiError::new(
+            HttpStatus::Forbidden,
+            "The current password is incorrect".to_string(),
+        )
+        .to_response(),
+        Err(err) => storage_error(err),
+    }
+}
+
+// Closed databases can be dropped as well.
+fn drop_database(context: &Context) -> Response {
+    let db = match api::database_files(context, "name") {
+        Ok(db) => db,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let name = db.name().to_string();
+    match db.destroy() {
+        Ok(_) => {
+            let mut obj = JsonObject::new();
+            obj["name".to_string()] = Json::String(name);
+            obj["status".to_string()] = Json::String("dropped".to_string());
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Err(err) => storage_error(err),
+    }
+}
+
+fn database_stats(context: &Context) -> Response {
+    match api::database(context, "name").and_then(|db| db.stats().map_err(storage_error)) {
+        Ok(stats) => Response::json(HttpStatus::Ok, stats.to_json()),
+        Err(resp) => resp,
+    }
+}
+
+// Flushes the memtable first, so that documents deleted since the last flush are dropped from
+// the merged tables as well.
+fn compact(context: &Context) -> Response {
+    let compacted = api::database(context, "name").and_then(|db| {
+        db.flush()
+            .and_then(|_| db.compact(2))
+            .map_err(storage_error)
+    });
+    match compacted {
+        Ok(removed) => {
+            let mut obj = JsonObject::new();
+            obj["removed_files".to_string()] = Json::Number(JsonNumber::Int(removed.files as i64));
+            obj["reclaimed_bytes".to_string()] =
+                Json::Number(JsonNumber::Int(removed.bytes as i64));
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Err(resp) => resp,
+    }
+}
+
+fn backup(context: &Context) -> Response {
+    let db = match api::database(context, "name") {
+        Ok(db) => db,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let incremental = matches!(
+        context.request.query_value("incremental"),
+        Some("true" | "1")
+    );
+    match db.backup(&context.server.root, incremental) {
+        Ok(copied) => Response::json(HttpStatus::Created, copied.to_json()),
+        Err(err) => {
+            let mut details = JsonObject::new();
+            details["database".to_string()] = Json::String(db.name().to_string());
+            context.server.alerts.raise(
+                Alert::new(
+                    AlertKind::BackupFailed,
+                    format!("The backup of the database {} failed: {}", db.name(), err),
+                )
+                .with_details(Json::Object(details)),
+            );
+            storage_error(err)
+        }
+    }
+}
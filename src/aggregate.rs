@@ -0,0 +1,386 @@
+use std::{cmp::Ordering, collections::HashMap, ops::Bound};
+
+use crate::{
+    db::{DB, ID_FIELD},
+    json::{Json, JsonNumber, JsonObject},
+    query::{self, Filter, Projection, SortKey},
+};
+
+// A string starting with '$' refers to a field of the document, anything else is a constant.
+pub enum Expr {
+    Field(String),
+    Value(Json),
+}
+
+impl Expr {
+    fn parse(json: &Json) -> Expr {
+        match json {
+            Json::String(value) if value.len() > 1 && value.starts_with('$') => {
+                Expr::Field(value[1..].to_string())
+            }
+            value => Expr::Value(value.clone()),
+        }
+    }
+
+    fn eval(&self, document: &Json) -> Option<Json> {
+        match self {
+            Expr::Field(path) => query::lookup(document, path).cloned(),
+            Expr::Value(value) => Some(value.clone()),
+        }
+    }
+}
+
+pub enum Accumulator {
+    Count,
+    Sum(Expr),
+    Avg(Expr),
+    Min(Expr),
+    Max(Expr),
+}
+
+impl Accumulator {
+    fn parse(field: &str, json: &Json) -> Result<Accumulator, String> {
+        let operator = match json {
+            Json::Object(obj) if obj.len() == 1 => obj.iter().next().unwrap(),
+            _ => {
+                return Err(format!(
+                    "The group field {} should be an object with a single accumulator such as {{\"$sum\": \"$amount\"}}",
+                    field
+                ));
+            }
+        };
+        Ok(match operator {
+            (op, _) if op == "$count" => Accumulator::Count,
+            (op, expr) if op == "$sum" => Accumulator::Sum(Expr::parse(expr)),
+            (op, expr) if op == "$avg" => Accumulator::Avg(Expr::parse(expr)),
+            (op, expr) if op == "$min" => Accumulator::Min(Expr::parse(expr)),
+            (op, expr) if op == "$max" => Accumulator::Max(Expr::parse(expr)),
+            (op, _) => {
+                return Err(format!(
+                    "Unknown accumulator {} in the group field {}",
+                    op, field
+                ));
+            }
+        })
+    }
+
+    fn start(&self) -> Total {
+        match self {
+            Accumulator::Count => Total::Count(0),
+            Accumulator::Sum(_) => Total::Sum(Sum::Int(0)),
+            Accumulator::Avg(_) => Total::Avg(0.0, 0),
+            Accumulator::Min(_) | Accumulator::Max(_) => Total::Extreme(None),
+        }
+    }
+}
+
+enum Sum {
+    Int(i64),
+    Float(f64),
+}
+
+enum Total {
+    Count(u64),
+    Sum(Sum),
+    Avg(f64, u64),
+    Extreme(Option<Json>),
+}
+
+impl Total {
+    // Values that are not numbers are left out of sums and averages.
+    fn add(&mut self, accumulator: &Accumulator, document: &Json) {
+        match (self, accumulator) {
+            (Total::Count(count), _) => *count += 1,
+            (Total::Sum(sum), Accumulator::Sum(expr)) => match (&*sum, expr.eval(document)) {
+                (Sum::Int(total), Some(Json::Number(JsonNumber::Int(value)))) => {
+                    *sum = match total.checked_add(value) {
+                        Some(total) => Sum::Int(total),
+                        None => Sum::Float(*total as f64 + value as f64),
+                    };
+                }
+                (Sum::Int(total), Some(Json::Number(value))) => {
+                    *sum = Sum::Float(*total as f64 + query::number(&value));
+                }
+                (Sum::Float(total), Some(Json::Number(value))) => {
+                    *sum = Sum::Float(total + query::number(&value));
+                }
+                _ => {}
+            },
+            (Total::Avg(total, count), Accumulator::Avg(expr)) => {
+                if let Some(Json::Number(value)) = expr.eval(document) {
+                    *total += query::number(&value);
+                    *count += 1;
+                }
+            }
+            (Total::Extreme(extreme), Accumulator::Min(expr) | Accumulator::Max(expr)) => {
+                let Some(value) = expr.eval(document) else {
+                    return;
+                };
+                let wanted = match accumulator {
+                    Accumulator::Min(_) => Ordering::Less,
+                    _ => Ordering::Greater,
+                };
+                if extreme.as_ref().is_none_or(|current| {
+                    query::sort_compare(Some(&value), Some(current)) == wanted
+                }) {
+                    *extreme = Some(value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn result(self) -> Json {
+        match self {
+            Total::Count(count) => Json::Number(JsonNumber::Int(count as i64)),
+            Total::Sum(Sum::Int(total)) => Json::Number(JsonNumber::Int(total)),
+            Total::Sum(Sum::Float(total)) => Json::Number(JsonNumber::Float(total)),
+            Total::Avg(_, 0) => Json::Null,
+            Total::Avg(total, count) => Json::Number(JsonNumber::Float(total / count as f64)),
+            Total::Extreme(extreme) => extreme.unwrap_or(Json::Null),
+        }
+    }
+}
+
+// The key of a group is null for a single group over every document, an expression, or an
+// object of expressions.
+pub enum GroupKey {
+    Expr(Expr),
+    Fields(Vec<(String, Expr)>),
+}
+
+impl GroupKey {
+    fn eval(&self, document: &Json) -> Json {
+        match self {
+            GroupKey::Expr(expr) => expr.eval(document).unwrap_or(Json::Null),
+            GroupKey::Fields(fields) => {
+                let mut obj = JsonObject::new();
+                for (field, expr) in fields {
+                    obj[field.clone()] = expr.eval(document).unwrap_or(Json::Null);
+                }
+                Json::Object(obj)
+            }
+        }
+    }
+}
+
+pub struct Group {
+    pub key: GroupKey,
+    pub fields: Vec<(String, Accumulator)>,
+}
+
+impl Group {
+    fn parse(json: &Json) -> Result<Group, String> {
+        let Json::Object(obj) = json else {
+            return Err("The $group stage should be an object".to_string());
+        };
+        let key = match obj.get(ID_FIELD) {
+            Some(Json::Object(fields)) => GroupKey::Fields(
+                query::sorted(fields)
+                    .into_iter()
+                    .map(|(field, expr)| (field.clone(), Expr::parse(expr)))
+                    .collect(),
+            ),
+            Some(expr) => GroupKey::Expr(Expr::parse(expr)),
+            None => {
+                return Err("The $group stage needs an _id with the key to group by".to_string());
+            }
+        };
+        let fields = query::sorted(obj)
+            .into_iter()
+            .filter(|(field, _)| *field != ID_FIELD)
+            .map(|(field, json)| Ok((field.clone(), Accumulator::parse(field, json)?)))
+            .collect::<Result<_, String>>()?;
+        Ok(Group { key, fields })
+    }
+}
+
+// Groups come out in the order their first document was seen.
+struct Groups<'a> {
+    group: &'a Group,
+    index: HashMap<String, usize>,
+    groups: Vec<(Json, Vec<Total>)>,
+}
+
+impl<'a> Groups<'a> {
+    fn new(group: &'a Group) -> Groups<'a> {
+        Groups {
+            group,
+            index: HashMap::new(),
+            groups: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, document: &Json) {
+        let key = self.group.key.eval(document);
+        let ind = *self.index.entry(key.canonical()).or_insert_with(|| {
+            let totals = self
+                .group
+                .fields
+                .iter()
+                .map(|(_, accumulator)| accumulator.start())
+                .collect();
+            self.groups.push((key, totals));
+            self.groups.len() - 1
+        });
+        let totals = &mut self.groups[ind].1;
+        for ((_, accumulator), total) in self.group.fields.iter().zip(totals.iter_mut()) {
+            total.add(accumulator, document);
+        }
+    }
+
+    fn finish(self) -> Vec<Json> {
+        self.groups
+            .into_iter()
+            .map(|(key, totals)| {
+                let mut obj = JsonObject::new();
+                obj[ID_FIELD.to_string()] = key;
+                for ((field, _), total) in self.group.fields.iter().zip(totals) {
+                    obj[field.clone()] = total.result();
+                }
+                Json::Object(obj)
+            })
+            .collect()
+    }
+}
+
+pub enum Stage {
+    Match(Filter),
+    Group(Group),
+    Project(Projection),
+    Sort(Vec<SortKey>),
+    Skip(usize),
+    Limit(usize),
+}
+
+impl Stage {
+    fn parse(json: &Json) -> Result<Stage, String> {
+        let (name, spec) = match json {
+            Json::Object(obj) if obj.len() == 1 => obj.iter().next().unwrap(),
+            _ => {
+                return Err(
+                    "Each stage of the pipeline should be an object with a single stage name"
+                        .to_string(),
+                );
+            }
+        };
+        let count = || match spec {
+            Json::Number(JsonNumber::Int(count)) if *count >= 0 => Ok(*count as usize),
+            _ => Err(format!(
+                "The {} stage should be a non-negative integer",
+                name
+            )),
+        };
+        Ok(match name.as_str() {
+            "$match" => Stage::Match(Filter::parse(spec)?),
+            "$group" => Stage::Group(Group::parse(spec)?),
+            "$project" => Stage::Project(Projection::parse(spec)?),
+            "$sort" => Stage::Sort(match spec {
+                Json::List(keys) => keys.iter().map(SortKey::parse).collect::<Result<_, _>>()?,
+                key => vec![SortKey::parse(key)?],
+            }),
+            "$skip" => Stage::Skip(count()?),
+            "$limit" => Stage::Limit(count()?),
+            name => {
+                return Err(format!("Unknown pipeline stage {}", name));
+            }
+        })
+    }
+
+    fn apply(&self, documents: Vec<Json>) -> Vec<Json> {
+        match self {
+            Stage::Match(filter) => documents
+                .into_iter()
+                .filter(|document| filter.matches(document))
+                .collect(),
+            Stage::Group(group) => {
+                let mut groups = Groups::new(group);
+                for document in &documents {
+                    groups.add(document);
+                }
+                groups.finish()
+            }
+            Stage::Project(projection) => documents
+                .iter()
+                .map(|document| projection.apply(document))
+                .collect(),
+            Stage::Sort(keys) => {
+                let mut documents = documents;
+                documents.sort_by(|a, b| {
+                    keys.iter()
+                        .map(|key| {
+                            let order = query::sort_compare(
+                                query::lookup(a, &key.path),
+                                query::lookup(b, &key.path),
+                            );
+                            match key.descending {
+                                true => order.reverse(),
+                                false => order,
+                            }
+                        })
+                        .find(|order| order.is_ne())
+                        .unwrap_or(Ordering::Equal)
+                });
+                documents
+            }
+            Stage::Skip(skip) => documents.into_iter().skip(*skip).collect(),
+            Stage::Limit(limit) => documents.into_iter().take(*limit).collect(),
+        }
+    }
+}
+
+pub struct Pipeline {
+    pub stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    pub fn parse(body: Option<&Json>) -> Result<Pipeline, String> {
+        let stages = match body {
+            Some(Json::Object(obj)) if obj.len() == 1 => obj.get("pipeline"),
+            _ => None,
+        };
+        let Some(Json::List(stages)) = stages else {
+            return Err(
+                "Expected a JSON object with the pipeline field as a list of stages".to_string(),
+            );
+        };
+        Ok(Pipeline {
+            stages: stages.iter().map(Stage::parse).collect::<Result<_, _>>()?,
+        })
+    }
+
+    // The leading $match stages, and a $group right after them, are applied while the
+    // collection is scanned, so grouping never holds more than one document per group.
+    pub fn run(&self, db: &DB, collection: &str) -> Result<Vec<Json>, String> {
+        let matches = self
+            .stages
+            .iter()
+            .take_while(|stage| matches!(stage, Stage::Match(_)))
+            .count();
+        let mut groups = match self.stages.get(matches) {
+            Some(Stage::Group(group)) => Some(Groups::new(group)),
+            _ => None,
+        };
+        let mut documents = Vec::new();
+        for document in db.scan(collection, (Bound::Unbounded, Bound::Unbounded))? {
+            let document = document?;
+            let matched = self.stages[..matches].iter().all(|stage| match stage {
+                Stage::Match(filter) => filter.matches(&document),
+                _ => true,
+            });
+            match (&mut groups, matched) {
+                (_, false) => {}
+                (Some(groups), true) => groups.add(&document),
+                (None, true) => documents.push(document),
+            }
+        }
+        let mut rest = &self.stages[matches..];
+        if let Some(groups) = groups {
+            documents = groups.finish();
+            rest = &rest[1..];
+        }
+        Ok(rest
+            .iter()
+            .fold(documents, |documents, stage| stage.apply(documents)))
+    }
+}
@@ -0,0 +1,500 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    io::{BufRead, BufReader, Read, Write},
+    net::{IpAddr, TcpStream},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+
+use crate::{
+    json::{Json, JsonNumber, JsonObject},
+    server::Server,
+    service::Service,
+    tls,
+    types::ID,
+};
+
+pub const DEFAULT_ALERT_THROTTLE: u64 = 900;
+pub const DEFAULT_DISK_LOW_BYTES: u64 = 1 << 30;
+pub const DEFAULT_AUTH_FAILURES: usize = 100;
+const AUTH_FAILURE_WINDOW: Duration = Duration::from_secs(60);
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum AlertKind {
+    DiskLow,
+    BackupFailed,
+    AuthFailures,
+    IdRollover,
+}
+
+impl Display for AlertKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlertKind::DiskLow => write!(f, "disk-low"),
+            AlertKind::BackupFailed => write!(f, "backup-failed"),
+            AlertKind::AuthFailures => write!(f, "auth-failures"),
+            AlertKind::IdRollover => write!(f, "id-rollover"),
+        }
+    }
+}
+
+pub struct Alert {
+    pub kind: AlertKind,
+    pub message: String,
+    pub details: Json,
+}
+
+impl Alert {
+    pub fn new(kind: AlertKind, message: String) -> Alert {
+        Alert {
+            kind,
+            message,
+            details: Json::None,
+        }
+    }
+
+    pub fn with_details(mut self, details: Json) -> Alert {
+        self.details = details;
+        self
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["event".to_string()] = Json::String(self.kind.to_string());
+        obj["message".to_string()] = Json::String(self.message.clone());
+        obj["details".to_string()] = self.details.clone();
+        obj["time".to_string()] = Json::String(Utc::now().to_rfc3339());
+        Json::Object(obj)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct WebhookUrl {
+    pub secure: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl WebhookUrl {
+    // The host as it is written in a URL or a Host header.
+    pub fn authority_host(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        }
+    }
+}
+
+impl FromStr for WebhookUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (secure, rest) = if let Some(rest) = s.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = s.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(format!(
+                "The URL {} should start with http:// or https://",
+                s
+            ));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        // IPv6 literals are written in brackets, since they contain colons themselves.
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, rest) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| format!("The IPv6 address of the URL {} is not closed", s))?;
+                match rest {
+                    "" => (host, None),
+                    _ => match rest.strip_prefix(':') {
+                        Some(port) => (host, Some(port)),
+                        None => {
+                            return Err(format!("The port of the URL {} is invalid", s));
+                        }
+                    },
+                }
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port
+                .parse::<u16>()
+                .map_err(|_| format!("The port of the URL {} is invalid", s))?,
+            None => {
+                if secure {
+                    443
+                } else {
+                    80
+                }
+            }
+        };
+        if host.is_empty() {
+            return Err(format!("The URL {} does not have a host", s));
+        }
+        Ok(WebhookUrl {
+            secure,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum AlertSink {
+    Webhook(WebhookUrl),
+    Email {
+        server: String,
+        from: String,
+        to: Vec<String>,
+    },
+}
+
+impl AlertSink {
+    pub fn deliver(&self, alert: &Alert) -> Result<(), String> {
+        match self {
+            AlertSink::Webhook(url) => post_json(url, &alert.to_json().to_string()),
+            AlertSink::Email { server, from, to } => send_email(server, from, to, alert),
+        }
+    }
+}
+
+impl Display for AlertSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlertSink::Webhook(url) => write!(
+                f,
+                "webhook {}://{}:{}{}",
+                if url.secure { "https" } else { "http" },
+                url.authority_host(),
+                url.port,
+                url.path
+            ),
+            AlertSink::Email { server, to, .. } => {
+                write!(f, "email to {} via {}", to.join(", "), server)
+            }
+        }
+    }
+}
+
+fn connect(host: &str, port: u16) -> Result<TcpStream, String> {
+    let stream = TcpStream::connect((host, port))
+        .map_err(|err| format!("Could not connect to {}:{}: {}", host, port, err))?;
+    let _ = stream.set_read_timeout(Some(DELIVERY_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(DELIVERY_TIMEOUT));
+    Ok(stream)
+}
+
+pub fn post_json(url: &WebhookUrl, body: &str) -> Result<(), String> {
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: db6\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        url.path,
+        url.authority_host(),
+        body.len(),
+        body
+    );
+    let stream = connect(&url.host, url.port)?;
+    let mut response = Vec::<u8>::new();
+    let result = if url.secure {
+        let mut tls_stream = tls::connect(&url.host, stream)?;
+        tls_stream
+            .write_all(request.as_bytes())
+            .and_then(|_| tls_stream.read_to_end(&mut response))
+    } else {
+        let mut stream = stream;
+        stream
+            .write_all(request.as_bytes())
+            .and_then(|_| stream.read_to_end(&mut response))
+    };
+    if let Err(err) = result
+        && response.is_empty()
+    {
+        return Err(format!("Error while posting to {}: {}", url.host, err));
+    }
+    let status_line = String::from_utf8_lossy(&response)
+        .lines()
+        .next()
+        .unwrap_or("")
+        .to_string();
+    match status_line.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(format!("{} answered with {}", url.host, status_line)),
+    }
+}
+
+fn smtp_reply(reader: &mut impl BufRead, expected: char) -> Result<(), String> {
+    loop {
+        let mut line = String::new();
+        reader
+            .read_line(&mut line)
+            .map_err(|err| format!("Error while reading the SMTP reply: {}", err))?;
+        if !line.starts_with(expected) {
+            return Err(format!("Unexpected SMTP reply: {}", line.trim_end()));
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+fn send_email(server: &str, from: &str, to: &[String], alert: &Alert) -> Result<(), String> {
+    let (host, port) = match server.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse::<u16>()
+                .map_err(|_| format!("The port of the SMTP server {} is invalid", server))?,
+        ),
+        None => (server, 25),
+    };
+    let stream = connect(host, port)?;
+    let mut writer = stream
+        .try_clone()
+        .map_err(|err| format!("Error while setting up the SMTP session: {}", err))?;
+    let mut reader = BufReader::new(stream);
+    smtp_reply(&mut reader, '2')?;
+    let mut command = |line: String, expected: char| -> Result<(), String> {
+        writer
+            .write_all((line + "\r\n").as_bytes())
+            .map_err(|err| format!("Error while talking to the SMTP server {}: {}", server, err))?;
+        smtp_reply(&mut reader, expected)
+    };
+    command("EHLO db6".to_string(), '2')?;
+    command(format!("MAIL FROM:<{}>", from), '2')?;
+    for recipient in to {
+        command(format!("RCPT TO:<{}>", recipient), '2')?;
+    }
+    command("DATA".to_string(), '3')?;
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: [db6] {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+        from,
+        to.join(", "),
+        alert.kind,
+        Utc::now().to_rfc2822(),
+        alert.message
+    );
+    if !matches!(alert.details, Json::None) {
+        message += &format!("\r\n{}\r\n", alert.details);
+    }
+    let body = message
+        .lines()
+        .map(|line| match line.strip_prefix('.') {
+            Some(_) => ".".to_string() + line,
+            None => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n");
+    command(body + "\r\n.", '2')?;
+    command("QUIT".to_string(), '2')
+}
+
+pub struct AlertConfig {
+    pub sinks: Vec<AlertSink>,
+    pub throttle: Duration,
+    pub disk_low_bytes: u64,
+    // The number of 401 and 403 responses within a minute that raises an alert.
+    pub auth_failures: usize,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AlertConfig {
+    pub fn new() -> AlertConfig {
+        AlertConfig {
+            sinks: Vec::new(),
+            throttle: Duration::from_secs(DEFAULT_ALERT_THROTTLE),
+            disk_low_bytes: DEFAULT_DISK_LOW_BYTES,
+            auth_failures: DEFAULT_AUTH_FAILURES,
+        }
+    }
+}
+
+pub struct Alerter {
+    pub config: AlertConfig,
+    last_sent: Mutex<HashMap<AlertKind, Instant>>,
+    // The times of the latest authentication failures, at most as many as raise an alert.
+    auth_failures: Mutex<VecDeque<Instant>>,
+}
+
+impl Alerter {
+    pub fn new(config: AlertConfig) -> Alerter {
+        Alerter {
+            config,
+            last_sent: Mutex::new(HashMap::new()),
+            auth_failures: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.config.sinks.is_empty()
+    }
+
+    pub fn raise(&self, alert: Alert) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        {
+            let mut last_sent = self.last_sent.lock().unwrap();
+            if let Some(sent_at) = last_sent.get(&alert.kind)
+                && sent_at.elapsed() < self.config.throttle
+            {
+                return false;
+            }
+            last_sent.insert(alert.kind, Instant::now());
+        }
+        let sinks = self.config.sinks.clone();
+        thread::spawn(move || {
+            for sink in &sinks {
+                if let Err(err) = sink.deliver(&alert) {
+                    eprintln!(
+                        "Could not deliver the {} alert with {}: {}",
+                        alert.kind, sink, err
+                    );
+                }
+            }
+        });
+        true
+    }
+
+    // Counts a request that was refused for its credentials, and raises an alert once there
+    // were enough of them within a minute.
+    pub fn auth_failed(&self, client: Option<IpAddr>) {
+        if !self.is_enabled() || self.config.auth_failures == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let failures = {
+            let mut failures = self.auth_failures.lock().unwrap();
+            while failures
+                .front()
+                .is_some_and(|failed| now.duration_since(*failed) >= AUTH_FAILURE_WINDOW)
+                || failures.len() >= self.config.auth_failures
+            {
+                failures.pop_front();
+            }
+            failures.push_back(now);
+            failures.len()
+        };
+        if failures < self.config.auth_failures {
+            return;
+        }
+        let mut details = JsonObject::new();
+        details["failures".to_string()] = Json::Number(JsonNumber::Int(failures as i64));
+        details["window_seconds".to_string()] =
+            Json::Number(JsonNumber::Int(AUTH_FAILURE_WINDOW.as_secs() as i64));
+        if let Some(client) = client {
+            details["last_client".to_string()] = Json::String(client.to_string());
+        }
+        self.raise(
+            Alert::new(
+                AlertKind::AuthFailures,
+                format!(
+                    "{} requests were refused for their credentials within {} seconds",
+                    failures,
+                    AUTH_FAILURE_WINDOW.as_secs()
+                ),
+            )
+            .with_details(Json::Object(details)),
+        );
+    }
+}
+
+pub fn check_disk(alerter: &Alerter, root: &Path) {
+    match fs4::available_space(root) {
+        Ok(available) if available < alerter.config.disk_low_bytes => {
+            let mut details = JsonObject::new();
+            details["path".to_string()] = Json::String(root.to_string_lossy().to_string());
+            details["available_bytes".to_string()] =
+                Json::Number(JsonNumber::Int(available as i64));
+            details["threshold_bytes".to_string()] =
+                Json::Number(JsonNumber::Int(alerter.config.disk_low_bytes as i64));
+            alerter.raise(
+                Alert::new(
+                    AlertKind::DiskLow,
+                    format!(
+                        "Only {} bytes of disk space are left for the databases in {}",
+                        available,
+                        root.display()
+                    ),
+                )
+                .with_details(Json::Object(details)),
+            );
+        }
+        Ok(_) => {}
+        Err(err) => {
+            eprintln!(
+                "Could not check the available disk space of {}: {}",
+                root.display(),
+                err
+            );
+        }
+    }
+}
+
+// Raises an alert when the ID space extends by a segment. Only the alerter of the last server
+// that listens gets them.
+pub fn watch_ids(alerter: &Arc<Alerter>) {
+    let alerter = Arc::downgrade(alerter);
+    ID::on_rollover(move |segments| {
+        let Some(alerter) = Weak::upgrade(&alerter) else {
+            return;
+        };
+        let mut details = JsonObject::new();
+        details["segments".to_string()] = Json::Number(JsonNumber::Int(segments as i64));
+        alerter.raise(
+            Alert::new(
+                AlertKind::IdRollover,
+                format!(
+                    "The ID space rolled over, IDs are now {} segments long",
+                    segments
+                ),
+            )
+            .with_details(Json::Object(details)),
+        );
+    });
+}
+
+pub struct DiskMonitor;
+
+impl Service for DiskMonitor {
+    fn name(&self) -> &str {
+        "disk-monitor"
+    }
+
+    fn start(&self, server: &Server) -> Result<(), String> {
+        monitor_disk(server.alerts.clone(), PathBuf::from(&server.root));
+        Ok(())
+    }
+}
+
+pub fn monitor_disk(alerter: Arc<Alerter>, root: PathBuf) {
+    if !alerter.is_enabled() {
+        return;
+    }
+    thread::spawn(move || {
+        loop {
+            check_disk(&alerter, &root);
+            thread::sleep(DISK_CHECK_INTERVAL);
+        }
+    });
+}
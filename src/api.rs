@@ -0,0 +1,1426 @@
+use std::{io::BufWriter, time::Duration};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    aggregate::Pipeline,
+    changes::{self, Tail},
+    db::{self, Commit, DB, ID_FIELD, Mutation, SoftDelete, WriteError, WriteMode},
+    error::ApiError,
+    etag,
+    export::{self, Export},
+    http::{ChunkedWriter, ContentType, HttpMethod, HttpStatus, Request, Response},
+    import::{self, DEFAULT_BATCH_SIZE, ImportOptions},
+    infer,
+    json::{Json, JsonNumber, JsonObject},
+    patch::{FindAndModify, Patch},
+    query::{self, Lint, Query},
+    router::{Context, Router},
+    schema::{Schema, Validation},
+    sse,
+    sstable::Codec,
+    websocket,
+};
+
+const COLLECTIONS: &str = "/dbs/:db/collections";
+const COLLECTION: &str = "/dbs/:db/collections/:col";
+const SCHEMA: &str = "/dbs/:db/collections/:col/schema";
+const INFERRED_SCHEMA: &str = "/dbs/:db/collections/:col/schema/inferred";
+const DOCUMENTS: &str = "/dbs/:db/collections/:col/docs";
+const DOCUMENT: &str = "/dbs/:db/collections/:col/docs/:id";
+const INCREMENT: &str = "/dbs/:db/collections/:col/docs/:id/increment";
+const RESTORE: &str = "/dbs/:db/collections/:col/docs/:id/restore";
+const PURGE: &str = "/dbs/:db/collections/:col/purge";
+const QUERY: &str = "/dbs/:db/collections/:col/query";
+const FIND_AND_MODIFY: &str = "/dbs/:db/collections/:col/find-and-modify";
+const AGGREGATE: &str = "/dbs/:db/collections/:col/aggregate";
+const CHANGES: &str = "/dbs/:db/collections/:col/changes";
+const IMPORT: &str = "/dbs/:db/collections/:col/import";
+const EXPORT: &str = "/dbs/:db/collections/:col/export";
+const COLLECTION_HASH: &str = "/dbs/:db/collections/:col/hash";
+const DOCUMENT_HASH: &str = "/dbs/:db/collections/:col/docs/:id/hash";
+const SAMPLE: &str = "/dbs/:db/collections/:col/sample";
+const TRANSACTION: &str = "/dbs/:db/transaction";
+const STATS: &str = "/dbs/:db/stats";
+const DEFAULT_CHANGES_LIMIT: u64 = 100;
+const MAX_CHANGES_LIMIT: u64 = 1000;
+const DEFAULT_POLL_TIMEOUT: u64 = 30;
+const MAX_POLL_TIMEOUT: u64 = 60;
+const DEFAULT_SAMPLE_SIZE: u64 = 50;
+const MAX_SAMPLE_SIZE: u64 = 1000;
+
+pub fn register(router: &mut Router) {
+    router.add(HttpMethod::GET, "/dbs", list_databases);
+    router.add(HttpMethod::GET, STATS, stats);
+    router.add(HttpMethod::GET, COLLECTIONS, list_collections);
+    router.add(HttpMethod::GET, COLLECTION, get_collection);
+    router
+        .add(HttpMethod::PUT, COLLECTION, create_collection)
+        .accepts(ContentType::ApplicationJson);
+    router.add(HttpMethod::DELETE, COLLECTION, drop_collection);
+    router.add(HttpMethod::GET, SCHEMA, get_schema);
+    router
+        .add(HttpMethod::PUT, SCHEMA, set_schema)
+        .accepts(ContentType::ApplicationJson);
+    router.add(HttpMethod::DELETE, SCHEMA, remove_schema);
+    router
+        .add(HttpMethod::GET, INFERRED_SCHEMA, infer_schema)
+        .query_param("sample");
+    router
+        .add(HttpMethod::GET, DOCUMENT, get_document)
+        .query_param("deleted");
+    router
+        .add(HttpMethod::PUT, DOCUMENT, put_document)
+        .accepts(ContentType::ApplicationJson)
+        .query_param("mode");
+    router
+        .add(HttpMethod::PATCH, DOCUMENT, patch_document)
+        .accepts(ContentType::ApplicationJson);
+    router
+        .add(HttpMethod::DELETE, DOCUMENT, delete_document)
+        .query_param("purge");
+    router.add(HttpMethod::POST, RESTORE, restore_document);
+    router
+        .add(HttpMethod::POST, PURGE, purge)
+        .accepts(ContentType::ApplicationJson);
+    router
+        .add(HttpMethod::POST, DOCUMENTS, insert_document)
+        .accepts(ContentType::ApplicationJson);
+    router
+        .add(HttpMethod::POST, QUERY, query)
+        .accepts(ContentType::ApplicationJson);
+    router
+        .add(HttpMethod::POST, INCREMENT, increment)
+        .accepts(ContentType::ApplicationJson);
+    router
+        .add(HttpMethod::POST, FIND_AND_MODIFY, find_and_modify)
+        .accepts(ContentType::ApplicationJson);
+    router
+        .add(HttpMethod::POST, AGGREGATE, aggregate)
+        .accepts(ContentType::ApplicationJson);
+    router
+        .add(HttpMethod::GET, CHANGES, changes)
+        .query_param("since")
+        .query_param("limit")
+        .query_param("timeout")
+        .query_param("delta");
+    router
+        .add(HttpMethod::POST, IMPORT, import)
+        .accepts(ContentType::ApplicationNdjson)
+        .query_param("batch_size")
+        .query_param("compact")
+        .streaming();
+    router
+        .add(HttpMethod::GET, EXPORT, export)
+        .query_param("format")
+        .query_param("filter")
+        .query_param("fields")
+        .query_param("include_deleted");
+    router.add(HttpMethod::GET, COLLECTION_HASH, collection_hash);
+    router.add(HttpMethod::GET, DOCUMENT_HASH, document_hash);
+    router.add(HttpMethod::GET, SAMPLE, sample).query_param("n");
+    router
+        .add(HttpMethod::POST, TRANSACTION, transaction)
+        .accepts(ContentType::ApplicationJson);
+}
+
+struct Collection {
+    db: DB,
+    database: String,
+    name: String,
+}
+
+pub fn publish(database: &str, collection: &str, change: &str, id: Option<&str>) {
+    let mut event = JsonObject::new();
+    event["type".to_string()] = Json::String(change.to_string());
+    event["collection".to_string()] = Json::String(collection.to_string());
+    if let Some(id) = id {
+        event[ID_FIELD.to_string()] = Json::String(id.to_string());
+    }
+    changes::publish(database, &Json::Object(event));
+}
+
+impl Collection {
+    fn publish(&self, change: &str, id: Option<&str>) {
+        publish(&self.database, &self.name, change, id);
+    }
+
+    fn not_found(&self) -> Response {
+        ApiError::not_found(format!(
+            "The collection {} does not exist in the database {}",
+            self.name, self.database
+        ))
+        .to_response()
+    }
+}
+
+pub fn storage_error(err: String) -> Response {
+    ApiError::internal(err)
+        .with_code("storage_error")
+        .to_response()
+}
+
+fn write_error(err: WriteError) -> Response {
+    match err {
+        WriteError::Rejected(reason) => ApiError::new(HttpStatus::UnprocessableContent, reason)
+            .with_code("write_rejected")
+            .to_response(),
+        WriteError::Invalid(reason, violations) => {
+            ApiError::new(HttpStatus::UnprocessableContent, reason)
+                .with_code("schema_violation")
+                .with_details(Json::List(
+                    violations
+                        .iter()
+                        .map(|violation| violation.to_json())
+                        .collect(),
+                ))
+                .to_response()
+        }
+        WriteError::Exists(reason) => ApiError::conflict(reason)
+            .with_code("document_exists")
+            .to_response(),
+        WriteError::Missing(reason) => ApiError::not_found(reason).to_response(),
+        WriteError::Failed(reason) => ApiError::new(HttpStatus::PreconditionFailed, reason)
+            .with_code("precondition_failed")
+            .to_response(),
+        WriteError::Storage(err) => storage_error(err),
+    }
+}
+
+type Check<'a> = Box<dyn Fn(Option<&Json>) -> Result<(), String> + 'a>;
+
+// Writes with If-Match or If-None-Match headers check them against the ETag of the current
+// document under the lock of the write, so no other write can land in between.
+fn preconditions(request: &Request) -> Option<Check<'_>> {
+    if request.if_match.is_none() && request.if_none_match.is_none() {
+        return None;
+    }
+    Some(Box::new(move |current| {
+        etag::check_preconditions(request, current.map(etag::document_etag).as_deref())
+    }))
+}
+
+fn write_mode(value: Option<&str>) -> Result<WriteMode, String> {
+    match value {
+        None => Ok(WriteMode::Upsert),
+        Some(value) => WriteMode::parse(value).ok_or_else(|| {
+            format!(
+                "The write mode {} is invalid. Expected upsert, create or replace",
+                value
+            )
+        }),
+    }
+}
+
+fn valid_param(context: &Context, name: &str, kind: &str) -> Result<String, Response> {
+    let value = context.param::<String>(name)?;
+    if !db::is_valid_name(&value) {
+        return Err(ApiError::bad_request(format!(
+            "The {} {} is invalid. Names may contain letters, digits, '-', '_' and '.', and may not start with '.'",
+            kind, value
+        ))
+        .to_response());
+    }
+    Ok(value)
+}
+
+pub fn database(context: &Context, param: &str) -> Result<DB, Response> {
+    let db = database_files(context, param)?;
+    if db.is_closed() {
+        return Err(
+            ApiError::conflict(format!("The database {} is closed", db.name()))
+                .with_code("database_closed")
+                .to_response(),
+        );
+    }
+    Ok(db)
+}
+
+// Finds the database whether it is open or closed.
+pub fn database_files(context: &Context, param: &str) -> Result<DB, Response> {
+    let name = context.param::<String>(param)?;
+    match DB::open(&context.server.root, &name) {
+        Ok(Some(db)) => Ok(db),
+        Ok(None) => {
+            Err(ApiError::not_found(format!("The database {} does not exist", name)).to_response())
+        }
+        Err(err) => Err(storage_error(err)),
+    }
+}
+
+fn collection(context: &Context) -> Result<Collection, Response> {
+    let db = database(context, "db")?;
+    Ok(Collection {
+        database: db.name().to_string(),
+        db,
+        name: valid_param(context, "col", "collection name")?,
+    })
+}
+
+fn document_body<'a>(context: &Context<'a>) -> Result<&'a Json, Response> {
+    match context.request.json() {
+        Some(document @ Json::Object(_)) => match db::expiry(document) {
+            Ok(_) => Ok(document),
+            Err(err) => Err(ApiError::bad_request(err).to_response()),
+        },
+        _ => Err(
+            ApiError::bad_request("Expected the document as a JSON object".to_string())
+                .to_response(),
+        ),
+    }
+}
+
+// The settings a collection is put with. Settings that are left out keep their value.
+#[derive(Default)]
+struct CollectionSettings {
+    ttl: Option<Option<u64>>,
+    soft_delete: Option<Option<SoftDelete>>,
+    compression: Option<Codec>,
+}
+
+// The TTL of a collection is given as {"ttl": seconds}, where null removes it. Soft deletes are
+// turned on with {"soft_delete": true} or {"soft_delete": {"retention": seconds}}, and off with
+// false or null. Tables are compressed with {"compression": "lz4" | "zstd"}, and no longer with
+// "none" or null.
+fn collection_settings(context: &Context) -> Result<Option<CollectionSettings>, Response> {
+    let bad_request = |message: &str| ApiError::bad_request(message.to_string()).to_response();
+    let obj = match context.request.json() {
+        None => return Ok(None),
+        Some(Json::Object(obj)) if !obj.is_empty() => obj,
+        Some(_) => {
+            return Err(bad_request(
+                "Expected a JSON object with the ttl, soft_delete or compression fields",
+            ));
+        }
+    };
+    let mut settings = CollectionSettings::default();
+    for (field, value) in obj.iter() {
+        match (field.as_str(), value) {
+            ("ttl", Json::Null) => settings.ttl = Some(None),
+            ("ttl", Json::Number(JsonNumber::Int(ttl))) if *ttl > 0 => {
+                settings.ttl = Some(Some(*ttl as u64));
+            }
+            ("ttl", _) => {
+                return Err(bad_request(
+                    "The ttl field should be a positive number of seconds or null",
+                ));
+            }
+            ("soft_delete", Json::Null | Json::Bool(false)) => settings.soft_delete = Some(None),
+            ("soft_delete", Json::Bool(true)) => {
+                settings.soft_delete = Some(Some(SoftDelete { retention: None }));
+            }
+            ("soft_delete", value) => match SoftDelete::from_json(value) {
+                Some(soft_delete) => settings.soft_delete = Some(Some(soft_delete)),
+                None => {
+                    return Err(bad_request(
+                        "The soft_delete field should be true, false, null or an object with \
+                         the retention field as a positive number of seconds",
+                    ));
+                }
+            },
+            ("compression", Json::Null) => settings.compression = Some(Codec::None),
+            ("compression", Json::String(name)) if Codec::parse(name).is_some() => {
+                settings.compression = Codec::parse(name);
+            }
+            ("compression", _) => {
+                return Err(bad_request(
+                    "The compression field should be one of none, lz4, zstd or null",
+                ));
+            }
+            (field, _) => {
+                return Err(bad_request(&format!(
+                    "Unknown collection setting {}",
+                    field
+                )));
+            }
+        }
+    }
+    Ok(Some(settings))
+}
+
+// A schema is given as {"schema": {...}, "validation": "strict" | "warn"}, where the validation
+// defaults to strict.
+fn collection_schema(context: &Context) -> Result<(Schema, Validation), Response> {
+    let bad_request = |message: String| ApiError::bad_request(message).to_response();
+    let Some(Json::Object(obj)) = context.request.json() else {
+        return Err(bad_request(
+            "Expected a JSON object with the schema field".to_string(),
+        ));
+    };
+    if let Some((key, _)) = obj
+        .iter()
+        .find(|(key, _)| !matches!(key.as_str(), "schema" | "validation"))
+    {
+        return Err(bad_request(format!("Unknown field {}", key)));
+    }
+    let schema = match obj.get("schema") {
+        Some(schema) => Schema::new(schema).map_err(bad_request)?,
+        None => {
+            return Err(bad_request(
+                "Expected a JSON object with the schema field".to_string(),
+            ));
+        }
+    };
+    let validation = match obj.get("validation") {
+        None => Validation::Strict,
+        Some(Json::String(validation)) => match Validation::parse(validation) {
+            Some(validation) => validation,
+            None => {
+                return Err(bad_request(format!(
+                    "The validation {} is invalid. Expected strict or warn",
+                    validation
+                )));
+            }
+        },
+        Some(_) => {
+            return Err(bad_request(
+                "Expected the validation field as strict or warn".to_string(),
+            ));
+        }
+    };
+    Ok((schema, validation))
+}
+
+fn collection_status(status: HttpStatus, name: &str, change: &str) -> Response {
+    let mut obj = JsonObject::new();
+    obj["name".to_string()] = Json::String(name.to_string());
+    obj["status".to_string()] = Json::String(change.to_string());
+    Response::json(status, Json::Object(obj))
+}
+
+fn write_status(status: HttpStatus, id: &str, change: &str) -> Response {
+    let mut obj = JsonObject::new();
+    obj[ID_FIELD.to_string()] = Json::String(id.to_string());
+    obj["status".to_string()] = Json::String(change.to_string());
+    Response::json(status, Json::Object(obj))
+}
+
+fn list_databases(context: &Context) -> Response {
+    match db::list(&context.server.root) {
+        Ok(names) => {
+            let mut obj = JsonObject::new();
+            obj["databases".to_string()] =
+                Json::List(names.into_iter().map(Json::String).collect());
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Err(err) => storage_error(err),
+    }
+}
+
+fn stats(context: &Context) -> Response {
+    match database(context, "db").and_then(|db| db.stats().map_err(storage_error)) {
+        Ok(stats) => Response::json(HttpStatus::Ok, stats.to_json()),
+        Err(resp) => resp,
+    }
+}
+
+fn list_collections(context: &Context) -> Response {
+    let db = match database(context, "db") {
+        Ok(db) => db,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let collections = db.list_collections().and_then(|names| {
+        names
+            .iter()
+            .map(|name| match db.collection(name)? {
+                Some(meta) => Ok(Some(meta.to_json())),
+                None => Ok(None),
+            })
+            .filter_map(Result::transpose)
+            .collect::<Result<Vec<_>, String>>()
+    });
+    match collections {
+        Ok(collections) => {
+            let mut obj = JsonObject::new();
+            obj["collections".to_string()] = Json::List(collections);
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Err(err) => storage_error(err),
+    }
+}
+
+fn get_collection(context: &Context) -> Response {
+    let col = match collection(context) {
+        Ok(col) => col,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let meta = match col.db.collection(&col.name) {
+        Ok(Some(meta)) => meta,
+        Ok(None) => {
+            return col.not_found();
+        }
+        Err(err) => {
+            return storage_error(err);
+        }
+    };
+    match col.db.collection_stats(&col.name) {
+        Ok(stats) => {
+            let mut obj = JsonObject::new();
+            obj["name".to_string()] = Json::String(meta.name);
+            obj["created".to_string()] = Json::String(meta.created.to_rfc3339());
+            if let Some(ttl) = meta.ttl {
+                obj["ttl".to_string()] = Json::Number(JsonNumber::Int(ttl as i64));
+            }
+            if let Some(soft_delete) = &meta.soft_delete {
+                obj["soft_delete".to_string()] = soft_delete.to_json();
+            }
+            obj["compression".to_string()] = Json::String(meta.compression.name().to_string());
+            obj["documents".to_string()] = Json::Number(JsonNumber::Int(stats.documents as i64));
+            obj["bytes".to_string()] = Json::Number(JsonNumber::Int(stats.bytes as i64));
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Err(err) => storage_error(err),
+    }
+}
+
+fn create_collection(context: &Context) -> Response {
+    let target = collection(context).and_then(|col| Ok((col, collection_settings(context)?)));
+    let (col, settings) = match target {
+        Ok(target) => target,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let created = match col.db.create_collection(&col.name) {
+        Ok(created) => created,
+        Err(err) => {
+            return storage_error(err);
+        }
+    };
+    if created {
+        col.publish("create_collection", None);
+    }
+    if let Some(settings) = &settings {
+        let updated = match settings.ttl {
+            Some(ttl) => col.db.set_ttl(&col.name, ttl),
+            None => Ok(true),
+        }
+        .and_then(|_| match settings.soft_delete {
+            Some(soft_delete) => col.db.set_soft_delete(&col.name, soft_delete),
+            None => Ok(true),
+        })
+        .and_then(|_| match settings.compression {
+            Some(compression) => col.db.set_compression(&col.name, compression),
+            None => Ok(true),
+        });
+        if let Err(err) = updated {
+            return storage_error(err);
+        }
+    }
+    match (created, settings) {
+        (true, _) => collection_status(HttpStatus::Created, &col.name, "created"),
+        (false, Some(_)) => collection_status(HttpStatus::Ok, &col.name, "updated"),
+        (false, None) => collection_status(HttpStatus::Ok, &col.name, "exists"),
+    }
+}
+
+fn drop_collection(context: &Context) -> Response {
+    let col = match collection(context) {
+        Ok(col) => col,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    match col.db.drop_collection(&col.name) {
+        Ok(true) => {
+            col.publish("drop_collection", None);
+            collection_status(HttpStatus::Ok, &col.name, "dropped")
+        }
+        Ok(false) => col.not_found(),
+        Err(err) => storage_error(err),
+    }
+}
+
+fn get_schema(context: &Context) -> Response {
+    let col = match collection(context) {
+        Ok(col) => col,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    match col.db.collection(&col.name) {
+        Ok(Some(meta)) => match meta.schema {
+            Some(schema) => {
+                let mut obj = JsonObject::new();
+                obj["name".to_string()] = Json::String(meta.name);
+                obj["schema".to_string()] = schema;
+                obj["validation".to_string()] = Json::String(meta.validation.as_str().to_string());
+                Response::json(HttpStatus::Ok, Json::Object(obj))
+            }
+            None => ApiError::not_found(format!("The collection {} has no schema", col.name))
+                .to_response(),
+        },
+        Ok(None) => col.not_found(),
+        Err(err) => storage_error(err),
+    }
+}
+
+// Documents already in the collection are not checked against a new schema, only the writes
+// made after it is set.
+fn set_schema(context: &Context) -> Response {
+    let target = collection(context).and_then(|col| Ok((col, collection_schema(context)?)));
+    let (col, (schema, validation)) = match target {
+        Ok(target) => target,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    match col.db.set_schema(&col.name, Some((&schema, validation))) {
+        Ok(true) => collection_status(HttpStatus::Ok, &col.name, "updated"),
+        Ok(false) => col.not_found(),
+        Err(err) => storage_error(err),
+    }
+}
+
+fn remove_schema(context: &Context) -> Response {
+    let col = match collection(context) {
+        Ok(col) => col,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    match col.db.set_schema(&col.name, None) {
+        Ok(true) => collection_status(HttpStatus::Ok, &col.name, "removed"),
+        Ok(false) => col.not_found(),
+        Err(err) => storage_error(err),
+    }
+}
+
+// Describes the fields the documents have and suggests a schema they all match, which can be
+// set as it is. A sample of the documents is enough for large collections.
+fn infer_schema(context: &Context) -> Response {
+    let target = collection(context).and_then(|col| Ok((col, number_param(context, "sample")?)));
+    let (col, sample) = match target {
+        Ok(target) => target,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    if let Some(n) = sample
+        && (n == 0 || n > MAX_SAMPLE_SIZE)
+    {
+        return ApiError::bad_request(format!(
+            "The sample size should be between 1 and {}",
+            MAX_SAMPLE_SIZE
+        ))
+        .to_response();
+    }
+    match col.db.collection(&col.name) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return col.not_found();
+        }
+        Err(err) => {
+            return storage_error(err);
+        }
+    }
+    match infer::infer(&col.db, &col.name, sample.map(|n| n as usize)) {
+        Ok(inference) => Response::json(HttpStatus::Ok, inference.to_json()),
+        Err(err) => storage_error(err),
+    }
+}
+
+fn get_document(context: &Context) -> Response {
+    let target =
+        collection(context).and_then(|col| Ok((col, valid_param(context, "id", "document ID")?)));
+    let (col, id) = match target {
+        Ok(target) => target,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let document = match context.request.query_value("deleted") {
+        Some("true" | "1") => col.db.get_including_deleted(&col.name, &id),
+        _ => col.db.get(&col.name, &id),
+    };
+    match document {
+        Ok(Some(document)) => Response::json(HttpStatus::Ok, document),
+        Ok(None) => ApiError::not_found(format!(
+            "The document {} does not exist in the collection {}",
+            id, col.name
+        ))
+        .to_response(),
+        Err(err) => storage_error(err),
+    }
+}
+
+// Documents are upserted unless the mode query parameter asks to only create or only replace
+// them. The status tells which of the two happened either way.
+fn put_document(context: &Context) -> Response {
+    let target = collection(context).and_then(|col| {
+        let mode = write_mode(context.request.query_value("mode"))
+            .map_err(|err| ApiError::bad_request(err).to_response())?;
+        Ok((
+            col,
+            valid_param(context, "id", "document ID")?,
+            document_body(context)?,
+            mode,
+        ))
+    });
+    let (col, id, document, mode) = match target {
+        Ok(target) => target,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let condition = preconditions(context.request);
+    match col
+        .db
+        .write_if(&col.name, &id, document, mode, condition.as_deref())
+    {
+        Ok(true) => {
+            col.publish("insert", Some(&id));
+            write_status(HttpStatus::Created, &id, "created")
+        }
+        Ok(false) => {
+            col.publish("update", Some(&id));
+            write_status(HttpStatus::Ok, &id, "updated")
+        }
+        Err(err) => write_error(err),
+    }
+}
+
+// The body is either a JSON merge patch or a document of update operators, and the response is
+// the updated document.
+fn patch_document(context: &Context) -> Response {
+    let target = collection(context).and_then(|col| {
+        let id = valid_param(context, "id", "document ID")?;
+        let patch = context
+            .request
+            .json()
+            .ok_or_else(|| "Expected the patch as a JSON object".to_string())
+            .and_then(Patch::parse)
+            .map_err(|err| ApiError::bad_request(err).to_response())?;
+        Ok((col, id, patch))
+    });
+    let (col, id, patch) = match target {
+        Ok(target) => target,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let condition = preconditions(context.request);
+    match col
+        .db
+        .patch_if(&col.name, &id, &patch, condition.as_deref())
+    {
+        Ok(document) => {
+            col.publish("update", Some(&id));
+            Response::json(HttpStatus::Ok, document)
+        }
+        Err(err) => write_error(err),
+    }
+}
+
+// The body is {"field": path, "by": number}, where by defaults to 1. The document is created if
+// it does not exist, and the response has the new value of the field.
+fn increment(context: &Context) -> Response {
+    let target = collection(context).and_then(|col| {
+        let id = valid_param(context, "id", "document ID")?;
+        let (field, by) = increment_body(context.request.json())
+            .map_err(|err| ApiError::bad_request(err).to_response())?;
+        Ok((col, id, field, by))
+    });
+    let (col, id, field, by) = match target {
+        Ok(target) => target,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    match col.db.increment(&col.name, &id, &field, &by) {
+        Ok((value, inserted)) => {
+            col.publish(if inserted { "insert" } else { "update" }, Some(&id));
+            let mut obj = JsonObject::new();
+            obj[ID_FIELD.to_string()] = Json::String(id);
+            obj["field".to_string()] = Json::String(field);
+            obj["value".to_string()] = value;
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Err(err) => write_error(err),
+    }
+}
+
+fn increment_body(body: Option<&Json>) -> Result<(String, JsonNumber), String> {
+    let Some(Json::Object(obj)) = body else {
+        return Err("Expected the increment as a JSON object".to_string());
+    };
+    if let Some((field, _)) = obj
+        .iter()
+        .find(|(field, _)| !matches!(field.as_str(), "field" | "by"))
+    {
+        return Err(format!("Unknown increment field {}", field));
+    }
+    let field = match obj.get("field") {
+        Some(Json::String(field)) if !field.is_empty() => field.clone(),
+        _ => {
+            return Err("The field to increment should be given as a field path".to_string());
+        }
+    };
+    match obj.get("by") {
+        None => Ok((field, JsonNumber::Int(1))),
+        Some(Json::Number(by)) => Ok((field, by.clone())),
+        Some(_) => Err("The by field should be a number".to_string()),
+    }
+}
+
+// A collection with soft deletes keeps a tombstone of the document, unless the purge query
+// parameter asks to delete it for good.
+fn delete_document(context: &Context) -> Response {
+    let target =
+        collection(context).and_then(|col| Ok((col, valid_param(context, "id", "document ID")?)));
+    let (col, id) = match target {
+        Ok(target) => target,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let condition = preconditions(context.request);
+    let (deleted, change) = match context.request.query_value("purge") {
+        Some("true" | "1") => (
+            col.db
+                .purge_document_if(&col.name, &id, condition.as_deref()),
+            "purge",
+        ),
+        _ => (
+            col.db.delete_if(&col.name, &id, condition.as_deref()),
+            "delete",
+        ),
+    };
+    match deleted {
+        Ok(true) => {
+            col.publish(change, Some(&id));
+            write_status(HttpStatus::Ok, &id, "deleted")
+        }
+        Ok(false) => ApiError::not_found(format!(
+            "The document {} does not exist in the collection {}",
+            id, col.name
+        ))
+        .to_response(),
+        Err(err) => write_error(err),
+    }
+}
+
+fn restore_document(context: &Context) -> Response {
+    let target =
+        collection(context).and_then(|col| Ok((col, valid_param(context, "id", "document ID")?)));
+    let (col, id) = match target {
+        Ok(target) => target,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    match col.db.restore(&col.name, &id) {
+        Ok(true) => {
+            col.publish("insert", Some(&id));
+            write_status(HttpStatus::Ok, &id, "restored")
+        }
+        Ok(false) => ApiError::not_found(format!(
+            "The document {} is not a deleted document of the collection {}",
+            id, col.name
+        ))
+        .to_response(),
+        Err(err) => write_error(err),
+    }
+}
+
+// Purges the tombstones of the collection, or with {"before": time} only those of documents
+// deleted at or before the time.
+fn purge(context: &Context) -> Response {
+    let col = match collection(context) {
+        Ok(col) => col,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let before = match context.request.json() {
+        None => Ok(Utc::now()),
+        Some(Json::Object(obj)) if obj.len() == 1 => match obj.get("before") {
+            Some(Json::String(before)) => DateTime::parse_from_rfc3339(before)
+                .map(|before| before.with_timezone(&Utc))
+                .map_err(|_| format!("The time {} is not an RFC 3339 timestamp", before)),
+            _ => Err("Expected the before field as an RFC 3339 timestamp".to_string()),
+        },
+        Some(_) => Err("Expected a JSON object with the before field".to_string()),
+    };
+    let before = match before {
+        Ok(before) => before,
+        Err(err) => {
+            return ApiError::bad_request(err).to_response();
+        }
+    };
+    match col.db.purge(&col.name, before) {
+        Ok(purged) => {
+            for id in &purged {
+                col.publish("purge", Some(id));
+            }
+            let mut obj = JsonObject::new();
+            obj["purged".to_string()] = Json::Number(JsonNumber::Int(purged.len() as i64));
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Err(err) => storage_error(err),
+    }
+}
+
+fn insert_document(context: &Context) -> Response {
+    let target = collection(context).and_then(|col| Ok((col, document_body(context)?)));
+    let (col, document) = match target {
+        Ok(target) => target,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    match col.db.insert(&col.name, document) {
+        Ok(id) => {
+            col.publish("insert", Some(&id));
+            let mut resp = write_status(HttpStatus::Created, &id, "created");
+            resp.set_header(
+                "Location",
+                context.request.route.trim_end_matches('/').to_string() + "/" + &id,
+            );
+            resp
+        }
+        Err(err) => write_error(err),
+    }
+}
+
+// Responds with the found document, or null when no document matched.
+fn find_and_modify(context: &Context) -> Response {
+    let col = match collection(context) {
+        Ok(col) => col,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let request = context
+        .request
+        .json()
+        .ok_or_else(|| "Expected the find-and-modify request as a JSON object".to_string())
+        .and_then(FindAndModify::parse);
+    let request = match request {
+        Ok(request) => request,
+        Err(err) => {
+            return ApiError::bad_request(err).to_response();
+        }
+    };
+    match request.run(&col.db, &col.name) {
+        Ok(found) => {
+            let mut obj = JsonObject::new();
+            obj["document".to_string()] = match found {
+                Some(found) => {
+                    col.publish(
+                        if found.inserted { "insert" } else { "update" },
+                        Some(&found.id),
+                    );
+                    found.document.unwrap_or(Json::Null)
+                }
+                None => Json::Null,
+            };
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Err(err) => write_error(err),
+    }
+}
+
+fn query(context: &Context) -> Response {
+    let col = match collection(context) {
+        Ok(col) => col,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    // The filter is linted before it is parsed, so that an unknown operator is refused with its
+    // path and a suggestion. Other problems are warnings unless the query is strict.
+    let lints = match context
+        .request
+        .json()
+        .and_then(|body| query::lookup(body, "filter"))
+    {
+        Some(filter) => match col.db.lint(&col.name, filter) {
+            Ok(lints) => lints,
+            Err(err) => {
+                return storage_error(err);
+            }
+        },
+        None => Vec::new(),
+    };
+    let details = match lints.is_empty() {
+        true => Json::None,
+        false => Json::List(lints.iter().map(Lint::to_json).collect()),
+    };
+    let query = match Query::parse(context.request.json()) {
+        Ok(query) => query,
+        Err(err) => {
+            return ApiError::bad_request(err)
+                .with_details(details)
+                .to_response();
+        }
+    };
+    if query.strict && !lints.is_empty() {
+        return ApiError::bad_request(format!(
+            "The filter has {} problem(s): {}",
+            lints.len(),
+            lints
+                .iter()
+                .map(|lint| format!("{}: {}", lint.path, lint.message))
+                .collect::<Vec<_>>()
+                .join("; ")
+        ))
+        .with_code("filter_lint")
+        .with_details(details)
+        .to_response();
+    }
+    match query.run(&col.db, &col.name) {
+        Ok(page) => {
+            let mut obj = JsonObject::new();
+            obj["count".to_string()] = Json::Number(JsonNumber::Int(page.documents.len() as i64));
+            obj["documents".to_string()] = Json::List(page.documents);
+            if let Some(next) = page.next {
+                obj["next".to_string()] = Json::String(next);
+            }
+            if !lints.is_empty() {
+                obj["warnings".to_string()] = details;
+            }
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Err(err) => storage_error(err),
+    }
+}
+
+fn aggregate(context: &Context) -> Response {
+    let col = match collection(context) {
+        Ok(col) => col,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let pipeline = match Pipeline::parse(context.request.json()) {
+        Ok(pipeline) => pipeline,
+        Err(err) => {
+            return ApiError::bad_request(err).to_response();
+        }
+    };
+    match pipeline.run(&col.db, &col.name) {
+        Ok(documents) => {
+            let mut obj = JsonObject::new();
+            obj["count".to_string()] = Json::Number(JsonNumber::Int(documents.len() as i64));
+            obj["documents".to_string()] = Json::List(documents);
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Err(err) => storage_error(err),
+    }
+}
+
+// An operation is {"op": "put" | "upsert" | "insert" | "delete", "collection": ..., "_id": ...,
+// "document": ...}, where inserts get a generated ID. Puts take an optional "mode" of upsert,
+// create or replace, and upserts are puts in the upsert mode.
+fn mutation(operation: &Json) -> Result<(Mutation, &'static str), String> {
+    let Json::Object(obj) = operation else {
+        return Err("Expected every operation as a JSON object".to_string());
+    };
+    let name = |field: &str, kind: &str| match obj.get(field) {
+        Some(Json::String(name)) if db::is_valid_name(name) => Ok(name.clone()),
+        Some(Json::String(name)) => Err(format!("The {} {} is invalid", kind, name)),
+        _ => Err(format!("The operation has no {} field", field)),
+    };
+    let document = || match obj.get("document") {
+        Some(document @ Json::Object(_)) => db::expiry(document).map(|_| document.clone()),
+        _ => Err("Expected the document of the operation as a JSON object".to_string()),
+    };
+    let collection = name("collection", "collection name")?;
+    let mode = match obj.get("mode") {
+        None => WriteMode::Upsert,
+        Some(Json::String(mode)) => write_mode(Some(mode))?,
+        Some(_) => {
+            return Err(
+                "Expected upsert, create or replace as the mode of the operation".to_string(),
+            );
+        }
+    };
+    let (id, document, change) = match obj.get("op") {
+        Some(Json::String(op)) if op == "put" => {
+            (name(ID_FIELD, "document ID")?, Some(document()?), "put")
+        }
+        Some(Json::String(op)) if op == "upsert" && mode == WriteMode::Upsert => {
+            (name(ID_FIELD, "document ID")?, Some(document()?), "put")
+        }
+        Some(Json::String(op)) if op == "insert" => {
+            (db::generate_id()?, Some(document()?), "insert")
+        }
+        Some(Json::String(op)) if op == "delete" => {
+            (name(ID_FIELD, "document ID")?, None, "delete")
+        }
+        Some(Json::String(op)) if op == "upsert" => {
+            return Err("Upserts only have the upsert mode".to_string());
+        }
+        _ => {
+            return Err(
+                "Expected put, upsert, insert or delete as the op of the operation".to_string(),
+            );
+        }
+    };
+    if change != "put" && obj.get("mode").is_some() {
+        return Err("Only puts and upserts have a mode".to_string());
+    }
+    Ok((
+        Mutation {
+            collection,
+            id,
+            document,
+            mode,
+        },
+        change,
+    ))
+}
+
+// The body is read a line at a time while it arrives, so an import is not limited by the
+// maximum body size, only each of its lines is.
+fn import(context: &Context) -> Response {
+    let col = match collection(context) {
+        Ok(col) => col,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let batch_size = match context.request.query_value("batch_size") {
+        None => DEFAULT_BATCH_SIZE,
+        Some(value) => match value.parse::<usize>() {
+            Ok(batch_size) if batch_size > 0 => batch_size,
+            _ => {
+                return ApiError::bad_request(
+                    "Expected a positive number of documents for batch_size".to_string(),
+                )
+                .to_response();
+            }
+        },
+    };
+    let compact = matches!(context.request.query_value("compact"), Some("true" | "1"));
+    let options = ImportOptions {
+        batch_size,
+        max_line: context.server.settings().max_body_size,
+    };
+    let imported = import::import(
+        &col.db,
+        &col.name,
+        &mut *context.body(),
+        &options,
+        |_, written| {
+            for (id, inserted) in written {
+                col.publish(if *inserted { "insert" } else { "update" }, Some(id));
+            }
+        },
+    );
+    let report = match imported {
+        Ok(report) => report,
+        Err(err) => {
+            return storage_error(err);
+        }
+    };
+    if compact && let Err(err) = col.db.flush().and_then(|_| col.db.compact(2)) {
+        return storage_error(err);
+    }
+    Response::json(HttpStatus::Ok, report.to_json())
+}
+
+// The documents are written to the connection while they are scanned, in chunks, and the
+// connection is closed after the last one. An export that fails midway ends without the last
+// chunk, so that clients see it as cut short.
+fn export(context: &Context) -> Response {
+    let col = match collection(context) {
+        Ok(col) => col,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let query = |name: &str| context.request.query_value(name);
+    let mut export = match Export::parse(query("format"), query("filter"), query("fields")) {
+        Ok(export) => export,
+        Err(err) => {
+            return ApiError::bad_request(err).to_response();
+        }
+    };
+    export.include_deleted = matches!(query("include_deleted"), Some("true" | "1"));
+    match col.db.collection(&col.name) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return col.not_found();
+        }
+        Err(err) => {
+            return storage_error(err);
+        }
+    }
+    let mut resp = Response::new(HttpStatus::Ok);
+    resp.set_header("Content-Type", export.format.content_type().to_string());
+    resp.set_header(
+        "Content-Disposition",
+        format!(
+            "attachment; filename=\"{}.{}\"",
+            col.name,
+            export.format.extension()
+        ),
+    );
+    if context.request.method == HttpMethod::HEAD {
+        return resp;
+    }
+    resp.set_header("Transfer-Encoding", "chunked".to_string());
+    resp.set_header("Connection", "close".to_string());
+    resp.upgrade = Some(Box::new(move |stream| {
+        let mut out = BufWriter::new(ChunkedWriter::new(stream));
+        let written = export::write(&col.db, &col.name, &export, &mut out).and_then(|_| {
+            out.into_inner()
+                .map_err(|err| err.to_string())
+                .and_then(|out| out.finish().map_err(|err| err.to_string()))
+        });
+        if let Err(err) = written {
+            eprintln!(
+                "The export of the collection {} in the database {} failed: {}",
+                col.name, col.database, err
+            );
+        }
+    }));
+    resp
+}
+
+// The hash of a collection is the SHA-256 of its NDJSON export, so it can be checked against an
+// exported file as well as against the same collection elsewhere.
+fn collection_hash(context: &Context) -> Response {
+    let col = match collection(context) {
+        Ok(col) => col,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    match col.db.collection(&col.name) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return col.not_found();
+        }
+        Err(err) => {
+            return storage_error(err);
+        }
+    }
+    match export::collection_hash(&col.db, &col.name) {
+        Ok((documents, hash)) => {
+            let mut obj = JsonObject::new();
+            obj["name".to_string()] = Json::String(col.name);
+            obj["documents".to_string()] = Json::Number(JsonNumber::Int(documents as i64));
+            obj["hash".to_string()] = Json::String(hash);
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Err(err) => storage_error(err),
+    }
+}
+
+fn document_hash(context: &Context) -> Response {
+    let target =
+        collection(context).and_then(|col| Ok((col, valid_param(context, "id", "document ID")?)));
+    let (col, id) = match target {
+        Ok(target) => target,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    match col.db.get(&col.name, &id) {
+        Ok(Some(document)) => {
+            let mut obj = JsonObject::new();
+            obj[ID_FIELD.to_string()] = Json::String(id);
+            obj["hash".to_string()] = Json::String(export::document_hash(&document));
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Ok(None) => ApiError::not_found(format!(
+            "The document {} does not exist in the collection {}",
+            id, col.name
+        ))
+        .to_response(),
+        Err(err) => storage_error(err),
+    }
+}
+
+// Returns a uniform random sample of the documents, for a quick look at what a collection holds.
+fn sample(context: &Context) -> Response {
+    let target = collection(context).and_then(|col| {
+        let n = number_param(context, "n")?.unwrap_or(DEFAULT_SAMPLE_SIZE);
+        Ok((col, n))
+    });
+    let (col, n) = match target {
+        Ok(target) => target,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    if n == 0 || n > MAX_SAMPLE_SIZE {
+        return ApiError::bad_request(format!(
+            "The sample size n should be between 1 and {}",
+            MAX_SAMPLE_SIZE
+        ))
+        .to_response();
+    }
+    match col.db.collection(&col.name) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return col.not_found();
+        }
+        Err(err) => {
+            return storage_error(err);
+        }
+    }
+    match col.db.sample(&col.name, n as usize) {
+        Ok((documents, seen)) => {
+            let mut obj = JsonObject::new();
+            obj["documents".to_string()] = Json::List(documents);
+            obj["of".to_string()] = Json::Number(JsonNumber::Int(seen as i64));
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Err(err) => storage_error(err),
+    }
+}
+
+fn transaction(context: &Context) -> Response {
+    let db = match database(context, "db") {
+        Ok(db) => db,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    let operations = match context.request.json() {
+        Some(Json::Object(obj)) => match obj.get("operations") {
+            Some(Json::List(operations)) => operations
+                .iter()
+                .enumerate()
+                .map(|(ind, operation)| {
+                    mutation(operation).map_err(|err| format!("Operation {}: {}", ind, err))
+                })
+                .collect::<Result<Vec<_>, String>>(),
+            _ => Err("Expected the operations as a list".to_string()),
+        },
+        _ => Err("Expected a JSON object with the operations field".to_string()),
+    };
+    let (mutations, changes): (Vec<_>, Vec<_>) = match operations {
+        Ok(operations) => operations.into_iter().unzip(),
+        Err(err) => {
+            return ApiError::bad_request(err).to_response();
+        }
+    };
+    let results = match db.commit(&mutations) {
+        Ok(Commit::Committed(results)) => results,
+        Ok(Commit::Aborted(err)) => {
+            return ApiError::conflict(format!("The transaction was aborted. {}", err))
+                .with_code("transaction_aborted")
+                .to_response();
+        }
+        Err(err) => {
+            return storage_error(err);
+        }
+    };
+    let mut statuses = Vec::new();
+    for ((mutation, change), changed) in mutations.iter().zip(changes).zip(results) {
+        let change = match (change, changed) {
+            ("delete", _) => "delete",
+            (_, true) => "insert",
+            (_, false) => "update",
+        };
+        publish(db.name(), &mutation.collection, change, Some(&mutation.id));
+        let mut obj = JsonObject::new();
+        obj["collection".to_string()] = Json::String(mutation.collection.clone());
+        obj[ID_FIELD.to_string()] = Json::String(mutation.id.clone());
+        obj["status".to_string()] = Json::String(
+            match change {
+                "insert" => "created",
+                "update" => "updated",
+                _ => "deleted",
+            }
+            .to_string(),
+        );
+        statuses.push(Json::Object(obj));
+    }
+    let mut obj = JsonObject::new();
+    obj["status".to_string()] = Json::String("committed".to_string());
+    obj["results".to_string()] = Json::List(statuses);
+    Response::json(HttpStatus::Ok, Json::Object(obj))
+}
+
+fn number_param(context: &Context, name: &str) -> Result<Option<u64>, Response> {
+    match context.request.query_value(name) {
+        Some(value) => value.parse::<u64>().map(Some).map_err(|_| {
+            ApiError::bad_request(format!(
+                "The query parameter {} should be a non-negative integer, not {}",
+                name, value
+            ))
+            .to_response()
+        }),
+        None => Ok(None),
+    }
+}
+
+// Changes are read after the since sequence number, or after the Last-Event-ID of a
+// reconnecting event stream. WebSocket and event stream clients follow the feed, any other
+// client long-polls it and gets at least one change unless the timeout passes first.
+fn changes(context: &Context) -> Response {
+    let request = context.request;
+    let target = collection(context).and_then(|col| {
+        let since = match request.header("Last-Event-ID") {
+            Some(id) if request.query_value("since").is_none() => id.trim().parse().ok(),
+            _ => number_param(context, "since")?,
+        };
+        let limit = number_param(context, "limit")?.unwrap_or(DEFAULT_CHANGES_LIMIT);
+        let timeout = number_param(context, "timeout")?.unwrap_or(DEFAULT_POLL_TIMEOUT);
+        Ok((col, since.unwrap_or(0), limit, timeout))
+    });
+    let (col, since, limit, timeout) = match target {
+        Ok(target) => target,
+        Err(resp) => {
+            return resp;
+        }
+    };
+    match col.db.collection(&col.name) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return col.not_found();
+        }
+        Err(err) => {
+            return storage_error(err);
+        }
+    }
+    if limit == 0 || limit > MAX_CHANGES_LIMIT {
+        return ApiError::bad_request(format!(
+            "The limit should be between 1 and {}",
+            MAX_CHANGES_LIMIT
+        ))
+        .to_response();
+    }
+    let deltas = matches!(request.query_value("delta"), Some("true" | "1"));
+    let mut tail = Tail::new(col.db, &col.name, since, deltas);
+    if request.upgrade.is_some() {
+        return match websocket::handshake(request) {
+            Ok(mut resp) => {
+                resp.upgrade = Some(Box::new(move |stream| {
+                    if let Err(err) = websocket::serve(stream, tail) {
+                        eprintln!("The change feed of {} ended with error: {}", col.name, err);
+                    }
+                }));
+                resp
+            }
+            Err(resp) => resp,
+        };
+    }
+    if request
+        .header("Accept")
+        .is_some_and(|accept| accept.contains("text/event-stream"))
+    {
+        return sse::response(tail);
+    }
+    let timeout = Duration::from_secs(timeout.min(MAX_POLL_TIMEOUT));
+    match tail.wait(limit as usize, timeout) {
+        Ok(changes) => {
+            let mut obj = JsonObject::new();
+            obj["changes".to_string()] =
+                Json::List(changes.into_iter().map(|change| change.change).collect());
+            obj["last_seq".to_string()] = Json::Number(JsonNumber::Int(tail.since() as i64));
+            Response::json(HttpStatus::Ok, Json::Object(obj))
+        }
+        Err(err) => storage_error(err),
+    }
+}
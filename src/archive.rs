@@ -0,0 +1,607 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, ErrorKind, Read},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+};
+
+use chrono::Utc;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use tar::{Builder, EntryType, Header};
+
+use crate::{
+    db::{
+        self, COLLECTION_FILE, CollectionCheck, DATA_DIR, DB, FileStats, LOCK_FILE, MANIFEST_FILE,
+        Manifest, SIDE_DIRS, WAL_DIR,
+    },
+    engine,
+    json::{Json, JsonNumber, JsonObject},
+    query::Query,
+    root::Root,
+    sstable::{TABLE_EXTENSION, Table, TableId},
+    wal::{self, CHECKPOINT_FILE},
+};
+
+pub const ARCHIVE_EXTENSION: &str = "tar.gz";
+pub const RESTORE_DIR: &str = ".restore";
+pub const INCREMENT_FILE: &str = "increment.json";
+
+// A full backup has the layout of a database directory. Its tables hold every write up to one
+// log record and its checkpoint is at that record, so it opens without a log to replay.
+//
+// An incremental backup holds the log records written since the backup before it, in a single
+// log segment, next to an increment file with the range of those records.
+struct Archive {
+    builder: Builder<GzEncoder<BufWriter<File>>>,
+    path: PathBuf,
+    mtime: u64,
+    files: u64,
+}
+
+impl Archive {
+    fn error(&self, err: io::Error) -> String {
+        format!(
+            "Error while writing the archive {}: {}",
+            self.path.display(),
+            err
+        )
+    }
+
+    fn dir(&mut self, path: &str) -> Result<(), String> {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_mtime(self.mtime);
+        header.set_size(0);
+        self.builder
+            .append_data(&mut header, path, io::empty())
+            .map_err(|err| self.error(err))
+    }
+
+    fn file(&mut self, path: &str, size: u64, content: impl Read) -> Result<(), String> {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_mtime(self.mtime);
+        header.set_size(size);
+        self.builder
+            .append_data(&mut header, path, content)
+            .map_err(|err| self.error(err))?;
+        self.files += 1;
+        Ok(())
+    }
+
+    fn bytes(&mut self, path: &str, content: &[u8]) -> Result<(), String> {
+        self.file(path, content.len() as u64, content)
+    }
+}
+
+// Builds the archive in a temporary file, which only replaces the path once it is complete.
+fn build(
+    path: &Path,
+    contents: impl FnOnce(&mut Archive) -> Result<(), String>,
+) -> Result<FileStats, String> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let temp = path.with_file_name(format!(".{}.{}", file_name, db::TEMP_EXTENSION));
+    let file = File::create(&temp).map_err(|err| {
+        format!(
+            "Error while creating the archive {}: {}",
+            temp.display(),
+            err
+        )
+    })?;
+    let mut archive = Archive {
+        builder: Builder::new(GzEncoder::new(BufWriter::new(file), Compression::default())),
+        path: temp.clone(),
+        mtime: Utc::now().timestamp() as u64,
+        files: 0,
+    };
+    let written = contents(&mut archive).and_then(|_| {
+        let files = archive.files;
+        let finished = archive
+            .builder
+            .into_inner()
+            .and_then(|encoder| encoder.finish())
+            .and_then(|out| out.into_inner().map_err(|err| err.into_error()))
+            .and_then(|file| file.sync_all())
+            .and_then(|_| fs::rename(&temp, path));
+        finished.map(|_| files).map_err(|err| {
+            format!(
+                "Error while writing the archive {}: {}",
+                temp.display(),
+                err
+            )
+        })
+    });
+    let files = match written {
+        Ok(files) => files,
+        Err(err) => {
+            let _ = fs::remove_file(&temp);
+            return Err(err);
+        }
+    };
+    let bytes = fs::metadata(path).map(|meta| meta.len()).unwrap_or(0);
+    Ok(FileStats {
+        path: path.to_string_lossy().to_string(),
+        files,
+        bytes,
+    })
+}
+
+// Writes a full backup from a snapshot of the database, so writes only wait while the
+// snapshot is taken and not while the tables are copied.
+pub fn write(db: &DB, path: &Path) -> Result<FileStats, String> {
+    let snapshot = engine::snapshot(db)?;
+    let written = build(path, |archive| {
+        archive.bytes(
+            MANIFEST_FILE,
+            db.manifest().to_json().canonical().as_bytes(),
+        )?;
+        archive.bytes(LOCK_FILE, b"")?;
+        archive.dir(WAL_DIR)?;
+        archive.bytes(
+            &format!("{}/{}", WAL_DIR, CHECKPOINT_FILE),
+            wal::checkpoint_content(snapshot.last_seq).as_bytes(),
+        )?;
+        archive.dir(DATA_DIR)?;
+        let collections = db.list_collections()?;
+        for name in &collections {
+            let meta = db.data_dir().join(name).join(COLLECTION_FILE);
+            let content = match fs::read(&meta) {
+                Ok(content) => content,
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    continue;
+                }
+                Err(err) => {
+                    return Err(format!(
+                        "Error while reading the collection metadata {}: {}",
+                        meta.display(),
+                        err
+                    ));
+                }
+            };
+            archive.dir(&format!("{}/{}", DATA_DIR, name))?;
+            archive.bytes(
+                &format!("{}/{}/{}", DATA_DIR, name, COLLECTION_FILE),
+                &content,
+            )?;
+        }
+        // The writes logged after the checkpoint go in one more table, which would have been
+        // written by the next flush.
+        let pending = TableId {
+            first_seq: snapshot.checkpoint + 1,
+            last_seq: snapshot.last_seq,
+        };
+        let mut keys = snapshot
+            .tables
+            .keys()
+            .chain(snapshot.memtable.keys())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            let collection = key.split_once('/').map(|(collection, _)| collection);
+            if !collections.contains(&collection.unwrap_or(key).to_string()) {
+                continue;
+            }
+            let dir = format!("{}/{}", DATA_DIR, key);
+            if collection.is_some() {
+                archive.dir(&dir)?;
+            }
+            let tables = snapshot.tables.get(key).map(|tables| tables.iter());
+            for table in tables.into_iter().flatten() {
+                if pending.first_seq <= pending.last_seq && pending.covers(&table.id) {
+                    continue;
+                }
+                let path = format!("{}/{}", dir, table.id.file_name());
+                archive.file(&path, table.bytes, table.contents())?;
+            }
+            let Some(memtable) = snapshot
+                .memtable
+                .get(key)
+                .filter(|memtable| !memtable.is_empty())
+            else {
+                continue;
+            };
+            let mut content = Vec::new();
+            Table::encode(
+                memtable.iter().map(|(key, value)| (key, value.as_deref())),
+                db::table_codec(&db.data_dir(), key)?,
+                &mut content,
+            )
+            .map_err(|err| archive.error(err))?;
+            archive.bytes(&format!("{}/{}", dir, pending.file_name()), &content)?;
+        }
+        Ok(())
+    })?;
+    wal::write_backup(&db.wal_dir(), snapshot.last_seq)?;
+    Ok(written)
+}
+
+// Writes an incremental backup with the log records after the last backup of the database,
+// which the log keeps until then.
+pub fn write_increment(db: &DB, path: &Path) -> Result<FileStats, String> {
+    let wal_dir = db.wal_dir();
+    let since = wal::read_backup(&wal_dir)?.ok_or_else(|| {
+        format!(
+            "The database {} has no backup yet, an incremental backup needs a full backup before it",
+            db.name()
+        )
+    })?;
+    let mut log = Vec::new();
+    let mut last_seq = since;
+    'segments: for segment in wal::segments(&wal_dir)? {
+        // A record cut short at the end of the last segment is still being written.
+        for record in wal::read_segment(&segment.path)?.records {
+            if record.seq <= since {
+                continue;
+            }
+            if record.seq != last_seq + 1 {
+                if last_seq == since {
+                    return Err(format!(
+                        "The log of the database {} no longer holds the records after {}, take a full backup instead",
+                        db.name(),
+                        since
+                    ));
+                }
+                break 'segments;
+            }
+            log.extend(wal::encode(record.entry.canonical().as_bytes()));
+            last_seq = record.seq;
+        }
+    }
+    let increment = Increment {
+        name: db.name().to_string(),
+        first_seq: since + 1,
+        last_seq,
+    };
+    let written = build(path, |archive| {
+        archive.bytes(INCREMENT_FILE, increment.to_json().canonical().as_bytes())?;
+        if !log.is_empty() {
+            archive.dir(WAL_DIR)?;
+            archive.bytes(&increment.segment(), &log)?;
+        }
+        Ok(())
+    })?;
+    wal::write_backup(&wal_dir, last_seq)?;
+    Ok(written)
+}
+
+struct Increment {
+    name: String,
+    first_seq: u64,
+    last_seq: u64,
+}
+
+impl Increment {
+    fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["name".to_string()] = Json::String(self.name.clone());
+        obj["first_seq".to_string()] = Json::Number(JsonNumber::Int(self.first_seq as i64));
+        obj["last_seq".to_string()] = Json::Number(JsonNumber::Int(self.last_seq as i64));
+        Json::Object(obj)
+    }
+
+    fn read(dir: &Path) -> Result<Increment, String> {
+        let path = dir.join(INCREMENT_FILE);
+        let content = fs::read(&path)
+            .map_err(|err| format!("Error while reading {}: {}", path.display(), err))?;
+        let obj = match Json::parse(&content) {
+            Ok(Json::Object(obj)) => obj,
+            _ => {
+                return Err(format!("The increment {} is invalid", path.display()));
+            }
+        };
+        let seq = |name: &str| match obj.get(name) {
+            Some(Json::Number(JsonNumber::Int(seq))) => u64::try_from(*seq).ok(),
+            _ => None,
+        };
+        match (obj.get("name"), seq("first_seq"), seq("last_seq")) {
+            (Some(Json::String(name)), Some(first_seq), Some(last_seq))
+                if first_seq <= last_seq + 1 =>
+            {
+                Ok(Increment {
+                    name: name.clone(),
+                    first_seq,
+                    last_seq,
+                })
+            }
+            _ => Err(format!("The increment {} is invalid", path.display())),
+        }
+    }
+
+    fn segment(&self) -> String {
+        format!(
+            "{}/{:020}.{}",
+            WAL_DIR,
+            self.first_seq,
+            wal::SEGMENT_EXTENSION
+        )
+    }
+}
+
+fn unpack(archive: &Path, dir: &Path) -> Result<(), String> {
+    let error = |err: io::Error| {
+        format!(
+            "Error while unpacking the archive {}: {}",
+            archive.display(),
+            err
+        )
+    };
+    let file = File::open(archive).map_err(error)?;
+    let mut entries = tar::Archive::new(GzDecoder::new(file));
+    for entry in entries.entries().map_err(error)? {
+        let mut entry = entry.map_err(error)?;
+        let kind = entry.header().entry_type();
+        if kind != EntryType::Regular && kind != EntryType::Directory {
+            return Err(format!(
+                "The archive {} is not a backup, it holds the entry {} which is not a file or a directory",
+                archive.display(),
+                entry.path().map_err(error)?.display()
+            ));
+        }
+        if !entry.unpack_in(dir).map_err(error)? {
+            return Err(format!(
+                "The archive {} is not a backup, it holds the entry {} outside of the database directory",
+                archive.display(),
+                entry.path().map_err(error)?.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Reads every entry of every table, so a damaged table fails the restore instead of the first
+// read that happens to reach it.
+fn verify(db: &DB) -> Result<(), String> {
+    for name in db.list_collections()? {
+        let dir = db.data_dir().join(&name);
+        let sides = SIDE_DIRS
+            .iter()
+            .map(|side| dir.join(side))
+            .collect::<Vec<_>>();
+        for dir in sides.into_iter().chain([dir]) {
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries {
+                let path = entry
+                    .map_err(|err| {
+                        format!(
+                            "Error while listing the files in {}: {}",
+                            dir.display(),
+                            err
+                        )
+                    })?
+                    .path();
+                if path.extension().is_none_or(|ext| ext != TABLE_EXTENSION) {
+                    continue;
+                }
+                let table = Arc::new(Table::open(&path)?);
+                for entry in table.scan_uncached() {
+                    entry?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Restores the archive, followed by its incremental backups in order, as a new database in the
+// root. The archives are unpacked, checked and their log replayed in a directory of their own,
+// and the database is only moved into the root once it is complete, where running servers find
+// it like any other database.
+pub fn restore(
+    root: &Root,
+    archive: &Path,
+    increments: &[PathBuf],
+    name: Option<&str>,
+) -> Result<DB, String> {
+    let staging = Path::new(root.path()).join(RESTORE_DIR).join(format!(
+        "{}-{}",
+        process::id(),
+        Utc::now().timestamp_millis()
+    ));
+    let restored = restore_in(root, archive, increments, name, &staging);
+    let _ = fs::remove_dir_all(&staging);
+    let _ = fs::remove_dir(Path::new(root.path()).join(RESTORE_DIR));
+    restored
+}
+
+fn restore_in(
+    root: &Root,
+    archive: &Path,
+    increments: &[PathBuf],
+    name: Option<&str>,
+    staging: &Path,
+) -> Result<DB, String> {
+    let unpacked = staging.join(DATA_DIR);
+    fs::create_dir_all(&unpacked).map_err(|err| {
+        format!(
+            "Error while creating the directory {}: {}",
+            unpacked.display(),
+            err
+        )
+    })?;
+    unpack(archive, &unpacked)?;
+    let mut manifest = Manifest::read(&unpacked)
+        .map_err(|err| format!("The archive {} is not a backup: {}", archive.display(), err))?;
+    // Each increment continues the log where the backup before it ends.
+    let mut last_seq = wal::read_checkpoint(&unpacked.join(WAL_DIR))?;
+    for (ind, path) in increments.iter().enumerate() {
+        let dir = staging.join(format!("increment-{}", ind));
+        fs::create_dir_all(&dir).map_err(|err| {
+            format!(
+                "Error while creating the directory {}: {}",
+                dir.display(),
+                err
+            )
+        })?;
+        unpack(path, &dir)?;
+        let increment = Increment::read(&dir).map_err(|err| {
+            format!(
+                "The archive {} is not an incremental backup: {}",
+                path.display(),
+                err
+            )
+        })?;
+        if increment.name != manifest.name {
+            return Err(format!(
+                "The incremental backup {} belongs to the database {}, not {}",
+                path.display(),
+                increment.name,
+                manifest.name
+            ));
+        }
+        if increment.first_seq != last_seq + 1 {
+            return Err(format!(
+                "The incremental backup {} starts after the log record {}, but the backups before it end at {}",
+                path.display(),
+                increment.first_seq - 1,
+                last_seq
+            ));
+        }
+        if increment.first_seq <= increment.last_seq {
+            let segment = dir.join(increment.segment());
+            let contents = wal::read_segment(&segment)?;
+            let complete = !contents.torn
+                && contents.records.len() as u64 == increment.last_seq - increment.first_seq + 1
+                && contents
+                    .records
+                    .iter()
+                    .zip(increment.first_seq..)
+                    .all(|(record, seq)| record.seq == seq);
+            if !complete {
+                return Err(format!(
+                    "The incremental backup {} is damaged, its log does not hold the records {} to {}",
+                    path.display(),
+                    increment.first_seq,
+                    increment.last_seq
+                ));
+            }
+            let target = unpacked.join(increment.segment());
+            fs::rename(&segment, &target).map_err(|err| {
+                format!(
+                    "Error while moving {} to {}: {}",
+                    segment.display(),
+                    target.display(),
+                    err
+                )
+            })?;
+        }
+        last_seq = increment.last_seq;
+    }
+    let name = name.unwrap_or(&manifest.name).to_string();
+    if !db::is_valid_name(&name) {
+        return Err(format!(
+            "The database name {} is invalid. Names may contain letters, digits, '-', '_' and '.', and may not start with '.'",
+            name
+        ));
+    }
+    root.check_available(&name)?;
+    let dir = staging.join(&name);
+    fs::rename(&unpacked, &dir).map_err(|err| {
+        format!(
+            "Error while moving {} to {}: {}",
+            unpacked.display(),
+            dir.display(),
+            err
+        )
+    })?;
+    if manifest.name != name {
+        manifest.name = name.clone();
+        manifest.write(&dir)?;
+    }
+    let staged = DB::open(&staging.to_string_lossy(), &name)?
+        .ok_or_else(|| format!("The archive {} is not a backup", archive.display()))?;
+    verify(&staged)?;
+    // Writes logged after the tables in the archive are replayed here and flushed, so the
+    // database starts from a checkpoint like one that was shut down cleanly.
+    let replayed = staged.engine().and_then(|engine| {
+        let mut store = engine.lock();
+        if store.recovery().truncated {
+            return Err(format!(
+                "The archive {} is damaged, its log ends with a torn record",
+                archive.display()
+            ));
+        }
+        store.flush()
+    });
+    engine::close(Path::new(staged.path()));
+    replayed?;
+    root.check_available(&name)?;
+    let target = PathBuf::from(root.path()).join(&name);
+    fs::rename(&dir, &target)
+        .and_then(|_| db::sync_dir(Path::new(root.path())))
+        .map_err(|err| {
+            format!(
+                "Error while moving the restored database to {}: {}",
+                target.display(),
+                err
+            )
+        })?;
+    DB::open(root.path(), &name)?
+        .ok_or_else(|| format!("The restored database {} could not be opened", name))
+}
+
+// What a backup restored to: the checks of its collections, and the number of documents each
+// query returned or why it failed, in the order the queries were given.
+pub struct Verification {
+    pub name: String,
+    pub collections: Vec<CollectionCheck>,
+    pub queries: Vec<Result<usize, String>>,
+}
+
+impl Verification {
+    pub fn passed(&self) -> bool {
+        self.collections
+            .iter()
+            .all(|collection| collection.problems.is_empty())
+            && self.queries.iter().all(Result::is_ok)
+    }
+}
+
+// Restores the archive and its increments like a restore does, but into a temporary root that
+// is removed afterwards, then checks every collection and runs the queries on the restored
+// database. A backup that cannot be restored is an error, while problems with what it restored
+// to are reported in the verification.
+pub fn verify_backup(
+    archive: &Path,
+    increments: &[PathBuf],
+    queries: &[(String, Query)],
+) -> Result<Verification, String> {
+    let dir = env::temp_dir().join(format!(
+        "db6-verify-{}-{}",
+        process::id(),
+        Utc::now().timestamp_millis()
+    ));
+    let verified = Root::open(&dir.to_string_lossy()).and_then(|root| {
+        let db = restore(&root, archive, increments, None)?;
+        let verified = check_restored(&db, queries);
+        engine::close(Path::new(db.path()));
+        verified
+    });
+    let _ = fs::remove_dir_all(&dir);
+    verified
+}
+
+fn check_restored(db: &DB, queries: &[(String, Query)]) -> Result<Verification, String> {
+    let collections = db
+        .list_collections()?
+        .iter()
+        .map(|name| db.check_collection(name))
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok(Verification {
+        name: db.name().to_string(),
+        collections,
+        queries: queries
+            .iter()
+            .map(|(collection, query)| query.run(db, collection).map(|page| page.documents.len()))
+            .collect(),
+    })
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db::DB,
+    error::ApiError,
+    http::{ContentType, Credentials, HttpMethod, HttpStatus, Request, Response},
+    json::{Json, JsonNumber, JsonObject},
+    middleware,
+    router::{Context, Router},
+    scram::{self, ClientFirst, ScramExchange, ScramVerifier},
+    server::Server,
+};
+
+pub const AUTH_ROUTE: &str = "/_auth";
+pub const SESSION_TTL: Duration = Duration::from_secs(3600);
+const EXCHANGE_TTL: Duration = Duration::from_secs(60);
+const MAX_EXCHANGES: usize = 10_000;
+const MAX_SESSIONS: usize = 100_000;
+const ID_BYTES: usize = 24;
+const TOKEN_FINGERPRINT_BYTES: usize = 6;
+
+struct Session {
+    user: String,
+    expires: Instant,
+}
+
+// The SCRAM exchanges in progress and the sessions of the clients that logged in. A database
+// is a user, whose password is the password of the database.
+pub struct Sessions {
+    secret: Vec<u8>,
+    exchanges: Mutex<HashMap<String, (ScramExchange, Instant)>>,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions {
+            secret: random_id().into_bytes(),
+            exchanges: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Users without a verifier get one made up from the secret, the same for every attempt, so
+    // the answer does not tell whether the user exists.
+    fn verifier(&self, root: &str, user: &str) -> ScramVerifier {
+        let stored = DB::open(root, user)
+            .ok()
+            .flatten()
+            .and_then(|db| db.manifest().scram.clone());
+        stored.unwrap_or_else(|| {
+            let derive = |purpose: &str| {
+                let mut mac =
+                    Hmac::<Sha256>::new_from_slice(&self.secret).expect("any key length works");
+                mac.update(purpose.as_bytes());
+                mac.update(user.as_bytes());
+                <[u8; 32]>::from(mac.finalize().into_bytes())
+            };
+            ScramVerifier {
+                salt: derive("salt")[..16].to_vec(),
+                iterations: scram::DEFAULT_ITERATIONS,
+                stored_key: derive("stored"),
+                server_key: derive("server"),
+            }
+        })
+    }
+
+    fn start(&self, exchange: ScramExchange) -> Result<String, ApiError> {
+        let mut exchanges = self.exchanges.lock().unwrap();
+        exchanges.retain(|_, (_, started)| started.elapsed() < EXCHANGE_TTL);
+        if exchanges.len() >= MAX_EXCHANGES {
+            return Err(ApiError::new(
+                HttpStatus::ServiceUnavailable,
+                "Too many logins are in progress, try again later".to_string(),
+            ));
+        }
+        let id = random_id();
+        exchanges.insert(id.clone(), (exchange, Instant::now()));
+        Ok(id)
+    }
+
+    // An exchange is only used once, whether the proof is right or not.
+    fn take(&self, id: &str) -> Option<ScramExchange> {
+        self.exchanges
+            .lock()
+            .unwrap()
+            .remove(id)
+            .filter(|(_, started)| started.elapsed() < EXCHANGE_TTL)
+            .map(|(exchange, _)| exchange)
+    }
+
+    fn open(&self, user: String) -> Result<String, ApiError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= MAX_SESSIONS {
+            let now = Instant::now();
+            sessions.retain(|_, session| session.expires > now);
+        }
+        if sessions.len() >= MAX_SESSIONS {
+            return Err(ApiError::new(
+                HttpStatus::ServiceUnavailable,
+                "Too many sessions are open, try again later".to_string(),
+            ));
+        }
+        let token = random_id();
+        sessions.insert(
+            token.clone(),
+            Session {
+                user,
+                expires: Instant::now() + SESSION_TTL,
+            },
+        );
+        Ok(token)
+    }
+
+    pub fn user(&self, token: &str) -> Option<String> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(token) {
+            Some(session) if session.expires > Instant::now() => Some(session.user.clone()),
+            Some(_) => {
+                sessions.remove(token);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+fn random_id() -> String {
+    let mut bytes = [0u8; ID_BYTES];
+    getrandom::getrandom(&mut bytes).expect("the operating system provides random bytes");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// The identity the server verified for the request: a client certificate, a session from
+// /_auth or the admin token. Basic credentials are not checked by the server.
+pub fn identity(server: &Server, request: &Request) -> Option<String> {
+    match &request.credentials {
+        Some(Credentials::Certificate(user)) => Some(format!("user:{}", user)),
+        Some(Credentials::Bearer(token)) => {
+            if let Some(user) = server.sessions.user(token) {
+                return Some(format!("user:{}", user));
+            }
+            let settings = server.settings();
+            let expected = settings.admin_token.as_deref()?;
+            middleware::token_matches(token, expected).then(|| {
+                let digest = Sha256::digest(token.as_bytes());
+                let mut fingerprint = String::from("token:");
+                for byte in &digest[..TOKEN_FINGERPRINT_BYTES] {
+                    fingerprint += &format!("{:02x}", byte);
+                }
+                fingerprint
+            })
+        }
+        _ => None,
+    }
+}
+
+pub fn register(router: &mut Router) {
+    router
+        .add(HttpMethod::POST, AUTH_ROUTE, login)
+        .accepts(ContentType::ApplicationJson);
+}
+
+// A SCRAM-SHA-256 login in two requests. The first sends the client-first message and gets
+// the server-first message with an exchange ID. The second sends the client-final message
+// with that ID, and gets the server signature and a session token to send as a Bearer token.
+fn login(context: &Context) -> Response {
+    let Some(Json::Object(body)) = context.request.json() else {
+        return ApiError::bad_request(
+            "Expected a JSON object with a message field and an optional exchange field"
+                .to_string(),
+        )
+        .to_response();
+    };
+    let field = |name: &str| match body.get(name) {
+        Some(Json::String(value)) => Ok(Some(value.as_str())),
+        None => Ok(None),
+        Some(_) => Err(
+            ApiError::bad_request(format!("The {} field should be a string", name)).to_response(),
+        ),
+    };
+    let (message, exchange) = match (field("message"), field("exchange")) {
+        (Ok(Some(message)), Ok(exchange)) => (message, exchange),
+        (Ok(None), _) => {
+            return ApiError::bad_request("The message field is missing".to_string()).to_response();
+        }
+        (Err(resp), _) | (_, Err(resp)) => {
+            return resp;
+        }
+    };
+    let sessions = &context.server.sessions;
+    let result = match exchange {
+        None => client_first(context, message),
+        Some(id) => match sessions.take(id) {
+            Some(exchange) => client_final(sessions, &exchange, message),
+            None => Err(unauthorized(
+                "The exchange is unknown or has expired".to_string(),
+            )),
+        },
+    };
+    match result {
+        Ok(obj) => Response::json(HttpStatus::Ok, Json::Object(obj)),
+        Err(err) => {
+            let unauthorized = err.status == HttpStatus::Unauthorized;
+            let mut resp = err.to_response();
+            if unauthorized {
+                resp.set_header("WWW-Authenticate", scram::MECHANISM.to_string());
+            }
+            resp
+        }
+    }
+}
+
+fn unauthorized(message: String) -> ApiError {
+    ApiError::new(HttpStatus::Unauthorized, message).with_code("authentication_failed")
+}
+
+fn client_first(context: &Context, message: &str) -> Result<JsonObject, ApiError> {
+    let client_first = message
+        .parse::<ClientFirst>()
+        .map_err(ApiError::bad_request)?;
+    let sessions = &context.server.sessions;
+    let verifier = sessions.verifier(&context.server.root, &client_first.user);
+    let exchange = ScramExchange::start(&client_first, verifier).map_err(ApiError::internal)?;
+    let mut obj = JsonObject::new();
+    obj["message".to_string()] = Json::String(exchange.server_first().to_string());
+    obj["exchange".to_string()] = Json::String(sessions.start(exchange)?);
+    Ok(obj)
+}
+
+fn client_final(
+    sessions: &Sessions,
+    exchange: &ScramExchange,
+    message: &str,
+) -> Result<JsonObject, ApiError> {
+    let server_final = exchange.finish(message).map_err(unauthorized)?;
+    let mut obj = JsonObject::new();
+    obj["message".to_string()] = Json::String(server_final);
+    obj["token".to_string()] = Json::String(sessions.open(exchange.user.clone())?);
+    obj["expires_in".to_string()] = Json::Number(JsonNumber::Int(SESSION_TTL.as_secs() as i64));
+    Ok(obj)
+}
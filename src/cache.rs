@@ -0,0 +1,158 @@
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use crate::json::{Json, JsonNumber, JsonObject};
+
+pub const DEFAULT_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+// Counted for every cached block on top of its data, so that a cache full of small blocks still
+// stays near its capacity.
+const BLOCK_OVERHEAD: u64 = 64;
+
+// A block is identified by the serial number its table got when it was opened, which is never
+// reused, and by its position in the table.
+pub type BlockKey = (u64, usize);
+
+// Blocks of every table are decoded once and shared by every read until the least recently used
+// ones are evicted to stay within the capacity.
+struct BlockCache {
+    capacity: u64,
+    bytes: u64,
+    tick: u64,
+    blocks: HashMap<BlockKey, (Arc<Vec<u8>>, u64)>,
+    // The keys of the blocks by the tick of their last use, least recent first.
+    recency: BTreeMap<u64, BlockKey>,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl BlockCache {
+    fn evict(&mut self) {
+        while self.bytes > self.capacity {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some((block, _)) = self.blocks.remove(&key) {
+                self.bytes -= block_size(&block);
+                self.evictions += 1;
+            }
+        }
+    }
+}
+
+thread_local! {
+    // The hits and misses of the lookups made on this thread, so that a request can tell its own
+    // apart from those of the requests handled at the same time.
+    static LOOKUPS: Cell<(u64, u64)> = const { Cell::new((0, 0)) };
+}
+
+static CACHE: LazyLock<Mutex<BlockCache>> = LazyLock::new(|| {
+    Mutex::new(BlockCache {
+        capacity: DEFAULT_CACHE_SIZE,
+        bytes: 0,
+        tick: 0,
+        blocks: HashMap::new(),
+        recency: BTreeMap::new(),
+        hits: 0,
+        misses: 0,
+        evictions: 0,
+    })
+});
+
+fn block_size(block: &[u8]) -> u64 {
+    block.len() as u64 + BLOCK_OVERHEAD
+}
+
+// A capacity of 0 turns the cache off. Shrinking it evicts blocks right away.
+pub fn configure(capacity: u64) {
+    let mut cache = CACHE.lock().unwrap();
+    cache.capacity = capacity;
+    cache.evict();
+}
+
+pub fn get(key: BlockKey) -> Option<Arc<Vec<u8>>> {
+    let mut cache = CACHE.lock().unwrap();
+    cache.tick += 1;
+    let tick = cache.tick;
+    let Some((block, used)) = cache.blocks.get_mut(&key) else {
+        cache.misses += 1;
+        LOOKUPS.set((LOOKUPS.get().0, LOOKUPS.get().1 + 1));
+        return None;
+    };
+    let block = block.clone();
+    let last_used = std::mem::replace(used, tick);
+    cache.recency.remove(&last_used);
+    cache.recency.insert(tick, key);
+    cache.hits += 1;
+    LOOKUPS.set((LOOKUPS.get().0 + 1, LOOKUPS.get().1));
+    Some(block)
+}
+
+// Blocks larger than the whole cache are not kept.
+pub fn insert(key: BlockKey, block: Arc<Vec<u8>>) {
+    let mut cache = CACHE.lock().unwrap();
+    if block_size(&block) > cache.capacity {
+        return;
+    }
+    cache.tick += 1;
+    let tick = cache.tick;
+    cache.bytes += block_size(&block);
+    if let Some((previous, last_used)) = cache.blocks.insert(key, (block, tick)) {
+        cache.bytes -= block_size(&previous);
+        cache.recency.remove(&last_used);
+    }
+    cache.recency.insert(tick, key);
+    cache.evict();
+}
+
+// The hits and misses of the lookups made on this thread so far.
+pub fn thread_lookups() -> (u64, u64) {
+    LOOKUPS.get()
+}
+
+pub struct CacheStats {
+    pub capacity: u64,
+    pub bytes: u64,
+    pub blocks: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        for (name, value) in [
+            ("capacity", self.capacity),
+            ("bytes", self.bytes),
+            ("blocks", self.blocks),
+            ("hits", self.hits),
+            ("misses", self.misses),
+            ("evictions", self.evictions),
+        ] {
+            obj[name.to_string()] = Json::Number(JsonNumber::Int(value as i64));
+        }
+        let lookups = self.hits + self.misses;
+        let hit_rate = match lookups {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        };
+        obj["hit_rate".to_string()] = Json::Number(JsonNumber::Float(hit_rate));
+        Json::Object(obj)
+    }
+}
+
+pub fn stats() -> CacheStats {
+    let cache = CACHE.lock().unwrap();
+    CacheStats {
+        capacity: cache.capacity,
+        bytes: cache.bytes,
+        blocks: cache.blocks.len() as u64,
+        hits: cache.hits,
+        misses: cache.misses,
+        evictions: cache.evictions,
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Mutex,
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    db::DB,
+    json::{Json, JsonNumber, JsonObject},
+    sse::Event,
+};
+
+const TAIL_BATCH: usize = 256;
+// The events a subscriber may fall behind by before it is dropped.
+pub const SUBSCRIBER_BUFFER: usize = 1024;
+
+struct Subscriber {
+    database: String,
+    sender: SyncSender<String>,
+    // Wake-ups only tell that something changed, so one that is already waiting is enough.
+    wake_up: bool,
+}
+
+static SUBSCRIBERS: Mutex<Vec<Subscriber>> = Mutex::new(Vec::new());
+
+// A subscriber that falls SUBSCRIBER_BUFFER events behind is dropped, which ends its stream once
+// it read the events it has, so that a slow client cannot hold on to more and more memory.
+pub fn subscribe(database: &str) -> Receiver<String> {
+    add_subscriber(database, SUBSCRIBER_BUFFER, false)
+}
+
+fn wake_ups(database: &str) -> Receiver<String> {
+    add_subscriber(database, 1, true)
+}
+
+fn add_subscriber(database: &str, buffer: usize, wake_up: bool) -> Receiver<String> {
+    let (sender, receiver) = mpsc::sync_channel(buffer);
+    SUBSCRIBERS.lock().unwrap().push(Subscriber {
+        database: database.to_string(),
+        sender,
+        wake_up,
+    });
+    receiver
+}
+
+pub fn publish(database: &str, event: &Json) {
+    let message = event.to_string();
+    SUBSCRIBERS.lock().unwrap().retain(|subscriber| {
+        subscriber.database != database
+            || match subscriber.sender.try_send(message.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => subscriber.wake_up,
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+    });
+}
+
+pub fn subscriber_count(database: &str) -> usize {
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|subscriber| subscriber.database == database)
+        .count()
+}
+
+// A JSON merge patch (RFC 7386) that turns the old document into the new one.
+pub fn delta(old: &Json, new: &Json) -> Json {
+    let (Json::Object(old), Json::Object(new)) = (old, new) else {
+        return new.clone();
+    };
+    let mut patch = JsonObject::new();
+    for (key, value) in new.iter() {
+        match old.get(key) {
+            Some(previous) if previous.canonical() == value.canonical() => {}
+            Some(previous) => patch[key.clone()] = delta(previous, value),
+            None => patch[key.clone()] = value.clone(),
+        }
+    }
+    for (key, _) in old.iter() {
+        if new.get(key).is_none() {
+            patch[key.clone()] = Json::Null;
+        }
+    }
+    Json::Object(patch)
+}
+
+// Event streams are fed from anything that can wait for the next event.
+pub trait Events<T> {
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError>;
+}
+
+impl<T> Events<T> for Receiver<T> {
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        Receiver::recv_timeout(self, timeout)
+    }
+}
+
+pub struct Change {
+    pub seq: u64,
+    pub change: Json,
+}
+
+impl From<Change> for Event {
+    fn from(change: Change) -> Self {
+        Event {
+            id: Some(change.seq.to_string()),
+            ..Event::named("change", change.change.to_string())
+        }
+    }
+}
+
+impl From<Change> for String {
+    fn from(change: Change) -> Self {
+        change.change.to_string()
+    }
+}
+
+// Follows the change feed of a collection from a sequence number. Notifications of the
+// database only wake it up, the changes themselves are always read from the feed, so none are
+// missed or repeated, however far behind the client is.
+pub struct Tail {
+    db: DB,
+    collection: String,
+    since: u64,
+    deltas: bool,
+    pending: VecDeque<Change>,
+    notifications: Receiver<String>,
+}
+
+impl Tail {
+    pub fn new(db: DB, collection: &str, since: u64, deltas: bool) -> Tail {
+        Tail {
+            notifications: wake_ups(db.name()),
+            db,
+            collection: collection.to_string(),
+            since,
+            deltas,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn since(&self) -> u64 {
+        self.since
+    }
+
+    // Returns the changes that are in the feed, up to about limit of them.
+    pub fn read(&mut self, limit: usize) -> Result<Vec<Change>, String> {
+        let mut changes = Vec::new();
+        for mut change in self.db.changes(&self.collection, self.since, limit)? {
+            let Json::Object(obj) = &mut change else {
+                continue;
+            };
+            let seq = match obj.get("seq") {
+                Some(Json::Number(JsonNumber::Int(seq))) => *seq as u64,
+                _ => {
+                    return Err(format!(
+                        "A change in the feed of {} has no sequence number",
+                        self.collection
+                    ));
+                }
+            };
+            if !self.deltas {
+                obj.remove("delta");
+            }
+            self.since = seq;
+            changes.push(Change { seq, change });
+        }
+        Ok(changes)
+    }
+
+    // Waits until there are changes after the sequence number, or the timeout passes.
+    pub fn wait(&mut self, limit: usize, timeout: Duration) -> Result<Vec<Change>, String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let changes = self.read(limit)?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !changes.is_empty() || remaining.is_zero() {
+                return Ok(changes);
+            }
+            if self.notifications.recv_timeout(remaining).is_err() {
+                return Ok(Vec::new());
+            }
+        }
+    }
+}
+
+impl Events<Change> for Tail {
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<Change, RecvTimeoutError> {
+        if self.pending.is_empty() {
+            match self.wait(TAIL_BATCH, timeout) {
+                Ok(changes) => self.pending.extend(changes),
+                Err(err) => {
+                    eprintln!(
+                        "Could not read the change feed of {}: {}",
+                        self.collection, err
+                    );
+                    return Err(RecvTimeoutError::Disconnected);
+                }
+            }
+        }
+        self.pending.pop_front().ok_or(RecvTimeoutError::Timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lagging_subscribers_are_dropped() {
+        let database = "lagging-subscribers";
+        let receiver = subscribe(database);
+        let event = Json::String("changed".to_string());
+        for _ in 0..SUBSCRIBER_BUFFER {
+            publish(database, &event);
+        }
+        assert_eq!(subscriber_count(database), 1);
+        publish(database, &event);
+        assert_eq!(subscriber_count(database), 0);
+        // The events it was sent are still read before the stream ends.
+        assert_eq!(receiver.try_iter().count(), SUBSCRIBER_BUFFER);
+        assert_eq!(
+            receiver.recv_timeout(Duration::ZERO),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn wake_ups_are_kept_however_many_are_missed() {
+        let database = "missed-wake-ups";
+        let receiver = wake_ups(database);
+        let event = Json::String("changed".to_string());
+        for _ in 0..3 {
+            publish(database, &event);
+        }
+        assert_eq!(subscriber_count(database), 1);
+        assert_eq!(receiver.try_iter().count(), 1);
+        drop(receiver);
+        publish(database, &event);
+        assert_eq!(subscriber_count(database), 0);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::compression::{self, Encoding};
+
+pub const CODEC_MAGIC: &[u8; 4] = b"DB6C";
+pub const CODEC_FORMAT_VERSION: u8 = 1;
+
+pub trait Codec: Send + Sync {
+    fn id(&self) -> u8;
+
+    fn name(&self) -> &'static str;
+
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, String>;
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+pub struct IdentityCodec;
+
+impl Codec for IdentityCodec {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn name(&self) -> &'static str {
+        "identity"
+    }
+
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(data.to_vec())
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(data.to_vec())
+    }
+}
+
+pub struct CompressionCodec {
+    id: u8,
+    encoding: Encoding,
+    limit: usize,
+}
+
+impl CompressionCodec {
+    pub fn gzip(limit: usize) -> CompressionCodec {
+        CompressionCodec {
+            id: 1,
+            encoding: Encoding::Gzip,
+            limit,
+        }
+    }
+
+    pub fn deflate(limit: usize) -> CompressionCodec {
+        CompressionCodec {
+            id: 2,
+            encoding: Encoding::Deflate,
+            limit,
+        }
+    }
+}
+
+impl Codec for CompressionCodec {
+    fn id(&self) -> u8 {
+        self.id
+    }
+
+    fn name(&self) -> &'static str {
+        self.encoding.name()
+    }
+
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        compression::compress(data, self.encoding)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        compression::decompress(data, self.encoding, self.limit)
+    }
+}
+
+pub struct CodecRegistry {
+    codecs: Vec<Box<dyn Codec>>,
+}
+
+impl Default for CodecRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CodecRegistry {
+    pub fn new() -> CodecRegistry {
+        CodecRegistry { codecs: Vec::new() }
+    }
+
+    pub fn with_defaults(limit: usize) -> CodecRegistry {
+        let mut registry = CodecRegistry::new();
+        let defaults: Vec<Box<dyn Codec>> = vec![
+            Box::new(IdentityCodec),
+            Box::new(CompressionCodec::gzip(limit)),
+            Box::new(CompressionCodec::deflate(limit)),
+        ];
+        for codec in defaults {
+            let _ = registry.register(codec);
+        }
+        registry
+    }
+
+    pub fn register(&mut self, codec: Box<dyn Codec>) -> Result<(), String> {
+        if let Some(existing) = self.get(codec.id()) {
+            return Err(format!(
+                "Cannot register the codec {} with ID {}, as the codec {} already uses that ID",
+                codec.name(),
+                codec.id(),
+                existing.name()
+            ));
+        }
+        if self.by_name(codec.name()).is_some() {
+            return Err(format!(
+                "A codec named {} is already registered",
+                codec.name()
+            ));
+        }
+        self.codecs.push(codec);
+        Ok(())
+    }
+
+    pub fn get(&self, id: u8) -> Option<&dyn Codec> {
+        self.codecs
+            .iter()
+            .find(|codec| codec.id() == id)
+            .map(|codec| codec.as_ref())
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&dyn Codec> {
+        self.codecs
+            .iter()
+            .find(|codec| codec.name() == name)
+            .map(|codec| codec.as_ref())
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.codecs.iter().map(|codec| codec.name()).collect()
+    }
+
+    pub fn encode(&self, data: &[u8], chain: &[&str]) -> Result<Vec<u8>, String> {
+        if chain.len() > u8::MAX as usize {
+            return Err(format!(
+                "A codec chain can have at most {} codecs, found {}",
+                u8::MAX,
+                chain.len()
+            ));
+        }
+        let mut header = Vec::<u8>::with_capacity(CODEC_MAGIC.len() + 2 + chain.len());
+        header.extend_from_slice(CODEC_MAGIC);
+        header.push(CODEC_FORMAT_VERSION);
+        header.push(chain.len() as u8);
+        let mut payload = data.to_vec();
+        for name in chain {
+            match self.by_name(name) {
+                Some(codec) => {
+                    header.push(codec.id());
+                    payload = codec.encode(&payload)?;
+                }
+                None => {
+                    return Err(format!(
+                        "The codec {} is not registered. Available codecs are {}",
+                        name,
+                        self.names().join(", ")
+                    ));
+                }
+            }
+        }
+        header.extend_from_slice(&payload);
+        Ok(header)
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let prefix = CODEC_MAGIC.len() + 2;
+        if data.len() < prefix || &data[..CODEC_MAGIC.len()] != CODEC_MAGIC {
+            return Err("The data does not start with a codec header".to_string());
+        }
+        let version = data[CODEC_MAGIC.len()];
+        if version > CODEC_FORMAT_VERSION {
+            return Err(format!(
+                "The data was written with codec format version {}, but this build only supports up to version {}",
+                version, CODEC_FORMAT_VERSION
+            ));
+        }
+        let count = data[CODEC_MAGIC.len() + 1] as usize;
+        if data.len() < prefix + count {
+            return Err("The codec header is truncated".to_string());
+        }
+        let ids = &data[prefix..(prefix + count)];
+        let mut payload = data[(prefix + count)..].to_vec();
+        for id in ids.iter().rev() {
+            match self.get(*id) {
+                Some(codec) => {
+                    payload = codec.decode(&payload)?;
+                }
+                None => {
+                    return Err(format!(
+                        "The data uses the codec with ID {}, which is not registered in this build",
+                        id
+                    ));
+                }
+            }
+        }
+        Ok(payload)
+    }
+}
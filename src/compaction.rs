@@ -0,0 +1,195 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    db::{self, DB},
+    engine::{self, MAX_TABLES},
+    server::Server,
+    service::Service,
+};
+
+pub const DEFAULT_COMPACTION_RATE: u64 = 16 * 1024 * 1024;
+// The background compaction leaves collections with fewer tables alone, a compaction on
+// demand merges any collection with more than one.
+pub const BACKGROUND_MIN_TABLES: usize = 4;
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+const MIN_SLEEP: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CompactionConfig {
+    // Bytes read and written per second by a compaction, or 0 for no limit.
+    pub rate: u64,
+}
+
+static CONFIG: RwLock<CompactionConfig> = RwLock::new(CompactionConfig {
+    rate: DEFAULT_COMPACTION_RATE,
+});
+
+pub fn configure(config: CompactionConfig) {
+    *CONFIG.write().unwrap() = config;
+}
+
+pub fn config() -> CompactionConfig {
+    *CONFIG.read().unwrap()
+}
+
+// Keeps the bytes moved by a compaction under the configured rate, by sleeping whenever it is
+// ahead of it.
+pub struct Throttle {
+    rate: u64,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Throttle {
+        Throttle {
+            rate,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    pub fn consume(&mut self, bytes: u64) {
+        if self.rate == 0 {
+            return;
+        }
+        self.bytes += bytes;
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.rate as f64);
+        let ahead = due.saturating_sub(self.start.elapsed());
+        if ahead >= MIN_SLEEP {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+// The collections a flush left with more than MAX_TABLES tables, by database path, and whether a
+// thread is merging them.
+struct Merges {
+    pending: BTreeSet<(PathBuf, String)>,
+    running: bool,
+}
+
+static MERGES: Mutex<Merges> = Mutex::new(Merges {
+    pending: BTreeSet::new(),
+    running: false,
+});
+
+// Merges the tables of the collection in the background, throttled like every other compaction.
+// A thread is started for the pending merges and stops once there are none left, so flushes
+// outside of a server are merged as well.
+pub fn schedule(path: &Path, collection: &str) {
+    let mut merges = MERGES.lock().unwrap();
+    merges
+        .pending
+        .insert((path.to_path_buf(), collection.to_string()));
+    if merges.running {
+        return;
+    }
+    merges.running = true;
+    thread::spawn(|| {
+        loop {
+            let next = {
+                let mut merges = MERGES.lock().unwrap();
+                let next = merges.pending.pop_first();
+                merges.running = next.is_some();
+                next
+            };
+            let Some((path, collection)) = next else {
+                return;
+            };
+            // A database that was closed meanwhile is merged by the next flush or compaction.
+            let Some(engine) = engine::loaded(&path) else {
+                continue;
+            };
+            let mut throttle = Throttle::new(config().rate);
+            if let Err(err) = engine.compact(&collection, MAX_TABLES + 1, &mut throttle) {
+                eprintln!(
+                    "Could not merge the tables of the collection {} at {}: {}",
+                    collection,
+                    path.display(),
+                    err
+                );
+            }
+        }
+    });
+}
+
+// Waits until the merges scheduled by flushes are done.
+pub fn wait_for_merges() {
+    while MERGES.lock().unwrap().running {
+        thread::sleep(MIN_SLEEP);
+    }
+}
+
+pub struct Compactor;
+
+impl Service for Compactor {
+    fn name(&self) -> &str {
+        "compactor"
+    }
+
+    fn start(&self, server: &Server) -> Result<(), String> {
+        let root = server.root.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(COMPACTION_INTERVAL);
+                for name in db::list(&root).unwrap_or_default() {
+                    let compacted = DB::open(&root, &name).and_then(|db| match db {
+                        Some(db) if !db.is_closed() => {
+                            db.compact(BACKGROUND_MIN_TABLES).map(|_| ())
+                        }
+                        _ => Ok(()),
+                    });
+                    if let Err(err) = compacted {
+                        eprintln!("Could not compact the database {}: {}", name, err);
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use super::*;
+    use crate::{json::Json, test_util::TempDir};
+
+    fn tables(db: &DB) -> usize {
+        db.engine().unwrap().lock().tables().snapshot("keys").len()
+    }
+
+    #[test]
+    fn flushes_leave_merging_to_the_background() {
+        let dir = TempDir::new().unwrap();
+        let db = DB::create(&dir.path().to_string_lossy(), "merged", String::new()).unwrap();
+        let document = Json::parse(b"{}").unwrap();
+        for round in 0..MAX_TABLES {
+            db.put("keys", &format!("k{}", round), &document).unwrap();
+            db.flush().unwrap();
+        }
+        assert_eq!(tables(&db), MAX_TABLES);
+        // Holding the compaction keeps the merge the next flush schedules from starting.
+        let compacting = engine::COMPACTING.lock().unwrap();
+        db.put("keys", "last", &document).unwrap();
+        db.flush().unwrap();
+        assert_eq!(tables(&db), MAX_TABLES + 1);
+        drop(compacting);
+        wait_for_merges();
+        assert_eq!(tables(&db), 1);
+        assert_eq!(
+            db.scan("keys", (Bound::Unbounded, Bound::Unbounded))
+                .unwrap()
+                .count(),
+            MAX_TABLES + 1
+        );
+    }
+}
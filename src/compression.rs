@@ -0,0 +1,171 @@
+use std::io::{Read, Write};
+
+use flate2::{
+    Compression,
+    read::{DeflateDecoder, GzDecoder},
+    write::{DeflateEncoder, GzEncoder},
+};
+
+use crate::http::{Body, Request, Response};
+
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+    Identity,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Identity => "identity",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name.trim() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            "identity" | "" => Some(Encoding::Identity),
+            _ => None,
+        }
+    }
+}
+
+pub fn negotiate(accept_encoding: &str) -> Encoding {
+    let mut best = Encoding::Identity;
+    let mut best_quality = 0.0f32;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let mut quality = 1.0f32;
+        for param in parts {
+            if let Some(q) = param.trim().strip_prefix("q=") {
+                quality = q.parse::<f32>().unwrap_or(0.0);
+            }
+        }
+        let encoding = match name {
+            "*" => Encoding::Gzip,
+            _ => match Encoding::from_name(name) {
+                Some(encoding) => encoding,
+                None => {
+                    continue;
+                }
+            },
+        };
+        if encoding != Encoding::Identity && quality > best_quality {
+            best = encoding;
+            best_quality = quality;
+        }
+    }
+    best
+}
+
+pub fn compress(data: &[u8], encoding: Encoding) -> Result<Vec<u8>, String> {
+    let result = match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).and_then(|_| encoder.finish())
+        }
+        Encoding::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).and_then(|_| encoder.finish())
+        }
+        Encoding::Identity => Ok(data.to_vec()),
+    };
+    result.map_err(|err| format!("Error while compressing with {}: {}", encoding.name(), err))
+}
+
+pub fn decompress(data: &[u8], encoding: Encoding, limit: usize) -> Result<Vec<u8>, String> {
+    let mut result = Vec::<u8>::new();
+    let read = match encoding {
+        Encoding::Gzip => GzDecoder::new(data)
+            .take(limit as u64 + 1)
+            .read_to_end(&mut result),
+        Encoding::Deflate => DeflateDecoder::new(data)
+            .take(limit as u64 + 1)
+            .read_to_end(&mut result),
+        Encoding::Identity => {
+            result.extend_from_slice(data);
+            Ok(data.len())
+        }
+    };
+    match read {
+        Ok(_) if result.len() > limit => Err(format!(
+            "The decompressed request body exceeds the maximum allowed size of {} bytes",
+            limit
+        )),
+        Ok(_) => Ok(result),
+        Err(err) => Err(format!(
+            "Error while decompressing the {} request body: {}",
+            encoding.name(),
+            err
+        )),
+    }
+}
+
+pub fn compress_response(request: &Request, resp: &mut Response) {
+    let encoding = match &request.accept_encoding {
+        Some(accept) => negotiate(accept),
+        None => Encoding::Identity,
+    };
+    if encoding == Encoding::Identity
+        || !matches!(resp.body, Body::ApplicationJson(_) | Body::TextPlain(_))
+        || resp.header("Content-Encoding").is_some()
+    {
+        return;
+    }
+    let content = resp.body.to_bytes();
+    if content.len() < COMPRESSION_THRESHOLD {
+        return;
+    }
+    if let Ok(compressed) = compress(&content, encoding) {
+        resp.set_header("Content-Type", resp.body.content_type().to_string());
+        resp.set_header("Content-Encoding", encoding.name().to_string());
+        resp.set_header("Vary", "Accept-Encoding".to_string());
+        if let Some(tag) = resp.header("ETag")
+            && !tag.starts_with("W/")
+        {
+            resp.set_header("ETag", format!("W/{}", tag));
+        }
+        resp.body = Body::ApplicationOctetStream(compressed);
+    }
+}
+
+pub enum Decoder<R: Read> {
+    Identity(R),
+    Gzip(GzDecoder<R>),
+    Deflate(DeflateDecoder<R>),
+}
+
+impl<R: Read> Decoder<R> {
+    pub fn new(reader: R, encoding: Encoding) -> Decoder<R> {
+        match encoding {
+            Encoding::Gzip => Decoder::Gzip(GzDecoder::new(reader)),
+            Encoding::Deflate => Decoder::Deflate(DeflateDecoder::new(reader)),
+            Encoding::Identity => Decoder::Identity(reader),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        match self {
+            Decoder::Identity(reader) => reader,
+            Decoder::Gzip(decoder) => decoder.get_mut(),
+            Decoder::Deflate(decoder) => decoder.get_mut(),
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Decoder::Identity(reader) => reader.read(buf),
+            Decoder::Gzip(decoder) => decoder.read(buf),
+            Decoder::Deflate(decoder) => decoder.read(buf),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::{env, fs, path::Path};
+
+use toml::{Table, Value};
+
+pub const CONFIG_FILE: &str = "db6.toml";
+pub const ENV_PREFIX: &str = "DB6_";
+
+const FLAGS: &[&str] = &["log-bodies", "warm-up"];
+
+pub const RUN_SETTINGS: &[&str] = &[
+    "port",
+    "bind",
+    "log-sample",
+    "log-redact",
+    "log-format",
+    "log-bodies",
+    "idle-timeout",
+    "max-body-size",
+    "read-timeout",
+    "write-timeout",
+    "workers",
+    "queue-size",
+    "max-connections",
+    "cors-origin",
+    "cors-max-age",
+    "tls-cert",
+    "tls-key",
+    "tls-client-ca",
+    "admin-token",
+    "maintenance",
+    "token-quota",
+    "db-quota",
+    "rate-limit-read",
+    "rate-limit-write",
+    "rate-limit-admin",
+    "alert-webhook",
+    "alert-smtp",
+    "alert-email-from",
+    "alert-email-to",
+    "alert-throttle",
+    "alert-disk-low",
+    "alert-auth-failures",
+    "durability",
+    "wal-segment-size",
+    "compaction-rate",
+    "cache-size",
+    "warm-up",
+];
+
+pub struct ConfigArg {
+    pub flag: String,
+    pub value: Option<String>,
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(string) => Some(string.clone()),
+        Value::Integer(int) => Some(int.to_string()),
+        Value::Float(float) => Some(float.to_string()),
+        Value::Datetime(datetime) => Some(datetime.to_string()),
+        _ => None,
+    }
+}
+
+fn collect(
+    table: &Table,
+    prefix: &str,
+    path: &Path,
+    args: &mut Vec<ConfigArg>,
+) -> Result<(), String> {
+    for (name, value) in table {
+        let key = prefix.to_string() + &name.replace('_', "-");
+        if let Value::Table(section) = value {
+            collect(section, &(key + "-"), path, args)?;
+            continue;
+        }
+        if !RUN_SETTINGS.contains(&key.as_str()) {
+            return Err(format!(
+                "Unknown setting {} in the configuration file {}",
+                key,
+                path.display()
+            ));
+        }
+        let value = match value {
+            Value::Boolean(true) => None,
+            Value::Boolean(false) => continue,
+            Value::Array(items) => Some(
+                items
+                    .iter()
+                    .map(|item| {
+                        scalar(item).ok_or_else(|| {
+                            format!(
+                                "The setting {} in the configuration file {} should be a list of plain values",
+                                key,
+                                path.display()
+                            )
+                        })
+                    })
+                    .collect::<Result<Vec<_>, String>>()?
+                    .join(","),
+            ),
+            value => scalar(value),
+        };
+        args.push(ConfigArg {
+            flag: "--".to_string() + &key,
+            value,
+        });
+    }
+    Ok(())
+}
+
+pub fn env_name(setting: &str) -> String {
+    ENV_PREFIX.to_string() + &setting.to_ascii_uppercase().replace('-', "_")
+}
+
+pub fn from_env(settings: &[&str]) -> Result<Vec<ConfigArg>, String> {
+    let mut args = Vec::new();
+    for setting in settings {
+        let name = env_name(setting);
+        let value = match env::var(&name) {
+            Ok(value) => value,
+            Err(env::VarError::NotPresent) => continue,
+            Err(env::VarError::NotUnicode(_)) => {
+                return Err(format!(
+                    "The environment variable {} is not valid UTF-8",
+                    name
+                ));
+            }
+        };
+        let value = if FLAGS.contains(setting) {
+            match value.trim().to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" => None,
+                "" | "0" | "false" | "no" => continue,
+                _ => {
+                    return Err(format!(
+                        "Expected true or false for the environment variable {}",
+                        name
+                    ));
+                }
+            }
+        } else {
+            Some(value)
+        };
+        args.push(ConfigArg {
+            flag: "--".to_string() + setting,
+            value,
+        });
+    }
+    Ok(args)
+}
+
+pub fn load(root: &str) -> Result<Vec<ConfigArg>, String> {
+    let path = Path::new(root).join(CONFIG_FILE);
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(&path).map_err(|err| {
+        format!(
+            "Error while reading the configuration file {}: {}",
+            path.display(),
+            err
+        )
+    })?;
+    let table = content.parse::<Table>().map_err(|err| {
+        format!(
+            "The configuration file {} is not valid TOML: {}",
+            path.display(),
+            err
+        )
+    })?;
+    let mut args = Vec::new();
+    collect(&table, "", &path, &mut args)?;
+    Ok(args)
+}
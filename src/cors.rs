@@ -0,0 +1,225 @@
+use std::{fs, path::Path};
+
+use crate::{
+    db,
+    http::{HttpMethod, HttpStatus, Request, Response},
+    json::Json,
+    server::Server,
+};
+
+pub const CORS_CONFIG_FILE: &str = "cors.json";
+
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub max_age: u64,
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CorsConfig {
+    pub fn new() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_headers: vec![
+                "content-type".to_string(),
+                "content-encoding".to_string(),
+                "authorization".to_string(),
+            ],
+            max_age: 600,
+            allow_credentials: false,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.allowed_origins.is_empty()
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    pub fn from_json(value: &Json) -> Result<CorsConfig, String> {
+        let obj = match value {
+            Json::Object(obj) => obj,
+            _ => {
+                return Err("The CORS configuration should be a JSON object".to_string());
+            }
+        };
+        let mut config = CorsConfig::new();
+        if let Some(origins) = obj.get("origins") {
+            config.allowed_origins = string_list(origins, "origins")?;
+        }
+        if let Some(headers) = obj.get("headers") {
+            config.allowed_headers = string_list(headers, "headers")?
+                .iter()
+                .map(|header| header.to_ascii_lowercase())
+                .collect();
+        }
+        match obj.get("max_age") {
+            Some(Json::Number(num)) => {
+                config.max_age = num.to_string().parse::<u64>().map_err(|_| {
+                    format!(
+                        "The max_age {} of the CORS configuration should be a non-negative integer",
+                        num
+                    )
+                })?;
+            }
+            Some(_) => {
+                return Err(
+                    "The max_age of the CORS configuration should be a number of seconds"
+                        .to_string(),
+                );
+            }
+            None => {}
+        }
+        match obj.get("credentials") {
+            Some(Json::Bool(val)) => {
+                config.allow_credentials = *val;
+            }
+            Some(_) => {
+                return Err(
+                    "The credentials field of the CORS configuration should be a boolean"
+                        .to_string(),
+                );
+            }
+            None => {}
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Option<CorsConfig>, String> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let content = fs::read(path).map_err(|err| {
+            format!(
+                "Error while reading the CORS configuration at {}: {}",
+                path.display(),
+                err
+            )
+        })?;
+        let value = Json::parse(&content).map_err(|err| {
+            format!(
+                "The CORS configuration at {} is not valid JSON: {}",
+                path.display(),
+                err
+            )
+        })?;
+        CorsConfig::from_json(&value).map(Some)
+    }
+}
+
+fn string_list(value: &Json, field: &str) -> Result<Vec<String>, String> {
+    match value {
+        Json::List(items) => items
+            .iter()
+            .map(|item| match item {
+                Json::String(val) => Ok(val.clone()),
+                _ => Err(format!(
+                    "The {} of the CORS configuration should only contain strings",
+                    field
+                )),
+            })
+            .collect(),
+        _ => Err(format!(
+            "The {} of the CORS configuration should be a list of strings",
+            field
+        )),
+    }
+}
+
+pub fn resolve(server: &Server, request: &Request) -> CorsConfig {
+    if let Some(name) = db::route_database(&request.route) {
+        let path = Path::new(&server.root).join(name).join(CORS_CONFIG_FILE);
+        match CorsConfig::load(&path) {
+            Ok(Some(config)) => {
+                return config;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                eprintln!("{}. Falling back to the global CORS configuration", err);
+            }
+        }
+    }
+    server.settings().cors.clone()
+}
+
+pub fn is_preflight(request: &Request) -> bool {
+    request.method == HttpMethod::OPTIONS
+        && request.origin.is_some()
+        && request.access_control_request_method.is_some()
+}
+
+pub fn preflight(config: &CorsConfig, request: &Request, allowed: Vec<HttpMethod>) -> Response {
+    let origin = request.origin.clone().unwrap_or_default();
+    if !config.allows_origin(&origin) {
+        return Response::error(
+            HttpStatus::Forbidden,
+            format!("Cross-origin requests from {} are not allowed", origin),
+        );
+    }
+    if allowed.is_empty() {
+        return Response::error(
+            HttpStatus::NotFound,
+            format!("No route found for {}", request.route),
+        );
+    }
+    let mut resp = Response::new(HttpStatus::NoContent);
+    let mut methods = vec![HttpMethod::OPTIONS.to_string()];
+    for method in allowed {
+        if !methods.contains(&method.to_string()) {
+            methods.push(method.to_string());
+        }
+    }
+    resp.set_header("Access-Control-Allow-Methods", methods.join(", "));
+    let headers = if config.allowed_headers.iter().any(|header| header == "*") {
+        request
+            .access_control_request_headers
+            .clone()
+            .unwrap_or_default()
+    } else {
+        config.allowed_headers.join(", ")
+    };
+    if !headers.is_empty() {
+        resp.set_header("Access-Control-Allow-Headers", headers);
+    }
+    resp.set_header("Access-Control-Max-Age", config.max_age.to_string());
+    resp
+}
+
+pub fn apply(config: &CorsConfig, request: &Request, resp: &mut Response) {
+    let origin = match &request.origin {
+        Some(origin) if config.allows_origin(origin) => origin,
+        _ => {
+            return;
+        }
+    };
+    let wildcard = !config.allow_credentials && config.allowed_origins.iter().any(|o| o == "*");
+    resp.set_header(
+        "Access-Control-Allow-Origin",
+        if wildcard {
+            "*".to_string()
+        } else {
+            origin.clone()
+        },
+    );
+    if config.allow_credentials {
+        resp.set_header("Access-Control-Allow-Credentials", "true".to_string());
+    }
+    if !wildcard {
+        let vary = match resp.header("Vary") {
+            Some(existing) => existing.to_string() + ", Origin",
+            None => "Origin".to_string(),
+        };
+        resp.set_header("Vary", vary);
+    }
+}
@@ -1,26 +1,2683 @@
-use std::{fs, path::Path};
+use std::{
+    collections::HashMap,
+    fs::{self, File, TryLockError},
+    io::{ErrorKind, Write},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 
-use crate::cli::Cli;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use ring::aead::{AES_256_GCM, Aad, LessSafeKey, Nonce, UnboundKey};
+use sha2::Sha256;
+
+use crate::{
+    archive, changes,
+    compaction::{self, Throttle},
+    engine::{self, Range, Store, Tables},
+    hooks::{Hooks, Operation, WriteEvent},
+    json::{Json, JsonNumber, JsonObject},
+    metrics::{self, Phase},
+    patch::Patch,
+    query::{self, Lint, lookup},
+    schema::{self, Schema, Validation, Violation},
+    scram::ScramVerifier,
+    sketch::Reservoir,
+    sstable::{Codec, Table, TableId},
+    wal::{self, Durability, Recovery},
+    warmup,
+};
+
+pub const ID_FIELD: &str = "_id";
+pub const EXPIRES_FIELD: &str = "_expires";
+// The time a document was soft deleted at. Only the database sets it.
+pub const DELETED_FIELD: &str = "_deleted";
+const MAX_NAME_LEN: usize = 128;
+const DOCUMENT_EXTENSION: &str = "json";
+const GENERATED_ID_BYTES: usize = 12;
+pub const TEMP_EXTENSION: &str = "tmp";
+const STALE_TEMP_AGE: Duration = Duration::from_secs(60);
+pub const BACKUP_DIR: &str = ".backups";
+pub const MANIFEST_FILE: &str = "manifest.json";
+pub const LOCK_FILE: &str = "LOCK";
+pub const DATA_DIR: &str = "data";
+pub const WAL_DIR: &str = "wal";
+pub const COLLECTION_FILE: &str = ".collection.json";
+pub const CHANGES_DIR: &str = ".changes";
+pub const STATS_DIR: &str = ".stats";
+// The directories of the side collections, which keep what the database tracks about a
+// collection inside the directory of the collection.
+pub const SIDE_DIRS: [&str; 2] = [CHANGES_DIR, STATS_DIR];
+const COUNTS_KEY: &str = "counts";
+pub const FORMAT_VERSION: u32 = 2;
+const KDF: &str = "pbkdf2-sha256";
+const KDF_ITERATIONS: u32 = 100_000;
+const SALT_LENGTH: usize = 16;
+const KEY_LENGTH: usize = 32;
+const KEY_CHECK_MESSAGE: &[u8] = b"db6 key check";
+const NONCE_LENGTH: usize = 12;
+
+// The lock file keeps other processes out, and this keeps two changes in this process apart.
+static PASSWORD_CHANGE: Mutex<()> = Mutex::new(());
+
+// The data key of a database is random, and kept in the manifest wrapped with AES-256-GCM under
+// the key derived from the password, so a password change only wraps it again. Manifests written
+// before there was a data key have no wrapped key, and get one with the next password change.
+// Nothing is encrypted with it yet: the server opens databases without their password, so it
+// could not unwrap the key to read encrypted tables or logs.
+#[derive(Clone)]
+pub struct KeyParams {
+    pub kdf: String,
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub key_check: Vec<u8>,
+    // The nonce followed by the sealed data key and its tag.
+    pub wrapped_key: Option<Vec<u8>>,
+}
+
+impl KeyParams {
+    fn derive_key(password: &str, salt: &[u8], iterations: u32) -> [u8; KEY_LENGTH] {
+        let mut key = [0u8; KEY_LENGTH];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut key);
+        key
+    }
+
+    fn key_check(key: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(KEY_CHECK_MESSAGE);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn sealing_key(key: &[u8]) -> LessSafeKey {
+        LessSafeKey::new(
+            UnboundKey::new(&AES_256_GCM, key).expect("the key has the AES-256 length"),
+        )
+    }
+
+    // Generates a new data key.
+    pub fn new(password: &str) -> Result<KeyParams, String> {
+        let mut data_key = [0u8; KEY_LENGTH];
+        getrandom::getrandom(&mut data_key)
+            .map_err(|err| format!("Could not generate the data key: {}", err))?;
+        KeyParams::wrap(password, &data_key)
+    }
+
+    fn wrap(password: &str, data_key: &[u8; KEY_LENGTH]) -> Result<KeyParams, String> {
+        let mut salt = vec![0u8; SALT_LENGTH];
+        let mut nonce = [0u8; NONCE_LENGTH];
+        getrandom::getrandom(&mut salt)
+            .and_then(|_| getrandom::getrandom(&mut nonce))
+            .map_err(|err| format!("Could not generate the key salt and nonce: {}", err))?;
+        let key = KeyParams::derive_key(password, &salt, KDF_ITERATIONS);
+        let mut sealed = data_key.to_vec();
+        KeyParams::sealing_key(&key)
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(KEY_CHECK_MESSAGE),
+                &mut sealed,
+            )
+            .map_err(|_| "Could not wrap the data key".to_string())?;
+        Ok(KeyParams {
+            kdf: KDF.to_string(),
+            iterations: KDF_ITERATIONS,
+            salt,
+            key_check: KeyParams::key_check(&key),
+            wrapped_key: Some([nonce.as_slice(), &sealed].concat()),
+        })
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        let key = KeyParams::derive_key(password, &self.salt, self.iterations);
+        KeyParams::key_check(&key) == self.key_check
+    }
+
+    // Returns None when the password does not match, or when there is no wrapped data key.
+    pub fn data_key(&self, password: &str) -> Option<[u8; KEY_LENGTH]> {
+        let wrapped = self.wrapped_key.as_ref()?;
+        let (nonce, sealed) = wrapped.split_at_checked(NONCE_LENGTH)?;
+        let key = KeyParams::derive_key(password, &self.salt, self.iterations);
+        let mut sealed = sealed.to_vec();
+        let data_key = KeyParams::sealing_key(&key)
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).ok()?,
+                Aad::from(KEY_CHECK_MESSAGE),
+                &mut sealed,
+            )
+            .ok()?;
+        data_key.try_into().ok()
+    }
+
+    // Wraps the same data key under the new password, or a new one when there was none yet.
+    // Returns None when the current password does not match.
+    pub fn rewrap(&self, current: &str, password: &str) -> Result<Option<KeyParams>, String> {
+        if !self.verify(current) {
+            return Ok(None);
+        }
+        match &self.wrapped_key {
+            Some(_) => {
+                let data_key = self
+                    .data_key(current)
+                    .ok_or_else(|| "The wrapped data key is damaged".to_string())?;
+                KeyParams::wrap(password, &data_key).map(Some)
+            }
+            None => KeyParams::new(password).map(Some),
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["kdf".to_string()] = Json::String(self.kdf.clone());
+        obj["iterations".to_string()] = Json::Number(JsonNumber::Int(self.iterations as i64));
+        obj["salt".to_string()] = Json::String(STANDARD.encode(&self.salt));
+        obj["key_check".to_string()] = Json::String(STANDARD.encode(&self.key_check));
+        if let Some(wrapped_key) = &self.wrapped_key {
+            obj["wrapped_key".to_string()] = Json::String(STANDARD.encode(wrapped_key));
+        }
+        Json::Object(obj)
+    }
+
+    fn from_json(json: &Json) -> Option<KeyParams> {
+        let Json::Object(obj) = json else {
+            return None;
+        };
+        let bytes = |field: &str| match obj.get(field) {
+            Some(Json::String(value)) => STANDARD.decode(value).ok(),
+            _ => None,
+        };
+        let kdf = match obj.get("kdf") {
+            Some(Json::String(kdf)) if kdf == KDF => kdf.clone(),
+            _ => {
+                return None;
+            }
+        };
+        let iterations = match obj.get("iterations") {
+            Some(Json::Number(JsonNumber::Int(iterations))) => u32::try_from(*iterations).ok()?,
+            _ => {
+                return None;
+            }
+        };
+        Some(KeyParams {
+            kdf,
+            iterations,
+            salt: bytes("salt")?,
+            key_check: bytes("key_check")?,
+            wrapped_key: match obj.get("wrapped_key") {
+                Some(_) => Some(bytes("wrapped_key")?),
+                None => None,
+            },
+        })
+    }
+}
+
+pub struct Manifest {
+    pub format_version: u32,
+    pub name: String,
+    pub created: DateTime<Utc>,
+    pub encryption: Option<KeyParams>,
+    // Lets clients log in on /_auth without sending the password. Databases whose password was
+    // set before it existed get one when the password changes.
+    pub scram: Option<ScramVerifier>,
+}
+
+impl Manifest {
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["format_version".to_string()] =
+            Json::Number(JsonNumber::Int(self.format_version as i64));
+        obj["name".to_string()] = Json::String(self.name.clone());
+        obj["created".to_string()] = Json::String(self.created.to_rfc3339());
+        obj["encryption".to_string()] = match &self.encryption {
+            Some(params) => params.to_json(),
+            None => Json::Null,
+        };
+        if let Some(verifier) = &self.scram {
+            obj["scram".to_string()] = Json::String(verifier.to_string());
+        }
+        Json::Object(obj)
+    }
+
+    fn from_json(json: &Json) -> Result<Manifest, String> {
+        let Json::Object(obj) = json else {
+            return Err("Expected a JSON object".to_string());
+        };
+        let format_version = match obj.get("format_version") {
+            Some(Json::Number(JsonNumber::Int(version))) => u32::try_from(*version)
+                .map_err(|_| format!("The format version {} is invalid", version))?,
+            _ => {
+                return Err("The format version is missing".to_string());
+            }
+        };
+        if format_version > FORMAT_VERSION {
+            return Err(format!(
+                "The format version {} is newer than the supported version {}",
+                format_version, FORMAT_VERSION
+            ));
+        }
+        let name = match obj.get("name") {
+            Some(Json::String(name)) => name.clone(),
+            _ => {
+                return Err("The database name is missing".to_string());
+            }
+        };
+        let created = match obj.get("created") {
+            Some(Json::String(created)) => DateTime::parse_from_rfc3339(created)
+                .map_err(|err| format!("The creation time {} is invalid: {}", created, err))?
+                .with_timezone(&Utc),
+            _ => {
+                return Err("The creation time is missing".to_string());
+            }
+        };
+        let encryption = match obj.get("encryption") {
+            None | Some(Json::Null) => None,
+            Some(params) => Some(
+                KeyParams::from_json(params)
+                    .ok_or_else(|| "The encryption parameters are invalid".to_string())?,
+            ),
+        };
+        let scram = match obj.get("scram") {
+            None | Some(Json::Null) => None,
+            Some(Json::String(verifier)) => Some(verifier.parse::<ScramVerifier>()?),
+            Some(_) => {
+                return Err("The SCRAM verifier is invalid".to_string());
+            }
+        };
+        Ok(Manifest {
+            format_version,
+            name,
+            created,
+            encryption,
+            scram,
+        })
+    }
+
+    pub fn read(dir: &Path) -> Result<Manifest, String> {
+        let path = dir.join(MANIFEST_FILE);
+        let content = fs::read(&path).map_err(|err| {
+            format!(
+                "Error while reading the manifest {}: {}",
+                path.display(),
+                err
+            )
+        })?;
+        Json::parse(&content)
+            .map_err(|err| err.to_string())
+            .and_then(|json| Manifest::from_json(&json))
+            .map_err(|err| format!("The manifest {} is invalid: {}", path.display(), err))
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<(), String> {
+        let path = dir.join(MANIFEST_FILE);
+        write_metadata(&path, &self.to_json()).map_err(|err| {
+            format!(
+                "Error while writing the manifest {}: {}",
+                path.display(),
+                err
+            )
+        })
+    }
+}
+
+// Documents written to a collection with a TTL expire that many seconds later, unless they
+// set their own expiry. Documents written to a collection with a schema are checked against it.
+// Deletes from a collection with soft deletes leave tombstones behind. The tables of the
+// collection are compressed with its codec from the next flush or compaction on.
+pub struct CollectionMeta {
+    pub name: String,
+    pub created: DateTime<Utc>,
+    pub ttl: Option<u64>,
+    pub schema: Option<Json>,
+    pub validation: Validation,
+    pub soft_delete: Option<SoftDelete>,
+    pub compression: Codec,
+    // When compaction last merged the tables of the collection.
+    pub compacted: Option<DateTime<Utc>>,
+}
+
+// The metadata of a collection along with its compiled schema, as writes use them.
+pub struct Collection {
+    pub meta: CollectionMeta,
+    schema: Option<Schema>,
+}
+
+// Tombstones are purged that many seconds after the delete, when there is a retention.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SoftDelete {
+    pub retention: Option<u64>,
+}
+
+impl SoftDelete {
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        if let Some(retention) = self.retention {
+            obj["retention".to_string()] = Json::Number(JsonNumber::Int(retention as i64));
+        }
+        Json::Object(obj)
+    }
+
+    pub fn from_json(json: &Json) -> Option<SoftDelete> {
+        let Json::Object(obj) = json else {
+            return None;
+        };
+        match obj.get("retention") {
+            _ if obj.iter().any(|(key, _)| key != "retention") => None,
+            None | Some(Json::Null) => Some(SoftDelete { retention: None }),
+            Some(Json::Number(JsonNumber::Int(retention))) if *retention > 0 => Some(SoftDelete {
+                retention: Some(*retention as u64),
+            }),
+            Some(_) => None,
+        }
+    }
+}
+
+impl CollectionMeta {
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["name".to_string()] = Json::String(self.name.clone());
+        obj["created".to_string()] = Json::String(self.created.to_rfc3339());
+        if let Some(ttl) = self.ttl {
+            obj["ttl".to_string()] = Json::Number(JsonNumber::Int(ttl as i64));
+        }
+        if let Some(schema) = &self.schema {
+            obj["schema".to_string()] = schema.clone();
+            obj["validation".to_string()] = Json::String(self.validation.as_str().to_string());
+        }
+        if let Some(soft_delete) = &self.soft_delete {
+            obj["soft_delete".to_string()] = soft_delete.to_json();
+        }
+        if self.compression != Codec::None {
+            obj["compression".to_string()] = Json::String(self.compression.name().to_string());
+        }
+        if let Some(compacted) = self.compacted {
+            obj["compacted".to_string()] = Json::String(compacted.to_rfc3339());
+        }
+        Json::Object(obj)
+    }
+
+    fn read(dir: &Path, name: &str) -> Result<CollectionMeta, String> {
+        let path = dir.join(COLLECTION_FILE);
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let created = fs::metadata(dir)
+                    .and_then(|meta| meta.created().or_else(|_| meta.modified()))
+                    .map(DateTime::<Utc>::from)
+                    .unwrap_or_else(|_| Utc::now());
+                return Ok(CollectionMeta {
+                    name: name.to_string(),
+                    created,
+                    ttl: None,
+                    schema: None,
+                    validation: Validation::Strict,
+                    soft_delete: None,
+                    compression: Codec::None,
+                    compacted: None,
+                });
+            }
+            Err(err) => {
+                return Err(format!(
+                    "Error while reading the collection metadata {}: {}",
+                    path.display(),
+                    err
+                ));
+            }
+        };
+        let meta = match Json::parse(&content) {
+            Ok(Json::Object(obj)) => {
+                let created = match obj.get("created") {
+                    Some(Json::String(created)) => DateTime::parse_from_rfc3339(created)
+                        .ok()
+                        .map(|created| created.with_timezone(&Utc)),
+                    _ => None,
+                };
+                let ttl = match obj.get("ttl") {
+                    None => Some(None),
+                    Some(Json::Number(JsonNumber::Int(ttl))) if *ttl > 0 => Some(Some(*ttl as u64)),
+                    Some(_) => None,
+                };
+                let validation = match obj.get("validation") {
+                    None => Some(Validation::Strict),
+                    Some(Json::String(validation)) => Validation::parse(validation),
+                    Some(_) => None,
+                };
+                let soft_delete = match obj.get("soft_delete") {
+                    None => Some(None),
+                    Some(soft_delete) => SoftDelete::from_json(soft_delete).map(Some),
+                };
+                let compression = match obj.get("compression") {
+                    None => Some(Codec::None),
+                    Some(Json::String(compression)) => Codec::parse(compression),
+                    Some(_) => None,
+                };
+                let compacted = match obj.get("compacted") {
+                    None => Some(None),
+                    Some(Json::String(compacted)) => DateTime::parse_from_rfc3339(compacted)
+                        .ok()
+                        .map(|compacted| Some(compacted.with_timezone(&Utc))),
+                    Some(_) => None,
+                };
+                let schema = obj.get("schema").cloned();
+                created
+                    .zip(ttl)
+                    .zip(validation)
+                    .zip(soft_delete)
+                    .zip(compression)
+                    .zip(compacted)
+                    .map(
+                        |(
+                            ((((created, ttl), validation), soft_delete), compression),
+                            compacted,
+                        )| {
+                            (
+                                created,
+                                ttl,
+                                schema,
+                                validation,
+                                soft_delete,
+                                compression,
+                                compacted,
+                            )
+                        },
+                    )
+            }
+            _ => None,
+        };
+        match meta {
+            Some((created, ttl, schema, validation, soft_delete, compression, compacted)) => {
+                Ok(CollectionMeta {
+                    name: name.to_string(),
+                    created,
+                    ttl,
+                    schema,
+                    validation,
+                    soft_delete,
+                    compression,
+                    compacted,
+                })
+            }
+            None => Err(format!(
+                "The collection metadata {} is invalid",
+                path.display()
+            )),
+        }
+    }
+}
+
+fn write_metadata(path: &Path, json: &Json) -> std::io::Result<()> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let temp = path.with_file_name(format!(".{}.{}", file_name, TEMP_EXTENSION));
+    replace_file(&temp, path, json.canonical().as_bytes(), true)
+}
+
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+fn sync_parent(path: &Path, sync: bool) -> std::io::Result<()> {
+    match path.parent() {
+        Some(dir) if sync => sync_dir(dir),
+        _ => Ok(()),
+    }
+}
+
+fn durable() -> bool {
+    wal::config().durability == Durability::Always
+}
+
+fn log_entry(op: &str, collection: &str, id: Option<&str>, document: Option<&Json>) -> Json {
+    let mut obj = JsonObject::new();
+    obj["op".to_string()] = Json::String(op.to_string());
+    obj["collection".to_string()] = Json::String(collection.to_string());
+    if let Some(id) = id {
+        obj[ID_FIELD.to_string()] = Json::String(id.to_string());
+    }
+    if let Some(document) = document {
+        obj["document".to_string()] = document.clone();
+    }
+    Json::Object(obj)
+}
+
+// The content is synced before the rename and the directory after it, so a crash leaves
+// either the old or the new file in place.
+pub fn replace_file(temp: &Path, path: &Path, content: &[u8], sync: bool) -> std::io::Result<()> {
+    File::create(temp)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            match sync {
+                true => file.sync_all(),
+                false => Ok(()),
+            }
+        })
+        .and_then(|_| fs::rename(temp, path))
+        .and_then(|_| sync_parent(path, sync))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(temp);
+        })
+}
+
+// Databases of format version 1 kept every document in its own file.
+fn document_id(path: &Path) -> Option<&str> {
+    if path.extension()? != DOCUMENT_EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str().filter(|id| is_valid_name(id))
+}
+
+// A document of None deletes the document, and the mode only applies to the other writes.
+pub struct Mutation {
+    pub collection: String,
+    pub id: String,
+    pub document: Option<Json>,
+    pub mode: WriteMode,
+}
+
+// How a write of a document with a given ID treats the document it would replace.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WriteMode {
+    // Inserts the document if it is absent and replaces it if it is present.
+    Upsert,
+    // Only inserts the document, and fails if it is present.
+    Create,
+    // Only replaces the document, and fails if it is absent.
+    Replace,
+}
+
+impl WriteMode {
+    pub fn parse(value: &str) -> Option<WriteMode> {
+        match value {
+            "upsert" => Some(WriteMode::Upsert),
+            "create" => Some(WriteMode::Create),
+            "replace" => Some(WriteMode::Replace),
+            _ => None,
+        }
+    }
+
+    fn check(&self, collection: &str, id: &str, exists: bool) -> Result<(), WriteError> {
+        match (self, exists) {
+            (WriteMode::Create, true) => Err(WriteError::Exists(format!(
+                "The document {} already exists in the collection {}",
+                id, collection
+            ))),
+            (WriteMode::Replace, false) => Err(WriteError::Missing(format!(
+                "The document {} does not exist in the collection {}",
+                id, collection
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+// For each mutation, whether it created or deleted a document.
+pub enum Commit {
+    Committed(Vec<bool>),
+    Aborted(String),
+}
+
+#[derive(Debug)]
+pub enum WriteError {
+    Rejected(String),
+    Invalid(String, Vec<Violation>),
+    Exists(String),
+    Missing(String),
+    Failed(String),
+    Storage(String),
+}
+
+// A conditional write checks the current document, or None when there is none, under the lock of
+// the write, and fails with the reason it gives instead of writing.
+pub type Condition<'a> = &'a dyn Fn(Option<&Json>) -> Result<(), String>;
+
+// The ID of a loaded document and whether it was inserted rather than replaced.
+pub type Loaded = Result<(String, bool), WriteError>;
+
+impl From<String> for WriteError {
+    fn from(err: String) -> Self {
+        WriteError::Storage(err)
+    }
+}
+
+impl From<WriteError> for String {
+    fn from(err: WriteError) -> Self {
+        match err {
+            WriteError::Rejected(err)
+            | WriteError::Invalid(err, _)
+            | WriteError::Exists(err)
+            | WriteError::Missing(err)
+            | WriteError::Failed(err)
+            | WriteError::Storage(err) => err,
+        }
+    }
+}
+
+// A document before and after an update. There is no document before an update that created it.
+pub struct Modified {
+    pub before: Option<Json>,
+    pub after: Json,
+}
 
 pub struct DB {
+    name: String,
     path: String,
+    manifest: Manifest,
+}
+
+// The documents and bytes are those of the live documents, while tombstones are counted apart.
+// The tables are those of the collection and of its side collections, and their raw and stored
+// bytes are the size of their blocks before and after compression.
+pub struct CollectionStats {
+    pub name: String,
+    pub documents: u64,
+    pub deleted: u64,
+    pub bytes: u64,
+    pub tables: u64,
+    pub disk_bytes: u64,
+    pub index_bytes: u64,
+    pub compression: Codec,
+    pub raw_bytes: u64,
+    pub stored_bytes: u64,
+    pub compacted: Option<DateTime<Utc>>,
+    // The sequence number of the last write to the collection, where its change feed ends.
+    pub seq: u64,
+}
+
+impl CollectionStats {
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["name".to_string()] = Json::String(self.name.clone());
+        obj["documents".to_string()] = Json::Number(JsonNumber::Int(self.documents as i64));
+        obj["deleted".to_string()] = Json::Number(JsonNumber::Int(self.deleted as i64));
+        obj["bytes".to_string()] = Json::Number(JsonNumber::Int(self.bytes as i64));
+        obj["tables".to_string()] = Json::Number(JsonNumber::Int(self.tables as i64));
+        obj["disk_bytes".to_string()] = Json::Number(JsonNumber::Int(self.disk_bytes as i64));
+        obj["index_bytes".to_string()] = Json::Number(JsonNumber::Int(self.index_bytes as i64));
+        obj["compression".to_string()] =
+            compression_json(Some(self.compression), self.raw_bytes, self.stored_bytes);
+        if let Some(compacted) = self.compacted {
+            obj["compacted".to_string()] = Json::String(compacted.to_rfc3339());
+        }
+        obj["seq".to_string()] = Json::Number(JsonNumber::Int(self.seq as i64));
+        Json::Object(obj)
+    }
+}
+
+// The ratio is how many times smaller the blocks are on disk, and 1 when nothing is stored.
+fn compression_json(codec: Option<Codec>, raw_bytes: u64, stored_bytes: u64) -> Json {
+    let mut obj = JsonObject::new();
+    if let Some(codec) = codec {
+        obj["codec".to_string()] = Json::String(codec.name().to_string());
+    }
+    obj["raw_bytes".to_string()] = Json::Number(JsonNumber::Int(raw_bytes as i64));
+    obj["stored_bytes".to_string()] = Json::Number(JsonNumber::Int(stored_bytes as i64));
+    let ratio = match stored_bytes {
+        0 => 1.0,
+        stored_bytes => raw_bytes as f64 / stored_bytes as f64,
+    };
+    obj["ratio".to_string()] = Json::Number(JsonNumber::Float((ratio * 100.0).round() / 100.0));
+    Json::Object(obj)
+}
+
+pub struct CollectionCheck {
+    pub name: String,
+    pub documents: u64,
+    pub problems: Vec<String>,
+}
+
+pub struct DbStats {
+    pub name: String,
+    pub collections: Vec<CollectionStats>,
+    pub wal_bytes: u64,
+}
+
+impl DbStats {
+    pub fn to_json(&self) -> Json {
+        let total = |counter: fn(&CollectionStats) -> u64| {
+            Json::Number(JsonNumber::Int(
+                self.collections.iter().map(counter).sum::<u64>() as i64,
+            ))
+        };
+        let mut obj = JsonObject::new();
+        obj["name".to_string()] = Json::String(self.name.clone());
+        obj["documents".to_string()] = total(|col| col.documents);
+        obj["deleted".to_string()] = total(|col| col.deleted);
+        obj["bytes".to_string()] = total(|col| col.bytes);
+        obj["disk_bytes".to_string()] = total(|col| col.disk_bytes);
+        obj["index_bytes".to_string()] = total(|col| col.index_bytes);
+        obj["wal_bytes".to_string()] = Json::Number(JsonNumber::Int(self.wal_bytes as i64));
+        obj["compression".to_string()] = compression_json(
+            None,
+            self.collections.iter().map(|col| col.raw_bytes).sum(),
+            self.collections.iter().map(|col| col.stored_bytes).sum(),
+        );
+        if let Some(compacted) = self
+            .collections
+            .iter()
+            .filter_map(|col| col.compacted)
+            .max()
+        {
+            obj["compacted".to_string()] = Json::String(compacted.to_rfc3339());
+        }
+        obj["collections".to_string()] =
+            Json::List(self.collections.iter().map(|col| col.to_json()).collect());
+        Json::Object(obj)
+    }
+}
+
+pub struct FileStats {
+    pub path: String,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl FileStats {
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["path".to_string()] = Json::String(self.path.clone());
+        obj["files".to_string()] = Json::Number(JsonNumber::Int(self.files as i64));
+        obj["bytes".to_string()] = Json::Number(JsonNumber::Int(self.bytes as i64));
+        Json::Object(obj)
+    }
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'))
+}
+
+pub fn exists(root: &str, name: &str) -> bool {
+    is_valid_name(name) && Path::new(root).join(name).join(MANIFEST_FILE).is_file()
+}
+
+pub fn route_database(route: &str) -> Option<&str> {
+    let mut parts = route.split('/').filter(|part| !part.is_empty());
+    match (parts.next(), parts.next()) {
+        (Some("db" | "dbs"), Some(name)) if is_valid_name(name) => Some(name),
+        _ => None,
+    }
+}
+
+pub fn list(root: &str) -> Result<Vec<String>, String> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Ok(Vec::new());
+        }
+        Err(err) => {
+            return Err(format!(
+                "Error while listing the databases in {}: {}",
+                root, err
+            ));
+        }
+    };
+    let mut names = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join(MANIFEST_FILE).is_file())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| is_valid_name(name))
+        .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+}
+
+pub fn generate_id() -> Result<String, String> {
+    let mut bytes = [0u8; GENERATED_ID_BYTES];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|err| format!("Could not generate a document ID: {}", err))?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn parse_document(collection: &str, id: &str, content: &[u8]) -> Result<Json, String> {
+    Json::parse(content).map_err(|err| {
+        format!(
+            "The document {} in the collection {} is corrupted: {}",
+            id, collection, err
+        )
+    })
+}
+
+// The expiry of a document is an RFC 3339 timestamp in its _expires field.
+pub fn expiry(document: &Json) -> Result<Option<DateTime<Utc>>, String> {
+    let Json::Object(obj) = document else {
+        return Ok(None);
+    };
+    match obj.get(EXPIRES_FIELD) {
+        None => Ok(None),
+        Some(Json::String(expires)) => DateTime::parse_from_rfc3339(expires)
+            .map(|expires| Some(expires.with_timezone(&Utc)))
+            .map_err(|_| {
+                format!(
+                    "The {} field should be an RFC 3339 timestamp, not {}",
+                    EXPIRES_FIELD, expires
+                )
+            }),
+        Some(_) => Err(format!(
+            "The {} field should be an RFC 3339 timestamp",
+            EXPIRES_FIELD
+        )),
+    }
+}
+
+// The change feed of a collection is kept as a collection of its own in a subdirectory of
+// the collection, keyed by the sequence number of each change.
+pub fn changes_collection(collection: &str) -> String {
+    format!("{}/{}", collection, CHANGES_DIR)
+}
+
+// The counters of a collection are kept in a collection of their own as well, so that they are
+// flushed and replayed along with its documents.
+pub fn stats_collection(collection: &str) -> String {
+    format!("{}/{}", collection, STATS_DIR)
+}
+
+pub fn side_collections(collection: &str) -> Vec<String> {
+    SIDE_DIRS
+        .iter()
+        .map(|dir| format!("{}/{}", collection, dir))
+        .collect()
+}
+
+// The writes of a log record, which is either one write or a batch of them.
+fn log_writes(entry: &Json) -> Result<Vec<&Json>, String> {
+    match entry {
+        Json::Object(obj) if matches!(obj.get("op"), Some(Json::String(op)) if op == "batch") => {
+            match obj.get("writes") {
+                Some(Json::List(writes)) => Ok(writes.iter().collect()),
+                _ => Err("The log record has no writes field".to_string()),
+            }
+        }
+        entry => Ok(vec![entry]),
+    }
+}
+
+fn log_field<'a>(obj: &'a JsonObject, name: &str) -> Result<&'a str, String> {
+    match obj.get(name) {
+        Some(Json::String(value)) => Ok(value.as_str()),
+        _ => Err(format!("The log record has no {} field", name)),
+    }
+}
+
+fn change_key(seq: u64, ind: usize) -> String {
+    format!("{:020}.{:06}", seq, ind)
+}
+
+fn change(kind: &str, id: &str, delta: Option<Json>) -> Json {
+    let mut obj = JsonObject::new();
+    obj["type".to_string()] = Json::String(kind.to_string());
+    obj[ID_FIELD.to_string()] = Json::String(id.to_string());
+    if let Some(delta) = delta {
+        obj["delta".to_string()] = delta;
+    }
+    Json::Object(obj)
+}
+
+// A change that is already in the feed was recorded before a crash, against the document as it
+// was then, so it is kept when the log is replayed.
+fn record_change(
+    tables: &mut Tables,
+    collection: &str,
+    seq: u64,
+    ind: usize,
+    mut change: Json,
+) -> Result<(), String> {
+    let feed = changes_collection(collection);
+    let key = change_key(seq, ind);
+    if tables.get(&feed, &key)?.is_some() {
+        return Ok(());
+    }
+    if let Json::Object(obj) = &mut change {
+        obj["seq".to_string()] = Json::Number(JsonNumber::Int(seq as i64));
+    }
+    tables.put(&feed, &key, change.canonical().into_bytes());
+    Ok(())
+}
+
+// The codec of the tables of a collection. Side collections are compressed like their collection.
+pub fn table_codec(data_dir: &Path, collection: &str) -> Result<Codec, String> {
+    let name = collection.split('/').next().unwrap_or(collection);
+    CollectionMeta::read(&data_dir.join(name), name).map(|meta| meta.compression)
+}
+
+// The counters remember the last write they counted. They are flushed before the documents, so
+// after a crash in between, the writes they already counted are skipped when the log is
+// replayed.
+#[derive(Default)]
+struct Counts {
+    documents: u64,
+    deleted: u64,
+    bytes: u64,
+    seq: u64,
+    ind: u64,
+}
+
+impl Counts {
+    fn read(tables: &Tables, collection: &str) -> Result<Option<Counts>, String> {
+        let Some(content) = tables.get(&stats_collection(collection), COUNTS_KEY)? else {
+            return Ok(None);
+        };
+        let counts = parse_document(collection, COUNTS_KEY, &content)?;
+        let counter = |name: &str| match lookup(&counts, name) {
+            Some(Json::Number(JsonNumber::Int(value))) if *value >= 0 => Ok(*value as u64),
+            _ => Err(format!(
+                "The counters of the collection {} have no {} field",
+                collection, name
+            )),
+        };
+        Ok(Some(Counts {
+            documents: counter("documents")?,
+            deleted: counter("deleted")?,
+            bytes: counter("bytes")?,
+            seq: counter("seq")?,
+            ind: counter("ind")?,
+        }))
+    }
+
+    fn write(&self, tables: &mut Tables, collection: &str) {
+        let mut obj = JsonObject::new();
+        for (name, value) in [
+            ("documents", self.documents),
+            ("deleted", self.deleted),
+            ("bytes", self.bytes),
+            ("seq", self.seq),
+            ("ind", self.ind),
+        ] {
+            obj[name.to_string()] = Json::Number(JsonNumber::Int(value as i64));
+        }
+        tables.put(
+            &stats_collection(collection),
+            COUNTS_KEY,
+            Json::Object(obj).canonical().into_bytes(),
+        );
+    }
+
+    fn count(&mut self, document: &Json, bytes: usize, added: bool) {
+        let change = |counter: &mut u64, by: u64| match added {
+            true => *counter += by,
+            false => *counter = counter.saturating_sub(by),
+        };
+        match deleted(document) {
+            Some(_) => change(&mut self.deleted, 1),
+            None => {
+                change(&mut self.documents, 1);
+                change(&mut self.bytes, bytes as u64);
+            }
+        }
+    }
+}
+
+// Counts a write that replaces the previous version of a document, if any, by a new one, if any.
+fn count_write(
+    tables: &mut Tables,
+    collection: &str,
+    seq: u64,
+    ind: usize,
+    previous: Option<(&Json, usize)>,
+    document: Option<(&Json, usize)>,
+) -> Result<(), String> {
+    let mut counts = Counts::read(tables, collection)?.unwrap_or_default();
+    if (seq, ind as u64) <= (counts.seq, counts.ind) {
+        return Ok(());
+    }
+    if let Some((previous, bytes)) = previous {
+        counts.count(previous, bytes, false);
+    }
+    if let Some((document, bytes)) = document {
+        counts.count(document, bytes, true);
+    }
+    counts.seq = seq;
+    counts.ind = ind as u64;
+    counts.write(tables, collection);
+    Ok(())
+}
+
+// Counts the documents of the collections that have no counters yet, because they were written
+// before counters were kept. Writes logged after the checkpoint are counted when the log is
+// replayed.
+pub fn count_documents(tables: &mut Tables, checkpoint: u64) -> Result<(), String> {
+    for collection in tables.collections() {
+        if Counts::read(tables, &collection)?.is_some() {
+            continue;
+        }
+        let mut counts = Counts {
+            seq: checkpoint,
+            ..Counts::default()
+        };
+        for entry in tables.scan(&collection, (Bound::Unbounded, Bound::Unbounded)) {
+            let (id, content) = entry?;
+            counts.count(
+                &parse_document(&collection, &id, &content)?,
+                content.len(),
+                true,
+            );
+        }
+        counts.write(tables, &collection);
+    }
+    Ok(())
+}
+
+fn collection_stats(tables: &Tables, meta: &CollectionMeta) -> Result<CollectionStats, String> {
+    let name = meta.name.as_str();
+    let counts = Counts::read(tables, name)?.unwrap_or_default();
+    let mut stats = CollectionStats {
+        name: name.to_string(),
+        documents: counts.documents,
+        deleted: counts.deleted,
+        bytes: counts.bytes,
+        tables: 0,
+        disk_bytes: 0,
+        index_bytes: 0,
+        compression: meta.compression,
+        raw_bytes: 0,
+        stored_bytes: 0,
+        compacted: meta.compacted,
+        seq: counts.seq,
+    };
+    for collection in side_collections(name).into_iter().chain([name.to_string()]) {
+        for table in tables.snapshot(&collection).iter() {
+            stats.tables += 1;
+            stats.disk_bytes += table.bytes;
+            stats.index_bytes += table.index_bytes();
+            stats.raw_bytes += table.raw_bytes;
+            stats.stored_bytes += table.data_bytes();
+        }
+    }
+    Ok(stats)
+}
+
+// Returns when the document was soft deleted, if it is a tombstone.
+pub fn deleted(document: &Json) -> Option<DateTime<Utc>> {
+    match document {
+        Json::Object(obj) => match obj.get(DELETED_FIELD) {
+            Some(Json::String(deleted)) => DateTime::parse_from_rfc3339(deleted)
+                .ok()
+                .map(|deleted| deleted.with_timezone(&Utc)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn tombstone(document: &Json, now: DateTime<Utc>) -> Json {
+    let mut document = document.clone();
+    if let Json::Object(obj) = &mut document {
+        obj[DELETED_FIELD.to_string()] = Json::String(now.to_rfc3339());
+    }
+    document
+}
+
+// Writes treat a tombstone or an expired document like a document that does not exist.
+fn live(collection: &str, id: &str, content: Option<Vec<u8>>) -> Result<Option<Vec<u8>>, String> {
+    match content {
+        Some(content) => {
+            let document = parse_document(collection, id, &content)?;
+            match deleted(&document).is_some() || is_expired(&document, Utc::now()) {
+                true => Ok(None),
+                false => Ok(Some(content)),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+fn is_expired(document: &Json, now: DateTime<Utc>) -> bool {
+    expiry(document)
+        .ok()
+        .flatten()
+        .is_some_and(|expires| expires <= now)
+}
+
+fn batch_entry(entries: Vec<Json>) -> Json {
+    let mut obj = JsonObject::new();
+    obj["op".to_string()] = Json::String("batch".to_string());
+    obj["writes".to_string()] = Json::List(entries);
+    Json::Object(obj)
+}
+
+fn with_id(id: &str, document: &Json) -> Json {
+    let mut obj = JsonObject::new();
+    obj[ID_FIELD.to_string()] = Json::String(id.to_string());
+    if let Json::Object(fields) = document {
+        for (key, value) in fields
+            .iter()
+            .filter(|(key, _)| *key != ID_FIELD && *key != DELETED_FIELD)
+        {
+            obj[key.clone()] = value.clone();
+        }
+    }
+    Json::Object(obj)
 }
 
 impl DB {
-    pub fn create(cl: &mut Cli, name: String, password: String) -> Result<DB, String> {
-        let db_dir = Path::new(cl.root.as_str()).join(&name);
-        match fs::create_dir(db_dir.to_string_lossy().to_string()) {
-            Ok(_) => {
-                todo!();
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    pub fn data_dir(&self) -> PathBuf {
+        Path::new(&self.path).join(DATA_DIR)
+    }
+
+    pub fn wal_dir(&self) -> PathBuf {
+        Path::new(&self.path).join(WAL_DIR)
+    }
+
+    pub fn open(root: &str, name: &str) -> Result<Option<DB>, String> {
+        if !is_valid_name(name) {
+            return Ok(None);
+        }
+        let path = Path::new(root).join(name);
+        if !path.join(MANIFEST_FILE).is_file() {
+            return Ok(None);
+        }
+        let manifest = Manifest::read(&path)?;
+        if manifest.name != name {
+            return Err(format!(
+                "The manifest in {} belongs to the database {}",
+                path.display(),
+                manifest.name
+            ));
+        }
+        Ok(Some(DB {
+            name: name.to_string(),
+            manifest,
+            path: path.to_string_lossy().to_string(),
+        }))
+    }
+
+    pub fn lock(&self) -> Result<File, String> {
+        let path = Path::new(&self.path).join(LOCK_FILE);
+        let file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|err| {
+                format!(
+                    "Error while opening the lock file {}: {}",
+                    path.display(),
+                    err
+                )
+            })?;
+        match file.try_lock() {
+            Ok(_) => Ok(file),
+            Err(TryLockError::WouldBlock) => Err(format!(
+                "The database {} is in use by another process",
+                self.name
+            )),
+            Err(TryLockError::Error(err)) => Err(format!(
+                "Error while locking the database {}: {}",
+                self.name, err
+            )),
+        }
+    }
+
+    pub fn engine(&self) -> Result<Arc<engine::Engine>, String> {
+        engine::open(self)
+    }
+
+    // A closed database keeps its files, but its engine is unloaded and requests to it fail
+    // until it is opened again. Returns false when it was already closed.
+    pub fn close(&self) -> bool {
+        engine::shut(Path::new(&self.path))
+    }
+
+    // Loads the engine of a closed database, which replays its log. Returns false when it was
+    // not closed.
+    pub fn reopen(&self) -> Result<bool, String> {
+        engine::reopen(self)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        engine::is_closed(Path::new(&self.path))
+    }
+
+    // Wraps the data key under a key derived from the new password with a new salt, so no data
+    // is written again. Returns false when the current password does not match. The manifest is
+    // read again and replaced under the lock, so two changes at once cannot undo each other, and
+    // it is replaced atomically, so a crash leaves either the old or the new password.
+    pub fn change_password(&self, current: &str, password: &str) -> Result<bool, String> {
+        if password.is_empty() {
+            return Err("The new password should not be empty".to_string());
+        }
+        let _lock = engine::lock(self)?;
+        let _changing = PASSWORD_CHANGE.lock().unwrap();
+        let mut manifest = Manifest::read(Path::new(&self.path))?;
+        let Some(params) = &manifest.encryption else {
+            return Err(format!(
+                "The database {} was created without a password",
+                self.name
+            ));
+        };
+        let Some(params) = params.rewrap(current, password)? else {
+            return Ok(false);
+        };
+        manifest.encryption = Some(params);
+        manifest.scram = Some(ScramVerifier::new(password)?);
+        manifest.write(Path::new(&self.path))?;
+        Ok(true)
+    }
+
+    pub fn get(&self, collection: &str, id: &str) -> Result<Option<Json>, String> {
+        Ok(self
+            .get_including_deleted(collection, id)?
+            .filter(|document| deleted(document).is_none()))
+    }
+
+    // Also returns the document when it is a tombstone. Expired documents are left out like
+    // they were deleted, while they wait for the sweeper.
+    pub fn get_including_deleted(
+        &self,
+        collection: &str,
+        id: &str,
+    ) -> Result<Option<Json>, String> {
+        warmup::record(&self.name, collection);
+        match self.engine()?.get(collection, id)? {
+            Some(content) => Ok(Some(parse_document(collection, id, &content)?)
+                .filter(|document| !is_expired(document, Utc::now()))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn recover(&self) -> Result<Recovery, String> {
+        Ok(self.engine()?.lock().recovery())
+    }
+
+    // Every mutation goes through here, both when it is first made and when the log is
+    // replayed. The writes of a batch share the sequence number of their record.
+    pub fn apply(&self, tables: &mut Tables, seq: u64, entry: &Json) -> Result<(), String> {
+        for (ind, write) in log_writes(entry)?.into_iter().enumerate() {
+            self.apply_write(tables, seq, ind, write)?;
+        }
+        Ok(())
+    }
+
+    // Does what can fail in applying the entry before it is logged: the entry is checked, the
+    // collections it writes to are made, and the documents and counters it replaces are read.
+    // Applying it after that only fails when the files fail in between.
+    fn check(&self, tables: &Tables, entry: &Json) -> Result<(), String> {
+        for write in log_writes(entry)? {
+            let Json::Object(obj) = write else {
+                return Err("The log record is not a JSON object".to_string());
+            };
+            let collection = log_field(obj, "collection")?;
+            match log_field(obj, "op")? {
+                op @ ("put" | "delete") => {
+                    let id = log_field(obj, ID_FIELD)?;
+                    if op == "put" {
+                        if obj.get("document").is_none() {
+                            return Err("The log record has no document field".to_string());
+                        }
+                        if !self.data_dir().join(collection).is_dir() {
+                            self.make_collection(collection, durable())?;
+                        }
+                    }
+                    if let Some(content) = tables.get(collection, id)? {
+                        parse_document(collection, id, &content)?;
+                    }
+                    Counts::read(tables, collection)?;
+                }
+                "create_collection" => {
+                    self.make_collection(collection, durable())?;
+                }
+                "drop_collection" => {}
+                op => {
+                    return Err(format!("Unknown operation {} in the log record", op));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Every write is logged through here, checked first so that it cannot be logged and then
+    // fail to apply.
+    fn log(&self, store: &mut Store, entry: &Json) -> Result<u64, String> {
+        self.check(store.tables(), entry)?;
+        store.write(entry, |tables, seq| self.apply(tables, seq, entry))
+    }
+
+    fn apply_write(
+        &self,
+        tables: &mut Tables,
+        seq: u64,
+        ind: usize,
+        entry: &Json,
+    ) -> Result<(), String> {
+        let Json::Object(obj) = entry else {
+            return Err("The log record is not a JSON object".to_string());
+        };
+        let field = |name: &str| log_field(obj, name);
+        let sync = durable();
+        let op = field("op")?;
+        let collection = field("collection")?;
+        match op {
+            "put" => {
+                let id = field(ID_FIELD)?;
+                let document = obj
+                    .get("document")
+                    .ok_or_else(|| "The log record has no document field".to_string())?;
+                if tables.writable() && !self.data_dir().join(collection).is_dir() {
+                    self.make_collection(collection, sync)?;
+                }
+                // The feed sees a tombstone as the delete of the document, and its restore as
+                // an insert.
+                let previous = match tables.get(collection, id)? {
+                    Some(previous) => {
+                        Some((parse_document(collection, id, &previous)?, previous.len()))
+                    }
+                    None => None,
+                };
+                let content = document.canonical().into_bytes();
+                count_write(
+                    tables,
+                    collection,
+                    seq,
+                    ind,
+                    previous
+                        .as_ref()
+                        .map(|(previous, bytes)| (previous, *bytes)),
+                    Some((document, content.len())),
+                )?;
+                let previous = previous
+                    .map(|(previous, _)| previous)
+                    .filter(|previous| deleted(previous).is_none());
+                let change = match (previous, deleted(document)) {
+                    (Some(_), Some(_)) => Some(change("delete", id, None)),
+                    (Some(previous), None) => Some(change(
+                        "update",
+                        id,
+                        Some(changes::delta(&previous, document)),
+                    )),
+                    (None, Some(_)) => None,
+                    (None, None) => Some(change("insert", id, Some(document.clone()))),
+                };
+                if let Some(change) = change {
+                    record_change(tables, collection, seq, ind, change)?;
+                }
+                tables.put(collection, id, content);
+            }
+            "delete" => {
+                let id = field(ID_FIELD)?;
+                if let Some(content) = tables.get(collection, id)? {
+                    let previous = parse_document(collection, id, &content)?;
+                    count_write(
+                        tables,
+                        collection,
+                        seq,
+                        ind,
+                        Some((&previous, content.len())),
+                        None,
+                    )?;
+                    if deleted(&previous).is_none() {
+                        record_change(tables, collection, seq, ind, change("delete", id, None))?;
+                    }
+                }
+                tables.delete(collection, id);
+            }
+            "create_collection" if tables.writable() => {
+                self.make_collection(collection, sync)?;
+            }
+            "create_collection" => {}
+            "drop_collection" => {
+                tables.drop_collection(collection);
+                for side in side_collections(collection) {
+                    tables.drop_collection(&side);
+                }
+                if tables.writable() {
+                    self.remove_collection(collection, sync)?;
+                }
+            }
+            op => {
+                return Err(format!("Unknown operation {} in the log record", op));
+            }
+        }
+        Ok(())
+    }
+
+    // Mutations hold the lock of the engine while they are logged and applied, so they are
+    // applied in log order.
+    pub fn put(&self, collection: &str, id: &str, document: &Json) -> Result<bool, WriteError> {
+        self.write(collection, id, document, WriteMode::Upsert)
+    }
+
+    // Returns whether the document was inserted rather than replaced. The mode is checked under
+    // the lock, so a concurrent write cannot slip in between.
+    pub fn write(
+        &self,
+        collection: &str,
+        id: &str,
+        document: &Json,
+        mode: WriteMode,
+    ) -> Result<bool, WriteError> {
+        self.write_if(collection, id, document, mode, None)
+    }
+
+    pub fn write_if(
+        &self,
+        collection: &str,
+        id: &str,
+        document: &Json,
+        mode: WriteMode,
+        condition: Option<Condition>,
+    ) -> Result<bool, WriteError> {
+        let hooks = Hooks::find(&self.name, collection);
+        let document = self.expiring(collection, with_id(id, document))?;
+        let engine = self.engine()?;
+        let mut store = engine.lock();
+        let previous = live(collection, id, store.tables().get(collection, id)?)?;
+        if let Some(condition) = condition {
+            let current = match &previous {
+                Some(previous) => Some(parse_document(collection, id, previous)?),
+                None => None,
+            };
+            condition(current.as_ref()).map_err(WriteError::Failed)?;
+        }
+        mode.check(collection, id, previous.is_some())?;
+        let write = self.prepare(&hooks, collection, id, previous, Some(document))?;
+        let entry = log_entry("put", collection, Some(id), write.document.as_ref());
+        self.log(&mut store, &entry)?;
+        drop(store);
+        hooks.after(&write);
+        Ok(write.operation == Operation::Insert)
+    }
+
+    // Applies the patch to the document under the lock, so that no other write can land between
+    // reading the document and writing it back. Returns the document as it was written.
+    pub fn patch(&self, collection: &str, id: &str, patch: &Patch) -> Result<Json, WriteError> {
+        self.patch_if(collection, id, patch, None)
+    }
+
+    pub fn patch_if(
+        &self,
+        collection: &str,
+        id: &str,
+        patch: &Patch,
+        condition: Option<Condition>,
+    ) -> Result<Json, WriteError> {
+        let modified = self.modify(collection, id, |current| {
+            if let Some(condition) = condition {
+                condition(current).map_err(WriteError::Failed)?;
+            }
+            let Some(current) = current else {
+                return Err(WriteError::Missing(format!(
+                    "The document {} does not exist in the collection {}",
+                    id, collection
+                )));
+            };
+            patch.apply(current).map(Some).map_err(|err| {
+                WriteError::Rejected(format!(
+                    "The patch of the document {} in the collection {} failed: {}",
+                    id, collection, err
+                ))
+            })
+        })?;
+        Ok(modified
+            .map(|modified| modified.after)
+            .unwrap_or(Json::Null))
+    }
+
+    // Adds to a numeric field of the document and returns its new value, and whether the
+    // document was inserted. A missing field, or a missing document, starts from zero.
+    pub fn increment(
+        &self,
+        collection: &str,
+        id: &str,
+        field: &str,
+        by: &JsonNumber,
+    ) -> Result<(Json, bool), WriteError> {
+        let mut fields = JsonObject::new();
+        fields[field.to_string()] = Json::Number(by.clone());
+        let mut update = JsonObject::new();
+        update["$inc".to_string()] = Json::Object(fields);
+        let patch = Patch::parse(&Json::Object(update)).map_err(WriteError::Rejected)?;
+        let modified = self.modify(collection, id, |current| {
+            let empty = Json::Object(JsonObject::new());
+            patch
+                .apply(current.unwrap_or(&empty))
+                .map(Some)
+                .map_err(|err| {
+                    WriteError::Rejected(format!(
+                        "The increment of the document {} in the collection {} failed: {}",
+                        id, collection, err
+                    ))
+                })
+        })?;
+        Ok(match modified {
+            Some(modified) => (
+                lookup(&modified.after, field)
+                    .cloned()
+                    .unwrap_or(Json::Null),
+                modified.before.is_none(),
+            ),
+            None => (Json::Null, false),
+        })
+    }
+
+    // Reads the document and writes what the update makes of it under the lock. The update gets
+    // None when the document does not exist, and returns None to leave it as it is, in which case
+    // nothing is written.
+    pub fn modify(
+        &self,
+        collection: &str,
+        id: &str,
+        update: impl FnOnce(Option<&Json>) -> Result<Option<Json>, WriteError>,
+    ) -> Result<Option<Modified>, WriteError> {
+        let hooks = Hooks::find(&self.name, collection);
+        let engine = self.engine()?;
+        let mut store = engine.lock();
+        let previous = live(collection, id, store.tables().get(collection, id)?)?;
+        let before = match &previous {
+            Some(previous) => Some(parse_document(collection, id, previous)?),
+            None => None,
+        };
+        let Some(document) = update(before.as_ref())? else {
+            return Ok(None);
+        };
+        let document = self.expiring(collection, with_id(id, &document))?;
+        let write = self.prepare(&hooks, collection, id, previous, Some(document))?;
+        let entry = log_entry("put", collection, Some(id), write.document.as_ref());
+        self.log(&mut store, &entry)?;
+        drop(store);
+        hooks.after(&write);
+        Ok(Some(Modified {
+            before,
+            after: write.document.clone().unwrap_or(Json::Null),
+        }))
+    }
+
+    pub fn insert(&self, collection: &str, document: &Json) -> Result<String, WriteError> {
+        let hooks = Hooks::find(&self.name, collection);
+        let document = self.expiring(collection, document.clone())?;
+        let engine = self.engine()?;
+        let mut store = engine.lock();
+        loop {
+            let id = generate_id()?;
+            if store.tables().get(collection, &id)?.is_none() {
+                let write =
+                    self.prepare(&hooks, collection, &id, None, Some(with_id(&id, &document)))?;
+                let entry = log_entry("put", collection, Some(&id), write.document.as_ref());
+                self.log(&mut store, &entry)?;
+                drop(store);
+                hooks.after(&write);
+                return Ok(id);
+            }
+        }
+    }
+
+    // Deleting from a collection with soft deletes replaces the document with a tombstone.
+    pub fn delete(&self, collection: &str, id: &str) -> Result<bool, WriteError> {
+        self.delete_if(collection, id, None)
+    }
+
+    pub fn delete_if(
+        &self,
+        collection: &str,
+        id: &str,
+        condition: Option<Condition>,
+    ) -> Result<bool, WriteError> {
+        let soft = self.soft_delete(collection)?.is_some();
+        self.remove(collection, id, soft, condition)
+    }
+
+    // Deletes the document for good, even from a collection with soft deletes, and also purges
+    // its tombstone.
+    pub fn purge_document(&self, collection: &str, id: &str) -> Result<bool, WriteError> {
+        self.purge_document_if(collection, id, None)
+    }
+
+    pub fn purge_document_if(
+        &self,
+        collection: &str,
+        id: &str,
+        condition: Option<Condition>,
+    ) -> Result<bool, WriteError> {
+        self.remove(collection, id, false, condition)
+    }
+
+    // The condition sees a tombstone as no document.
+    fn remove(
+        &self,
+        collection: &str,
+        id: &str,
+        soft: bool,
+        condition: Option<Condition>,
+    ) -> Result<bool, WriteError> {
+        let hooks = Hooks::find(&self.name, collection);
+        let engine = self.engine()?;
+        let mut store = engine.lock();
+        let previous = store.tables().get(collection, id)?;
+        let document = match &previous {
+            Some(previous) => Some(parse_document(collection, id, previous)?),
+            None => None,
+        };
+        if let Some(condition) = condition {
+            let current = document
+                .as_ref()
+                .filter(|document| deleted(document).is_none());
+            condition(current).map_err(WriteError::Failed)?;
+        }
+        let (Some(previous), Some(document)) = (previous, document) else {
+            return Ok(false);
+        };
+        // The hooks already saw the delete that left the tombstone.
+        if deleted(&document).is_some() {
+            if soft {
+                return Ok(false);
+            }
+            let entry = log_entry("delete", collection, Some(id), None);
+            self.log(&mut store, &entry)?;
+            return Ok(true);
+        }
+        let write = self.prepare(&hooks, collection, id, Some(previous), None)?;
+        let entry = match soft {
+            true => {
+                let tombstone = tombstone(&document, Utc::now());
+                log_entry("put", collection, Some(id), Some(&tombstone))
+            }
+            false => log_entry("delete", collection, Some(id), None),
+        };
+        self.log(&mut store, &entry)?;
+        drop(store);
+        hooks.after(&write);
+        Ok(true)
+    }
+
+    // Brings a soft deleted document back, which the hooks and the schema see as an insert.
+    // Returns false when the document is not a tombstone.
+    pub fn restore(&self, collection: &str, id: &str) -> Result<bool, WriteError> {
+        let hooks = Hooks::find(&self.name, collection);
+        let engine = self.engine()?;
+        let mut store = engine.lock();
+        let Some(content) = store.tables().get(collection, id)? else {
+            return Ok(false);
+        };
+        let document = parse_document(collection, id, &content)?;
+        if deleted(&document).is_none() {
+            return Ok(false);
+        }
+        let write = self.prepare(&hooks, collection, id, None, Some(with_id(id, &document)))?;
+        let entry = log_entry("put", collection, Some(id), write.document.as_ref());
+        self.log(&mut store, &entry)?;
+        drop(store);
+        hooks.after(&write);
+        Ok(true)
+    }
+
+    // Removes the tombstones of documents deleted at or before the time, and returns their IDs.
+    pub fn purge(&self, collection: &str, before: DateTime<Utc>) -> Result<Vec<String>, String> {
+        let purgeable = |document: &Json| deleted(document).is_some_and(|at| at <= before);
+        let mut candidates = Vec::new();
+        for document in
+            self.scan_including_deleted(collection, (Bound::Unbounded, Bound::Unbounded))?
+        {
+            let document = document?;
+            if let Json::Object(obj) = &document
+                && let Some(Json::String(id)) = obj.get(ID_FIELD)
+                && purgeable(&document)
+            {
+                candidates.push(id.clone());
+            }
+        }
+        if candidates.is_empty() {
+            return Ok(candidates);
+        }
+        let engine = self.engine()?;
+        let mut store = engine.lock();
+        let mut purged = Vec::new();
+        for id in candidates {
+            if let Some(content) = store.tables().get(collection, &id)?
+                && purgeable(&parse_document(collection, &id, &content)?)
+            {
+                purged.push(id);
+            }
+        }
+        if !purged.is_empty() {
+            let entry = batch_entry(
+                purged
+                    .iter()
+                    .map(|id| log_entry("delete", collection, Some(id), None))
+                    .collect(),
+            );
+            self.log(&mut store, &entry)?;
+        }
+        Ok(purged)
+    }
+
+    // The writes are logged as a single record, so either all of them survive a crash or none
+    // do. A delete of a document that does not exist, a write whose mode does not allow the
+    // document to exist or not, or a write rejected by a hook, aborts the whole batch.
+    pub fn commit(&self, mutations: &[Mutation]) -> Result<Commit, String> {
+        let engine = self.engine()?;
+        let mut store = engine.lock();
+        let mut pending: HashMap<(&str, &str), Option<Vec<u8>>> = HashMap::new();
+        let mut entries = Vec::new();
+        let mut writes = Vec::new();
+        for mutation in mutations {
+            let (collection, id) = (mutation.collection.as_str(), mutation.id.as_str());
+            let previous = match pending.remove(&(collection, id)) {
+                Some(previous) => previous,
+                None => live(collection, id, store.tables().get(collection, id)?)?,
+            };
+            if mutation.document.is_none() && previous.is_none() {
+                return Ok(Commit::Aborted(format!(
+                    "The document {} does not exist in the collection {}",
+                    id, collection
+                )));
+            }
+            if mutation.document.is_some()
+                && let Err(err) = mutation.mode.check(collection, id, previous.is_some())
+            {
+                return Ok(Commit::Aborted(err.into()));
+            }
+            let document = match &mutation.document {
+                Some(document) => match self.expiring(collection, with_id(id, document)) {
+                    Ok(document) => Some(document),
+                    Err(WriteError::Storage(err)) => {
+                        return Err(err);
+                    }
+                    Err(err) => {
+                        return Ok(Commit::Aborted(err.into()));
+                    }
+                },
+                None => None,
+            };
+            let tombstone = match (&document, &previous) {
+                (None, Some(previous)) if self.soft_delete(collection)?.is_some() => Some(
+                    tombstone(&parse_document(collection, id, previous)?, Utc::now()),
+                ),
+                _ => None,
+            };
+            let hooks = Hooks::find(&self.name, collection);
+            let write = match self.prepare(&hooks, collection, id, previous, document) {
+                Ok(write) => write,
+                Err(WriteError::Storage(err)) => {
+                    return Err(err);
+                }
+                Err(err) => {
+                    return Ok(Commit::Aborted(err.into()));
+                }
+            };
+            entries.push(match write.document.as_ref().or(tombstone.as_ref()) {
+                Some(document) => log_entry("put", collection, Some(id), Some(document)),
+                None => log_entry("delete", collection, Some(id), None),
+            });
+            pending.insert(
+                (collection, id),
+                write
+                    .document
+                    .as_ref()
+                    .map(|document| document.canonical().into_bytes()),
+            );
+            writes.push((hooks, write));
+        }
+        if !entries.is_empty() {
+            let entry = batch_entry(entries);
+            self.log(&mut store, &entry)?;
+        }
+        drop(store);
+        for (hooks, write) in &writes {
+            hooks.after(write);
+        }
+        Ok(Commit::Committed(
+            writes
+                .iter()
+                .map(|(_, write)| write.operation != Operation::Update)
+                .collect(),
+        ))
+    }
+
+    // Writes the documents to the collection in a single log record, like a transaction, except
+    // that a document rejected by a hook or by the schema is left out instead of aborting the
+    // others. Documents without an ID get a generated one.
+    pub fn load(&self, collection: &str, documents: &[Json]) -> Result<Vec<Loaded>, String> {
+        let hooks = Hooks::find(&self.name, collection);
+        let engine = self.engine()?;
+        let mut store = engine.lock();
+        let mut pending: HashMap<String, Option<Vec<u8>>> = HashMap::new();
+        let mut results = Vec::new();
+        let mut entries = Vec::new();
+        let mut writes = Vec::new();
+        for document in documents {
+            let id = match document {
+                Json::Object(obj) => match obj.get(ID_FIELD) {
+                    Some(Json::String(id)) if is_valid_name(id) => Some(id.clone()),
+                    Some(_) => None,
+                    None => loop {
+                        let id = generate_id()?;
+                        if !pending.contains_key(&id)
+                            && store.tables().get(collection, &id)?.is_none()
+                        {
+                            break Some(id);
+                        }
+                    },
+                },
+                _ => {
+                    results.push(Err(WriteError::Rejected(
+                        "Expected the document as a JSON object".to_string(),
+                    )));
+                    continue;
+                }
+            };
+            let Some(id) = id else {
+                results.push(Err(WriteError::Rejected(format!(
+                    "The {} field should be a valid document ID",
+                    ID_FIELD
+                ))));
+                continue;
+            };
+            if let Err(err) = expiry(document) {
+                results.push(Err(WriteError::Rejected(err)));
+                continue;
+            }
+            let previous = match pending.get(&id) {
+                Some(previous) => previous.clone(),
+                None => live(collection, &id, store.tables().get(collection, &id)?)?,
+            };
+            let document = self.expiring(collection, with_id(&id, document))?;
+            let write = match self.prepare(&hooks, collection, &id, previous, Some(document)) {
+                Ok(write) => write,
+                Err(WriteError::Storage(err)) => {
+                    return Err(err);
+                }
+                Err(err) => {
+                    results.push(Err(err));
+                    continue;
+                }
+            };
+            entries.push(log_entry(
+                "put",
+                collection,
+                Some(&id),
+                write.document.as_ref(),
+            ));
+            pending.insert(
+                id.clone(),
+                write
+                    .document
+                    .as_ref()
+                    .map(|document| document.canonical().into_bytes()),
+            );
+            results.push(Ok((id, write.operation == Operation::Insert)));
+            writes.push(write);
+        }
+        if !entries.is_empty() {
+            let entry = batch_entry(entries);
+            self.log(&mut store, &entry)?;
+        }
+        drop(store);
+        for write in &writes {
+            hooks.after(write);
+        }
+        Ok(results)
+    }
+
+    // Runs the before hooks of the collection on a write, which may change its document, and
+    // checks the result against the schema of the collection. The previous document is the
+    // content the write replaces.
+    fn prepare(
+        &self,
+        hooks: &Hooks,
+        collection: &str,
+        id: &str,
+        previous: Option<Vec<u8>>,
+        document: Option<Json>,
+    ) -> Result<WriteEvent, WriteError> {
+        let operation = match (&document, &previous) {
+            (None, _) => Operation::Delete,
+            (Some(_), Some(_)) => Operation::Update,
+            (Some(_), None) => Operation::Insert,
+        };
+        let previous = match (hooks.is_empty(), previous) {
+            (false, Some(previous)) => Some(parse_document(collection, id, &previous)?),
+            _ => None,
+        };
+        let mut write = WriteEvent {
+            database: self.name.clone(),
+            collection: collection.to_string(),
+            id: id.to_string(),
+            operation,
+            previous,
+            document,
+        };
+        hooks.before(&mut write).map_err(|reason| {
+            WriteError::Rejected(format!(
+                "The write of the document {} in the collection {} was rejected: {}",
+                id, collection, reason
+            ))
+        })?;
+        if let Some(document) = &mut write.document {
+            *document = with_id(id, document);
+            self.check_schema(collection, id, document)?;
+        }
+        Ok(write)
+    }
+
+    // The fields the database sets itself are left out, so that a schema without them may
+    // still forbid additional fields.
+    fn check_schema(&self, collection: &str, id: &str, document: &Json) -> Result<(), WriteError> {
+        let Some(settings) = self.settings(collection)? else {
+            return Ok(());
+        };
+        let Some(schema) = &settings.schema else {
+            return Ok(());
+        };
+        let mut document = document.clone();
+        if let Json::Object(obj) = &mut document {
+            obj.remove(ID_FIELD);
+            obj.remove(EXPIRES_FIELD);
+        }
+        let violations = metrics::spend(Phase::Validate, || schema.validate(&document));
+        if violations.is_empty() {
+            return Ok(());
+        }
+        let message = format!(
+            "The document {} does not match the schema of the collection {}: {}",
+            id,
+            collection,
+            schema::describe(&violations)
+        );
+        match settings.meta.validation {
+            Validation::Strict => Err(WriteError::Invalid(message, violations)),
+            Validation::Warn => {
+                eprintln!("{} (accepted in the database {})", message, self.name);
+                Ok(())
+            }
+        }
+    }
+
+    // Documents without an expiry of their own get the TTL of their collection. An expiry that
+    // is not a timestamp is rejected, like it is when documents are loaded.
+    fn expiring(&self, collection: &str, mut document: Json) -> Result<Json, WriteError> {
+        expiry(&document).map_err(WriteError::Rejected)?;
+        let ttl = self
+            .settings(collection)?
+            .and_then(|settings| settings.meta.ttl);
+        if let (Json::Object(obj), Some(ttl)) = (&mut document, ttl)
+            && obj.get(EXPIRES_FIELD).is_none()
+        {
+            let expires = Utc::now() + chrono::Duration::seconds(ttl as i64);
+            obj[EXPIRES_FIELD.to_string()] = Json::String(expires.to_rfc3339());
+        }
+        Ok(document)
+    }
+
+    // Deletes the documents of the collection that expired by now and returns their IDs. The
+    // expiry is checked again under the lock, so a document rewritten since the scan survives.
+    pub fn expire(&self, collection: &str, now: DateTime<Utc>) -> Result<Vec<String>, String> {
+        let mut candidates = Vec::new();
+        for document in self.scan_stored(collection, (Bound::Unbounded, Bound::Unbounded))? {
+            let document = document?;
+            if let Json::Object(obj) = &document
+                && let Some(Json::String(id)) = obj.get(ID_FIELD)
+                && is_expired(&document, now)
+            {
+                candidates.push(id.clone());
+            }
+        }
+        if candidates.is_empty() {
+            return Ok(candidates);
+        }
+        let engine = self.engine()?;
+        let mut store = engine.lock();
+        let mut expired = Vec::new();
+        for id in candidates {
+            if let Some(content) = store.tables().get(collection, &id)?
+                && is_expired(&parse_document(collection, &id, &content)?, now)
+            {
+                expired.push(id);
+            }
+        }
+        if !expired.is_empty() {
+            let entry = batch_entry(
+                expired
+                    .iter()
+                    .map(|id| log_entry("delete", collection, Some(id), None))
+                    .collect(),
+            );
+            self.log(&mut store, &entry)?;
+        }
+        Ok(expired)
+    }
+
+    // Yields the documents of the collection whose IDs are in the range, in ID order, leaving
+    // out tombstones.
+    pub fn scan(
+        &self,
+        collection: &str,
+        range: Range,
+    ) -> Result<impl Iterator<Item = Result<Json, String>> + use<>, String> {
+        Ok(self
+            .scan_including_deleted(collection, range)?
+            .filter(|document| {
+                document
+                    .as_ref()
+                    .map_or(true, |document| deleted(document).is_none())
+            }))
+    }
+
+    // Leaves out expired documents, like get_including_deleted.
+    pub fn scan_including_deleted(
+        &self,
+        collection: &str,
+        range: Range,
+    ) -> Result<impl Iterator<Item = Result<Json, String>> + use<>, String> {
+        let now = Utc::now();
+        Ok(self
+            .scan_stored(collection, range)?
+            .filter(move |document| {
+                document
+                    .as_ref()
+                    .map_or(true, |document| !is_expired(document, now))
+            }))
+    }
+
+    // Every stored document, expired or not.
+    fn scan_stored(
+        &self,
+        collection: &str,
+        range: Range,
+    ) -> Result<impl Iterator<Item = Result<Json, String>> + use<>, String> {
+        warmup::record(&self.name, collection);
+        let collection = collection.to_string();
+        Ok(self.engine()?.scan(&collection, range).map(move |entry| {
+            entry.and_then(|(id, content)| parse_document(&collection, &id, &content))
+        }))
+    }
+
+    // Returns the changes of the collection after the sequence number, oldest first. A batch
+    // is never split, so there may be more than limit of them.
+    pub fn changes(&self, collection: &str, since: u64, limit: usize) -> Result<Vec<Json>, String> {
+        let feed = changes_collection(collection);
+        let start = Bound::Included(change_key(since + 1, 0));
+        let mut changes = Vec::new();
+        let mut last_seq = None;
+        for entry in self.engine()?.scan(&feed, (start, Bound::Unbounded)) {
+            let (key, content) = entry?;
+            let seq = key.split('.').next().map(str::to_string);
+            if changes.len() >= limit && seq != last_seq {
+                break;
+            }
+            changes.push(parse_document(&feed, &key, &content)?);
+            last_seq = seq;
+        }
+        Ok(changes)
+    }
+
+    // A uniform random sample of up to n documents of the collection, taken in one pass over
+    // it, along with the number of documents it was taken from.
+    pub fn sample(&self, collection: &str, n: usize) -> Result<(Vec<Json>, u64), String> {
+        let mut reservoir = Reservoir::new(n)?;
+        for document in self.scan(collection, (Bound::Unbounded, Bound::Unbounded))? {
+            reservoir.insert(document?);
+        }
+        let seen = reservoir.seen();
+        Ok((reservoir.into_items(), seen))
+    }
+
+    // Reads every stored document of the collection and checks that it parses, that it holds
+    // the ID it is stored under, and that the counters agree with what is stored. Every problem
+    // is returned, and only a table that cannot be read fails the check.
+    pub fn check_collection(&self, collection: &str) -> Result<CollectionCheck, String> {
+        let mut problems = Vec::new();
+        let mut counted = Counts::default();
+        for entry in self
+            .engine()?
+            .scan(collection, (Bound::Unbounded, Bound::Unbounded))
+        {
+            let (id, content) = entry?;
+            let document = match parse_document(collection, &id, &content) {
+                Ok(document) => document,
+                Err(err) => {
+                    problems.push(err);
+                    continue;
+                }
+            };
+            if !matches!(lookup(&document, ID_FIELD), Some(Json::String(stored)) if *stored == id) {
+                problems.push(format!(
+                    "The document {} in the collection {} does not hold its ID",
+                    id, collection
+                ));
+            }
+            counted.count(&document, content.len(), true);
+        }
+        let counts =
+            engine::inspect(self, |tables| Counts::read(tables, collection))?.unwrap_or_default();
+        for (name, kept, stored) in [
+            ("documents", counts.documents, counted.documents),
+            ("soft deleted documents", counts.deleted, counted.deleted),
+            ("bytes of documents", counts.bytes, counted.bytes),
+        ] {
+            if kept != stored {
+                problems.push(format!(
+                    "The collection {} counts {} {}, but {} are stored",
+                    collection, kept, name, stored
+                ));
+            }
+        }
+        Ok(CollectionCheck {
+            name: collection.to_string(),
+            documents: counted.documents,
+            problems,
+        })
+    }
+
+    pub fn documents(&self, collection: &str) -> Result<Vec<Json>, String> {
+        self.scan(collection, (Bound::Unbounded, Bound::Unbounded))?
+            .collect()
+    }
+
+    pub fn flush(&self) -> Result<(), String> {
+        self.engine()?.lock().flush()
+    }
+
+    // Moves the documents of a format version 1 database into tables. The documents are only
+    // removed once their table is written, so an interrupted upgrade is simply run again.
+    pub fn upgrade(&self) -> Result<(), String> {
+        if self.manifest.format_version >= FORMAT_VERSION {
+            return Ok(());
+        }
+        let legacy = TableId {
+            first_seq: 0,
+            last_seq: 0,
+        };
+        for name in self.list_collections()? {
+            let dir = self.data_dir().join(&name);
+            let mut files = self
+                .read_dir(&dir)?
+                .into_iter()
+                .map(|entry| entry.path())
+                .filter_map(|path| Some((document_id(&path)?.to_string(), path.clone())))
+                .collect::<Vec<_>>();
+            if files.is_empty() {
+                continue;
+            }
+            files.sort();
+            let mut documents = Vec::new();
+            for (id, path) in &files {
+                let content = fs::read(path).map_err(|err| {
+                    format!(
+                        "Error while reading the document {}: {}",
+                        path.display(),
+                        err
+                    )
+                })?;
+                let document = with_id(id, &parse_document(&name, id, &content)?);
+                documents.push((id.clone(), Some(document.canonical().into_bytes())));
+            }
+            let table = dir.join(legacy.file_name());
+            if table.exists() {
+                Table::open(&table)?.remove()?;
+            }
+            Table::write(&dir, legacy, Codec::None, documents)?;
+            for (_, path) in &files {
+                fs::remove_file(path).map_err(|err| {
+                    format!(
+                        "Error while removing the upgraded document {}: {}",
+                        path.display(),
+                        err
+                    )
+                })?;
+            }
+        }
+        Manifest {
+            format_version: FORMAT_VERSION,
+            name: self.manifest.name.clone(),
+            created: self.manifest.created,
+            encryption: self.manifest.encryption.clone(),
+            scram: self.manifest.scram.clone(),
+        }
+        .write(Path::new(&self.path))
+    }
+
+    fn read_dir(&self, dir: &Path) -> Result<Vec<fs::DirEntry>, String> {
+        fs::read_dir(dir)
+            .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| {
+                format!(
+                    "Error while reading the directory {}: {}",
+                    dir.display(),
+                    err
+                )
+            })
+    }
+
+    pub fn create_collection(&self, name: &str) -> Result<bool, String> {
+        let engine = self.engine()?;
+        let mut store = engine.lock();
+        if self.data_dir().join(name).is_dir() {
+            return Ok(false);
+        }
+        let entry = log_entry("create_collection", name, None, None);
+        self.log(&mut store, &entry)?;
+        Ok(true)
+    }
+
+    fn make_collection(&self, name: &str, sync: bool) -> Result<bool, String> {
+        let dir = self.data_dir().join(name);
+        match fs::create_dir(&dir).and_then(|_| sync_parent(&dir, sync)) {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                return Ok(false);
             }
             Err(err) => {
                 return Err(format!(
-                    "Error while creating the directory {} for the database {}. The error is {}",
-                    db_dir.to_string_lossy(),
-                    name,
+                    "Error while creating the collection directory {}: {}",
+                    dir.display(),
                     err
                 ));
             }
         }
+        let meta = CollectionMeta {
+            name: name.to_string(),
+            created: Utc::now(),
+            ttl: None,
+            schema: None,
+            validation: Validation::Strict,
+            soft_delete: None,
+            compression: Codec::None,
+            compacted: None,
+        };
+        let path = dir.join(COLLECTION_FILE);
+        write_metadata(&path, &meta.to_json()).map_err(|err| {
+            format!(
+                "Error while writing the collection metadata {}: {}",
+                path.display(),
+                err
+            )
+        })?;
+        Ok(true)
+    }
+
+    pub fn collection(&self, name: &str) -> Result<Option<CollectionMeta>, String> {
+        let dir = self.data_dir().join(name);
+        if !dir.is_dir() {
+            return Ok(None);
+        }
+        CollectionMeta::read(&dir, name).map(Some)
+    }
+
+    pub fn set_ttl(&self, name: &str, ttl: Option<u64>) -> Result<bool, String> {
+        self.update_collection(name, |meta| meta.ttl = ttl)
+    }
+
+    pub fn set_soft_delete(
+        &self,
+        name: &str,
+        soft_delete: Option<SoftDelete>,
+    ) -> Result<bool, String> {
+        self.update_collection(name, |meta| meta.soft_delete = soft_delete)
+    }
+
+    pub fn set_compression(&self, name: &str, compression: Codec) -> Result<bool, String> {
+        self.update_collection(name, |meta| meta.compression = compression)
+    }
+
+    fn soft_delete(&self, collection: &str) -> Result<Option<SoftDelete>, String> {
+        Ok(self
+            .settings(collection)?
+            .and_then(|settings| settings.meta.soft_delete))
+    }
+
+    // The settings that writes to the collection use, cached by the engine. None when the
+    // collection does not exist.
+    fn settings(&self, name: &str) -> Result<Option<Arc<Collection>>, String> {
+        self.engine()?.collection(name, || {
+            let Some(meta) = self.collection(name)? else {
+                return Ok(None);
+            };
+            let schema = match &meta.schema {
+                Some(source) => Some(Schema::new(source).map_err(|err| {
+                    format!("The schema of the collection {} is invalid: {}", name, err)
+                })?),
+                None => None,
+            };
+            Ok(Some(Collection { meta, schema }))
+        })
+    }
+
+    // Lints a filter against the schema of the collection, if it has one.
+    pub fn lint(&self, collection: &str, filter: &Json) -> Result<Vec<Lint>, String> {
+        let settings = self.settings(collection)?;
+        let schema = settings
+            .as_ref()
+            .and_then(|settings| settings.schema.as_ref());
+        Ok(query::lint(filter, schema))
+    }
+
+    // The schema is stored as given, and compiled once it is used.
+    pub fn set_schema(
+        &self,
+        name: &str,
+        schema: Option<(&Schema, Validation)>,
+    ) -> Result<bool, String> {
+        self.update_collection(name, |meta| match schema {
+            Some((schema, validation)) => {
+                meta.schema = Some(schema.source().clone());
+                meta.validation = validation;
+            }
+            None => {
+                meta.schema = None;
+                meta.validation = Validation::Strict;
+            }
+        })
+    }
+
+    fn update_collection(
+        &self,
+        name: &str,
+        update: impl FnOnce(&mut CollectionMeta),
+    ) -> Result<bool, String> {
+        let engine = self.engine()?;
+        let _store = engine.lock();
+        let Some(mut meta) = self.collection(name)? else {
+            return Ok(false);
+        };
+        update(&mut meta);
+        let path = self.data_dir().join(name).join(COLLECTION_FILE);
+        let written = write_metadata(&path, &meta.to_json()).map_err(|err| {
+            format!(
+                "Error while writing the collection metadata {}: {}",
+                path.display(),
+                err
+            )
+        });
+        // A failed write may still have replaced the file.
+        engine.forget_collection(name);
+        written.map(|_| true)
+    }
+
+    pub fn drop_collection(&self, name: &str) -> Result<bool, String> {
+        let engine = self.engine()?;
+        let mut store = engine.lock();
+        if !self.data_dir().join(name).is_dir() {
+            return Ok(false);
+        }
+        let entry = log_entry("drop_collection", name, None, None);
+        let logged = self.log(&mut store, &entry);
+        engine.forget_collection(name);
+        logged.map(|_| true)
+    }
+
+    fn remove_collection(&self, name: &str, sync: bool) -> Result<bool, String> {
+        let dir = self.data_dir().join(name);
+        match fs::remove_dir_all(&dir).and_then(|_| sync_parent(&dir, sync)) {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(format!(
+                "Error while dropping the collection {}: {}",
+                dir.display(),
+                err
+            )),
+        }
+    }
+
+    pub fn list_collections(&self) -> Result<Vec<String>, String> {
+        let mut names = self
+            .read_dir(&self.data_dir())?
+            .into_iter()
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| is_valid_name(name))
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn collection_stats(&self, name: &str) -> Result<CollectionStats, String> {
+        let meta = self
+            .collection(name)?
+            .ok_or_else(|| format!("The collection {} does not exist", name))?;
+        engine::inspect(self, |tables| collection_stats(tables, &meta))
+    }
+
+    // The stats come from the counters kept by every write and from the sizes of the tables and
+    // log segments, so they are read without scanning any document. They do not load the engine
+    // either, so they can be read while another process has the database open.
+    pub fn stats(&self) -> Result<DbStats, String> {
+        let mut metas = Vec::new();
+        for name in self.list_collections()? {
+            metas.extend(self.collection(&name)?);
+        }
+        let collections = engine::inspect(self, |tables| {
+            metas
+                .iter()
+                .map(|meta| collection_stats(tables, meta))
+                .collect::<Result<Vec<_>, String>>()
+        })?;
+        let wal_bytes = wal::segments(&Path::new(&self.path).join(WAL_DIR))?
+            .iter()
+            .map(|segment| segment.bytes)
+            .sum();
+        Ok(DbStats {
+            name: self.name.clone(),
+            collections,
+            wal_bytes,
+        })
+    }
+
+    // Removes stale temporary files, and merges the tables of every collection with at least
+    // min_tables of them, dropping overwritten versions and tombstones.
+    pub fn compact(&self, min_tables: usize) -> Result<FileStats, String> {
+        let mut removed = FileStats {
+            path: self.path.clone(),
+            files: 0,
+            bytes: 0,
+        };
+        let now = SystemTime::now();
+        for name in self.list_collections()? {
+            for entry in self.read_dir(&self.data_dir().join(name))? {
+                let path = entry.path();
+                if path.extension().is_none_or(|ext| ext != TEMP_EXTENSION) {
+                    continue;
+                }
+                let Ok(meta) = entry.metadata() else {
+                    continue;
+                };
+                let stale = meta
+                    .modified()
+                    .ok()
+                    .and_then(|modified| now.duration_since(modified).ok())
+                    .is_some_and(|age| age >= STALE_TEMP_AGE);
+                if stale && fs::remove_file(&path).is_ok() {
+                    removed.files += 1;
+                    removed.bytes += meta.len();
+                }
+            }
+        }
+        let engine = self.engine()?;
+        let mut throttle = Throttle::new(compaction::config().rate);
+        for name in self.list_collections()? {
+            let mut compacted = false;
+            for collection in side_collections(&name).into_iter().chain([name.clone()]) {
+                let merged = engine.compact(&collection, min_tables, &mut throttle)?;
+                if let Some((files, bytes)) = merged {
+                    removed.files += files;
+                    removed.bytes += bytes;
+                    compacted = true;
+                }
+            }
+            if compacted {
+                self.update_collection(&name, |meta| meta.compacted = Some(Utc::now()))?;
+            }
+        }
+        Ok(removed)
+    }
+
+    pub fn backup(&self, root: &str, incremental: bool) -> Result<FileStats, String> {
+        let dir = Path::new(root).join(BACKUP_DIR).join(&self.name);
+        fs::create_dir_all(&dir).map_err(|err| {
+            format!(
+                "Error while creating the backup directory {}: {}",
+                dir.display(),
+                err
+            )
+        })?;
+        let timestamp = Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+        match incremental {
+            true => archive::write_increment(
+                self,
+                &dir.join(format!(
+                    "{}.incremental.{}",
+                    timestamp,
+                    archive::ARCHIVE_EXTENSION
+                )),
+            ),
+            false => archive::write(
+                self,
+                &dir.join(format!("{}.{}", timestamp, archive::ARCHIVE_EXTENSION)),
+            ),
+        }
+    }
+
+    pub fn destroy(self) -> Result<(), String> {
+        engine::remove(Path::new(&self.path));
+        fs::remove_dir_all(&self.path).map_err(|err| {
+            format!(
+                "Error while deleting the database {} at {}: {}",
+                self.name, self.path, err
+            )
+        })
+    }
+
+    pub fn create(root: &str, name: &str, password: String) -> Result<DB, String> {
+        if !is_valid_name(name) {
+            return Err(format!(
+                "The database name {} is invalid. Names may contain letters, digits, '-', '_' and '.', and may not start with '.'",
+                name
+            ));
+        }
+        let db_dir = Path::new(root).join(name);
+        fs::create_dir(&db_dir).map_err(|err| {
+            format!(
+                "Error while creating the directory {} for the database {}. The error is {}",
+                db_dir.display(),
+                name,
+                err
+            )
+        })?;
+        let manifest = Manifest {
+            format_version: FORMAT_VERSION,
+            name: name.to_string(),
+            created: Utc::now(),
+            encryption: match password.is_empty() {
+                true => None,
+                false => Some(KeyParams::new(&password)?),
+            },
+            scram: match password.is_empty() {
+                true => None,
+                false => Some(ScramVerifier::new(&password)?),
+            },
+        };
+        let layout = fs::create_dir(db_dir.join(DATA_DIR))
+            .and_then(|_| fs::create_dir(db_dir.join(WAL_DIR)))
+            .and_then(|_| fs::write(db_dir.join(LOCK_FILE), b""))
+            .and_then(|_| sync_parent(&db_dir, true))
+            .map_err(|err| {
+                format!(
+                    "Error while creating the layout of the database {} in {}: {}",
+                    name,
+                    db_dir.display(),
+                    err
+                )
+            })
+            .and_then(|_| manifest.write(&db_dir));
+        if let Err(err) = layout {
+            let _ = fs::remove_dir_all(&db_dir);
+            return Err(err);
+        }
+        Ok(DB {
+            name: name.to_string(),
+            path: db_dir.to_string_lossy().to_string(),
+            manifest,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn json(text: &str) -> Json {
+        Json::parse(text.as_bytes()).unwrap()
+    }
+
+    fn field(document: &Json, name: &str) -> Option<String> {
+        match document {
+            Json::Object(obj) => obj.get(name).map(|value| value.canonical()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn documents_survive_reopening_the_database() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let db = DB::create(&root, "store", String::new()).unwrap();
+        assert!(db.put("users", "ada", &json(r#"{"name":"Ada"}"#)).unwrap());
+        assert!(
+            !db.put(
+                "users",
+                "ada",
+                &json(r#"{"name":"Ada Lovelace","_id":"ignored"}"#)
+            )
+            .unwrap()
+        );
+        let id = db.insert("users", &json(r#"{"name":"Grace"}"#)).unwrap();
+        drop(db);
+
+        let db = DB::open(&root, "store").unwrap().unwrap();
+        let ada = db.get("users", "ada").unwrap().unwrap();
+        assert_eq!(field(&ada, "name").as_deref(), Some(r#""Ada Lovelace""#));
+        assert_eq!(field(&ada, "_id").as_deref(), Some(r#""ada""#));
+        let grace = db.get("users", &id).unwrap().unwrap();
+        assert_eq!(field(&grace, "_id"), Some(format!("\"{}\"", id)));
+        assert_eq!(db.documents("users").unwrap().len(), 2);
+
+        assert!(db.delete("users", "ada").unwrap());
+        assert!(!db.delete("users", "ada").unwrap());
+        assert!(db.get("users", "ada").unwrap().is_none());
+        assert_eq!(db.documents("users").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn writes_leave_no_temporary_files() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let db = DB::create(&root, "clean", String::new()).unwrap();
+        for round in 0..5 {
+            db.put("logs", "v1.2", &json(&format!(r#"{{"round":{}}}"#, round)))
+                .unwrap();
+        }
+        db.flush().unwrap();
+        let mut files = fs::read_dir(dir.path().join("clean").join(DATA_DIR).join("logs"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files.len(), 4, "{:?}", files);
+        assert_eq!(files[..3], [".changes", ".collection.json", ".stats"]);
+        assert!(files[3].ends_with(".sst"), "{:?}", files);
+        let document = db.get("logs", "v1.2").unwrap().unwrap();
+        assert_eq!(field(&document, "round").as_deref(), Some("4"));
     }
 }
@@ -0,0 +1,835 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
+    io::ErrorKind,
+    iter::Peekable,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, LazyLock, Mutex, MutexGuard, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    vec,
+};
+
+use crate::{
+    compaction::{self, Throttle},
+    db::{self, Collection, DATA_DIR, DB, WAL_DIR},
+    json::Json,
+    metrics::{self, Phase},
+    sstable::{Codec, Entry, Table, TableId, Value},
+    wal::{self, Recovery, Wal},
+};
+
+pub const MEMTABLE_SIZE: usize = 4 * 1024 * 1024;
+pub const MAX_TABLES: usize = 8;
+const ENTRY_OVERHEAD: usize = 32;
+
+// Compactions run one at a time, which also keeps the I/O they add to a single throttle.
+pub(crate) static COMPACTING: Mutex<()> = Mutex::new(());
+
+static ENGINES: LazyLock<Mutex<HashMap<PathBuf, Arc<Engine>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Databases closed at runtime are not loaded again until they are opened.
+static CLOSED: LazyLock<Mutex<HashSet<PathBuf>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+// The lock file of a database is locked once per process and shared by everything that holds
+// it, so an engine that is unloaded and loaded again while requests still hold the old one does
+// not find the database locked by itself.
+static LOCKS: LazyLock<Mutex<HashMap<PathBuf, Weak<File>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub type Range = (Bound<String>, Bound<String>);
+
+fn entry_size(key: &str, value: &Value) -> usize {
+    key.len() + value.as_ref().map_or(0, |value| value.len()) + ENTRY_OVERHEAD
+}
+
+fn resolve(tables: &[Arc<Table>], key: &str) -> Result<Option<Vec<u8>>, String> {
+    for table in tables {
+        if let Some(value) = table.get(key)? {
+            return Ok(value);
+        }
+    }
+    Ok(None)
+}
+
+// Recent writes live in a sorted memtable per collection and are written out as an immutable
+// table once the memtable grows past MEMTABLE_SIZE. The log keeps every write that is not in a
+// table yet, so the memtable is rebuilt from it after a restart.
+pub struct Tables {
+    data_dir: PathBuf,
+    // Tables read for a snapshot leave the files of the database alone.
+    writable: bool,
+    memtable: BTreeMap<String, BTreeMap<String, Value>>,
+    memtable_size: usize,
+    // Newest first, so the first table that has a key holds its latest value.
+    tables: HashMap<String, Arc<Vec<Arc<Table>>>>,
+}
+
+impl Tables {
+    fn load(data_dir: &Path, writable: bool) -> Result<Tables, String> {
+        let mut tables = Tables {
+            data_dir: data_dir.to_path_buf(),
+            writable,
+            memtable: BTreeMap::new(),
+            memtable_size: 0,
+            tables: HashMap::new(),
+        };
+        let entries = match fs::read_dir(data_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Ok(tables);
+            }
+            Err(err) => {
+                return Err(format!(
+                    "Error while reading the directory {}: {}",
+                    data_dir.display(),
+                    err
+                ));
+            }
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if db::is_valid_name(&name) && entry.path().is_dir() {
+                let loaded = Tables::load_collection(&entry.path(), writable)?;
+                tables.tables.insert(name.clone(), Arc::new(loaded));
+                for (side, collection) in db::SIDE_DIRS.iter().zip(db::side_collections(&name)) {
+                    let dir = entry.path().join(side);
+                    if dir.is_dir() {
+                        let loaded = Tables::load_collection(&dir, writable)?;
+                        tables.tables.insert(collection, Arc::new(loaded));
+                    }
+                }
+            }
+        }
+        Ok(tables)
+    }
+
+    // A merge that was interrupted leaves its inputs next to its output. The output covers
+    // them, so they are removed here.
+    fn load_collection(dir: &Path, writable: bool) -> Result<Vec<Arc<Table>>, String> {
+        let mut ids = fs::read_dir(dir)
+            .map_err(|err| {
+                format!(
+                    "Error while reading the directory {}: {}",
+                    dir.display(),
+                    err
+                )
+            })?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| TableId::parse(&entry.path()))
+            .collect::<Vec<_>>();
+        ids.sort_by_key(|id| (id.last_seq, id.last_seq - id.first_seq));
+        let mut tables: Vec<Arc<Table>> = Vec::new();
+        for id in ids.into_iter().rev() {
+            let path = dir.join(id.file_name());
+            if tables.iter().any(|table| table.id.covers(&id)) {
+                if !writable {
+                    continue;
+                }
+                fs::remove_file(&path).map_err(|err| {
+                    format!(
+                        "Error while removing the merged table {}: {}",
+                        path.display(),
+                        err
+                    )
+                })?;
+                continue;
+            }
+            tables.push(Arc::new(Table::open(&path)?));
+        }
+        Ok(tables)
+    }
+
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+
+    fn memtable_get(&self, collection: &str, key: &str) -> Option<&Value> {
+        self.memtable.get(collection)?.get(key)
+    }
+
+    pub fn snapshot(&self, collection: &str) -> Arc<Vec<Arc<Table>>> {
+        self.tables.get(collection).cloned().unwrap_or_default()
+    }
+
+    // The collections with tables or writes, leaving out their side collections.
+    pub fn collections(&self) -> Vec<String> {
+        let mut collections = self
+            .tables
+            .keys()
+            .chain(self.memtable.keys())
+            .filter(|collection| db::is_valid_name(collection))
+            .cloned()
+            .collect::<Vec<_>>();
+        collections.sort();
+        collections.dedup();
+        collections
+    }
+
+    // The scan copies the part of the memtable in the range, so it does not borrow the tables.
+    pub fn scan(&self, collection: &str, range: Range) -> Scan {
+        let memtable = self
+            .memtable
+            .get(collection)
+            .map(|memtable| {
+                memtable
+                    .range::<String, _>((range.0.as_ref(), range.1.as_ref()))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let start = match &range.0 {
+            Bound::Included(key) => Bound::Included(key.as_str()),
+            Bound::Excluded(key) => Bound::Excluded(key.as_str()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let mut sources = vec![Box::new(memtable.into_iter().map(Ok)) as Source];
+        sources.extend(
+            self.snapshot(collection)
+                .iter()
+                .map(|table| Box::new(table.scan(start)) as Source),
+        );
+        Scan {
+            merge: Merge::new(sources, range.1),
+        }
+    }
+
+    pub fn get(&self, collection: &str, key: &str) -> Result<Option<Vec<u8>>, String> {
+        metrics::spend(Phase::Storage, || {
+            match self.memtable_get(collection, key) {
+                Some(value) => Ok(value.clone()),
+                None => resolve(&self.snapshot(collection), key),
+            }
+        })
+    }
+
+    fn set(&mut self, collection: &str, key: &str, value: Value) {
+        self.memtable_size += entry_size(key, &value);
+        let previous = self
+            .memtable
+            .entry(collection.to_string())
+            .or_default()
+            .insert(key.to_string(), value);
+        if let Some(previous) = previous {
+            self.memtable_size -= entry_size(key, &previous);
+        }
+    }
+
+    pub fn put(&mut self, collection: &str, key: &str, value: Vec<u8>) {
+        self.set(collection, key, Some(value));
+    }
+
+    pub fn delete(&mut self, collection: &str, key: &str) {
+        self.set(collection, key, None);
+    }
+
+    // Forgets the collection. Its directory, and the tables in it, are removed by the caller.
+    pub fn drop_collection(&mut self, collection: &str) {
+        if let Some(memtable) = self.memtable.remove(collection) {
+            self.memtable_size -= memtable
+                .iter()
+                .map(|(key, value)| entry_size(key, value))
+                .sum::<usize>();
+        }
+        self.tables.remove(collection);
+    }
+
+    fn add_table(&mut self, collection: &str, table: Table) {
+        let mut tables = vec![Arc::new(table)];
+        tables.extend(self.snapshot(collection).iter().cloned());
+        self.tables.insert(collection.to_string(), Arc::new(tables));
+    }
+
+    // Writes the memtable out as one table per collection. The tables are added before the
+    // memtable is cleared, so a reader always finds a key in one of them. The counters of the
+    // collections go first, since they tell which writes they already counted. Returns the
+    // collections that have more than MAX_TABLES tables now.
+    fn flush(&mut self, id: TableId) -> Result<Vec<String>, String> {
+        let mut overfull = Vec::new();
+        let mut collections = self.memtable.keys().cloned().collect::<Vec<_>>();
+        collections.sort_by_key(|collection| !collection.ends_with(&format!("/{}", db::STATS_DIR)));
+        for collection in collections {
+            let dir = self.data_dir.join(&collection);
+            fs::create_dir_all(&dir).map_err(|err| {
+                format!(
+                    "Error while creating the collection directory {}: {}",
+                    dir.display(),
+                    err
+                )
+            })?;
+            let entries = self.memtable[&collection]
+                .iter()
+                .map(|(key, value)| (key, value.as_deref()));
+            let codec = db::table_codec(&self.data_dir, &collection)?;
+            let table = Table::write(&dir, id, codec, entries)?;
+            self.add_table(&collection, table);
+            if self.snapshot(&collection).len() > MAX_TABLES {
+                overfull.push(collection);
+            }
+        }
+        self.memtable.clear();
+        self.memtable_size = 0;
+        Ok(overfull)
+    }
+}
+
+// Merges the tables, which are every table of a collection, into one. Tombstones can be dropped,
+// since there is no older table left for them to hide a key in.
+fn merge(
+    dir: &Path,
+    tables: &[Arc<Table>],
+    codec: Codec,
+    throttle: &mut Throttle,
+) -> Result<Table, String> {
+    let id = TableId {
+        first_seq: tables.iter().map(|table| table.id.first_seq).min().unwrap(),
+        last_seq: tables.iter().map(|table| table.id.last_seq).max().unwrap(),
+    };
+    let sources = tables
+        .iter()
+        .map(|table| Box::new(table.scan_uncached()) as Source)
+        .collect();
+    // Entries are written as they are merged, so a large collection is never held in memory.
+    // A read error ends the table early, and the table is removed once it is written.
+    let mut failed = None;
+    let entries = Merge::new(sources, Bound::Unbounded)
+        .map_while(|entry| entry.map_err(|err| failed = Some(err)).ok())
+        .inspect(|(key, value)| throttle.consume(entry_size(key, value) as u64))
+        .filter(|(_, value)| value.is_some());
+    let merged = Table::write(dir, id, codec, entries)?;
+    match failed {
+        Some(err) => {
+            merged.remove()?;
+            Err(err)
+        }
+        None => Ok(merged),
+    }
+}
+
+pub struct Store {
+    wal: Wal,
+    tables: Tables,
+    recovery: Recovery,
+    // Set when the database is closed, for requests that got the engine before that.
+    closed: bool,
+    // Set when a logged record could not be applied. The memtable may hold part of it, so the
+    // engine is loaded again from the log.
+    poisoned: Arc<AtomicBool>,
+}
+
+impl Store {
+    pub fn tables(&self) -> &Tables {
+        &self.tables
+    }
+
+    pub fn recovery(&self) -> Recovery {
+        self.recovery.clone()
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.wal.next_seq() - 1
+    }
+
+    // Logs the entry and then applies it, the same way it is applied when the log is replayed.
+    // Whatever can fail should be checked before, since a logged entry is applied on replay even
+    // when the write failed. An entry that still fails to apply closes the store, and the next
+    // request loads the engine again, which replays the entry or fails to load the database.
+    pub fn write(
+        &mut self,
+        entry: &Json,
+        apply: impl FnOnce(&mut Tables, u64) -> Result<(), String>,
+    ) -> Result<u64, String> {
+        metrics::spend(Phase::Storage, || self.write_entry(entry, apply))
+    }
+
+    fn write_entry(
+        &mut self,
+        entry: &Json,
+        apply: impl FnOnce(&mut Tables, u64) -> Result<(), String>,
+    ) -> Result<u64, String> {
+        if self.closed {
+            return Err("The database is closed".to_string());
+        }
+        let seq = self.wal.append(entry)?;
+        if let Err(err) = apply(&mut self.tables, seq) {
+            self.closed = true;
+            self.poisoned.store(true, Ordering::Relaxed);
+            return Err(format!(
+                "The log record {} could not be applied, the database is loaded again from its log: {}",
+                seq, err
+            ));
+        }
+        // The write is logged and applied, so a failed flush is retried with the next write
+        // rather than reported as a failed write.
+        if self.tables.memtable_size >= MEMTABLE_SIZE
+            && let Err(err) = self.flush()
+        {
+            eprintln!(
+                "Could not flush the memtable after the log record {}: {}",
+                seq, err
+            );
+        }
+        Ok(seq)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            checkpoint: self.wal.checkpoint_seq(),
+            last_seq: self.wal.next_seq() - 1,
+            memtable: self.tables.memtable.clone(),
+            tables: self.tables.tables.clone(),
+        }
+    }
+
+    // Once the memtable is in tables, the log records before it are no longer needed. Merging
+    // the collections left with too many tables is handed to the background, so that the writes
+    // waiting for the lock are not held up by it.
+    pub fn flush(&mut self) -> Result<(), String> {
+        let last_seq = self.wal.next_seq() - 1;
+        let checkpoint = self.wal.checkpoint_seq();
+        if checkpoint == last_seq {
+            return Ok(());
+        }
+        let overfull = self.tables.flush(TableId {
+            first_seq: checkpoint + 1,
+            last_seq,
+        })?;
+        if let Some(path) = self.tables.data_dir.parent() {
+            for collection in overfull {
+                compaction::schedule(path, &collection);
+            }
+        }
+        self.wal.checkpoint()
+    }
+}
+
+// The state of a database as of one log record: its tables, and a memtable with the writes
+// logged after their checkpoint.
+pub struct Snapshot {
+    pub checkpoint: u64,
+    pub last_seq: u64,
+    pub memtable: BTreeMap<String, BTreeMap<String, Value>>,
+    pub tables: HashMap<String, Arc<Vec<Arc<Table>>>>,
+}
+
+impl Snapshot {
+    // Reads the files of a database that another process may be writing to. A checkpoint
+    // while they are read may remove tables or log segments, so reading starts over when the
+    // checkpoint moves.
+    fn read(db: &DB) -> Result<Snapshot, String> {
+        let path = PathBuf::from(db.path());
+        let wal_dir = path.join(WAL_DIR);
+        loop {
+            let checkpoint = wal::read_checkpoint(&wal_dir)?;
+            let read = Tables::load(&path.join(DATA_DIR), false).and_then(|mut tables| {
+                db::count_documents(&mut tables, checkpoint)?;
+                let mut last_seq = checkpoint;
+                for segment in wal::segments(&wal_dir)? {
+                    for record in wal::read_segment(&segment.path)?.records {
+                        if record.seq > checkpoint {
+                            db.apply(&mut tables, record.seq, &record.entry)?;
+                            last_seq = record.seq;
+                        }
+                    }
+                }
+                Ok((tables, last_seq))
+            });
+            if wal::read_checkpoint(&wal_dir)? != checkpoint {
+                continue;
+            }
+            let (tables, last_seq) = read?;
+            return Ok(Snapshot {
+                checkpoint,
+                last_seq,
+                memtable: tables.memtable,
+                tables: tables.tables,
+            });
+        }
+    }
+}
+
+pub struct Engine {
+    path: PathBuf,
+    store: Mutex<Store>,
+    poisoned: Arc<AtomicBool>,
+    // The settings of the collections that were written to, so that writes do not read and
+    // compile them again. Changing the settings of a collection forgets them.
+    collections: Mutex<HashMap<String, Arc<Collection>>>,
+    // Keeps other processes out of the database for as long as the engine is alive.
+    _lock: Arc<File>,
+}
+
+impl Engine {
+    fn load(db: &DB, lock: Arc<File>) -> Result<Engine, String> {
+        let path = PathBuf::from(db.path());
+        let mut wal = Wal::open(&path.join(WAL_DIR))?;
+        let mut tables = Tables::load(&path.join(DATA_DIR), true)?;
+        db::count_documents(&mut tables, wal.checkpoint_seq())?;
+        let recovery = wal.recover(|record| db.apply(&mut tables, record.seq, &record.entry))?;
+        let poisoned = Arc::new(AtomicBool::new(false));
+        let mut store = Store {
+            wal,
+            tables,
+            recovery,
+            closed: false,
+            poisoned: poisoned.clone(),
+        };
+        if store.tables.memtable_size >= MEMTABLE_SIZE {
+            store.flush()?;
+        }
+        Ok(Engine {
+            path,
+            store: Mutex::new(store),
+            poisoned,
+            collections: Mutex::new(HashMap::new()),
+            _lock: lock,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, Store> {
+        self.store.lock().unwrap()
+    }
+
+    // Loads the settings of the collection when they are not cached. They are loaded under the
+    // lock of the cache, so settings read before a change cannot be cached after it is forgotten.
+    // Collections that do not exist are not cached.
+    pub fn collection(
+        &self,
+        name: &str,
+        load: impl FnOnce() -> Result<Option<Collection>, String>,
+    ) -> Result<Option<Arc<Collection>>, String> {
+        let mut collections = self.collections.lock().unwrap();
+        if let Some(collection) = collections.get(name) {
+            return Ok(Some(collection.clone()));
+        }
+        let Some(collection) = load()? else {
+            return Ok(None);
+        };
+        let collection = Arc::new(collection);
+        collections.insert(name.to_string(), collection.clone());
+        Ok(Some(collection))
+    }
+
+    pub fn forget_collection(&self, name: &str) {
+        self.collections.lock().unwrap().remove(name);
+    }
+
+    // Only the memtable lookup holds the lock. Tables are immutable, so they are read after it
+    // is released.
+    pub fn get(&self, collection: &str, key: &str) -> Result<Option<Vec<u8>>, String> {
+        metrics::spend(Phase::Storage, || self.resolve(collection, key))
+    }
+
+    fn resolve(&self, collection: &str, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let tables = {
+            let store = self.lock();
+            if let Some(value) = store.tables.memtable_get(collection, key) {
+                return Ok(value.clone());
+            }
+            store.tables.snapshot(collection)
+        };
+        resolve(&tables, key)
+    }
+
+    // Merges the tables of the collection into one, but only holds the lock to pick the tables and to swap in the merged one, so reads and
+    // writes go on while it runs. Tables flushed meanwhile are newer than every merged one, so
+    // they are kept in front of it. Returns the number of files and bytes it freed.
+    pub fn compact(
+        &self,
+        collection: &str,
+        min_tables: usize,
+        throttle: &mut Throttle,
+    ) -> Result<Option<(u64, u64)>, String> {
+        let _compacting = COMPACTING.lock().unwrap();
+        let (data_dir, tables) = {
+            let store = self.lock();
+            (
+                store.tables.data_dir.clone(),
+                store.tables.snapshot(collection),
+            )
+        };
+        if tables.len() < min_tables.max(2) {
+            return Ok(None);
+        }
+        let codec = db::table_codec(&data_dir, collection)?;
+        let merged = merge(&data_dir.join(collection), &tables, codec, throttle)?;
+        let mut store = self.lock();
+        let current = store.tables.snapshot(collection);
+        let kept = current.len().checked_sub(tables.len()).filter(|kept| {
+            current[*kept..]
+                .iter()
+                .zip(tables.iter())
+                .all(|(table, merged)| Arc::ptr_eq(table, merged))
+        });
+        // A drop of the collection got to the tables first, or the database was closed.
+        let Some(kept) = kept.filter(|_| !store.closed) else {
+            drop(store);
+            merged.remove()?;
+            return Ok(None);
+        };
+        let bytes = merged.bytes;
+        let mut replaced = current[..kept].to_vec();
+        replaced.push(Arc::new(merged));
+        store
+            .tables
+            .tables
+            .insert(collection.to_string(), Arc::new(replaced));
+        drop(store);
+        for table in tables.iter() {
+            table.remove()?;
+        }
+        let before = tables.iter().map(|table| table.bytes).sum::<u64>();
+        Ok(Some((
+            tables.len() as u64 - 1,
+            before.saturating_sub(bytes),
+        )))
+    }
+
+    pub fn scan(&self, collection: &str, range: Range) -> Scan {
+        metrics::spend(Phase::Storage, || {
+            self.lock().tables.scan(collection, range)
+        })
+    }
+}
+
+type Source = Box<dyn Iterator<Item = Result<Entry, String>> + Send>;
+
+// Merges sorted sources into one sorted stream. When several sources have the same key, the
+// first of them wins, so sources are passed newest first.
+struct Merge {
+    sources: Vec<Peekable<Source>>,
+    end: Bound<String>,
+}
+
+impl Merge {
+    fn new(sources: Vec<Source>, end: Bound<String>) -> Merge {
+        Merge {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+            end,
+        }
+    }
+}
+
+impl Iterator for Merge {
+    type Item = Result<Entry, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next: Option<(usize, String)> = None;
+        for ind in 0..self.sources.len() {
+            match self.sources[ind].peek() {
+                Some(Ok((key, _))) if next.as_ref().is_none_or(|(_, next_key)| key < next_key) => {
+                    next = Some((ind, key.clone()));
+                }
+                Some(Err(_)) => {
+                    let err = self.sources[ind].next();
+                    self.sources.clear();
+                    return err;
+                }
+                _ => {}
+            }
+        }
+        let (ind, key) = next?;
+        let past_end = match &self.end {
+            Bound::Included(end) => key > *end,
+            Bound::Excluded(end) => key >= *end,
+            Bound::Unbounded => false,
+        };
+        if past_end {
+            self.sources.clear();
+            return None;
+        }
+        let entry = self.sources[ind].next();
+        for source in self.sources.iter_mut() {
+            source.next_if(|entry| matches!(entry, Ok((other, _)) if *other == key));
+        }
+        entry
+    }
+}
+
+// Yields the live documents of a collection in key order.
+pub struct Scan {
+    merge: Merge,
+}
+
+impl Iterator for Scan {
+    type Item = Result<(String, Vec<u8>), String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        metrics::spend(Phase::Storage, || self.next_live())
+    }
+}
+
+impl Scan {
+    fn next_live(&mut self) -> Option<Result<(String, Vec<u8>), String>> {
+        loop {
+            match self.merge.next()? {
+                Ok((key, Some(value))) => {
+                    return Some(Ok((key, value)));
+                }
+                Ok((_, None)) => {}
+                Err(err) => {
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+// Locks the database for this process, or fails when another process has it locked.
+pub fn lock(db: &DB) -> Result<Arc<File>, String> {
+    let path = PathBuf::from(db.path());
+    let mut locks = LOCKS.lock().unwrap();
+    if let Some(lock) = locks.get(&path).and_then(Weak::upgrade) {
+        return Ok(lock);
+    }
+    let lock = Arc::new(db.lock()?);
+    locks.insert(path, Arc::downgrade(&lock));
+    Ok(lock)
+}
+
+// The engine of a database is loaded once per process, which locks the database so no other
+// process writes to it meanwhile. Loading it replays the log of the database, and upgrades
+// databases with an older format.
+pub fn open(db: &DB) -> Result<Arc<Engine>, String> {
+    let path = PathBuf::from(db.path());
+    let mut engines = ENGINES.lock().unwrap();
+    if CLOSED.lock().unwrap().contains(&path) {
+        return Err(format!("The database {} is closed", db.name()));
+    }
+    if let Some(engine) = engines
+        .get(&path)
+        .filter(|engine| !engine.poisoned.load(Ordering::Relaxed))
+    {
+        return Ok(engine.clone());
+    }
+    let lock = lock(db)?;
+    db.upgrade()?;
+    let engine = Arc::new(Engine::load(db, lock)?);
+    engines.insert(path, engine.clone());
+    Ok(engine)
+}
+
+// Takes the snapshot from the engine when it is loaded in this process, and otherwise reads
+// it from the files without loading the engine, which may be loaded by a running server.
+pub fn snapshot(db: &DB) -> Result<Snapshot, String> {
+    let engine = ENGINES.lock().unwrap().get(Path::new(db.path())).cloned();
+    match engine {
+        Some(engine) => Ok(engine.lock().snapshot()),
+        None => Snapshot::read(db),
+    }
+}
+
+// Runs the function on the tables of the engine when it is loaded in this process, under its
+// lock, and otherwise on tables read from the files like a snapshot.
+pub fn inspect<T>(
+    db: &DB,
+    inspect: impl FnOnce(&Tables) -> Result<T, String>,
+) -> Result<T, String> {
+    let engine = ENGINES.lock().unwrap().get(Path::new(db.path())).cloned();
+    match engine {
+        Some(engine) => inspect(&engine.lock().tables),
+        None => {
+            let snapshot = Snapshot::read(db)?;
+            inspect(&Tables {
+                data_dir: PathBuf::from(db.path()).join(DATA_DIR),
+                writable: false,
+                memtable: snapshot.memtable,
+                memtable_size: 0,
+                tables: snapshot.tables,
+            })
+        }
+    }
+}
+
+// The engine of the database at the path, when it is loaded in this process.
+pub fn loaded(path: &Path) -> Option<Arc<Engine>> {
+    ENGINES.lock().unwrap().get(path).cloned()
+}
+
+pub fn close(path: &Path) {
+    ENGINES.lock().unwrap().remove(path);
+}
+
+pub fn is_closed(path: &Path) -> bool {
+    CLOSED.lock().unwrap().contains(path)
+}
+
+// Flushes the memtable of the database and unloads its engine until the database is opened
+// again, so its files can be handled while the server runs. Returns false when the database was
+// already closed.
+pub fn shut(path: &Path) -> bool {
+    let mut engines = ENGINES.lock().unwrap();
+    if !CLOSED.lock().unwrap().insert(path.to_path_buf()) {
+        return false;
+    }
+    if let Some(engine) = engines.remove(path) {
+        let mut store = engine.lock();
+        store.closed = true;
+        if let Err(err) = store.flush() {
+            // The log still has the writes, so they are replayed when the database is opened.
+            eprintln!(
+                "Could not flush the database at {} while closing it: {}",
+                path.display(),
+                err
+            );
+        }
+    }
+    true
+}
+
+// Loads the engine of a closed database again. Returns false when the database was not closed.
+pub fn reopen(db: &DB) -> Result<bool, String> {
+    let path = PathBuf::from(db.path());
+    if !CLOSED.lock().unwrap().remove(&path) {
+        return Ok(false);
+    }
+    open(db).map(|_| true)
+}
+
+// Forgets a database that is removed, closed or not.
+pub fn remove(path: &Path) {
+    ENGINES.lock().unwrap().remove(path);
+    CLOSED.lock().unwrap().remove(path);
+}
+
+// The last sequence number of every loaded database, with the gaps its log had when it was
+// replayed, by database path.
+pub fn sequences() -> Vec<(PathBuf, u64, u64)> {
+    let engines = ENGINES
+        .lock()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut sequences = engines
+        .iter()
+        .map(|engine| {
+            let store = engine.lock();
+            (engine.path.clone(), store.last_seq(), store.recovery.gaps)
+        })
+        .collect::<Vec<_>>();
+    sequences.sort();
+    sequences
+}
+
+pub fn flush_all() -> Vec<String> {
+    let engines = ENGINES
+        .lock()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    engines
+        .iter()
+        .filter_map(|engine| engine.lock().flush().err())
+        .collect()
+}
@@ -0,0 +1,90 @@
+use std::fmt::Display;
+
+use crate::{
+    http::{HttpStatus, Response},
+    json::{Json, JsonObject},
+};
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: HttpStatus,
+    pub code: String,
+    pub message: String,
+    pub details: Json,
+}
+
+impl ApiError {
+    pub fn new(status: HttpStatus, message: String) -> ApiError {
+        ApiError {
+            code: default_code(status),
+            status,
+            message,
+            details: Json::None,
+        }
+    }
+
+    pub fn with_code(mut self, code: &str) -> ApiError {
+        self.code = code.to_string();
+        self
+    }
+
+    pub fn with_details(mut self, details: Json) -> ApiError {
+        self.details = details;
+        self
+    }
+
+    pub fn bad_request(message: String) -> ApiError {
+        ApiError::new(HttpStatus::BadRequest, message)
+    }
+
+    pub fn not_found(message: String) -> ApiError {
+        ApiError::new(HttpStatus::NotFound, message)
+    }
+
+    pub fn conflict(message: String) -> ApiError {
+        ApiError::new(HttpStatus::Conflict, message)
+    }
+
+    pub fn internal(message: String) -> ApiError {
+        ApiError::new(HttpStatus::InternalServerError, message)
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut error = JsonObject::new();
+        error["code".to_string()] = Json::String(self.code.clone());
+        error["message".to_string()] = Json::String(self.message.clone());
+        error["details".to_string()] = self.details.clone();
+        let mut obj = JsonObject::new();
+        obj["error".to_string()] = Json::Object(error);
+        Json::Object(obj)
+    }
+
+    pub fn to_response(&self) -> Response {
+        Response::json(self.status, self.to_json())
+    }
+}
+
+fn default_code(status: HttpStatus) -> String {
+    status
+        .reason()
+        .to_ascii_lowercase()
+        .replace(['-', ' '], "_")
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}): {}", self.status, self.code, self.message)
+    }
+}
+
+impl From<ApiError> for Response {
+    fn from(err: ApiError) -> Self {
+        err.to_response()
+    }
+}
+
+impl From<String> for ApiError {
+    fn from(message: String) -> Self {
+        ApiError::internal(message)
+    }
+}
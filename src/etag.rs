@@ -0,0 +1,104 @@
+use sha2::{Digest, Sha256};
+
+use crate::{
+    http::{Body, HttpMethod, HttpStatus, Request, Response},
+    json::Json,
+};
+
+const ETAG_HASH_BYTES: usize = 16;
+const NOT_MODIFIED_HEADERS: [&str; 4] = ["ETag", "Vary", "Cache-Control", "Content-Location"];
+
+pub fn etag(content: &[u8]) -> String {
+    let digest = Sha256::digest(content);
+    let mut tag = String::with_capacity(ETAG_HASH_BYTES * 2 + 2);
+    tag.push('"');
+    for byte in &digest[..ETAG_HASH_BYTES] {
+        tag += &format!("{:02x}", byte);
+    }
+    tag.push('"');
+    tag
+}
+
+fn opaque(tag: &str) -> (bool, &str) {
+    let tag = tag.trim();
+    match tag.strip_prefix("W/") {
+        Some(rest) => (true, rest),
+        None => (false, tag),
+    }
+}
+
+pub fn matches(header: &str, current: &str, weak: bool) -> bool {
+    let (current_weak, current_tag) = opaque(current);
+    header.split(',').any(|candidate| {
+        let candidate = candidate.trim();
+        if candidate == "*" {
+            return true;
+        }
+        let (candidate_weak, candidate_tag) = opaque(candidate);
+        candidate_tag == current_tag && (weak || (!candidate_weak && !current_weak))
+    })
+}
+
+fn not_modified(resp: &Response) -> Response {
+    let mut not_modified = Response::new(HttpStatus::NotModified);
+    for name in NOT_MODIFIED_HEADERS {
+        if let Some(value) = resp.header(name) {
+            not_modified.set_header(name, value.to_string());
+        }
+    }
+    not_modified
+}
+
+// Checks the If-Match and If-None-Match headers of a write against the ETag of the current
+// resource, which is None when it does not exist, and gives the reason when they do not hold.
+// Reads are answered with 304 Not Modified by apply instead.
+pub fn check_preconditions(request: &Request, current: Option<&str>) -> Result<(), String> {
+    if let Some(if_match) = &request.if_match {
+        let matched = match current {
+            Some(current) => matches(if_match, current, false),
+            None => false,
+        };
+        if !matched {
+            return Err(format!(
+                "The resource at {} does not match the If-Match condition {}",
+                request.route, if_match
+            ));
+        }
+    }
+    if let (Some(if_none_match), Some(current)) = (&request.if_none_match, current)
+        && matches(if_none_match, current, true)
+    {
+        return Err(format!(
+            "The resource at {} matches the If-None-Match condition {}",
+            request.route, if_none_match
+        ));
+    }
+    Ok(())
+}
+
+// The ETag a document is served with, which is the one apply gives its JSON body.
+pub fn document_etag(document: &Json) -> String {
+    etag(document.canonical().as_bytes())
+}
+
+pub fn apply(request: &Request, resp: &mut Response) {
+    if !matches!(request.method, HttpMethod::GET | HttpMethod::HEAD)
+        || !resp.status.is_success()
+        || resp.upgrade.is_some()
+        || matches!(resp.body, Body::None)
+    {
+        return;
+    }
+    if resp.header("ETag").is_none() {
+        let tag = match &resp.body {
+            Body::ApplicationJson(json) => etag(json.canonical().as_bytes()),
+            body => etag(&body.to_bytes()),
+        };
+        resp.set_header("ETag", tag);
+    }
+    if let (Some(if_none_match), Some(current)) = (&request.if_none_match, resp.header("ETag"))
+        && matches(if_none_match, current, true)
+    {
+        *resp = not_modified(resp);
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, Write},
+    ops::Bound,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db::{DB, ID_FIELD},
+    json::Json,
+    query::{Filter, lookup},
+};
+
+const INVALID_FIELDS: &str = "The fields should be a comma-separated list of field paths";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Ndjson,
+    Csv,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Option<Format> {
+        match value {
+            "ndjson" => Some(Format::Ndjson),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Ndjson => "application/x-ndjson",
+            Format::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Ndjson => "ndjson",
+            Format::Csv => "csv",
+        }
+    }
+}
+
+// The columns of a CSV export are dotted field paths, as in query filters. Without them, the
+// columns are the top-level fields of the exported documents, found by a first pass over the
+// collection, with the ID first. Soft deleted documents are only exported when asked for, with
+// their _deleted field.
+pub struct Export {
+    pub format: Format,
+    pub filter: Filter,
+    pub fields: Vec<String>,
+    pub include_deleted: bool,
+}
+
+impl Export {
+    // Reads an export from its options as text, the way both the command line and the query
+    // string give them. The format defaults to NDJSON, and the fields are separated by commas.
+    pub fn parse(
+        format: Option<&str>,
+        filter: Option<&str>,
+        fields: Option<&str>,
+    ) -> Result<Export, String> {
+        let format = match format {
+            None => Format::Ndjson,
+            Some(value) => Format::parse(value).ok_or_else(|| {
+                format!(
+                    "The export format {} is invalid. Expected ndjson or csv",
+                    value
+                )
+            })?,
+        };
+        let filter = match filter {
+            None => Filter::all(),
+            Some(filter) => Json::parse(filter.as_bytes())
+                .map_err(|err| format!("The filter is not valid JSON: {}", err))
+                .and_then(|filter| Filter::parse(&filter))?,
+        };
+        let fields = match fields {
+            None => Vec::new(),
+            Some(_) if format != Format::Csv => {
+                return Err("Fields can only be selected for CSV exports".to_string());
+            }
+            Some(fields) => fields
+                .split(',')
+                .map(|field| match field.trim() {
+                    "" => Err(INVALID_FIELDS.to_string()),
+                    field => Ok(field.to_string()),
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(Export {
+            format,
+            filter,
+            fields,
+            include_deleted: false,
+        })
+    }
+}
+
+// Writes the documents of the collection that match the filter, one at a time in ID order,
+// and returns how many were written. IDs are ordered by their bytes and documents are written
+// in canonical JSON, so exports of the same documents are the same bytes, however the documents
+// were written.
+pub fn write(
+    db: &DB,
+    collection: &str,
+    export: &Export,
+    out: &mut dyn Write,
+) -> Result<u64, String> {
+    let write_err = |err: std::io::Error| format!("Error while writing the export: {}", err);
+    let mut columns = export.fields.clone();
+    if export.format == Format::Csv && columns.is_empty() {
+        columns = fields(db, collection, export)?;
+    }
+    if export.format == Format::Csv {
+        let header = columns
+            .iter()
+            .map(|column| csv_field(column))
+            .collect::<Vec<_>>();
+        writeln!(out, "{}", header.join(",")).map_err(write_err)?;
+    }
+    let mut count = 0;
+    for document in documents(db, collection, export)? {
+        let document = document?;
+        if !export.filter.matches(&document) {
+            continue;
+        }
+        let line = match export.format {
+            Format::Ndjson => document.canonical(),
+            Format::Csv => columns
+                .iter()
+                .map(|column| match lookup(&document, column) {
+                    None | Some(Json::Null | Json::None) => String::new(),
+                    Some(Json::String(value)) => csv_field(value),
+                    Some(value) => csv_field(&value.canonical()),
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+        writeln!(out, "{}", line).map_err(write_err)?;
+        count += 1;
+    }
+    out.flush().map_err(write_err)?;
+    Ok(count)
+}
+
+fn documents(
+    db: &DB,
+    collection: &str,
+    export: &Export,
+) -> Result<Box<dyn Iterator<Item = Result<Json, String>>>, String> {
+    let all = (Bound::Unbounded, Bound::Unbounded);
+    Ok(match export.include_deleted {
+        true => Box::new(db.scan_including_deleted(collection, all)?),
+        false => Box::new(db.scan(collection, all)?),
+    })
+}
+
+fn fields(db: &DB, collection: &str, export: &Export) -> Result<Vec<String>, String> {
+    let mut names = BTreeSet::new();
+    for document in documents(db, collection, export)? {
+        let document = document?;
+        if let Json::Object(obj) = &document
+            && export.filter.matches(&document)
+        {
+            names.extend(obj.iter().map(|(name, _)| name.clone()));
+        }
+    }
+    names.remove(ID_FIELD);
+    Ok(std::iter::once(ID_FIELD.to_string()).chain(names).collect())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Hashes what is written to it instead of keeping it.
+struct Hasher(Sha256);
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// The SHA-256 of the canonical JSON of a document, as it is read.
+pub fn document_hash(document: &Json) -> String {
+    hex(&Sha256::digest(document.canonical()))
+}
+
+// The SHA-256 of the NDJSON export of the whole collection, along with the number of documents
+// in it. Collections with the same documents have the same hash, so comparing hashes tells
+// whether two databases hold the same data without moving it.
+pub fn collection_hash(db: &DB, collection: &str) -> Result<(u64, String), String> {
+    let export = Export {
+        format: Format::Ndjson,
+        filter: Filter::all(),
+        fields: Vec::new(),
+        include_deleted: false,
+    };
+    let mut hasher = Hasher(Sha256::new());
+    let count = write(db, collection, &export, &mut hasher)?;
+    Ok((count, hex(&hasher.0.finalize())))
+}
@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, RwLock},
+};
+
+use crate::json::Json;
+
+// Hooks by database and collection name.
+type Registry = HashMap<(String, String), Vec<Arc<dyn Hook>>>;
+
+static HOOKS: LazyLock<RwLock<Registry>> = LazyLock::new(|| RwLock::new(HashMap::new()));
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Operation {
+    Insert,
+    Update,
+    Delete,
+}
+
+// The previous document is only read when the collection has hooks. The document is None for
+// deletes.
+pub struct WriteEvent {
+    pub database: String,
+    pub collection: String,
+    pub id: String,
+    pub operation: Operation,
+    pub previous: Option<Json>,
+    pub document: Option<Json>,
+}
+
+// Before hooks can change the document or reject the write by returning the reason. They run
+// while the database is locked for the write, so they must not write to it themselves. After
+// hooks run once the write is logged and the lock is released.
+pub trait Hook: Send + Sync {
+    fn before(&self, _write: &mut WriteEvent) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn after(&self, _write: &WriteEvent) {}
+}
+
+pub fn register(database: &str, collection: &str, hook: impl Hook + 'static) {
+    HOOKS
+        .write()
+        .unwrap()
+        .entry((database.to_string(), collection.to_string()))
+        .or_default()
+        .push(Arc::new(hook));
+}
+
+pub fn clear(database: &str, collection: &str) {
+    HOOKS
+        .write()
+        .unwrap()
+        .remove(&(database.to_string(), collection.to_string()));
+}
+
+// The hooks of a collection, in the order they were registered.
+pub struct Hooks {
+    hooks: Vec<Arc<dyn Hook>>,
+}
+
+impl Hooks {
+    pub fn find(database: &str, collection: &str) -> Hooks {
+        let hooks = HOOKS
+            .read()
+            .unwrap()
+            .get(&(database.to_string(), collection.to_string()))
+            .cloned()
+            .unwrap_or_default();
+        Hooks { hooks }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub fn before(&self, write: &mut WriteEvent) -> Result<(), String> {
+        self.hooks.iter().try_for_each(|hook| hook.before(write))
+    }
+
+    pub fn after(&self, write: &WriteEvent) {
+        for hook in &self.hooks {
+            hook.after(write);
+        }
+    }
+}
@@ -1,11 +1,44 @@
 use std::{
+    collections::HashMap,
     fmt::Display,
+    io::{self, BufRead, BufReader, Chain, Cursor, Read, Take, Write},
+    net::IpAddr,
     str::{self, FromStr},
+    sync::OnceLock,
 };
 
-use crate::json::Json;
+use base64::{Engine, engine::general_purpose::STANDARD};
 
-#[derive(Clone)]
+use crate::{
+    compression::{self, Decoder, Encoding},
+    error::ApiError,
+    json::{Json, JsonError, JsonNumber, JsonObject},
+    server::Stream,
+    types::ID,
+};
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+static REQUEST_ID_PREFIX: OnceLock<String> = OnceLock::new();
+
+fn request_id(given: Option<String>) -> String {
+    if let Some(id) = given
+        && !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id.bytes().all(|byte| byte.is_ascii_graphic())
+    {
+        return id;
+    }
+    let prefix = REQUEST_ID_PREFIX.get_or_init(|| {
+        let mut bytes = [0u8; 4];
+        let _ = getrandom::getrandom(&mut bytes);
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    });
+    format!("{}-{}", prefix, ID::new())
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum HttpMethod {
     GET,
     POST,
@@ -21,10 +54,7 @@ pub enum HttpMethod {
 impl HttpMethod {
     pub fn supports_request_body(&self) -> bool {
         use HttpMethod::*;
-        match self {
-            POST | PUT | PATCH | DELETE | OPTIONS => true,
-            _ => false,
-        }
+        matches!(self, POST | PUT | PATCH | DELETE | OPTIONS)
     }
 }
 
@@ -63,10 +93,13 @@ impl Display for HttpMethod {
     }
 }
 
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum ContentType {
     TextPlain,
     ApplicationJson,
     ApplicationOctetStream,
+    ApplicationNdjson,
+    MultipartFormData(String),
     None,
 }
 
@@ -74,10 +107,35 @@ impl FromStr for ContentType {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
+        let mut parts = s.split(';');
+        let media_type = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        match media_type.as_str() {
             "text/plain" => Ok(ContentType::TextPlain),
-            "application/json" => Ok(ContentType::ApplicationJson),
+            // A merge patch is a JSON document, read like any other.
+            "application/json" | "application/merge-patch+json" => Ok(ContentType::ApplicationJson),
             "application/octet-stream" => Ok(ContentType::ApplicationOctetStream),
+            "application/x-ndjson" | "application/ndjson" => Ok(ContentType::ApplicationNdjson),
+            "multipart/form-data" => {
+                for param in parts {
+                    if let Some((name, value)) = param.split_once('=')
+                        && name.trim().eq_ignore_ascii_case("boundary")
+                    {
+                        let value = value.trim();
+                        let boundary = value
+                            .strip_prefix('"')
+                            .and_then(|val| val.strip_suffix('"'))
+                            .unwrap_or(value);
+                        if boundary.is_empty() || boundary.len() > 70 {
+                            return Err(
+                                "The multipart boundary should be between 1 and 70 characters"
+                                    .to_string(),
+                            );
+                        }
+                        return Ok(ContentType::MultipartFormData(boundary.to_string()));
+                    }
+                }
+                Err("The multipart/form-data content type requires a boundary".to_string())
+            }
             _ => Err("Invalid content type".to_string()),
         }
     }
@@ -92,12 +150,39 @@ pub enum Body {
 
 impl Display for ContentType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(match *self {
-            ContentType::TextPlain => "text/plain",
-            ContentType::ApplicationJson => "application/json",
-            ContentType::ApplicationOctetStream => "application/octet-stream",
-            ContentType::None => "",
-        })
+        match self {
+            ContentType::TextPlain => f.write_str("text/plain"),
+            ContentType::ApplicationJson => f.write_str("application/json"),
+            ContentType::ApplicationOctetStream => f.write_str("application/octet-stream"),
+            ContentType::ApplicationNdjson => f.write_str("application/x-ndjson"),
+            ContentType::MultipartFormData(boundary) if boundary.is_empty() => {
+                f.write_str("multipart/form-data")
+            }
+            ContentType::MultipartFormData(boundary) => {
+                write!(f, "multipart/form-data; boundary={}", boundary)
+            }
+            ContentType::None => f.write_str(""),
+        }
+    }
+}
+
+impl Body {
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            Body::TextPlain(_) => ContentType::TextPlain,
+            Body::ApplicationJson(_) => ContentType::ApplicationJson,
+            Body::ApplicationOctetStream(_) => ContentType::ApplicationOctetStream,
+            Body::None => ContentType::None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Body::TextPlain(string) => string.as_bytes().to_vec(),
+            Body::ApplicationJson(json) => json.to_string().into_bytes(),
+            Body::ApplicationOctetStream(vec) => vec.clone(),
+            Body::None => Vec::new(),
+        }
     }
 }
 
@@ -117,106 +202,1152 @@ impl Display for Body {
     }
 }
 
+pub const MAX_HEADER_SIZE: usize = 64 * 1024;
+pub const MAX_HEADER_COUNT: usize = 100;
+const MAX_CHUNK_LINE: usize = 4096;
+
+fn is_token_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(ch)
+}
+
+fn parse_header_line(line: &str) -> Result<(String, String), ApiError> {
+    if line.starts_with([' ', '\t']) {
+        return Err(ApiError::bad_request(
+            "Folded header lines are not supported".to_string(),
+        ));
+    }
+    let (name, value) = match line.split_once(':') {
+        Some(parts) => parts,
+        None => {
+            return Err(ApiError::bad_request(format!(
+                "The header line {} does not contain a colon",
+                line
+            )));
+        }
+    };
+    if name.is_empty() || !name.chars().all(is_token_char) {
+        return Err(ApiError::bad_request(format!(
+            "The header name {} is invalid",
+            name
+        )));
+    }
+    let value = value.trim_matches([' ', '\t']);
+    if value.chars().any(|ch| ch.is_control() && ch != '\t') {
+        return Err(ApiError::bad_request(format!(
+            "The value of the header {} contains control characters",
+            name
+        )));
+    }
+    Ok((name.to_ascii_lowercase(), value.to_string()))
+}
+
 pub struct Request {
+    pub id: String,
+    pub client: Option<IpAddr>,
     pub method: HttpMethod,
     pub route: String,
+    pub query: HashMap<String, Vec<String>>,
     pub http_version: String,
     pub host: String,
     pub content_type: Option<ContentType>,
     pub content_length: Option<usize>,
+    pub keep_alive: bool,
+    pub accept_encoding: Option<String>,
+    pub content_encoding: Option<String>,
+    pub transfer_encoding: Option<String>,
+    pub origin: Option<String>,
+    pub access_control_request_method: Option<String>,
+    pub access_control_request_headers: Option<String>,
+    pub upgrade: Option<String>,
+    pub websocket_key: Option<String>,
+    pub websocket_version: Option<String>,
+    pub cookies: HashMap<String, String>,
+    pub if_match: Option<String>,
+    pub if_none_match: Option<String>,
+    pub range: Option<String>,
+    pub if_range: Option<String>,
+    pub credentials: Option<Credentials>,
     pub content: Vec<u8>,
+    pub body: Body,
+    pub headers: Vec<(String, String)>,
 }
 
 impl Request {
-    pub fn from_bytes(bytes: &[u8]) -> Result<Request, String> {
-        match str::from_utf8(bytes) {
-            Ok(header) => {
-                let mut method: Option<HttpMethod> = None;
-                let mut route: Option<String> = None;
-                let mut http_version: Option<String> = None;
-                let mut host: Option<String> = None;
-                let mut content_type: Option<ContentType> = None;
-                let mut content_length: Option<usize> = None;
-                let headers: Vec<&str> = header.split("\r\n").collect();
-                if headers.len() > 1 {
-                    let first_header: Vec<&str> = headers[0].split(" ").collect();
-                    if first_header.len() != 3 {
-                        return Err(
-                            "The first header of this request is of invalid format".to_string()
-                        );
-                    }
-                    method = match first_header[0].parse::<HttpMethod>() {
-                        Ok(method_val) => Some(method_val),
-                        Err(err) => {
-                            return Err(err);
-                        }
-                    };
-                    route = Some(first_header[1].to_string());
-                    http_version = Some(first_header[2].to_string());
-                    for line in headers[1..].iter() {
-                        if line.contains(": ") {
-                            let colon = line.find(": ").unwrap();
-                            if colon + 2 == line.len() {
-                                continue;
-                            }
-                            let name = &line[..colon];
-                            let value = &line[(colon + 2)..];
-                            match name {
-                                "Host" => {
-                                    host = Some(value.to_string());
-                                }
-                                "Content-Type" => {
-                                    if method.clone().unwrap().supports_request_body() {
-                                        content_type = match value.parse::<ContentType>() {
-                                            Ok(cont_ty) => Some(cont_ty),
-                                            Err(err) => {
-                                                return Err(err);
-                                            }
-                                        };
-                                    }
-                                }
-                                "Content-Length" => {
-                                    if method.clone().unwrap().supports_request_body() {
-                                        content_length = match value.parse::<usize>() {
-                                            Ok(len) => Some(len),
-                                            Err(_) => None,
-                                        };
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                }
-                if method.is_none() {
-                    return Err("Invalid request - Method was not found".to_string());
-                }
-                if route.is_none() {
-                    return Err("Invalid request - Route was not found".to_string());
+    pub fn from_bytes(bytes: &[u8]) -> Result<Request, ApiError> {
+        if bytes.len() > MAX_HEADER_SIZE {
+            return Err(ApiError::new(
+                HttpStatus::RequestHeaderFieldsTooLarge,
+                format!(
+                    "The request header of {} bytes exceeds the maximum allowed size of {} bytes",
+                    bytes.len(),
+                    MAX_HEADER_SIZE
+                ),
+            ));
+        }
+        let header = str::from_utf8(bytes).map_err(|err| {
+            ApiError::bad_request(format!("The request header is not valid UTF-8: {}", err))
+        })?;
+        let mut lines = header
+            .split('\n')
+            .map(|line| line.strip_suffix('\r').unwrap_or(line));
+        let request_line = lines.next().unwrap_or("");
+        let first_header: Vec<&str> = request_line.split(' ').collect();
+        if first_header.len() != 3 {
+            return Err(ApiError::bad_request(
+                "The first header of this request is of invalid format".to_string(),
+            ));
+        }
+        let method = first_header[0]
+            .parse::<HttpMethod>()
+            .map_err(ApiError::bad_request)?;
+        let target = first_header[1];
+        let (path, query_str) = match target.find('?') {
+            Some(ind) => (&target[..ind], &target[(ind + 1)..]),
+            None => (target, ""),
+        };
+        let route = percent_decode(path, false).map_err(ApiError::bad_request)?;
+        let query = parse_query(query_str).map_err(ApiError::bad_request)?;
+        let http_version = first_header[2].to_string();
+        if !http_version.starts_with("HTTP/") {
+            return Err(ApiError::bad_request(format!(
+                "The HTTP version {} of this request is invalid",
+                http_version
+            )));
+        }
+        let mut headers = Vec::<(String, String)>::new();
+        for line in lines {
+            if line.is_empty() {
+                continue;
+            }
+            if headers.len() == MAX_HEADER_COUNT {
+                return Err(ApiError::new(
+                    HttpStatus::RequestHeaderFieldsTooLarge,
+                    format!(
+                        "The request has more than the maximum of {} header fields",
+                        MAX_HEADER_COUNT
+                    ),
+                ));
+            }
+            headers.push(parse_header_line(line)?);
+        }
+        let single = |name: &str| -> Result<Option<&str>, ApiError> {
+            let mut values = headers
+                .iter()
+                .filter(|(header_name, _)| header_name == name)
+                .map(|(_, value)| value.as_str());
+            let first = values.next();
+            if let (Some(first), Some(other)) = (first, values.next())
+                && first != other
+            {
+                return Err(ApiError::bad_request(format!(
+                    "The request has conflicting {} headers",
+                    name
+                )));
+            }
+            Ok(first)
+        };
+        let list = |name: &str| -> Option<String> {
+            let values = headers
+                .iter()
+                .filter(|(header_name, _)| header_name == name)
+                .map(|(_, value)| value.as_str())
+                .collect::<Vec<_>>();
+            if values.is_empty() {
+                None
+            } else {
+                Some(values.join(", "))
+            }
+        };
+        let last = |name: &str| -> Option<String> {
+            headers
+                .iter()
+                .rev()
+                .find(|(header_name, _)| header_name == name)
+                .map(|(_, value)| value.clone())
+        };
+        let host = match single("host")? {
+            Some(host) => host.to_string(),
+            None => {
+                return Err(ApiError::bad_request(
+                    "Invalid request - Host is not found".to_string(),
+                ));
+            }
+        };
+        let mut content_type: Option<ContentType> = None;
+        let mut content_length: Option<usize> = None;
+        if method.supports_request_body() {
+            if let Some(value) = single("content-type")? {
+                content_type = Some(
+                    value
+                        .parse::<ContentType>()
+                        .map_err(|err| ApiError::new(HttpStatus::UnsupportedMediaType, err))?,
+                );
+            }
+            if let Some(value) = single("content-length")? {
+                content_length = Some(value.parse::<usize>().map_err(|_| {
+                    ApiError::bad_request(format!("The Content-Length {} is invalid", value))
+                })?);
+            }
+        }
+        let connection = list("connection").map(|value| value.to_ascii_lowercase());
+        let keep_alive = match connection.as_deref() {
+            Some(value) if value.split(',').any(|token| token.trim() == "close") => false,
+            Some(value) if value.split(',').any(|token| token.trim() == "keep-alive") => true,
+            _ => http_version == "HTTP/1.1",
+        };
+        let mut cookies = HashMap::<String, String>::new();
+        for (_, value) in headers.iter().filter(|(name, _)| name == "cookie") {
+            parse_cookies(value, &mut cookies);
+        }
+        let credentials = match single("authorization")? {
+            Some(value) => Credentials::parse(value).map_err(ApiError::bad_request)?,
+            None => None,
+        };
+        Ok(Request {
+            id: request_id(last("x-request-id")),
+            client: None,
+            method,
+            route,
+            query,
+            http_version,
+            host,
+            content_type,
+            content_length,
+            keep_alive,
+            accept_encoding: list("accept-encoding").map(|value| value.to_ascii_lowercase()),
+            content_encoding: last("content-encoding").map(|value| value.to_ascii_lowercase()),
+            transfer_encoding: list("transfer-encoding").map(|value| value.to_ascii_lowercase()),
+            origin: last("origin"),
+            access_control_request_method: last("access-control-request-method"),
+            access_control_request_headers: list("access-control-request-headers")
+                .map(|value| value.to_ascii_lowercase()),
+            upgrade: last("upgrade").map(|value| value.to_ascii_lowercase()),
+            websocket_key: last("sec-websocket-key"),
+            websocket_version: last("sec-websocket-version"),
+            cookies,
+            if_match: list("if-match"),
+            if_none_match: list("if-none-match"),
+            range: last("range"),
+            if_range: last("if-range"),
+            credentials,
+            content: Vec::new(),
+            body: Body::None,
+            headers,
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn multipart(&self) -> Result<Vec<Part>, String> {
+        match &self.content_type {
+            Some(ContentType::MultipartFormData(boundary)) => {
+                parse_multipart(&self.content, boundary)
+            }
+            _ => Err("The request body is not multipart/form-data".to_string()),
+        }
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies.get(name).map(|value| value.as_str())
+    }
+
+    pub fn query_value(&self, name: &str) -> Option<&str> {
+        self.query
+            .get(name)
+            .and_then(|values| values.first())
+            .map(|value| value.as_str())
+    }
+
+    pub fn parse_content(&mut self, bytes: Vec<u8>, max_size: usize) -> Result<(), ApiError> {
+        let mut content = bytes;
+        if let Some(length) = self.content_length {
+            content.truncate(length);
+        }
+        if let Some(encoding_name) = &self.content_encoding
+            && !content.is_empty()
+        {
+            let encoding = Encoding::from_name(encoding_name).ok_or_else(|| {
+                ApiError::new(
+                    HttpStatus::UnsupportedMediaType,
+                    format!("The content encoding {} is not supported", encoding_name),
+                )
+            })?;
+            content = compression::decompress(&content, encoding, max_size)
+                .map_err(ApiError::bad_request)?;
+        }
+        let body = match &self.content_type {
+            _ if content.is_empty() => Ok(Body::None),
+            Some(ContentType::TextPlain) => str::from_utf8(&content)
+                .map(|text| Body::TextPlain(text.to_string()))
+                .map_err(|_| {
+                    ApiError::bad_request("The request body is not valid UTF-8".to_string())
+                }),
+            Some(ContentType::ApplicationJson) => Json::parse(&content)
+                .map(Body::ApplicationJson)
+                .map_err(|err| json_error(&err, &content)),
+            Some(ContentType::ApplicationOctetStream) => {
+                Ok(Body::ApplicationOctetStream(content.clone()))
+            }
+            _ => Ok(Body::None),
+        };
+        self.content = content;
+        self.body = body?;
+        Ok(())
+    }
+
+    pub fn json(&self) -> Option<&Json> {
+        match &self.body {
+            Body::ApplicationJson(json) => Some(json),
+            _ => None,
+        }
+    }
+}
+
+const JSON_ERROR_EXCERPT_RADIUS: usize = 24;
+
+fn json_error(err: &JsonError, content: &[u8]) -> ApiError {
+    let mut location = JsonObject::new();
+    location["offset".to_string()] = Json::Number(JsonNumber::Int(err.offset as i64));
+    location["line".to_string()] = Json::Number(JsonNumber::Int(err.line as i64));
+    location["column".to_string()] = Json::Number(JsonNumber::Int(err.column as i64));
+    let mut details = JsonObject::new();
+    details["location".to_string()] = Json::Object(location);
+    details["excerpt".to_string()] = Json::String(err.excerpt(content, JSON_ERROR_EXCERPT_RADIUS));
+    ApiError::bad_request("The request body is not valid JSON: ".to_string() + &err.message)
+        .with_code("invalid_json")
+        .with_details(Json::Object(details))
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Basic { user: String, pass: String },
+    Bearer(String),
+    Certificate(String),
+}
+
+impl Credentials {
+    pub fn parse(header: &str) -> Result<Option<Credentials>, String> {
+        let (scheme, value) = match header.trim().split_once(' ') {
+            Some((scheme, value)) => (scheme, value.trim()),
+            None => (header.trim(), ""),
+        };
+        if scheme.eq_ignore_ascii_case("Basic") {
+            let decoded = STANDARD
+                .decode(value)
+                .map_err(|_| "The Basic credentials are not valid base64".to_string())?;
+            let decoded = String::from_utf8(decoded)
+                .map_err(|_| "The Basic credentials are not valid UTF-8".to_string())?;
+            match decoded.split_once(':') {
+                Some((user, pass)) => Ok(Some(Credentials::Basic {
+                    user: user.to_string(),
+                    pass: pass.to_string(),
+                })),
+                None => Err(
+                    "The Basic credentials should be a user and password separated by ':'"
+                        .to_string(),
+                ),
+            }
+        } else if scheme.eq_ignore_ascii_case("Bearer") {
+            if value.is_empty() || value.contains(' ') {
+                return Err("The Bearer token is missing or malformed".to_string());
+            }
+            Ok(Some(Credentials::Bearer(value.to_string())))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::Basic { user, .. } => write!(f, "Basic {{ user: {:?}, pass: *** }}", user),
+            Credentials::Bearer(_) => f.write_str("Bearer(***)"),
+            Credentials::Certificate(user) => write!(f, "Certificate({:?})", user),
+        }
+    }
+}
+
+pub struct Part {
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub data: Vec<u8>,
+}
+
+impl Part {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> Result<&str, String> {
+        str::from_utf8(&self.data).map_err(|err| {
+            format!(
+                "The multipart field {} is not valid UTF-8: {}",
+                self.name.as_deref().unwrap_or(""),
+                err
+            )
+        })
+    }
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if from > haystack.len() {
+        return None;
+    }
+    haystack[from..]
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|pos| pos + from)
+}
+
+fn disposition_param(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|param| {
+        let (key, val) = param.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case(name) {
+            return None;
+        }
+        let val = val.trim();
+        Some(
+            val.strip_prefix('"')
+                .and_then(|inner| inner.strip_suffix('"'))
+                .unwrap_or(val)
+                .to_string(),
+        )
+    })
+}
+
+pub fn parse_multipart(body: &[u8], boundary: &str) -> Result<Vec<Part>, String> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let separator = format!("\r\n--{}", boundary).into_bytes();
+    let mut cursor = match find_bytes(body, &delimiter, 0) {
+        Some(start) => start + delimiter.len(),
+        None => {
+            return Err("The multipart body does not contain the boundary".to_string());
+        }
+    };
+    let mut parts = Vec::<Part>::new();
+    loop {
+        if body[cursor..].starts_with(b"--") {
+            return Ok(parts);
+        }
+        if !body[cursor..].starts_with(b"\r\n") {
+            return Err("A multipart boundary is not followed by a line break".to_string());
+        }
+        cursor += 2;
+        let head_end = match find_bytes(body, b"\r\n\r\n", cursor) {
+            Some(end) => end,
+            None => {
+                return Err("The headers of a multipart section are not terminated".to_string());
+            }
+        };
+        let head = str::from_utf8(&body[cursor..head_end])
+            .map_err(|_| "The headers of a multipart section are not valid UTF-8".to_string())?;
+        let mut part = Part {
+            name: None,
+            filename: None,
+            content_type: None,
+            headers: Vec::new(),
+            data: Vec::new(),
+        };
+        for line in head.split("\r\n").filter(|line| !line.is_empty()) {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => {
+                    return Err(format!("Invalid multipart section header: {}", line));
                 }
-                if http_version.is_none() {
-                    return Err("Invalid request - HTTP version is not found".to_string());
+            };
+            if name.eq_ignore_ascii_case("Content-Disposition") {
+                part.name = disposition_param(value, "name");
+                part.filename = disposition_param(value, "filename");
+            } else if name.eq_ignore_ascii_case("Content-Type") {
+                part.content_type = Some(value.to_string());
+            }
+            part.headers.push((name.to_string(), value.to_string()));
+        }
+        let data_start = head_end + 4;
+        let data_end = match find_bytes(body, &separator, data_start) {
+            Some(end) => end,
+            None => {
+                return Err("The multipart body ends without a closing boundary".to_string());
+            }
+        };
+        part.data = body[data_start..data_end].to_vec();
+        parts.push(part);
+        cursor = data_end + separator.len();
+    }
+}
+
+fn parse_cookies(header: &str, cookies: &mut HashMap<String, String>) {
+    for pair in header.split(';') {
+        if let Some((name, value)) = pair.split_once('=') {
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|val| val.strip_suffix('"'))
+                .unwrap_or(value);
+            cookies
+                .entry(name.to_string())
+                .or_insert_with(|| value.to_string());
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl Display for SameSite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                SameSite::Strict => "Strict",
+                SameSite::Lax => "Lax",
+                SameSite::None => "None",
+            }
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub max_age: Option<i64>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Result<Cookie, String> {
+        let is_token = |ch: char| ch.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(ch);
+        if name.is_empty() || !name.chars().all(is_token) {
+            return Err(format!("{} is not a valid cookie name", name));
+        }
+        if !value
+            .chars()
+            .all(|ch| ch.is_ascii_graphic() && !matches!(ch, '"' | ',' | ';' | '\\'))
+        {
+            return Err(format!(
+                "The value of the cookie {} contains characters that are not allowed in cookies",
+                name
+            ));
+        }
+        Ok(Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: Some("/".to_string()),
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: true,
+            same_site: Some(SameSite::Lax),
+        })
+    }
+
+    pub fn removal(name: &str) -> Result<Cookie, String> {
+        let mut cookie = Cookie::new(name, "")?;
+        cookie.max_age = Some(0);
+        Ok(cookie)
+    }
+}
+
+impl Display for Cookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age)?;
+        }
+        if self.secure || self.same_site == Some(SameSite::None) {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn percent_decode(value: &str, plus_as_space: bool) -> Result<String, String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::<u8>::with_capacity(bytes.len());
+    let mut ind = 0;
+    while ind < bytes.len() {
+        match bytes[ind] {
+            b'%' => {
+                if ind + 2 >= bytes.len()
+                    || !bytes[ind + 1].is_ascii_hexdigit()
+                    || !bytes[ind + 2].is_ascii_hexdigit()
+                {
+                    return Err(format!(
+                        "Invalid percent-encoded sequence found in {}",
+                        value
+                    ));
                 }
-                if host.is_none() {
-                    return Err("Invalid request - Host is not found".to_string());
+                decoded
+                    .push(u8::from_str_radix(&value[(ind + 1)..(ind + 3)], 16).unwrap_or_default());
+                ind += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                ind += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                ind += 1;
+            }
+        }
+    }
+    match String::from_utf8(decoded) {
+        Ok(val) => Ok(val),
+        Err(_) => Err(format!(
+            "The percent-decoded value of {} is not valid UTF-8",
+            value
+        )),
+    }
+}
+
+pub fn parse_query(query: &str) -> Result<HashMap<String, Vec<String>>, String> {
+    let mut result = HashMap::<String, Vec<String>>::new();
+    for pair in query.split('&') {
+        if pair.is_empty() {
+            continue;
+        }
+        let (name, value) = match pair.find('=') {
+            Some(ind) => (&pair[..ind], &pair[(ind + 1)..]),
+            None => (pair, ""),
+        };
+        result
+            .entry(percent_decode(name, true)?)
+            .or_default()
+            .push(percent_decode(value, true)?);
+    }
+    Ok(result)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HttpStatus {
+    SwitchingProtocols,
+    Ok,
+    Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    NotModified,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    Conflict,
+    Gone,
+    LengthRequired,
+    PreconditionFailed,
+    PayloadTooLarge,
+    UriTooLong,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    UnprocessableContent,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    ServiceUnavailable,
+    HttpVersionNotSupported,
+}
+
+impl HttpStatus {
+    pub fn code(&self) -> u16 {
+        match self {
+            HttpStatus::SwitchingProtocols => 101,
+            HttpStatus::Ok => 200,
+            HttpStatus::Created => 201,
+            HttpStatus::Accepted => 202,
+            HttpStatus::NoContent => 204,
+            HttpStatus::PartialContent => 206,
+            HttpStatus::NotModified => 304,
+            HttpStatus::BadRequest => 400,
+            HttpStatus::Unauthorized => 401,
+            HttpStatus::Forbidden => 403,
+            HttpStatus::NotFound => 404,
+            HttpStatus::MethodNotAllowed => 405,
+            HttpStatus::RequestTimeout => 408,
+            HttpStatus::Conflict => 409,
+            HttpStatus::Gone => 410,
+            HttpStatus::LengthRequired => 411,
+            HttpStatus::PreconditionFailed => 412,
+            HttpStatus::PayloadTooLarge => 413,
+            HttpStatus::UriTooLong => 414,
+            HttpStatus::UnsupportedMediaType => 415,
+            HttpStatus::RangeNotSatisfiable => 416,
+            HttpStatus::UnprocessableContent => 422,
+            HttpStatus::TooManyRequests => 429,
+            HttpStatus::RequestHeaderFieldsTooLarge => 431,
+            HttpStatus::InternalServerError => 500,
+            HttpStatus::NotImplemented => 501,
+            HttpStatus::ServiceUnavailable => 503,
+            HttpStatus::HttpVersionNotSupported => 505,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            HttpStatus::SwitchingProtocols => "Switching Protocols",
+            HttpStatus::Ok => "OK",
+            HttpStatus::Created => "Created",
+            HttpStatus::Accepted => "Accepted",
+            HttpStatus::NoContent => "No Content",
+            HttpStatus::PartialContent => "Partial Content",
+            HttpStatus::NotModified => "Not Modified",
+            HttpStatus::BadRequest => "Bad Request",
+            HttpStatus::Unauthorized => "Unauthorized",
+            HttpStatus::Forbidden => "Forbidden",
+            HttpStatus::NotFound => "Not Found",
+            HttpStatus::MethodNotAllowed => "Method Not Allowed",
+            HttpStatus::RequestTimeout => "Request Timeout",
+            HttpStatus::Conflict => "Conflict",
+            HttpStatus::Gone => "Gone",
+            HttpStatus::LengthRequired => "Length Required",
+            HttpStatus::PreconditionFailed => "Precondition Failed",
+            HttpStatus::PayloadTooLarge => "Payload Too Large",
+            HttpStatus::UriTooLong => "URI Too Long",
+            HttpStatus::UnsupportedMediaType => "Unsupported Media Type",
+            HttpStatus::RangeNotSatisfiable => "Range Not Satisfiable",
+            HttpStatus::UnprocessableContent => "Unprocessable Content",
+            HttpStatus::TooManyRequests => "Too Many Requests",
+            HttpStatus::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            HttpStatus::InternalServerError => "Internal Server Error",
+            HttpStatus::NotImplemented => "Not Implemented",
+            HttpStatus::ServiceUnavailable => "Service Unavailable",
+            HttpStatus::HttpVersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
+
+    pub fn from_code(code: u16) -> Option<HttpStatus> {
+        match code {
+            101 => Some(HttpStatus::SwitchingProtocols),
+            200 => Some(HttpStatus::Ok),
+            201 => Some(HttpStatus::Created),
+            202 => Some(HttpStatus::Accepted),
+            204 => Some(HttpStatus::NoContent),
+            206 => Some(HttpStatus::PartialContent),
+            304 => Some(HttpStatus::NotModified),
+            400 => Some(HttpStatus::BadRequest),
+            401 => Some(HttpStatus::Unauthorized),
+            403 => Some(HttpStatus::Forbidden),
+            404 => Some(HttpStatus::NotFound),
+            405 => Some(HttpStatus::MethodNotAllowed),
+            408 => Some(HttpStatus::RequestTimeout),
+            409 => Some(HttpStatus::Conflict),
+            410 => Some(HttpStatus::Gone),
+            411 => Some(HttpStatus::LengthRequired),
+            412 => Some(HttpStatus::PreconditionFailed),
+            413 => Some(HttpStatus::PayloadTooLarge),
+            414 => Some(HttpStatus::UriTooLong),
+            415 => Some(HttpStatus::UnsupportedMediaType),
+            416 => Some(HttpStatus::RangeNotSatisfiable),
+            422 => Some(HttpStatus::UnprocessableContent),
+            429 => Some(HttpStatus::TooManyRequests),
+            431 => Some(HttpStatus::RequestHeaderFieldsTooLarge),
+            500 => Some(HttpStatus::InternalServerError),
+            501 => Some(HttpStatus::NotImplemented),
+            503 => Some(HttpStatus::ServiceUnavailable),
+            505 => Some(HttpStatus::HttpVersionNotSupported),
+            _ => None,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.code())
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.code())
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        self.code() >= 500
+    }
+
+    pub fn allows_body(&self) -> bool {
+        !matches!(
+            self,
+            HttpStatus::SwitchingProtocols | HttpStatus::NoContent | HttpStatus::NotModified
+        )
+    }
+}
+
+impl Display for HttpStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
+pub fn find_header_end(buf: &[u8], from: usize) -> Option<(usize, usize)> {
+    let start = from.saturating_sub(3);
+    buf.iter()
+        .enumerate()
+        .skip(start)
+        .filter(|(_, byte)| **byte == b'\n')
+        .find_map(|(ind, _)| match &buf[ind + 1..] {
+            [b'\n', ..] => Some((ind + 1, ind + 2)),
+            [b'\r', b'\n', ..] => Some((ind + 1, ind + 3)),
+            _ => None,
+        })
+}
+
+enum ChunkState {
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailer,
+}
+
+pub struct ChunkedDecoder {
+    state: ChunkState,
+    pos: usize,
+    pub body: Vec<u8>,
+}
+
+impl Default for ChunkedDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> ChunkedDecoder {
+        ChunkedDecoder {
+            state: ChunkState::Size,
+            pos: 0,
+            body: Vec::new(),
+        }
+    }
+
+    fn line<'b>(&mut self, buf: &'b [u8]) -> Result<Option<&'b [u8]>, ApiError> {
+        match buf[self.pos..].iter().position(|byte| *byte == b'\n') {
+            Some(end) => {
+                let line = &buf[self.pos..self.pos + end];
+                self.pos += end + 1;
+                Ok(Some(line.strip_suffix(b"\r").unwrap_or(line)))
+            }
+            None if buf.len() - self.pos > MAX_CHUNK_LINE => Err(ApiError::bad_request(format!(
+                "A line of the chunked request body exceeds {} bytes",
+                MAX_CHUNK_LINE
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn advance(&mut self, buf: &[u8], max_size: usize) -> Result<Option<usize>, ApiError> {
+        loop {
+            match self.state {
+                ChunkState::Size => {
+                    let Some(line) = self.line(buf)? else {
+                        return Ok(None);
+                    };
+                    let digits = line
+                        .split(|byte| *byte == b';')
+                        .next()
+                        .unwrap_or_default()
+                        .trim_ascii();
+                    let size = str::from_utf8(digits)
+                        .ok()
+                        .filter(|digits| {
+                            !digits.is_empty()
+                                && digits.len() <= 16
+                                && digits.bytes().all(|byte| byte.is_ascii_hexdigit())
+                        })
+                        .and_then(|digits| usize::from_str_radix(digits, 16).ok())
+                        .ok_or_else(|| {
+                            ApiError::bad_request(format!(
+                                "The chunk size {} is invalid",
+                                String::from_utf8_lossy(digits)
+                            ))
+                        })?;
+                    if size == 0 {
+                        self.state = ChunkState::Trailer;
+                    } else if self.body.len().saturating_add(size) > max_size {
+                        return Err(ApiError::new(
+                            HttpStatus::PayloadTooLarge,
+                            format!(
+                                "The chunked request body exceeds the maximum allowed size of {} bytes",
+                                max_size
+                            ),
+                        ));
+                    } else {
+                        self.state = ChunkState::Data(size);
+                    }
                 }
-                let mut content = Vec::<u8>::new();
-                if content_length.is_some() {
-                    content.reserve_exact(content_length.unwrap());
+                ChunkState::Data(remaining) => {
+                    let available = (buf.len() - self.pos).min(remaining);
+                    if available == 0 {
+                        return Ok(None);
+                    }
+                    self.body
+                        .extend_from_slice(&buf[self.pos..self.pos + available]);
+                    self.pos += available;
+                    self.state = if available == remaining {
+                        ChunkState::DataEnd
+                    } else {
+                        ChunkState::Data(remaining - available)
+                    };
                 }
-                Ok(Request {
-                    method: method.unwrap(),
-                    route: route.unwrap(),
-                    http_version: http_version.unwrap(),
-                    host: host.unwrap(),
-                    content_type: content_type,
-                    content_length: content_length,
-                    content,
-                })
+                ChunkState::DataEnd => match &buf[self.pos..] {
+                    [b'\r', b'\n', ..] | [b'\n', ..] => {
+                        self.pos += if buf[self.pos] == b'\r' { 2 } else { 1 };
+                        self.state = ChunkState::Size;
+                    }
+                    [] | [b'\r'] => {
+                        return Ok(None);
+                    }
+                    _ => {
+                        return Err(ApiError::bad_request(
+                            "A chunk of the request body is not followed by CRLF".to_string(),
+                        ));
+                    }
+                },
+                ChunkState::Trailer => match self.line(buf)? {
+                    Some([]) => {
+                        return Ok(Some(self.pos));
+                    }
+                    Some(_) => {}
+                    None => {
+                        return Ok(None);
+                    }
+                },
             }
-            Err(err) => Err(err.to_string()),
         }
     }
+}
+
+type RawBody<'a> = Chain<Cursor<Vec<u8>>, Take<&'a mut dyn Read>>;
+
+pub struct BodyReader<'a> {
+    inner: BufReader<Decoder<RawBody<'a>>>,
+}
+
+impl<'a> BodyReader<'a> {
+    pub fn new(
+        received: Vec<u8>,
+        stream: &'a mut dyn Read,
+        remaining: u64,
+        encoding: Encoding,
+    ) -> BodyReader<'a> {
+        let raw = Cursor::new(received).chain(stream.take(remaining));
+        BodyReader {
+            inner: BufReader::new(Decoder::new(raw, encoding)),
+        }
+    }
+
+    pub fn drain(mut self) -> io::Result<()> {
+        let _ = io::copy(&mut self.inner, &mut io::sink());
+        io::copy(self.inner.get_mut().get_mut(), &mut io::sink())?;
+        let (_, rest) = self.inner.get_mut().get_mut().get_ref();
+        if rest.limit() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The client disconnected before sending the whole request body",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl BufRead for BodyReader<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
 
-    fn parse_content(&mut self, bytes: Vec<u8>) {}
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+// Writes a response body whose length is not known up front with the chunked transfer
+// encoding. Every write is sent as a chunk of its own, so the writer should be buffered.
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> ChunkedWriter<W> {
+        ChunkedWriter { inner }
+    }
+
+    // Without the last chunk, the client sees the body as cut short.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub type Upgrade = Box<dyn FnOnce(&mut dyn Stream) + Send>;
+
+pub struct Response {
+    pub status: HttpStatus,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+    pub upgrade: Option<Upgrade>,
+}
+
+impl Response {
+    pub fn new(status: HttpStatus) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::None,
+            upgrade: None,
+        }
+    }
+
+    pub fn json(status: HttpStatus, json: Json) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::ApplicationJson(json),
+            upgrade: None,
+        }
+    }
+
+    pub fn error(status: HttpStatus, message: String) -> Response {
+        Response::error_with_details(status, message, Json::None)
+    }
+
+    pub fn error_with_details(status: HttpStatus, message: String, details: Json) -> Response {
+        ApiError::new(status, message)
+            .with_details(details)
+            .to_response()
+    }
+
+    pub fn text(status: HttpStatus, text: String) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::TextPlain(text),
+            upgrade: None,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn set_cookie(&mut self, cookie: &Cookie) {
+        self.headers
+            .push(("Set-Cookie".to_string(), cookie.to_string()));
+    }
+
+    pub fn set_header(&mut self, name: &str, value: String) {
+        match self
+            .headers
+            .iter_mut()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        {
+            Some(header) => {
+                header.1 = value;
+            }
+            None => {
+                self.headers.push((name.to_string(), value));
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.serialize(true)
+    }
+
+    pub fn head_bytes(&self) -> Vec<u8> {
+        self.serialize(false)
+    }
+
+    fn serialize(&self, include_body: bool) -> Vec<u8> {
+        let content = self.body.to_bytes();
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        if self.header("Content-Type").is_none() && !matches!(self.body, Body::None) {
+            head += &format!("Content-Type: {}\r\n", self.body.content_type());
+        }
+        if self.header("Content-Length").is_none()
+            && self.status.allows_body()
+            && self.upgrade.is_none()
+        {
+            head += &format!("Content-Length: {}\r\n", content.len());
+        }
+        for (name, value) in &self.headers {
+            head += &format!("{}: {}\r\n", name, value);
+        }
+        head += "\r\n";
+        let mut bytes = head.into_bytes();
+        if include_body && self.status.allows_body() {
+            bytes.extend_from_slice(&content);
+        }
+        bytes
+    }
+
+    pub fn write_to(&self, stream: &mut impl Write) -> Result<(), String> {
+        match stream.write_all(&self.to_bytes()) {
+            Ok(_) => match stream.flush() {
+                Ok(_) => Ok(()),
+                Err(err) => Err(err.to_string()),
+            },
+            Err(err) => Err(err.to_string()),
+        }
+    }
 }
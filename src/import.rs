@@ -0,0 +1,180 @@
+use std::io::{BufRead, Read};
+
+use crate::{
+    db::{DB, WriteError},
+    json::{Json, JsonNumber, JsonObject},
+};
+
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+// Only the first errors are kept for the report, the others are only counted.
+const MAX_REPORTED_ERRORS: usize = 1000;
+
+pub struct ImportOptions {
+    // The number of lines whose documents are written in each record of the write-ahead log.
+    pub batch_size: usize,
+    // Longer lines are reported as errors without being parsed.
+    pub max_line: usize,
+}
+
+pub struct LineError {
+    pub line: u64,
+    pub message: String,
+}
+
+impl LineError {
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["line".to_string()] = Json::Number(JsonNumber::Int(self.line as i64));
+        obj["message".to_string()] = Json::String(self.message.clone());
+        Json::Object(obj)
+    }
+}
+
+#[derive(Default)]
+pub struct ImportReport {
+    pub lines: u64,
+    pub inserted: u64,
+    pub updated: u64,
+    pub failed: u64,
+    pub batches: u64,
+    pub errors: Vec<LineError>,
+}
+
+impl ImportReport {
+    pub fn imported(&self) -> u64 {
+        self.inserted + self.updated
+    }
+
+    pub fn to_json(&self) -> Json {
+        let count = |count: u64| Json::Number(JsonNumber::Int(count as i64));
+        let mut obj = JsonObject::new();
+        obj["lines".to_string()] = count(self.lines);
+        obj["inserted".to_string()] = count(self.inserted);
+        obj["updated".to_string()] = count(self.updated);
+        obj["failed".to_string()] = count(self.failed);
+        obj["batches".to_string()] = count(self.batches);
+        obj["errors".to_string()] =
+            Json::List(self.errors.iter().map(LineError::to_json).collect());
+        Json::Object(obj)
+    }
+
+    fn fail(&mut self, line: u64, message: String) {
+        self.failed += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(LineError { line, message });
+        }
+    }
+}
+
+// Loads newline-delimited JSON documents into the collection. A line that is not a document,
+// or a document rejected by a hook or by the schema, is reported with its line number and
+// skipped. The batch callback runs after each batch is written, with the IDs of its documents
+// and whether each was inserted.
+pub fn import(
+    db: &DB,
+    collection: &str,
+    reader: &mut dyn BufRead,
+    options: &ImportOptions,
+    mut on_batch: impl FnMut(&ImportReport, &[(String, bool)]),
+) -> Result<ImportReport, String> {
+    let mut report = ImportReport::default();
+    // Lines that could not be parsed stay in the batch, so that errors are reported in order.
+    let mut batch = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = (&mut *reader)
+            .take((options.max_line as u64).saturating_add(1))
+            .read_until(b'\n', &mut line)
+            .map_err(|err| format!("Error while reading line {}: {}", report.lines + 1, err))?;
+        if read == 0 {
+            break;
+        }
+        report.lines += 1;
+        let parsed = if line.len() > options.max_line && line.last() != Some(&b'\n') {
+            skip_line(reader)
+                .map_err(|err| format!("Error while reading line {}: {}", report.lines, err))?;
+            Err(format!("The line exceeds {} bytes", options.max_line))
+        } else {
+            match line.trim_ascii() {
+                [] => continue,
+                content => Json::parse(content)
+                    .map_err(|err| format!("{} at column {}", err.message, err.column)),
+            }
+        };
+        batch.push((report.lines, parsed));
+        if batch.len() >= options.batch_size {
+            write_batch(db, collection, &mut batch, &mut report, &mut on_batch)?;
+        }
+    }
+    if !batch.is_empty() {
+        write_batch(db, collection, &mut batch, &mut report, &mut on_batch)?;
+    }
+    Ok(report)
+}
+
+fn write_batch(
+    db: &DB,
+    collection: &str,
+    batch: &mut Vec<(u64, Result<Json, String>)>,
+    report: &mut ImportReport,
+    on_batch: &mut impl FnMut(&ImportReport, &[(String, bool)]),
+) -> Result<(), String> {
+    let mut documents = Vec::new();
+    let mut lines = Vec::new();
+    for (line, parsed) in batch.drain(..) {
+        match parsed {
+            Ok(document) => {
+                documents.push(document);
+                lines.push((line, None));
+            }
+            Err(err) => lines.push((line, Some(err))),
+        }
+    }
+    let mut loaded = db.load(collection, &documents)?.into_iter();
+    let mut written = Vec::new();
+    for (line, err) in lines {
+        let result = match err {
+            None => loaded.next().unwrap(),
+            Some(err) => Err(WriteError::Rejected(err)),
+        };
+        match result {
+            Ok((id, true)) => {
+                report.inserted += 1;
+                written.push((id, true));
+            }
+            Ok((id, false)) => {
+                report.updated += 1;
+                written.push((id, false));
+            }
+            Err(WriteError::Storage(err)) => {
+                return Err(err);
+            }
+            Err(err) => {
+                report.fail(line, err.into());
+            }
+        }
+    }
+    report.batches += 1;
+    on_batch(report, &written);
+    Ok(())
+}
+
+fn skip_line(reader: &mut dyn BufRead) -> std::io::Result<()> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        match buf.iter().position(|byte| *byte == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
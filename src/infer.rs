@@ -0,0 +1,179 @@
+use std::{collections::BTreeMap, ops::Bound};
+
+use crate::{
+    db::{DB, DELETED_FIELD, EXPIRES_FIELD, ID_FIELD},
+    json::{Json, JsonNumber, JsonObject},
+};
+
+const MAX_EXAMPLES: usize = 3;
+const MAX_EXAMPLE_LEN: usize = 64;
+
+// What was seen at one path of the documents: the types of its values, a few of them as
+// examples, and, for objects and arrays, what was seen in their fields and items.
+#[derive(Default)]
+struct Field {
+    values: u64,
+    nulls: u64,
+    types: BTreeMap<&'static str, u64>,
+    examples: Vec<Json>,
+    objects: u64,
+    properties: BTreeMap<String, Field>,
+    items: Option<Box<Field>>,
+}
+
+fn type_name(value: &Json) -> &'static str {
+    match value {
+        Json::Null | Json::None => "null",
+        Json::Bool(_) => "boolean",
+        Json::Object(_) => "object",
+        Json::List(_) => "array",
+        Json::Number(JsonNumber::Int(_)) => "integer",
+        Json::Number(JsonNumber::Float(_)) => "number",
+        Json::String(_) => "string",
+    }
+}
+
+impl Field {
+    fn add(&mut self, value: &Json) {
+        self.values += 1;
+        *self.types.entry(type_name(value)).or_default() += 1;
+        match value {
+            Json::Null | Json::None => {
+                self.nulls += 1;
+            }
+            Json::Object(obj) => {
+                self.objects += 1;
+                for (name, value) in obj.iter() {
+                    self.properties.entry(name.clone()).or_default().add(value);
+                }
+            }
+            Json::List(items) => {
+                let field = self.items.get_or_insert_default();
+                for item in items {
+                    field.add(item);
+                }
+            }
+            value => {
+                let long = matches!(value, Json::String(text) if text.len() > MAX_EXAMPLE_LEN);
+                let seen = self
+                    .examples
+                    .iter()
+                    .any(|example| example.canonical() == value.canonical());
+                if self.examples.len() < MAX_EXAMPLES && !long && !seen {
+                    self.examples.push(value.clone());
+                }
+            }
+        }
+    }
+
+    // Fields missing from an object count as nulls, so the rate is over every object that could
+    // have had the field.
+    fn report(&self, path: &str, of: u64, fields: &mut Vec<Json>) {
+        let mut obj = JsonObject::new();
+        obj["path".to_string()] = Json::String(path.to_string());
+        let mut types = JsonObject::new();
+        for (name, count) in &self.types {
+            types[name.to_string()] = Json::Number(JsonNumber::Int(*count as i64));
+        }
+        obj["types".to_string()] = Json::Object(types);
+        obj["present".to_string()] = Json::Number(JsonNumber::Int(self.values as i64));
+        let nulls = self.nulls + of.saturating_sub(self.values);
+        let null_rate = match of {
+            0 => 0.0,
+            of => nulls as f64 / of as f64,
+        };
+        obj["null_rate".to_string()] = Json::Number(JsonNumber::Float(null_rate));
+        obj["examples".to_string()] = Json::List(self.examples.clone());
+        fields.push(Json::Object(obj));
+        for (name, field) in &self.properties {
+            field.report(&format!("{}.{}", path, name), self.objects, fields);
+        }
+        if let Some(items) = &self.items {
+            items.report(&format!("{}[]", path), items.values, fields);
+        }
+    }
+
+    // A schema that every value seen here matches. Fields present in every object are required.
+    fn schema(&self) -> Json {
+        let mut obj = JsonObject::new();
+        let types = self
+            .types
+            .keys()
+            .map(|name| Json::String(name.to_string()))
+            .collect::<Vec<_>>();
+        obj["type".to_string()] = match types.len() {
+            1 => types.into_iter().next().unwrap(),
+            _ => Json::List(types),
+        };
+        if self.objects > 0 {
+            let mut properties = JsonObject::new();
+            let mut required = Vec::new();
+            for (name, field) in &self.properties {
+                properties[name.clone()] = field.schema();
+                if field.values == self.objects {
+                    required.push(Json::String(name.clone()));
+                }
+            }
+            obj["properties".to_string()] = Json::Object(properties);
+            if !required.is_empty() {
+                obj["required".to_string()] = Json::List(required);
+            }
+        }
+        if let Some(items) = &self.items {
+            obj["items".to_string()] = items.schema();
+        }
+        Json::Object(obj)
+    }
+}
+
+// The fields, types and null rates of the documents of a collection, and a schema they match.
+// The fields the database sets itself are left out, like schemas leave them out.
+#[derive(Default)]
+pub struct Inference {
+    documents: Field,
+}
+
+impl Inference {
+    pub fn add(&mut self, document: &Json) {
+        let mut document = document.clone();
+        if let Json::Object(obj) = &mut document {
+            for name in [ID_FIELD, EXPIRES_FIELD, DELETED_FIELD] {
+                obj.remove(name);
+            }
+        }
+        self.documents.add(&document);
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut fields = Vec::new();
+        for (name, field) in &self.documents.properties {
+            field.report(name, self.documents.objects, &mut fields);
+        }
+        let mut obj = JsonObject::new();
+        obj["documents".to_string()] = Json::Number(JsonNumber::Int(self.documents.values as i64));
+        obj["fields".to_string()] = Json::List(fields);
+        obj["schema".to_string()] = match self.documents.values {
+            0 => Json::Object(JsonObject::new()),
+            _ => self.documents.schema(),
+        };
+        Json::Object(obj)
+    }
+}
+
+// Infers from every document of the collection, or from a random sample of them.
+pub fn infer(db: &DB, collection: &str, sample: Option<usize>) -> Result<Inference, String> {
+    let mut inference = Inference::default();
+    match sample {
+        Some(n) => {
+            for document in db.sample(collection, n)?.0 {
+                inference.add(&document);
+            }
+        }
+        None => {
+            for document in db.scan(collection, (Bound::Unbounded, Bound::Unbounded))? {
+                inference.add(&document?);
+            }
+        }
+    }
+    Ok(inference)
+}
@@ -5,6 +5,7 @@ use std::{
     str::FromStr,
 };
 
+#[derive(Clone, Debug)]
 pub enum Json {
     Number(JsonNumber),
     String(String),
@@ -15,14 +16,86 @@ pub enum Json {
     None,
 }
 
+#[derive(Clone, Debug)]
+pub struct JsonError {
+    pub message: String,
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl JsonError {
+    fn at(message: String, offset: usize) -> JsonError {
+        JsonError {
+            message,
+            offset,
+            line: 0,
+            column: 0,
+        }
+    }
+
+    fn locate(mut self, data: &[u8]) -> JsonError {
+        self.offset = self.offset.min(data.len());
+        self.line = 1;
+        self.column = 1;
+        for byte in &data[..self.offset] {
+            if *byte == b'\n' {
+                self.line += 1;
+                self.column = 1;
+            } else if (*byte & 0xC0) != 0x80 {
+                self.column += 1;
+            }
+        }
+        self
+    }
+
+    pub fn excerpt(&self, data: &[u8], radius: usize) -> String {
+        let offset = self.offset.min(data.len());
+        let start = offset.saturating_sub(radius);
+        let end = (offset + radius).min(data.len());
+        let mut excerpt = String::new();
+        if start > 0 {
+            excerpt += "...";
+        }
+        for ch in String::from_utf8_lossy(&data[start..end]).chars() {
+            if ch.is_control() {
+                excerpt += &ch.escape_default().to_string();
+            } else {
+                excerpt.push(ch);
+            }
+        }
+        if end < data.len() {
+            excerpt += "...";
+        }
+        excerpt
+    }
+}
+
+impl Display for JsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (at line {}, column {})",
+            self.message, self.line, self.column
+        )
+    }
+}
+
+impl From<JsonError> for String {
+    fn from(err: JsonError) -> String {
+        err.to_string()
+    }
+}
+
 impl FromStr for Json {
-    type Err = String;
+    type Err = JsonError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Json::parse(s.as_bytes())
     }
 }
 
+#[derive(Clone, Debug)]
 pub enum JsonNumber {
     Int(i64),
     Float(f64),
@@ -37,11 +110,18 @@ impl Display for JsonNumber {
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct JsonObject {
     map: HashMap<String, Json>,
     none: Box<Json>,
 }
 
+impl Default for JsonObject {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl JsonObject {
     pub fn new() -> JsonObject {
         JsonObject {
@@ -56,6 +136,30 @@ impl JsonObject {
             none: Box::<Json>::new(Json::None),
         }
     }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        self.map.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Json> {
+        self.map.get_mut(key)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Json> {
+        self.map.remove(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Json)> {
+        self.map.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
 }
 
 impl Display for JsonObject {
@@ -69,7 +173,7 @@ impl Display for JsonObject {
                 }
                 one_valid_value = true;
                 f.write_str("\"")?;
-                key.fmt(f)?;
+                f.write_str(&escape_string(key))?;
                 f.write_str("\" : ")?;
                 value.fmt(f)?;
             }
@@ -84,9 +188,9 @@ impl Index<String> for JsonObject {
 
     fn index(&self, index: String) -> &Self::Output {
         if self.map.contains_key(&index) {
-            return &self.map[&index];
+            &self.map[&index]
         } else {
-            return self.none.as_ref();
+            self.none.as_ref()
         }
     }
 }
@@ -96,7 +200,83 @@ impl IndexMut<String> for JsonObject {
         if !self.map.contains_key(&index) {
             self.map.insert(index.clone(), Json::None);
         }
-        return self.map.get_mut(&index).unwrap();
+        self.map.get_mut(&index).unwrap()
+    }
+}
+
+const MAX_DEPTH: usize = 512;
+
+pub fn escape_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            '\n' => escaped += "\\n",
+            '\r' => escaped += "\\r",
+            '\t' => escaped += "\\t",
+            '\x08' => escaped += "\\b",
+            '\x0c' => escaped += "\\f",
+            ch if (ch as u32) < 0x20 => escaped += &format!("\\u{:04x}", ch as u32),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+pub struct RawJson {
+    bytes: Vec<u8>,
+}
+
+impl RawJson {
+    pub fn new(bytes: Vec<u8>) -> Result<RawJson, JsonError> {
+        Json::validate(&bytes)?;
+        Ok(RawJson { bytes })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn parse(&self) -> Result<Json, JsonError> {
+        Json::parse(&self.bytes)
+    }
+
+    pub fn field(&self, key: &str) -> Result<Json, JsonError> {
+        let data = self.bytes.as_slice();
+        let mut cur = Json::skip_whitespace(data, 0);
+        if cur >= data.len() || data[cur] != b'{' {
+            return Err(JsonError::at(
+                "Fields can only be extracted from a JSON object".to_string(),
+                cur,
+            )
+            .locate(data));
+        }
+        cur = Json::skip_whitespace(data, cur + 1);
+        while cur < data.len() && data[cur] == b'"' {
+            let key_end = Json::string_end(data, cur).map_err(|err| err.locate(data))?;
+            let found_key = match Json::parse(&data[cur..key_end])? {
+                Json::String(val) => val,
+                _ => String::new(),
+            };
+            let value_start = Json::skip_whitespace(data, Json::skip_whitespace(data, key_end) + 1);
+            let value_end =
+                Json::value_end(data, value_start, 1).map_err(|err| err.locate(data))?;
+            if found_key == key {
+                return Json::parse(&data[value_start..value_end]);
+            }
+            cur = Json::skip_whitespace(data, value_end);
+            if cur < data.len() && data[cur] == b',' {
+                cur = Json::skip_whitespace(data, cur + 1);
+            } else {
+                break;
+            }
+        }
+        Ok(Json::None)
     }
 }
 
@@ -115,32 +295,29 @@ enum Token {
 }
 
 impl Json {
-    fn tokenise(data: &[u8]) -> Result<Vec<Token>, String> {
+    fn tokenise(data: &[u8]) -> Result<(Vec<Token>, Vec<usize>), JsonError> {
         let mut res = Vec::<Token>::new();
+        let mut positions = Vec::<usize>::new();
         let mut cur = 0usize;
         while cur < data.len() {
+            let token_start = cur;
             match data[cur] {
-                b'0'..=b'9' => {
-                    let mut found_decimal = false;
-                    let mut num_str = String::new();
-                    while data[cur].is_ascii_digit() || (!found_decimal && data[cur] == b'.') {
-                        if data[cur] == b'.' {
-                            found_decimal = true;
-                        }
-                        num_str += &(data[cur] as char).to_string();
-                        cur += 1;
-                    }
-                    if found_decimal {
+                b'-' | b'0'..=b'9' => {
+                    let (end, is_float) = Self::number_end(data, cur)?;
+                    let num_str = String::from_utf8_lossy(&data[cur..end]).to_string();
+                    if is_float {
                         match num_str.parse::<f64>() {
                             Ok(fl_num) => {
                                 res.push(Token::Float(fl_num));
                             }
                             Err(err) => {
-                                return Err("Failed to parse the floating point number "
-                                    .to_string()
-                                    + &num_str
-                                    + ". The error is "
-                                    + &err.to_string());
+                                return Err(JsonError::at(
+                                    "Failed to parse the floating point number ".to_string()
+                                        + &num_str
+                                        + ". The error is "
+                                        + &err.to_string(),
+                                    cur,
+                                ));
                             }
                         };
                     } else {
@@ -149,13 +326,17 @@ impl Json {
                                 res.push(Token::Int(int_num));
                             }
                             Err(err) => {
-                                return Err("Failed to parse the integer ".to_string()
-                                    + &num_str
-                                    + ". The error is "
-                                    + &err.to_string());
+                                return Err(JsonError::at(
+                                    "Failed to parse the integer ".to_string()
+                                        + &num_str
+                                        + ". The error is "
+                                        + &err.to_string(),
+                                    cur,
+                                ));
                             }
                         }
                     }
+                    cur = end;
                 }
                 b'{' => {
                     res.push(Token::CurlyOpen);
@@ -181,12 +362,12 @@ impl Json {
                     res.push(Token::Comma);
                     cur += 1;
                 }
-                b' ' | b'\t' | b'\n' => {
+                b' ' | b'\t' | b'\n' | b'\r' => {
                     cur += 1;
                 }
                 b'a'..=b'z' => {
                     let mut ident = String::new();
-                    while data[cur].is_ascii_lowercase() {
+                    while cur < data.len() && data[cur].is_ascii_lowercase() {
                         ident += &(data[cur] as char).to_string();
                         cur += 1;
                     }
@@ -196,103 +377,401 @@ impl Json {
                         res.push(Token::Bool(false));
                     } else if ident == "null" {
                         res.push(Token::Null);
+                    } else {
+                        return Err(JsonError::at(
+                            "Invalid identifier found in the JSON: ".to_string() + &ident,
+                            token_start,
+                        ));
                     }
                 }
                 b'"' => {
-                    let mut content = String::new();
-                    let mut escape = false;
+                    let mut content = Vec::<u8>::new();
                     cur += 1;
-                    while cur < data.len() && (escape || data[cur] != b'"') {
-                        if !escape && data[cur] == b'\\' {
-                            escape = true;
-                        } else if escape {
-                            if data[cur] == b'"' {
-                                content += &(b'"' as char).to_string();
-                            } else if data[cur] == b'\\' {
-                                content += &(b'\\' as char).to_string();
-                            } else if data[cur] == b'/' {
-                                content += &(b'/' as char).to_string();
-                            } else if data[cur] == b'b' {
-                                content += &(b'\x08' as char).to_string();
-                            } else if data[cur] == b'f' {
-                                content += &(b'\x0c' as char).to_string();
-                            } else if data[cur] == b'n' {
-                                content += &(b'\n' as char).to_string();
-                            } else if data[cur] == b'r' {
-                                content += &(b'\r' as char).to_string();
-                            } else if data[cur] == b't' {
-                                content += &(b'\t' as char).to_string();
-                            } else if data[cur] == b'u' {
-                                cur += 1;
-                                let mut uni_str = String::new();
-                                for i in 0..4 {
-                                    if (cur + i) < data.len() {
-                                        if data[cur + i].is_ascii_hexdigit() {
-                                            uni_str += &(data[cur + i] as char).to_string();
-                                        } else {
-                                            return Err(
-                                                "Expected 4 hex digits after \\u for the unicode character, but found the character ".to_string()
-                                                    + &(data[cur + i] as char).to_string()
-                                                    + " instead",
-                                            );
-                                        }
-                                    } else {
-                                        return Err("Expected 4 characters to be present after \\u for the unicode character, but the JSON representation ended".to_string());
-                                    }
+                    while cur < data.len() && data[cur] != b'"' {
+                        if data[cur] != b'\\' {
+                            content.push(data[cur]);
+                            cur += 1;
+                            continue;
+                        }
+                        cur += 1;
+                        if cur >= data.len() {
+                            break;
+                        }
+                        let escaped = match data[cur] {
+                            b'"' => '"',
+                            b'\\' => '\\',
+                            b'/' => '/',
+                            b'b' => '\x08',
+                            b'f' => '\x0c',
+                            b'n' => '\n',
+                            b'r' => '\r',
+                            b't' => '\t',
+                            b'u' => {
+                                if cur + 4 >= data.len() {
+                                    return Err(JsonError::at("Expected 4 characters to be present after \\u for the unicode character, but the JSON representation ended".to_string(), cur));
                                 }
-                                match u32::from_str_radix(uni_str.as_str(), 16) {
-                                    Ok(code) => match char::from_u32(code) {
-                                        Some(char_val) => {
-                                            content += &char_val.to_string();
-                                        }
-                                        None => {
-                                            return Err("Failed to convert the provided unicode codepoint \\u".to_string() + &uni_str + " to a unicode scalar value");
-                                        }
-                                    },
+                                let uni_str = String::from_utf8_lossy(&data[(cur + 1)..(cur + 5)])
+                                    .to_string();
+                                let code = match u32::from_str_radix(uni_str.as_str(), 16) {
+                                    Ok(code) => code,
                                     Err(err) => {
-                                        return Err(
+                                        return Err(JsonError::at(
                                             "Failed to parse the unicode code point here: \\u"
                                                 .to_string()
                                                 + &uni_str
                                                 + ". The error is "
                                                 + &err.to_string(),
-                                        );
+                                            cur,
+                                        ));
+                                    }
+                                };
+                                cur += 4;
+                                match char::from_u32(code) {
+                                    Some(char_val) => char_val,
+                                    None => {
+                                        return Err(JsonError::at(
+                                            "Failed to convert the provided unicode codepoint \\u"
+                                                .to_string()
+                                                + &uni_str
+                                                + " to a unicode scalar value",
+                                            cur,
+                                        ));
                                     }
                                 }
-                                cur += 3;
-                            } else {
-                                return Err("Invalid escape sequence \\".to_string()
-                                    + &(data[cur] as char).to_string()
-                                    + " found in JSON");
                             }
-                            escape = false;
-                        } else {
-                            content += &(data[cur] as char).to_string();
-                        }
+                            other => {
+                                return Err(JsonError::at(
+                                    "Invalid escape sequence \\".to_string()
+                                        + &(other as char).to_string()
+                                        + " found in JSON",
+                                    cur,
+                                ));
+                            }
+                        };
+                        let mut char_buf = [0u8; 4];
+                        content.extend_from_slice(escaped.encode_utf8(&mut char_buf).as_bytes());
                         cur += 1;
                     }
-                    if cur == data.len() {
-                        return Err("Could not find \" to end the string value".to_string());
-                    } else {
-                        cur += 1;
+                    if cur >= data.len() {
+                        return Err(JsonError::at(
+                            "Could not find \" to end the string value".to_string(),
+                            token_start,
+                        ));
+                    }
+                    cur += 1;
+                    match String::from_utf8(content) {
+                        Ok(content) => {
+                            res.push(Token::String(content));
+                        }
+                        Err(_) => {
+                            return Err(JsonError::at(
+                                "The string value is not valid UTF-8".to_string(),
+                                token_start,
+                            ));
+                        }
                     }
-                    res.push(Token::String(content));
                 }
                 _ => {
-                    return Err("Invalid character found in the JSON: ".to_string()
-                        + &(data[cur] as char).to_string());
+                    return Err(JsonError::at(
+                        "Invalid character found in the JSON: ".to_string()
+                            + &(data[cur] as char).to_string(),
+                        cur,
+                    ));
                 }
             }
+            while positions.len() < res.len() {
+                positions.push(token_start);
+            }
+        }
+        Ok((res, positions))
+    }
+
+    fn number_end(data: &[u8], start: usize) -> Result<(usize, bool), JsonError> {
+        let mut cur = start;
+        let mut is_float = false;
+        if cur < data.len() && data[cur] == b'-' {
+            cur += 1;
+        }
+        let digits_start = cur;
+        while cur < data.len() && data[cur].is_ascii_digit() {
+            cur += 1;
+        }
+        if cur == digits_start {
+            return Err(JsonError::at(
+                "Expected digits in the number, after -".to_string(),
+                cur,
+            ));
+        }
+        if cur < data.len() && data[cur] == b'.' {
+            is_float = true;
+            cur += 1;
+            let fraction_start = cur;
+            while cur < data.len() && data[cur].is_ascii_digit() {
+                cur += 1;
+            }
+            if cur == fraction_start {
+                return Err(JsonError::at(
+                    "Expected digits after the decimal point in the number".to_string(),
+                    cur,
+                ));
+            }
+        }
+        if cur < data.len() && (data[cur] == b'e' || data[cur] == b'E') {
+            is_float = true;
+            cur += 1;
+            if cur < data.len() && (data[cur] == b'+' || data[cur] == b'-') {
+                cur += 1;
+            }
+            let exponent_start = cur;
+            while cur < data.len() && data[cur].is_ascii_digit() {
+                cur += 1;
+            }
+            if cur == exponent_start {
+                return Err(JsonError::at(
+                    "Expected digits in the exponent of the number".to_string(),
+                    cur,
+                ));
+            }
         }
-        return Ok(res);
+        Ok((cur, is_float))
     }
 
-    fn parse_value<'a>(data: &'a Vec<Token>, ind: usize) -> Result<(Json, usize), String> {
+    fn skip_whitespace(data: &[u8], start: usize) -> usize {
+        let mut cur = start;
+        while cur < data.len() && matches!(data[cur], b' ' | b'\t' | b'\n' | b'\r') {
+            cur += 1;
+        }
+        cur
+    }
+
+    fn string_end(data: &[u8], start: usize) -> Result<usize, JsonError> {
+        let mut cur = start + 1;
+        while cur < data.len() {
+            match data[cur] {
+                b'"' => {
+                    return Ok(cur + 1);
+                }
+                b'\\' => {
+                    if cur + 1 >= data.len() {
+                        break;
+                    }
+                    match data[cur + 1] {
+                        b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => {
+                            cur += 2;
+                        }
+                        b'u' => {
+                            if cur + 6 > data.len()
+                                || !data[(cur + 2)..(cur + 6)]
+                                    .iter()
+                                    .all(|it| it.is_ascii_hexdigit())
+                            {
+                                return Err(JsonError::at(
+                                    "Expected 4 hex digits after \\u for the unicode character"
+                                        .to_string(),
+                                    cur,
+                                ));
+                            }
+                            cur += 6;
+                        }
+                        other => {
+                            return Err(JsonError::at(
+                                "Invalid escape sequence \\".to_string()
+                                    + &(other as char).to_string()
+                                    + " found in JSON",
+                                cur,
+                            ));
+                        }
+                    }
+                }
+                _ => {
+                    cur += 1;
+                }
+            }
+        }
+        Err(JsonError::at(
+            "Could not find \" to end the string value".to_string(),
+            start,
+        ))
+    }
+
+    fn value_end(data: &[u8], start: usize, depth: usize) -> Result<usize, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(JsonError::at(
+                format!(
+                    "The JSON is nested deeper than the supported limit of {} levels",
+                    MAX_DEPTH
+                ),
+                start,
+            ));
+        }
+        let mut cur = Self::skip_whitespace(data, start);
+        if cur >= data.len() {
+            return Err(JsonError::at(
+                "Expected to find a JSON value, but the JSON representation ended before that"
+                    .to_string(),
+                cur,
+            ));
+        }
+        match data[cur] {
+            b'"' => Self::string_end(data, cur),
+            b'-' | b'0'..=b'9' => Ok(Self::number_end(data, cur)?.0),
+            b't' | b'f' | b'n' => {
+                for literal in [&b"true"[..], &b"false"[..], &b"null"[..]] {
+                    if data[cur..].starts_with(literal) {
+                        return Ok(cur + literal.len());
+                    }
+                }
+                Err(JsonError::at(
+                    "Invalid identifier found in the JSON".to_string(),
+                    cur,
+                ))
+            }
+            b'[' => {
+                cur = Self::skip_whitespace(data, cur + 1);
+                if cur < data.len() && data[cur] == b']' {
+                    return Ok(cur + 1);
+                }
+                loop {
+                    cur = Self::skip_whitespace(data, Self::value_end(data, cur, depth + 1)?);
+                    if cur >= data.len() {
+                        return Err(JsonError::at("Expected either , or ] after the array value, but the JSON representation ended before that".to_string(), cur));
+                    }
+                    match data[cur] {
+                        b',' => {
+                            cur += 1;
+                        }
+                        b']' => {
+                            return Ok(cur + 1);
+                        }
+                        _ => {
+                            return Err(JsonError::at("Expected either , or ] after the array value, but found an invalid symbol".to_string(), cur));
+                        }
+                    }
+                }
+            }
+            b'{' => {
+                cur = Self::skip_whitespace(data, cur + 1);
+                if cur < data.len() && data[cur] == b'}' {
+                    return Ok(cur + 1);
+                }
+                loop {
+                    if cur >= data.len() || data[cur] != b'"' {
+                        return Err(JsonError::at(
+                            "Expected a string value for the key of the field".to_string(),
+                            cur,
+                        ));
+                    }
+                    cur = Self::skip_whitespace(data, Self::string_end(data, cur)?);
+                    if cur >= data.len() || data[cur] != b':' {
+                        return Err(JsonError::at(
+                            "Expected : after the key string, before the value of the field"
+                                .to_string(),
+                            cur,
+                        ));
+                    }
+                    cur = Self::skip_whitespace(data, Self::value_end(data, cur + 1, depth + 1)?);
+                    if cur >= data.len() {
+                        return Err(JsonError::at("Expected either a , or a } after the key-value pair, but the JSON ended".to_string(), cur));
+                    }
+                    match data[cur] {
+                        b',' => {
+                            cur = Self::skip_whitespace(data, cur + 1);
+                        }
+                        b'}' => {
+                            return Ok(cur + 1);
+                        }
+                        _ => {
+                            return Err(JsonError::at("Expected either a , or a } after the key-value pair, but found an invalid symbol".to_string(), cur));
+                        }
+                    }
+                }
+            }
+            other => Err(JsonError::at(
+                "Invalid character found in the JSON: ".to_string() + &(other as char).to_string(),
+                cur,
+            )),
+        }
+    }
+
+    pub fn canonical(&self) -> String {
+        let mut out = String::new();
+        self.write_canonical(&mut out);
+        out
+    }
+
+    fn write_canonical(&self, out: &mut String) {
+        match self {
+            Json::Number(num) => {
+                *out += &num.to_string();
+            }
+            Json::String(string) => {
+                out.push('"');
+                *out += &escape_string(string);
+                out.push('"');
+            }
+            Json::List(list) => {
+                out.push('[');
+                for (ind, item) in list.iter().enumerate() {
+                    if ind > 0 {
+                        out.push(',');
+                    }
+                    item.write_canonical(out);
+                }
+                out.push(']');
+            }
+            Json::Object(obj) => {
+                let mut entries = obj
+                    .iter()
+                    .filter(|(_, value)| !matches!(value, Json::None))
+                    .collect::<Vec<_>>();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                out.push('{');
+                for (ind, (key, value)) in entries.into_iter().enumerate() {
+                    if ind > 0 {
+                        out.push(',');
+                    }
+                    out.push('"');
+                    *out += &escape_string(key);
+                    *out += "\":";
+                    value.write_canonical(out);
+                }
+                out.push('}');
+            }
+            Json::Bool(val) => {
+                *out += if *val { "true" } else { "false" };
+            }
+            Json::Null => {
+                *out += "null";
+            }
+            Json::None => {}
+        }
+    }
+
+    pub fn validate(data: &[u8]) -> Result<(), JsonError> {
+        let end = match Self::value_end(data, 0, 0) {
+            Ok(end) => Self::skip_whitespace(data, end),
+            Err(err) => {
+                return Err(err.locate(data));
+            }
+        };
+        if end != data.len() {
+            return Err(JsonError::at(
+                "Found a valid JSON value first, but the JSON representation does not end after that"
+                    .to_string(),
+                end,
+            )
+            .locate(data));
+        }
+        Ok(())
+    }
+
+    fn parse_value(data: &[Token], ind: usize) -> Result<(Json, usize), JsonError> {
         if ind >= data.len() {
-            return Err(
+            return Err(JsonError::at(
                 "Expected to find a JSON value, but the JSON representation ended before that"
                     .to_string(),
-            );
+                ind,
+            ));
         }
         match &data[ind] {
             Token::Bool(val) => Ok((Json::Bool(*val), ind)),
@@ -303,27 +782,35 @@ impl Json {
             Token::CurlyOpen => {
                 let mut vals_map = HashMap::<String, Json>::new();
                 if ind + 1 >= data.len() {
-                    return Err("Found { first in the JSON, and expected key-value pairs after it, but the JSON representation ended".to_string());
+                    return Err(JsonError::at("Found { first in the JSON, and expected key-value pairs after it, but the JSON representation ended".to_string(), ind + 1));
                 }
                 let mut cur = ind + 1usize;
+                if matches!(data[cur], Token::CurlyClose) {
+                    return Ok((Json::Object(JsonObject::from_map(vals_map)), cur));
+                }
                 if !matches!(data[cur], Token::String(_)) {
-                    return Err(
-                        "Expected a string value for the key of the field, after {".to_string()
-                    );
+                    return Err(JsonError::at(
+                        "Expected a string value for the key of the field, after {".to_string(),
+                        cur,
+                    ));
                 }
                 'object_loop: while let Token::String(key) = &data[cur] {
                     if cur + 1 >= data.len() || !matches!(data[cur + 1], Token::Colon) {
-                        return Err("Expected : after the key string `".to_string()
-                            + &key
-                            + &"`, before the value of the field".to_string());
+                        return Err(JsonError::at(
+                            "Expected : after the key string `".to_string()
+                                + key
+                                + "`, before the value of the field",
+                            cur,
+                        ));
                     }
                     if cur + 2 >= data.len() {
-                        return Err(
+                        return Err(JsonError::at(
                             "Expected a value after : for the value of the field with key `"
                                 .to_string()
-                                + &key
-                                + &"`".to_string(),
-                        );
+                                + key
+                                + "`",
+                            cur,
+                        ));
                     }
                     match Self::parse_value(data, cur + 2) {
                         Ok(value) => {
@@ -331,29 +818,29 @@ impl Json {
                             cur = value.1;
                         }
                         Err(err) => {
-                            return Err("Error while parsing a value of the field with key `"
-                                .to_string()
-                                + &key
-                                + &"`. The error is ".to_string()
-                                + &err);
+                            return Err(JsonError::at(
+                                "Error while parsing a value of the field with key `".to_string()
+                                    + key
+                                    + "`. The error is "
+                                    + &err.message,
+                                err.offset,
+                            ));
                         }
                     }
                     if cur + 1 >= data.len() {
-                        return Err("Expected either a , or a } after the key-value pair, but the JSON ended".to_string());
+                        return Err(JsonError::at("Expected either a , or a } after the key-value pair, but the JSON ended".to_string(), cur));
                     }
                     if matches!(data[cur + 1], Token::Comma) {
                         cur += 1;
                         if cur + 1 >= data.len() {
-                            return Err("Expected a string after the , for the key of the next field. Trailing commas are not allowed in JSON".to_string());
+                            return Err(JsonError::at("Expected a string after the , for the key of the next field. Trailing commas are not allowed in JSON".to_string(), cur));
                         }
                         cur += 1;
                     } else if matches!(data[cur + 1], Token::CurlyClose) {
                         cur += 1;
                         break 'object_loop;
                     } else {
-                        return Err(
-                            "Expected either a , or a } after the key-value pair, but found an invalid symbol".to_string()
-                        );
+                        return Err(JsonError::at("Expected either a , or a } after the key-value pair, but found an invalid symbol".to_string(), cur));
                     }
                 }
                 Ok((Json::Object(JsonObject::from_map(vals_map)), cur))
@@ -361,7 +848,7 @@ impl Json {
             Token::BracketOpen => {
                 let mut list = Vec::<Json>::new();
                 if ind + 1 >= data.len() {
-                    return Err("Expected either values to be present after [ for the array, or for the array to end with a ], but the JSON representation ended before that".to_string());
+                    return Err(JsonError::at("Expected either values to be present after [ for the array, or for the array to end with a ], but the JSON representation ended before that".to_string(), ind + 1));
                 }
                 let mut cur = ind + 1usize;
                 'array_loop: while !matches!(data[cur], Token::BracketClose) {
@@ -370,21 +857,21 @@ impl Json {
                             list.push(val.0);
                             cur = val.1;
                             if cur + 1 >= data.len() {
-                                return Err("Expected either , after the value or ] to end the array, but the JSON representation ended before that".to_string());
+                                return Err(JsonError::at("Expected either , after the value or ] to end the array, but the JSON representation ended before that".to_string(), cur));
                             }
                             if matches!(data[cur + 1], Token::Comma) {
                                 if cur + 2 >= data.len() {
-                                    return Err("Expected a value to be present after , in the array, but the JSON representation ended before that".to_string());
+                                    return Err(JsonError::at("Expected a value to be present after , in the array, but the JSON representation ended before that".to_string(), cur));
                                 }
                                 if matches!(data[cur + 2], Token::BracketClose) {
-                                    return Err("Trailing commas are not supported in arrays. Found ] immediately after a ,".to_string());
+                                    return Err(JsonError::at("Trailing commas are not supported in arrays. Found ] immediately after a ,".to_string(), cur));
                                 }
-                                cur += 1;
+                                cur += 2;
                             } else if matches!(data[cur + 1], Token::BracketClose) {
                                 cur += 1;
                                 break 'array_loop;
                             } else {
-                                return Err("Expected either , or ] after the array value, but found an invalid symbol".to_string());
+                                return Err(JsonError::at("Expected either , or ] after the array value, but found an invalid symbol".to_string(), cur));
                             }
                         }
                         Err(err) => {
@@ -394,41 +881,47 @@ impl Json {
                 }
                 Ok((Json::List(list), cur))
             }
-            _ => {
-                return Err("Invalid symbol found in JSON".to_string());
-            }
+            _ => Err(JsonError::at(
+                "Invalid symbol found in JSON".to_string(),
+                ind,
+            )),
         }
     }
 
-    pub fn parse(data: &[u8]) -> Result<Json, String> {
-        match Self::tokenise(data) {
-            Ok(tokens) => {
-                if tokens.len() == 0 {
-                    return Err(
-                        "Could not parse a valid JSON value as the string representation is empty"
-                            .to_string(),
-                    );
+    pub fn parse(data: &[u8]) -> Result<Json, JsonError> {
+        let (tokens, positions) = match Self::tokenise(data) {
+            Ok(tokenised) => tokenised,
+            Err(err) => {
+                return Err(err.locate(data));
+            }
+        };
+        let to_byte_offset = |mut err: JsonError| {
+            err.offset = positions.get(err.offset).copied().unwrap_or(data.len());
+            err.locate(data)
+        };
+        if tokens.is_empty() {
+            return Err(JsonError::at(
+                "Could not parse a valid JSON value as the string representation is empty"
+                    .to_string(),
+                0,
+            )
+            .locate(data));
+        }
+        match Self::parse_value(&tokens, 0) {
+            Ok(val) => {
+                if val.1 == tokens.len() - 1 {
+                    Ok(val.0)
                 } else {
-                    match Self::parse_value(&tokens, 0) {
-                        Ok(val) => {
-                            if val.1 == tokens.len() - 1 {
-                                return Ok(val.0);
-                            } else {
-                                return Err(format!(
-                                    "Found the value {} first in the JSON, but the JSON representation does not end after that",
-                                    val.0
-                                ));
-                            }
-                        }
-                        Err(err) => {
-                            return Err(err);
-                        }
-                    }
+                    Err(to_byte_offset(JsonError::at(
+                        format!(
+                            "Found the value {} first in the JSON, but the JSON representation does not end after that",
+                            val.0
+                        ),
+                        val.1 + 1,
+                    )))
                 }
             }
-            Err(err) => {
-                return Err(err.to_string());
-            }
+            Err(err) => Err(to_byte_offset(err)),
         }
     }
 }
@@ -438,16 +931,16 @@ impl Display for Json {
         match self {
             Json::Number(num) => num.fmt(f),
             Json::String(string) => {
-                "\"".fmt(f)?;
-                string.fmt(f)?;
-                "\"".fmt(f)?;
+                f.write_str("\"")?;
+                f.write_str(&escape_string(string))?;
+                f.write_str("\"")?;
                 Ok(())
             }
             Json::List(list) => {
                 f.write_str("[")?;
                 for i in 0..list.len() {
                     list[i].fmt(f)?;
-                    if i != (list.len()) {
+                    if i != (list.len() - 1) {
                         f.write_str(", ")?;
                     }
                 }
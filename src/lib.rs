@@ -0,0 +1,52 @@
+pub mod admin;
+pub mod aggregate;
+pub mod alert;
+pub mod api;
+pub mod archive;
+pub mod auth;
+pub mod cache;
+pub mod changes;
+pub mod cli;
+pub mod codec;
+pub mod compaction;
+pub mod compression;
+pub mod config;
+pub mod copy;
+pub mod cors;
+pub mod db;
+pub mod engine;
+pub mod error;
+pub mod etag;
+pub mod export;
+pub mod hooks;
+pub mod http;
+pub mod import;
+pub mod infer;
+pub mod json;
+pub mod logging;
+pub mod metrics;
+pub mod middleware;
+pub mod patch;
+pub mod query;
+pub mod quota;
+pub mod range;
+pub mod ratelimit;
+pub mod root;
+pub mod router;
+pub mod schema;
+pub mod scram;
+pub mod server;
+pub mod service;
+pub mod sketch;
+pub mod sse;
+pub mod sstable;
+pub mod startup;
+pub mod systemd;
+pub mod telemetry;
+pub mod test_util;
+pub mod tls;
+pub mod ttl;
+pub mod types;
+pub mod wal;
+pub mod warmup;
+pub mod websocket;
@@ -0,0 +1,222 @@
+use std::{
+    fmt::Display,
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use chrono::Utc;
+
+use crate::{
+    http::{Credentials, HttpStatus, Request},
+    json::{Json, JsonNumber, JsonObject},
+};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LogFormat {
+    Text,
+    Common,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "common" => Ok(LogFormat::Common),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "Unsupported log format {}. Expected one of text, common or json",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Common => write!(f, "common"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+pub struct LogConfig {
+    pub sample_rate: u64,
+    pub log_bodies: bool,
+    pub redact_fields: Vec<String>,
+    pub format: LogFormat,
+}
+
+pub struct AccessEntry<'a> {
+    pub request: &'a Request,
+    pub client: IpAddr,
+    pub status: HttpStatus,
+    pub bytes: usize,
+    pub latency: Duration,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LogConfig {
+    pub fn new() -> LogConfig {
+        LogConfig {
+            sample_rate: 1,
+            log_bodies: false,
+            redact_fields: Vec::new(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+pub struct RequestLogger {
+    config: RwLock<LogConfig>,
+    successes: AtomicU64,
+}
+
+impl RequestLogger {
+    pub fn new(config: LogConfig) -> RequestLogger {
+        RequestLogger {
+            config: RwLock::new(config),
+            successes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn format(&self) -> LogFormat {
+        self.config.read().unwrap().format
+    }
+
+    pub fn reconfigure(&self, config: LogConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    pub fn should_log(&self, status: HttpStatus) -> bool {
+        if status.is_client_error() || status.is_server_error() {
+            return true;
+        }
+        let sample_rate = self.config.read().unwrap().sample_rate;
+        let count = self.successes.fetch_add(1, Ordering::Relaxed);
+        sample_rate <= 1 || count.is_multiple_of(sample_rate)
+    }
+
+    pub fn log(&self, entry: &AccessEntry, body: Option<&Json>) {
+        if !self.should_log(entry.status) {
+            return;
+        }
+        let config = self.config.read().unwrap();
+        let body = body
+            .filter(|_| config.log_bodies)
+            .map(|json| mask(json, &config.redact_fields));
+        let line = match config.format {
+            LogFormat::Text => text_line(entry, body),
+            LogFormat::Common => common_line(entry),
+            LogFormat::Json => json_line(entry, body),
+        };
+        if entry.status.is_client_error() || entry.status.is_server_error() {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    pub fn redact(&self, json: &Json) -> String {
+        self.masked(json).to_string()
+    }
+
+    pub fn masked(&self, json: &Json) -> Json {
+        mask(json, &self.config.read().unwrap().redact_fields)
+    }
+}
+
+fn mask(json: &Json, redact_fields: &[String]) -> Json {
+    match json {
+        Json::Object(obj) => {
+            let mut masked = JsonObject::new();
+            for (key, value) in obj.iter() {
+                masked[key.clone()] = if redact_fields.iter().any(|field| field == key) {
+                    Json::String("***".to_string())
+                } else {
+                    mask(value, redact_fields)
+                };
+            }
+            Json::Object(masked)
+        }
+        Json::List(list) => Json::List(list.iter().map(|item| mask(item, redact_fields)).collect()),
+        other => other.clone(),
+    }
+}
+
+fn text_line(entry: &AccessEntry, body: Option<Json>) -> String {
+    let mut line = format!(
+        "{} {} {} -> {} {}B {:.3}ms",
+        entry.client,
+        entry.request.method,
+        entry.request.route,
+        entry.status,
+        entry.bytes,
+        latency_millis(entry.latency)
+    );
+    if let Some(json) = body {
+        line += " body=";
+        line += &json.to_string();
+    }
+    line
+}
+
+fn json_line(entry: &AccessEntry, body: Option<Json>) -> String {
+    let mut obj = JsonObject::new();
+    obj["time".to_string()] = Json::String(Utc::now().to_rfc3339());
+    obj["client".to_string()] = Json::String(entry.client.to_string());
+    obj["method".to_string()] = Json::String(entry.request.method.to_string());
+    obj["route".to_string()] = Json::String(entry.request.route.clone());
+    obj["status".to_string()] = Json::Number(JsonNumber::Int(entry.status.code() as i64));
+    obj["bytes".to_string()] = Json::Number(JsonNumber::Int(entry.bytes as i64));
+    obj["latency_ms".to_string()] = Json::Number(JsonNumber::Float(latency_millis(entry.latency)));
+    if let Some(json) = body {
+        obj["body".to_string()] = json;
+    }
+    Json::Object(obj).canonical()
+}
+
+fn latency_millis(latency: Duration) -> f64 {
+    latency.as_secs_f64() * 1000.0
+}
+
+fn common_line(entry: &AccessEntry) -> String {
+    let user = match &entry.request.credentials {
+        Some(Credentials::Basic { user, .. } | Credentials::Certificate(user))
+            if !user.is_empty() =>
+        {
+            user.replace(' ', "+")
+        }
+        _ => "-".to_string(),
+    };
+    let bytes = if entry.bytes == 0 {
+        "-".to_string()
+    } else {
+        entry.bytes.to_string()
+    };
+    format!(
+        "{} - {} [{}] \"{} {} {}\" {} {}",
+        entry.client,
+        user,
+        Utc::now().format("%d/%b/%Y:%H:%M:%S %z"),
+        entry.request.method,
+        entry.request.route,
+        entry.request.http_version,
+        entry.status.code(),
+        bytes
+    )
+}
@@ -1,3 +1,290 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+
+use db6::{
+    archive,
+    cli::{Cli, CliCommand},
+    copy::{self, Remote},
+    db::DB,
+    export::{self, Export},
+    import::{self, ImportOptions},
+    json::Json,
+    query::Query,
+    root::Root,
+    server, telemetry,
+};
+
 fn main() {
-    println!("Hello, world!");
+    let cl = match Cli::new() {
+        Ok(cl) => cl,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let result = match &cl.command {
+        CliCommand::Backup(name, out, incremental) => Root::open(&cl.root).and_then(|root| {
+            let db = DB::open(root.path(), name)?
+                .ok_or_else(|| format!("The database {} does not exist", name))?;
+            let out = out.clone().unwrap_or_else(|| match incremental {
+                true => format!(
+                    "{}.{}.incremental.{}",
+                    name,
+                    Utc::now().format("%Y%m%dT%H%M%S"),
+                    archive::ARCHIVE_EXTENSION
+                ),
+                false => format!("{}.{}", name, archive::ARCHIVE_EXTENSION),
+            });
+            let written = match incremental {
+                true => archive::write_increment(&db, Path::new(&out)),
+                false => archive::write(&db, Path::new(&out)),
+            }?;
+            println!(
+                "Wrote {} files of the database {} to {} ({} bytes)",
+                written.files, name, written.path, written.bytes
+            );
+            Ok(())
+        }),
+        CliCommand::Copy(source, target, filter, batch_size, state) => {
+            copy_collection(source, target, filter.as_deref(), *batch_size, state.as_deref())
+        }
+        CliCommand::Export(name, collection, format, filter, fields, out) => Root::open(&cl.root)
+            .and_then(|root| {
+                let db = DB::open(root.path(), name)?
+                    .ok_or_else(|| format!("The database {} does not exist", name))?;
+                let export =
+                    Export::parse(format.as_deref(), filter.as_deref(), fields.as_deref())?;
+                if db.collection(collection)?.is_none() {
+                    return Err(format!(
+                        "The collection {} does not exist in the database {}",
+                        collection, name
+                    ));
+                }
+                let writer: Box<dyn Write> = match out {
+                    Some(out) => Box::new(
+                        File::create(out)
+                            .map_err(|err| format!("Could not create the file {}: {}", out, err))?,
+                    ),
+                    None => Box::new(io::stdout().lock()),
+                };
+                let count = export::write(&db, collection, &export, &mut BufWriter::new(writer))?;
+                if let Some(out) = out {
+                    println!(
+                        "Exported {} documents of the collection {} to {}",
+                        count, collection, out
+                    );
+                }
+                Ok(())
+            }),
+        CliCommand::Help => {
+            cl.help();
+            Ok(())
+        }
+        CliCommand::Import(name, collection, file, batch_size, compact) => Root::open(&cl.root)
+            .and_then(|root| {
+                let db = DB::open(root.path(), name)?
+                    .ok_or_else(|| format!("The database {} does not exist", name))?;
+                // Loading the engine locks the database, so a database that a server or another
+                // import has open is refused before any input is read.
+                db.engine()?;
+                let mut reader: Box<dyn BufRead> = match file.as_str() {
+                    "-" => Box::new(io::stdin().lock()),
+                    file => Box::new(BufReader::new(
+                        File::open(file)
+                            .map_err(|err| format!("Could not open the file {}: {}", file, err))?,
+                    )),
+                };
+                let options = ImportOptions {
+                    batch_size: *batch_size,
+                    max_line: usize::MAX,
+                };
+                let mut reported = 0;
+                let report = import::import(&db, collection, &mut reader, &options, |report, _| {
+                    for error in &report.errors[reported..] {
+                        eprintln!("Line {}: {}", error.line, error.message);
+                    }
+                    reported = report.errors.len();
+                    eprintln!(
+                        "Imported {} documents from {} lines",
+                        report.imported(),
+                        report.lines
+                    );
+                })?;
+                for error in &report.errors[reported..] {
+                    eprintln!("Line {}: {}", error.line, error.message);
+                }
+                if *compact {
+                    db.flush().and_then(|_| db.compact(2))?;
+                }
+                println!(
+                    "Imported {} documents into the collection {} ({} inserted, {} updated, {} failed)",
+                    report.imported(),
+                    collection,
+                    report.inserted,
+                    report.updated,
+                    report.failed
+                );
+                Ok(())
+            }),
+        CliCommand::New(name, password, insecure) => Root::open(&cl.root).and_then(|root| {
+            root.check_available(name)?;
+            let password = match password {
+                Some(password) => password.clone(),
+                None if *insecure => String::new(),
+                None => rpassword::prompt_password("Password for the new database: ")
+                    .map_err(|err| format!("Could not read the password: {}", err))?,
+            };
+            root.create(name, password).map(|_| ())
+        }),
+        CliCommand::Passwd(name, current, password) => Root::open(&cl.root).and_then(|root| {
+            let db = DB::open(root.path(), name)?
+                .ok_or_else(|| format!("The database {} does not exist", name))?;
+            let prompt = |prompt: &str| {
+                rpassword::prompt_password(prompt)
+                    .map_err(|err| format!("Could not read the password: {}", err))
+            };
+            let current = match current {
+                Some(current) => current.clone(),
+                None => prompt("Current password: ")?,
+            };
+            let password = match password {
+                Some(password) => password.clone(),
+                None => {
+                    let password = prompt("New password: ")?;
+                    if prompt("Repeat the new password: ")? != password {
+                        return Err("The new passwords do not match".to_string());
+                    }
+                    password
+                }
+            };
+            match db.change_password(&current, &password)? {
+                true => {
+                    println!("Changed the password of the database {}", name);
+                    Ok(())
+                }
+                false => Err("The current password is incorrect".to_string()),
+            }
+        }),
+        CliCommand::Restore(archive, increments, name) => Root::open(&cl.root).and_then(|root| {
+            let increments = increments.iter().map(PathBuf::from).collect::<Vec<_>>();
+            let db = archive::restore(&root, Path::new(archive), &increments, name.as_deref())?;
+            println!("Restored the database {} from {}", db.name(), archive);
+            Ok(())
+        }),
+        CliCommand::Run => server::listen(&cl),
+        CliCommand::Stats(name) => Root::open(&cl.root).and_then(|root| {
+            let db = DB::open(root.path(), name)?
+                .ok_or_else(|| format!("The database {} does not exist", name))?;
+            println!("{}", db.stats()?.to_json());
+            Ok(())
+        }),
+        CliCommand::Telemetry(action) => {
+            Root::open(&cl.root).and_then(|root| telemetry::run(root.path(), action))
+        }
+        CliCommand::VerifyBackup(archive, increments, queries) => {
+            verify_backup(archive, increments, queries)
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn copy_collection(
+    source: &str,
+    target: &str,
+    filter: Option<&str>,
+    batch_size: usize,
+    state: Option<&str>,
+) -> Result<(), String> {
+    let source = Remote::parse(source)?;
+    let target = Remote::parse(target)?;
+    let filter = filter
+        .map(|filter| {
+            Json::parse(filter.as_bytes())
+                .map_err(|err| format!("The filter is not valid JSON: {}", err))
+        })
+        .transpose()?;
+    let mut started = false;
+    let report = copy::copy(
+        &source,
+        &target,
+        filter.as_ref(),
+        batch_size,
+        state.map(Path::new),
+        |report| {
+            if !started && report.resumed {
+                eprintln!("Resuming the copy after {} documents", report.copied);
+            }
+            started = true;
+            eprintln!(
+                "Copied {} documents and caught up with {} changes",
+                report.copied, report.caught_up
+            );
+        },
+    )?;
+    println!(
+        "Copied {} documents from {} to {} and caught up with {} changes",
+        report.copied,
+        source.address(),
+        target.address(),
+        report.caught_up
+    );
+    Ok(())
+}
+
+// Prints what the restored backup holds and what the queries returned, and fails unless every
+// check and query passed.
+fn verify_backup(
+    archive: &str,
+    increments: &[String],
+    queries: &[(String, String)],
+) -> Result<(), String> {
+    let increments = increments.iter().map(PathBuf::from).collect::<Vec<_>>();
+    let parsed = queries
+        .iter()
+        .map(|(collection, query)| {
+            let parsed = Json::parse(query.as_bytes())
+                .map_err(|err| err.to_string())
+                .and_then(|query| Query::parse(Some(&query)))
+                .map_err(|err| format!("The query {} is invalid: {}", query, err))?;
+            Ok((collection.clone(), parsed))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let verified = archive::verify_backup(Path::new(archive), &increments, &parsed)
+        .map_err(|err| format!("{}\nFAIL", err))?;
+    println!("Restored the database {} from {}", verified.name, archive);
+    for collection in &verified.collections {
+        println!(
+            "Collection {}: {} documents, {} problems",
+            collection.name,
+            collection.documents,
+            collection.problems.len()
+        );
+        for problem in &collection.problems {
+            println!("    {}", problem);
+        }
+    }
+    for ((collection, query), result) in queries.iter().zip(&verified.queries) {
+        match result {
+            Ok(count) => println!(
+                "Query {}:{} returned {} documents",
+                collection, query, count
+            ),
+            Err(err) => println!("Query {}:{} failed: {}", collection, query, err),
+        }
+    }
+    match verified.passed() {
+        true => {
+            println!("PASS");
+            Ok(())
+        }
+        false => Err("FAIL".to_string()),
+    }
 }
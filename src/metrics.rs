@@ -0,0 +1,170 @@
+use std::{
+    cell::Cell,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::json::{Json, JsonNumber, JsonObject};
+
+pub const SLOW_REQUEST_THRESHOLD: Duration = Duration::from_millis(500);
+
+static METRICS: LazyLock<Mutex<PhaseMetrics>> = LazyLock::new(|| Mutex::new(PhaseMetrics::new()));
+
+thread_local! {
+    // Validation and storage happen deep inside the database, where the timings of the request
+    // are not at hand, so they are collected per thread and taken once the request is handled.
+    static SPENT: Cell<RequestTimings> = Cell::new(RequestTimings::new());
+    static CURRENT: Cell<Option<Phase>> = const { Cell::new(None) };
+    static CLAIMED: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Phase {
+    Validate,
+    Storage,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct RequestTimings {
+    pub parse: Duration,
+    pub validate: Duration,
+    pub storage: Duration,
+    pub serialize: Duration,
+}
+
+impl RequestTimings {
+    pub fn new() -> RequestTimings {
+        RequestTimings::default()
+    }
+
+    pub fn time<T>(phase: &mut Duration, action: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = action();
+        *phase += start.elapsed();
+        result
+    }
+
+    pub fn total(&self) -> Duration {
+        self.parse + self.validate + self.storage + self.serialize
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct PhaseMetrics {
+    pub requests: u64,
+    pub slow_requests: u64,
+    pub totals: RequestTimings,
+    pub max: RequestTimings,
+}
+
+impl PhaseMetrics {
+    pub fn new() -> PhaseMetrics {
+        PhaseMetrics::default()
+    }
+
+    fn add(&mut self, timings: &RequestTimings) {
+        self.requests += 1;
+        self.totals.parse += timings.parse;
+        self.totals.validate += timings.validate;
+        self.totals.storage += timings.storage;
+        self.totals.serialize += timings.serialize;
+        self.max.parse = self.max.parse.max(timings.parse);
+        self.max.validate = self.max.validate.max(timings.validate);
+        self.max.storage = self.max.storage.max(timings.storage);
+        self.max.serialize = self.max.serialize.max(timings.serialize);
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["requests".to_string()] = Json::Number(JsonNumber::Int(self.requests as i64));
+        obj["slow_requests".to_string()] = Json::Number(JsonNumber::Int(self.slow_requests as i64));
+        for (name, total, max) in [
+            ("parse", self.totals.parse, self.max.parse),
+            ("validate", self.totals.validate, self.max.validate),
+            ("storage", self.totals.storage, self.max.storage),
+            ("serialize", self.totals.serialize, self.max.serialize),
+        ] {
+            let mut phase = JsonObject::new();
+            phase["total_ms".to_string()] = millis(total);
+            phase["avg_ms".to_string()] = if self.requests == 0 {
+                millis(Duration::ZERO)
+            } else {
+                Json::Number(JsonNumber::Float(
+                    total.as_secs_f64() * 1000.0 / self.requests as f64,
+                ))
+            };
+            phase["max_ms".to_string()] = millis(max);
+            obj[name.to_string()] = Json::Object(phase);
+        }
+        Json::Object(obj)
+    }
+}
+
+fn millis(duration: Duration) -> Json {
+    Json::Number(JsonNumber::Float(duration.as_secs_f64() * 1000.0))
+}
+
+// Times the action as part of the phase of the request handled on this thread. A call inside a
+// call for the same phase is part of it, so the reads a write makes while it is applied are not
+// counted twice, while the time of a call for another phase is only counted for that phase.
+pub fn spend<T>(phase: Phase, action: impl FnOnce() -> T) -> T {
+    let outer = CURRENT.replace(Some(phase));
+    if outer == Some(phase) {
+        return action();
+    }
+    let claimed = CLAIMED.take();
+    let start = Instant::now();
+    let result = action();
+    let elapsed = start.elapsed();
+    let own = elapsed.saturating_sub(CLAIMED.replace(claimed + elapsed));
+    CURRENT.set(outer);
+    SPENT.with(|spent| {
+        let mut timings = spent.get();
+        match phase {
+            Phase::Validate => timings.validate += own,
+            Phase::Storage => timings.storage += own,
+        }
+        spent.set(timings);
+    });
+    result
+}
+
+// Takes the time spent on this thread since the last call. A request that panicked inside a
+// timed action left its phase open, so that is cleared as well.
+pub fn take_spent() -> RequestTimings {
+    CURRENT.set(None);
+    CLAIMED.set(Duration::ZERO);
+    SPENT.take()
+}
+
+// The time spent on this thread since the last take, left in place for the request.
+pub fn spent() -> RequestTimings {
+    SPENT.get()
+}
+
+pub fn record(route: &str, timings: &RequestTimings) {
+    let mut metrics = match METRICS.lock() {
+        Ok(metrics) => metrics,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    metrics.add(timings);
+    if timings.total() >= SLOW_REQUEST_THRESHOLD {
+        metrics.slow_requests += 1;
+        eprintln!(
+            "Slow request to {} took {:?} (parse {:?}, validate {:?}, storage {:?}, serialize {:?})",
+            route,
+            timings.total(),
+            timings.parse,
+            timings.validate,
+            timings.storage,
+            timings.serialize
+        );
+    }
+}
+
+pub fn snapshot() -> PhaseMetrics {
+    match METRICS.lock() {
+        Ok(metrics) => metrics.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
@@ -0,0 +1,262 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auth, cache, compression, cors,
+    error::ApiError,
+    etag,
+    http::{Credentials, HttpMethod, HttpStatus, Request, Response},
+    json::{Json, JsonNumber, JsonObject},
+    metrics, quota, range, ratelimit,
+    server::Server,
+};
+
+pub const EXPLAIN_HEADER: &str = "X-Db6-Explain";
+pub const TRACE_HEADER: &str = "X-Db6-Trace";
+
+pub type Middleware = Box<dyn Fn(&Request, Next) -> Response + Send + Sync>;
+
+pub struct Next<'a> {
+    pub server: &'a Server,
+    layers: &'a [Middleware],
+    endpoint: &'a dyn Fn(&Request) -> Response,
+}
+
+impl Next<'_> {
+    pub fn run(self, request: &Request) -> Response {
+        match self.layers.split_first() {
+            Some((layer, rest)) => layer(
+                request,
+                Next {
+                    layers: rest,
+                    ..self
+                },
+            ),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+pub fn run(
+    server: &Server,
+    request: &Request,
+    endpoint: &dyn Fn(&Request) -> Response,
+) -> Response {
+    Next {
+        server,
+        layers: &server.middleware,
+        endpoint,
+    }
+    .run(request)
+}
+
+pub fn defaults() -> Vec<Middleware> {
+    vec![
+        Box::new(cors),
+        Box::new(rate_limit),
+        Box::new(admin),
+        Box::new(explain),
+        Box::new(compression),
+        Box::new(range),
+        Box::new(etag),
+        Box::new(maintenance),
+        Box::new(quota),
+    ]
+}
+
+pub fn cors(request: &Request, next: Next) -> Response {
+    let config = cors::resolve(next.server, request);
+    if !config.is_enabled() {
+        return next.run(request);
+    }
+    let mut resp = if cors::is_preflight(request) {
+        cors::preflight(
+            &config,
+            request,
+            next.server.router.allowed_methods(&request.route),
+        )
+    } else {
+        next.run(request)
+    };
+    cors::apply(&config, request, &mut resp);
+    resp
+}
+
+pub fn compression(request: &Request, next: Next) -> Response {
+    let mut resp = next.run(request);
+    compression::compress_response(request, &mut resp);
+    resp
+}
+
+pub fn range(request: &Request, next: Next) -> Response {
+    let mut resp = next.run(request);
+    range::apply(request, &mut resp);
+    resp
+}
+
+pub fn etag(request: &Request, next: Next) -> Response {
+    let mut resp = next.run(request);
+    etag::apply(request, &mut resp);
+    resp
+}
+
+pub fn maintenance(request: &Request, next: Next) -> Response {
+    match next.server.maintenance_message() {
+        Some(message) if !request.route.starts_with("/_") => {
+            ApiError::new(HttpStatus::ServiceUnavailable, message)
+                .with_code("maintenance")
+                .to_response()
+        }
+        _ => next.run(request),
+    }
+}
+
+pub fn rate_limit(request: &Request, next: Next) -> Response {
+    if is_health_check(request) {
+        return next.run(request);
+    }
+    let limits = &next.server.rate_limits;
+    ratelimit::apply(limits, request, || next.run(request))
+}
+
+pub fn quota(request: &Request, next: Next) -> Response {
+    match quota::apply(next.server, request) {
+        Some(resp) => resp,
+        None => next.run(request),
+    }
+}
+
+fn wants_explain(request: &Request) -> bool {
+    matches!(request.header(EXPLAIN_HEADER), Some(value) if value.eq_ignore_ascii_case("true") || value == "1")
+}
+
+// Answers with the plan of the request and how long its phases took. A request that could change
+// data (anything but GET, HEAD and OPTIONS) is only planned: its handler is not run and the plan
+// comes back as a 200 with executed set to false.
+pub fn explain(request: &Request, next: Next) -> Response {
+    if !wants_explain(request) {
+        return next.run(request);
+    }
+    let mut trace = JsonObject::new();
+    trace["method".to_string()] = Json::String(request.method.to_string());
+    trace["route".to_string()] = Json::String(request.route.clone());
+    match next.server.router.resolve(&request.method, &request.route) {
+        Some((route, params)) => {
+            let mut plan = JsonObject::new();
+            plan["handler".to_string()] =
+                Json::String(format!("{} {}", route.method, route.pattern));
+            let mut params_obj = JsonObject::new();
+            for (name, value) in params {
+                params_obj[name] = Json::String(value);
+            }
+            plan["params".to_string()] = Json::Object(params_obj);
+            trace["plan".to_string()] = Json::Object(plan);
+        }
+        None => {
+            trace["plan".to_string()] = Json::Null;
+        }
+    }
+    let safe = matches!(
+        request.method,
+        HttpMethod::GET | HttpMethod::HEAD | HttpMethod::OPTIONS
+    );
+    trace["executed".to_string()] = Json::Bool(safe);
+    if !safe {
+        let mut obj = JsonObject::new();
+        obj["explain".to_string()] = Json::Object(trace);
+        return Response::json(HttpStatus::Ok, Json::Object(obj));
+    }
+    let spent = metrics::spent();
+    let (hits, misses) = cache::thread_lookups();
+    let start = Instant::now();
+    let mut resp = next.run(request);
+    let elapsed = start.elapsed();
+    let after = metrics::spent();
+    let millis =
+        |duration: Duration| Json::Number(JsonNumber::Float(duration.as_secs_f64() * 1000.0));
+    // Parsing is done before and serializing after the middleware, so only the phases of the
+    // handler are in the trace.
+    let mut timings = JsonObject::new();
+    timings["handler_ms".to_string()] = millis(elapsed);
+    timings["validate_ms".to_string()] = millis(after.validate - spent.validate);
+    timings["storage_ms".to_string()] = millis(after.storage - spent.storage);
+    trace["timings".to_string()] = Json::Object(timings);
+    let (hits_after, misses_after) = cache::thread_lookups();
+    let mut lookups = JsonObject::new();
+    lookups["hits".to_string()] = Json::Number(JsonNumber::Int((hits_after - hits) as i64));
+    lookups["misses".to_string()] = Json::Number(JsonNumber::Int((misses_after - misses) as i64));
+    trace["cache".to_string()] = Json::Object(lookups);
+    trace["status".to_string()] = Json::Number(JsonNumber::Int(resp.status.code() as i64));
+    resp.set_header(TRACE_HEADER, Json::Object(trace).canonical());
+    resp
+}
+
+fn is_health_check(request: &Request) -> bool {
+    request.route == "/_status" && matches!(request.method, HttpMethod::GET | HttpMethod::HEAD)
+}
+
+pub fn is_admin_path(route: &str) -> bool {
+    (route.starts_with("/_") && route != auth::AUTH_ROUTE)
+        || route == "/admin"
+        || route.starts_with("/admin/")
+}
+
+pub fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Without an admin token, the admin endpoints only answer clients on the same machine, so a
+// server that is reachable from elsewhere is never open by default.
+pub fn admin_access(request: &Request, token: Option<&str>) -> Result<(), ApiError> {
+    if !is_admin_path(&request.route) || is_health_check(request) {
+        return Ok(());
+    }
+    match token {
+        Some(expected) => match &request.credentials {
+            Some(Credentials::Bearer(token)) if token_matches(token, expected) => Ok(()),
+            _ => Err(ApiError::new(
+                HttpStatus::Unauthorized,
+                format!("The endpoint {} requires the admin token", request.route),
+            )),
+        },
+        None if request.client.is_some_and(is_local) => Ok(()),
+        None => Err(ApiError::new(
+            HttpStatus::Forbidden,
+            format!(
+                "The endpoint {} is only open to local clients when the server has no admin token",
+                request.route
+            ),
+        )),
+    }
+}
+
+fn is_local(client: IpAddr) -> bool {
+    match client {
+        IpAddr::V4(client) => client.is_loopback(),
+        IpAddr::V6(client) => client
+            .to_ipv4_mapped()
+            .map_or(client.is_loopback(), |client| client.is_loopback()),
+    }
+}
+
+pub fn admin(request: &Request, next: Next) -> Response {
+    let settings = next.server.settings();
+    match admin_access(request, settings.admin_token.as_deref()) {
+        Ok(()) => next.run(request),
+        Err(err) => {
+            let unauthorized = err.status == HttpStatus::Unauthorized;
+            let mut resp = err.to_response();
+            if unauthorized {
+                resp.set_header("WWW-Authenticate", "Bearer realm=\"db6\"".to_string());
+            }
+            resp
+        }
+    }
+}
@@ -0,0 +1,424 @@
+use crate::{
+    db::{DB, ID_FIELD, WriteError},
+    json::{Json, JsonNumber, JsonObject},
+    query::{Projection, Query, equals, lookup, number, sorted},
+};
+
+pub enum Update {
+    Set(Vec<String>, Json),
+    Unset(Vec<String>),
+    Inc(Vec<String>, Json),
+    Push(Vec<String>, Json),
+    Pull(Vec<String>, Json),
+}
+
+// A patch is either a JSON Merge Patch (RFC 7396) or a document of update operators such as
+// {"$set": {"address.city": "Oslo"}, "$inc": {"visits": 1}}, whose fields are dotted paths as
+// in query filters. A patch whose fields all start with $ is a document of operators.
+pub enum Patch {
+    Merge(JsonObject),
+    Operators(Vec<Update>),
+}
+
+impl Patch {
+    pub fn parse(json: &Json) -> Result<Patch, String> {
+        let Json::Object(obj) = json else {
+            return Err("Expected the patch as a JSON object".to_string());
+        };
+        let operators = obj.iter().filter(|(key, _)| key.starts_with('$')).count();
+        if operators == 0 {
+            return Ok(Patch::Merge(obj.clone()));
+        }
+        if operators < obj.len() {
+            return Err(
+                "A patch either has update operators or fields to merge, not both".to_string(),
+            );
+        }
+        let mut updates = Vec::new();
+        for (op, fields) in sorted(obj) {
+            let fields = match (op.as_str(), fields) {
+                ("$unset", Json::List(paths)) => paths
+                    .iter()
+                    .map(|path| match path {
+                        Json::String(path) => Ok((path.clone(), Json::Null)),
+                        _ => Err("The operator $unset expects field paths".to_string()),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+                (_, Json::Object(fields)) => sorted(fields)
+                    .into_iter()
+                    .map(|(path, value)| (path.clone(), value.clone()))
+                    .collect(),
+                _ => {
+                    return Err(format!(
+                        "The operator {} expects an object of field paths",
+                        op
+                    ));
+                }
+            };
+            for (path, value) in fields {
+                let segments = path.split('.').map(str::to_string).collect::<Vec<_>>();
+                if segments.iter().any(String::is_empty) {
+                    return Err(format!("The field path {} is invalid", path));
+                }
+                if segments[0] == ID_FIELD {
+                    return Err(format!("The {} field cannot be updated", ID_FIELD));
+                }
+                updates.push(match (op.as_str(), value) {
+                    ("$set", value) => Update::Set(segments, value),
+                    ("$unset", _) => Update::Unset(segments),
+                    ("$inc", value @ Json::Number(_)) => Update::Inc(segments, value),
+                    ("$inc", _) => {
+                        return Err(format!("The operator $inc expects a number for {}", path));
+                    }
+                    ("$push", value) => Update::Push(segments, value),
+                    ("$pull", value) => Update::Pull(segments, value),
+                    (op, _) => {
+                        return Err(format!(
+                            "Unknown update operator {}. The operators are $set, $unset, $inc, $push and $pull",
+                            op
+                        ));
+                    }
+                });
+            }
+        }
+        Ok(Patch::Operators(updates))
+    }
+
+    // Returns a patched copy of the document. Patches cannot change the ID of a document.
+    pub fn apply(&self, document: &Json) -> Result<Json, String> {
+        let mut patched = document.clone();
+        match self {
+            Patch::Merge(fields) => {
+                let current = match document {
+                    Json::Object(obj) => obj.get(ID_FIELD),
+                    _ => None,
+                };
+                if let Some(id) = fields.get(ID_FIELD)
+                    && current.is_none_or(|current| !equals(current, id))
+                {
+                    return Err(format!("The {} field cannot be updated", ID_FIELD));
+                }
+                merge(&mut patched, fields);
+            }
+            Patch::Operators(updates) => {
+                for update in updates {
+                    update.apply(&mut patched)?;
+                }
+            }
+        }
+        Ok(patched)
+    }
+}
+
+fn merge(target: &mut Json, fields: &JsonObject) {
+    if !matches!(target, Json::Object(_)) {
+        *target = Json::Object(JsonObject::new());
+    }
+    let Json::Object(obj) = target else {
+        return;
+    };
+    for (field, value) in fields.iter() {
+        match value {
+            Json::Null => {
+                obj.remove(field);
+            }
+            Json::Object(fields) => match obj.get_mut(field) {
+                Some(existing) => merge(existing, fields),
+                None => {
+                    let mut part = Json::Object(JsonObject::new());
+                    merge(&mut part, fields);
+                    obj[field.clone()] = part;
+                }
+            },
+            value => {
+                obj[field.clone()] = value.clone();
+            }
+        }
+    }
+}
+
+impl Update {
+    fn path(&self) -> &[String] {
+        match self {
+            Update::Set(path, _)
+            | Update::Unset(path)
+            | Update::Inc(path, _)
+            | Update::Push(path, _)
+            | Update::Pull(path, _) => path,
+        }
+    }
+
+    fn apply(&self, document: &mut Json) -> Result<(), String> {
+        let path = self.path();
+        let dotted = path.join(".");
+        let (last, parents) = path.split_last().unwrap();
+        // Removing something below a missing field leaves the document as it is.
+        let create = matches!(self, Update::Set(..) | Update::Inc(..) | Update::Push(..));
+        let Some(parent) = parent(document, parents, create)
+            .map_err(|err| format!("Could not update {}: {}", dotted, err))?
+        else {
+            return Ok(());
+        };
+        let slot = match parent {
+            Json::Object(obj) => {
+                if let Update::Unset(_) = self {
+                    obj.remove(last);
+                    return Ok(());
+                }
+                if obj.get(last).is_none() {
+                    if !create {
+                        return Ok(());
+                    }
+                    obj[last.clone()] = Json::None;
+                }
+                obj.get_mut(last).unwrap()
+            }
+            Json::List(items) => {
+                let ind = index(items, last)
+                    .map_err(|err| format!("Could not update {}: {}", dotted, err))?;
+                if let Update::Unset(_) = self {
+                    items[ind] = Json::Null;
+                    return Ok(());
+                }
+                &mut items[ind]
+            }
+            _ => {
+                return Err(format!(
+                    "Could not update {}: {} is not a field of an object",
+                    dotted, last
+                ));
+            }
+        };
+        match (self, slot) {
+            (Update::Set(_, value), slot) => {
+                *slot = value.clone();
+            }
+            (Update::Inc(_, amount) | Update::Push(_, amount), slot @ Json::None) => {
+                *slot = match self {
+                    Update::Push(..) => Json::List(vec![amount.clone()]),
+                    _ => amount.clone(),
+                };
+            }
+            (Update::Inc(_, Json::Number(amount)), Json::Number(num)) => {
+                *num = match (&*num, amount) {
+                    (JsonNumber::Int(num), JsonNumber::Int(amount)) => num
+                        .checked_add(*amount)
+                        .map(JsonNumber::Int)
+                        .ok_or_else(|| format!("Incrementing {} overflows", dotted))?,
+                    (num, amount) => JsonNumber::Float(number(num) + number(amount)),
+                };
+            }
+            (Update::Inc(..), _) => {
+                return Err(format!(
+                    "Could not increment {}, which is not a number",
+                    dotted
+                ));
+            }
+            (Update::Push(_, value), Json::List(items)) => {
+                items.push(value.clone());
+            }
+            (Update::Pull(_, value), Json::List(items)) => {
+                items.retain(|item| !equals(item, value));
+            }
+            _ => {
+                return Err(format!("Could not update {}, which is not a list", dotted));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Walks down to the value that holds the last field of a path. Missing objects on the way are
+// created when asked to, and otherwise end the walk.
+fn parent<'a>(
+    document: &'a mut Json,
+    path: &[String],
+    create: bool,
+) -> Result<Option<&'a mut Json>, String> {
+    let mut value = document;
+    for segment in path {
+        value = match value {
+            Json::Object(obj) => {
+                if obj.get(segment).is_none() {
+                    if !create {
+                        return Ok(None);
+                    }
+                    obj[segment.clone()] = Json::Object(JsonObject::new());
+                }
+                obj.get_mut(segment).unwrap()
+            }
+            Json::List(items) => {
+                let ind = index(items, segment)?;
+                &mut items[ind]
+            }
+            _ => {
+                return Err(format!("{} is not a field of an object", segment));
+            }
+        };
+    }
+    Ok(Some(value))
+}
+
+fn index(items: &[Json], segment: &str) -> Result<usize, String> {
+    segment
+        .parse::<usize>()
+        .ok()
+        .filter(|ind| *ind < items.len())
+        .ok_or_else(|| format!("{} is not an index of the list", segment))
+}
+
+// Which version of the document a find-and-modify returns.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Image {
+    Before,
+    After,
+}
+
+// Updates the first document that matches the filter, in the sort order, and returns it. The
+// search runs outside the lock, so the document is checked again before it is updated, and the
+// search starts over if a concurrent write made it stop matching. With upsert, a new document is
+// made from an empty one when no document matches.
+pub struct FindAndModify {
+    pub query: Query,
+    pub patch: Patch,
+    pub upsert: bool,
+    pub image: Image,
+    pub projection: Option<Projection>,
+}
+
+// The document a find-and-modify returns, and whether it had to be inserted.
+pub struct Found {
+    pub id: String,
+    pub document: Option<Json>,
+    pub inserted: bool,
+}
+
+impl FindAndModify {
+    // The request has the filter, sort and fields of a query, the update as a patch, whether to
+    // upsert, and which image to return, which defaults to the document after the update.
+    pub fn parse(json: &Json) -> Result<FindAndModify, String> {
+        let Json::Object(obj) = json else {
+            return Err("Expected the find-and-modify request as a JSON object".to_string());
+        };
+        let mut query = JsonObject::new();
+        let mut patch = None;
+        let mut upsert = false;
+        let mut image = Image::After;
+        let mut projection = None;
+        for (field, value) in sorted(obj) {
+            match (field.as_str(), value) {
+                ("filter" | "sort", value) => {
+                    query[field.clone()] = value.clone();
+                }
+                ("fields", fields) => {
+                    projection = Some(Projection::parse(fields)?);
+                }
+                ("update", update) => {
+                    patch = Some(Patch::parse(update)?);
+                }
+                ("upsert", Json::Bool(value)) => {
+                    upsert = *value;
+                }
+                ("return", Json::String(value)) if value == "before" => {
+                    image = Image::Before;
+                }
+                ("return", Json::String(value)) if value == "after" => {
+                    image = Image::After;
+                }
+                ("upsert", _) => {
+                    return Err("The upsert field should be a boolean".to_string());
+                }
+                ("return", _) => {
+                    return Err("The return field should be before or after".to_string());
+                }
+                (field, _) => {
+                    return Err(format!("Unknown find-and-modify field {}", field));
+                }
+            }
+        }
+        let Some(patch) = patch else {
+            return Err("The find-and-modify request has no update".to_string());
+        };
+        query["limit".to_string()] = Json::Number(JsonNumber::Int(1));
+        Ok(FindAndModify {
+            query: Query::parse(Some(&Json::Object(query)))?,
+            patch,
+            upsert,
+            image,
+            projection,
+        })
+    }
+
+    // Returns None when no document matches and upsert is off.
+    pub fn run(&self, db: &DB, collection: &str) -> Result<Option<Found>, WriteError> {
+        loop {
+            let page = self.query.run(db, collection)?;
+            let Some(id) =
+                page.documents
+                    .first()
+                    .and_then(|document| match lookup(document, ID_FIELD) {
+                        Some(Json::String(id)) => Some(id.clone()),
+                        _ => None,
+                    })
+            else {
+                return match self.upsert {
+                    true => self.insert(db, collection).map(Some),
+                    false => Ok(None),
+                };
+            };
+            let modified = db.modify(collection, &id, |current| match current {
+                Some(current) if self.query.filter.matches(current) => {
+                    self.patch.apply(current).map(Some).map_err(|err| {
+                        WriteError::Rejected(format!(
+                            "The patch of the document {} in the collection {} failed: {}",
+                            id, collection, err
+                        ))
+                    })
+                }
+                _ => Ok(None),
+            })?;
+            if let Some(modified) = modified {
+                let document = match self.image {
+                    Image::Before => modified.before,
+                    Image::After => Some(modified.after),
+                };
+                return Ok(Some(Found {
+                    id,
+                    document: document.map(|document| self.project(document)),
+                    inserted: false,
+                }));
+            }
+        }
+    }
+
+    fn insert(&self, db: &DB, collection: &str) -> Result<Found, WriteError> {
+        let document = self
+            .patch
+            .apply(&Json::Object(JsonObject::new()))
+            .map_err(|err| {
+                WriteError::Rejected(format!(
+                    "The patch of a new document in the collection {} failed: {}",
+                    collection, err
+                ))
+            })?;
+        let id = db.insert(collection, &document)?;
+        let document = match self.image {
+            Image::Before => None,
+            Image::After => db
+                .get(collection, &id)?
+                .map(|document| self.project(document)),
+        };
+        Ok(Found {
+            id,
+            document,
+            inserted: true,
+        })
+    }
+
+    fn project(&self, document: Json) -> Json {
+        match &self.projection {
+            Some(projection) => projection.apply(&document),
+            None => document,
+        }
+    }
+}
@@ -0,0 +1,902 @@
+use std::{cmp::Ordering, ops::Bound};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use regex::Regex;
+
+use crate::{
+    db::{DB, ID_FIELD},
+    json::{Json, JsonNumber, JsonObject},
+    schema::{Schema, type_name},
+};
+
+pub enum Condition {
+    Eq(Json),
+    Ne(Json),
+    Gt(Json),
+    Gte(Json),
+    Lt(Json),
+    Lte(Json),
+    In(Vec<Json>),
+    Nin(Vec<Json>),
+    Exists(bool),
+    Regex(Regex),
+    Prefix(String),
+}
+
+impl Condition {
+    fn parse(op: &str, value: &Json) -> Result<Condition, String> {
+        let list = || match value {
+            Json::List(values) => Ok(values.clone()),
+            _ => Err(format!("The operator {} expects a list of values", op)),
+        };
+        let string = || match value {
+            Json::String(value) => Ok(value.clone()),
+            _ => Err(format!("The operator {} expects a string", op)),
+        };
+        Ok(match op {
+            "$eq" => Condition::Eq(value.clone()),
+            "$ne" => Condition::Ne(value.clone()),
+            "$gt" => Condition::Gt(value.clone()),
+            "$gte" => Condition::Gte(value.clone()),
+            "$lt" => Condition::Lt(value.clone()),
+            "$lte" => Condition::Lte(value.clone()),
+            "$in" => Condition::In(list()?),
+            "$nin" => Condition::Nin(list()?),
+            "$exists" => match value {
+                Json::Bool(exists) => Condition::Exists(*exists),
+                _ => {
+                    return Err("The operator $exists expects true or false".to_string());
+                }
+            },
+            "$regex" => Condition::Regex(Regex::new(&string()?).map_err(|err| {
+                format!("The pattern of the operator $regex is invalid: {}", err)
+            })?),
+            "$prefix" => Condition::Prefix(string()?),
+            _ => {
+                return Err(format!("Unknown operator {}", op));
+            }
+        })
+    }
+
+    fn matches(&self, value: Option<&Json>) -> bool {
+        let ordered = |expected: &Json, accept: fn(Ordering) -> bool| {
+            value
+                .and_then(|value| compare(value, expected))
+                .is_some_and(accept)
+        };
+        match self {
+            Condition::Eq(expected) => value.is_some_and(|value| equals(value, expected)),
+            Condition::Ne(expected) => !value.is_some_and(|value| equals(value, expected)),
+            Condition::Gt(expected) => ordered(expected, Ordering::is_gt),
+            Condition::Gte(expected) => ordered(expected, Ordering::is_ge),
+            Condition::Lt(expected) => ordered(expected, Ordering::is_lt),
+            Condition::Lte(expected) => ordered(expected, Ordering::is_le),
+            Condition::In(options) => {
+                value.is_some_and(|value| options.iter().any(|option| equals(value, option)))
+            }
+            Condition::Nin(options) => {
+                !value.is_some_and(|value| options.iter().any(|option| equals(value, option)))
+            }
+            Condition::Exists(exists) => value.is_some() == *exists,
+            Condition::Regex(pattern) => {
+                matches!(value, Some(Json::String(value)) if pattern.is_match(value))
+            }
+            Condition::Prefix(prefix) => {
+                matches!(value, Some(Json::String(value)) if value.starts_with(prefix.as_str()))
+            }
+        }
+    }
+}
+
+// A filter is a JSON object. Its fields are dotted paths into the document, matched either
+// against a plain value or against an object of operators such as {"$gte": 18}. The fields of
+// a filter must all match, and $and, $or and $not combine filters.
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Field(String, Vec<Condition>),
+}
+
+impl Filter {
+    pub fn all() -> Filter {
+        Filter::And(Vec::new())
+    }
+
+    pub fn parse(json: &Json) -> Result<Filter, String> {
+        let Json::Object(obj) = json else {
+            return Err("The filter should be a JSON object".to_string());
+        };
+        let mut filters = Vec::new();
+        for (key, value) in sorted(obj) {
+            let filter = match key.as_str() {
+                "$and" | "$or" => {
+                    let Json::List(items) = value else {
+                        return Err(format!("The operator {} expects a list of filters", key));
+                    };
+                    let items = items.iter().map(Filter::parse).collect::<Result<_, _>>()?;
+                    match key.as_str() {
+                        "$and" => Filter::And(items),
+                        _ => Filter::Or(items),
+                    }
+                }
+                "$not" => Filter::Not(Box::new(Filter::parse(value)?)),
+                key if key.starts_with('$') => {
+                    return Err(format!("Unknown operator {}", key));
+                }
+                key => Filter::Field(key.to_string(), Filter::conditions(key, value)?),
+            };
+            filters.push(filter);
+        }
+        Ok(match filters.len() {
+            1 => filters.pop().unwrap(),
+            _ => Filter::And(filters),
+        })
+    }
+
+    fn conditions(field: &str, value: &Json) -> Result<Vec<Condition>, String> {
+        let operators = match value {
+            Json::Object(obj) if obj.iter().any(|(key, _)| key.starts_with('$')) => obj,
+            value => {
+                return Ok(vec![Condition::Eq(value.clone())]);
+            }
+        };
+        sorted(operators)
+            .into_iter()
+            .map(|(op, value)| match op.starts_with('$') {
+                true => Condition::parse(op, value),
+                false => Err(format!(
+                    "The condition on the field {} mixes operators with the field {}",
+                    field, op
+                )),
+            })
+            .collect()
+    }
+
+    pub fn matches(&self, document: &Json) -> bool {
+        match self {
+            Filter::And(filters) => filters.iter().all(|filter| filter.matches(document)),
+            Filter::Or(filters) => filters.iter().any(|filter| filter.matches(document)),
+            Filter::Not(filter) => !filter.matches(document),
+            Filter::Field(path, conditions) => {
+                let value = lookup(document, path);
+                conditions.iter().all(|condition| condition.matches(value))
+            }
+        }
+    }
+}
+
+const FILTER_OPERATORS: [&str; 3] = ["$and", "$or", "$not"];
+const CONDITION_OPERATORS: [&str; 11] = [
+    "$eq", "$ne", "$gt", "$gte", "$lt", "$lte", "$in", "$nin", "$exists", "$regex", "$prefix",
+];
+
+// A part of a filter that is refused, or that is accepted but cannot match what was meant, such
+// as a comparison with a value of another type than the schema gives the field.
+pub struct Lint {
+    pub path: String,
+    pub message: String,
+    pub suggestion: Option<String>,
+}
+
+impl Lint {
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["path".to_string()] = Json::String(self.path.clone());
+        obj["message".to_string()] = Json::String(self.message.clone());
+        if let Some(suggestion) = &self.suggestion {
+            obj["suggestion".to_string()] = Json::String(suggestion.clone());
+        }
+        Json::Object(obj)
+    }
+}
+
+// Lints the filter as it is given, so that it also works on filters that do not parse. The
+// paths are dotted paths into the filter, such as $or.0.age.$gte.
+pub fn lint(filter: &Json, schema: Option<&Schema>) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    lint_filter(filter, "", schema, &mut lints);
+    lints
+}
+
+fn join(at: &str, key: &str) -> String {
+    match at {
+        "" => key.to_string(),
+        at => format!("{}.{}", at, key),
+    }
+}
+
+fn lint_filter(filter: &Json, at: &str, schema: Option<&Schema>, lints: &mut Vec<Lint>) {
+    let Json::Object(obj) = filter else {
+        return;
+    };
+    for (key, value) in sorted(obj) {
+        let path = join(at, key);
+        match (key.as_str(), value) {
+            ("$and" | "$or", Json::List(items)) => {
+                for (ind, item) in items.iter().enumerate() {
+                    lint_filter(item, &join(&path, &ind.to_string()), schema, lints);
+                }
+            }
+            ("$not", value) => lint_filter(value, &path, schema, lints),
+            (op, _) if CONDITION_OPERATORS.contains(&op) => lints.push(Lint {
+                message: format!(
+                    "The operator {} applies to a field, as in {{\"field\": {{\"{}\": ...}}}}",
+                    op, op
+                ),
+                path,
+                suggestion: None,
+            }),
+            (op, _) if op.starts_with('$') => {
+                lints.push(unknown_operator(op, path, &FILTER_OPERATORS))
+            }
+            (field, value) => lint_field(field, value, &path, schema, lints),
+        }
+    }
+}
+
+fn lint_field(field: &str, value: &Json, at: &str, schema: Option<&Schema>, lints: &mut Vec<Lint>) {
+    let types = schema.and_then(|schema| schema.types_at(field));
+    let operators = match value {
+        Json::Object(obj) if obj.iter().any(|(key, _)| key.starts_with('$')) => obj,
+        Json::Object(obj) => {
+            // {"age": {"gte": 18}} compares the field with the whole object.
+            for (key, _) in sorted(obj) {
+                let op = format!("${}", key);
+                if CONDITION_OPERATORS.contains(&op.as_str()) {
+                    lints.push(Lint {
+                        path: join(at, key),
+                        message: format!(
+                            "The field {} is compared with the whole object, where {} is not an operator",
+                            field, key
+                        ),
+                        suggestion: Some(op),
+                    });
+                }
+            }
+            lint_comparison(field, types, "$eq", value, at, lints);
+            return;
+        }
+        value => {
+            lint_comparison(field, types, "$eq", value, at, lints);
+            return;
+        }
+    };
+    for (op, value) in sorted(operators) {
+        let path = join(at, op);
+        match (op.as_str(), value) {
+            ("$eq" | "$ne" | "$gt" | "$gte" | "$lt" | "$lte", value) => {
+                lint_comparison(field, types, op, value, &path, lints)
+            }
+            ("$in" | "$nin", Json::List(values)) => {
+                for (ind, value) in values.iter().enumerate() {
+                    lint_comparison(
+                        field,
+                        types,
+                        op,
+                        value,
+                        &join(&path, &ind.to_string()),
+                        lints,
+                    );
+                }
+            }
+            ("$regex" | "$prefix", _) => {
+                if let Some(types) = types.filter(|types| !types.iter().any(|t| t == "string")) {
+                    lints.push(Lint {
+                        message: format!(
+                            "The field {} is {} in the schema, and {} only matches strings",
+                            field,
+                            types.join(" or "),
+                            op
+                        ),
+                        path,
+                        suggestion: None,
+                    });
+                }
+            }
+            (op, _) if op.starts_with('$') && !CONDITION_OPERATORS.contains(&op) => {
+                lints.push(unknown_operator(op, path, &CONDITION_OPERATORS))
+            }
+            _ => {}
+        }
+    }
+}
+
+fn lint_comparison(
+    field: &str,
+    types: Option<&[String]>,
+    op: &str,
+    value: &Json,
+    path: &str,
+    lints: &mut Vec<Lint>,
+) {
+    let ordered = matches!(op, "$gt" | "$gte" | "$lt" | "$lte");
+    if ordered && matches!(value, Json::List(_) | Json::Object(_) | Json::Null) {
+        lints.push(Lint {
+            path: path.to_string(),
+            message: format!(
+                "The operator {} orders numbers, strings and booleans, so it never matches {}",
+                op,
+                value.canonical()
+            ),
+            suggestion: None,
+        });
+        return;
+    }
+    let Some(types) = types else {
+        return;
+    };
+    if types.iter().any(|name| kind(name) == type_name(value)) {
+        return;
+    }
+    let suggestion = match value {
+        Json::String(text) if types.iter().any(|name| kind(name) == "number") => text
+            .parse::<i64>()
+            .map(|num| Json::Number(JsonNumber::Int(num)))
+            .or_else(|_| {
+                text.parse::<f64>()
+                    .map(|num| Json::Number(JsonNumber::Float(num)))
+            })
+            .ok(),
+        Json::String(text) if types.iter().any(|name| name == "boolean") => match text.as_str() {
+            "true" => Some(Json::Bool(true)),
+            "false" => Some(Json::Bool(false)),
+            _ => None,
+        },
+        Json::Number(_) | Json::Bool(_) if types.iter().any(|name| name == "string") => {
+            Some(Json::String(value.canonical()))
+        }
+        _ => None,
+    };
+    lints.push(Lint {
+        path: path.to_string(),
+        message: format!(
+            "The field {} is {} in the schema, but it is compared with the {} {}",
+            field,
+            types.join(" or "),
+            type_name(value),
+            value.canonical()
+        ),
+        suggestion: suggestion.map(|value| value.canonical()),
+    });
+}
+
+// Integers and numbers compare with each other.
+fn kind(name: &str) -> &str {
+    match name {
+        "integer" => "number",
+        name => name,
+    }
+}
+
+fn unknown_operator(op: &str, path: String, known: &[&str]) -> Lint {
+    let suggestion = known
+        .iter()
+        .map(|known| (distance(op, known), *known))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, known)| known.to_string());
+    Lint {
+        path,
+        message: format!("Unknown operator {}", op),
+        suggestion,
+    }
+}
+
+// The Levenshtein distance, for suggesting the operator that was likely meant.
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+pub fn sorted(obj: &JsonObject) -> Vec<(&String, &Json)> {
+    let mut entries = obj.iter().collect::<Vec<_>>();
+    entries.sort_by_key(|(key, _)| *key);
+    entries
+}
+
+// Numeric segments of the path index into lists.
+pub fn lookup<'a>(document: &'a Json, path: &str) -> Option<&'a Json> {
+    path.split('.')
+        .try_fold(document, |value, segment| match value {
+            Json::Object(obj) => obj.get(segment),
+            Json::List(items) => items.get(segment.parse::<usize>().ok()?),
+            _ => None,
+        })
+}
+
+pub fn number(value: &JsonNumber) -> f64 {
+    match value {
+        JsonNumber::Int(num) => *num as f64,
+        JsonNumber::Float(num) => *num,
+    }
+}
+
+// Values of different types are not ordered, so a comparison between them never matches.
+pub fn compare(a: &Json, b: &Json) -> Option<Ordering> {
+    match (a, b) {
+        (Json::Number(JsonNumber::Int(a)), Json::Number(JsonNumber::Int(b))) => Some(a.cmp(b)),
+        (Json::Number(a), Json::Number(b)) => number(a).partial_cmp(&number(b)),
+        (Json::String(a), Json::String(b)) => Some(a.cmp(b)),
+        (Json::Bool(a), Json::Bool(b)) => Some(a.cmp(b)),
+        (Json::Null, Json::Null) => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+pub fn equals(a: &Json, b: &Json) -> bool {
+    match (a, b) {
+        (Json::Number(_), Json::Number(_)) => compare(a, b) == Some(Ordering::Equal),
+        _ => a.canonical() == b.canonical(),
+    }
+}
+
+// Orders values of any type: missing fields first, then null, booleans, numbers, strings, and
+// lists and objects by their canonical form.
+pub fn sort_compare(a: Option<&Json>, b: Option<&Json>) -> Ordering {
+    let rank = |value: Option<&Json>| match value {
+        None | Some(Json::None) => 0,
+        Some(Json::Null) => 1,
+        Some(Json::Bool(_)) => 2,
+        Some(Json::Number(_)) => 3,
+        Some(Json::String(_)) => 4,
+        Some(Json::List(_)) => 5,
+        Some(Json::Object(_)) => 6,
+    };
+    match (a, b) {
+        (Some(a @ (Json::List(_) | Json::Object(_))), Some(b))
+            if rank(Some(a)) == rank(Some(b)) =>
+        {
+            a.canonical().cmp(&b.canonical())
+        }
+        (Some(a), Some(b)) => compare(a, b).unwrap_or_else(|| rank(Some(a)).cmp(&rank(Some(b)))),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+pub struct SortKey {
+    pub path: String,
+    pub descending: bool,
+}
+
+impl SortKey {
+    pub fn parse(json: &Json) -> Result<SortKey, String> {
+        match json {
+            Json::String(path) => match path.strip_prefix('-') {
+                Some(path) if !path.is_empty() => Ok(SortKey {
+                    path: path.to_string(),
+                    descending: true,
+                }),
+                None if !path.is_empty() => Ok(SortKey {
+                    path: path.clone(),
+                    descending: false,
+                }),
+                _ => Err(format!("The sort field {} is empty", path)),
+            },
+            _ => Err("Expected the sort fields as strings".to_string()),
+        }
+    }
+
+    fn to_json(&self) -> Json {
+        Json::String(match self.descending {
+            true => format!("-{}", self.path),
+            false => self.path.clone(),
+        })
+    }
+}
+
+// A projection lists the fields to return, or with a leading '-' the fields to leave out. The
+// ID is always returned unless it is left out explicitly. Paths that reach into a list apply to
+// every element of it.
+pub enum Projection {
+    Include(Vec<Vec<String>>),
+    Exclude(Vec<Vec<String>>),
+}
+
+impl Projection {
+    pub fn parse(json: &Json) -> Result<Projection, String> {
+        let Json::List(fields) = json else {
+            return Err("Expected the projected fields as a list".to_string());
+        };
+        let mut include = Vec::new();
+        let mut exclude = Vec::new();
+        for field in fields {
+            let Json::String(field) = field else {
+                return Err("Expected the projected fields as strings".to_string());
+            };
+            let (paths, path) = match field.strip_prefix('-') {
+                Some(path) => (&mut exclude, path),
+                None => (&mut include, field.as_str()),
+            };
+            if path.split('.').any(str::is_empty) {
+                return Err(format!("The projected field {} is invalid", field));
+            }
+            paths.push(path.split('.').map(str::to_string).collect::<Vec<_>>());
+        }
+        match (include.is_empty(), exclude.is_empty()) {
+            (false, false) if exclude != [vec![ID_FIELD.to_string()]] => Err(
+                "A projection either lists the fields to include or the fields to exclude, except for _id"
+                    .to_string(),
+            ),
+            (false, _) => {
+                if exclude.is_empty() {
+                    include.push(vec![ID_FIELD.to_string()]);
+                }
+                Ok(Projection::Include(include))
+            }
+            (true, _) => Ok(Projection::Exclude(exclude)),
+        }
+    }
+
+    pub fn apply(&self, document: &Json) -> Json {
+        match self {
+            Projection::Include(paths) => {
+                let mut projected = Json::Object(JsonObject::new());
+                for path in paths {
+                    if let Some(part) = include(document, path) {
+                        merge(&mut projected, part);
+                    }
+                }
+                projected
+            }
+            Projection::Exclude(paths) => {
+                let mut projected = document.clone();
+                for path in paths {
+                    exclude(&mut projected, path);
+                }
+                projected
+            }
+        }
+    }
+}
+
+fn include(value: &Json, path: &[String]) -> Option<Json> {
+    match value {
+        Json::Object(obj) => {
+            let field = obj.get(&path[0])?;
+            let mut part = JsonObject::new();
+            part[path[0].clone()] = match path.len() {
+                1 => field.clone(),
+                _ => include(field, &path[1..])?,
+            };
+            Some(Json::Object(part))
+        }
+        // Elements that are not objects or lists are dropped, and every other element is kept
+        // so that the parts of several paths line up when they are merged.
+        Json::List(items) => Some(Json::List(
+            items
+                .iter()
+                .filter(|item| matches!(item, Json::Object(_) | Json::List(_)))
+                .map(|item| include(item, path).unwrap_or(Json::Object(JsonObject::new())))
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+fn merge(target: &mut Json, part: Json) {
+    match (target, part) {
+        (Json::Object(target), Json::Object(part)) => {
+            for (field, value) in part.iter() {
+                match target.get_mut(field) {
+                    Some(existing) => merge(existing, value.clone()),
+                    None => {
+                        target[field.clone()] = value.clone();
+                    }
+                }
+            }
+        }
+        (Json::List(target), Json::List(part)) => {
+            for (existing, value) in target.iter_mut().zip(part) {
+                merge(existing, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn exclude(value: &mut Json, path: &[String]) {
+    match value {
+        Json::Object(obj) if path.len() == 1 => {
+            obj.remove(&path[0]);
+        }
+        Json::Object(obj) => {
+            if let Some(field) = obj.get_mut(&path[0]) {
+                exclude(field, &path[1..]);
+            }
+        }
+        Json::List(items) => {
+            for item in items {
+                exclude(item, path);
+            }
+        }
+        _ => {}
+    }
+}
+
+// The sort values of a document followed by its ID, which makes the order total.
+type Position = Vec<Option<Json>>;
+
+pub struct Page {
+    pub documents: Vec<Json>,
+    pub next: Option<String>,
+}
+
+pub struct Query {
+    pub filter: Filter,
+    pub sort: Vec<SortKey>,
+    pub skip: usize,
+    pub limit: Option<usize>,
+    pub projection: Option<Projection>,
+    // Whether tombstones of soft deleted documents are included.
+    pub deleted: bool,
+    // Whether problems found by linting the filter fail the query instead of being warnings.
+    pub strict: bool,
+    after: Option<Position>,
+}
+
+impl Query {
+    pub fn parse(body: Option<&Json>) -> Result<Query, String> {
+        let mut query = Query {
+            filter: Filter::all(),
+            sort: Vec::new(),
+            skip: 0,
+            limit: None,
+            projection: None,
+            deleted: false,
+            strict: false,
+            after: None,
+        };
+        let obj = match body {
+            None => {
+                return Ok(query);
+            }
+            Some(Json::Object(obj)) => obj,
+            Some(_) => {
+                return Err("Expected the query as a JSON object".to_string());
+            }
+        };
+        let count = |field: &str, value: &Json| match value {
+            Json::Number(JsonNumber::Int(count)) if *count >= 0 => Ok(*count as usize),
+            _ => Err(format!(
+                "The query field {} should be a non-negative integer",
+                field
+            )),
+        };
+        for (field, value) in sorted(obj) {
+            match (field.as_str(), value) {
+                (_, Json::Null) => {}
+                ("filter", filter) => {
+                    query.filter = Filter::parse(filter)?;
+                }
+                ("sort", Json::List(keys)) => {
+                    query.sort = keys.iter().map(SortKey::parse).collect::<Result<_, _>>()?;
+                }
+                ("sort", key) => {
+                    query.sort = vec![SortKey::parse(key)?];
+                }
+                ("skip", skip) => {
+                    query.skip = count(field, skip)?;
+                }
+                ("limit", limit) => {
+                    query.limit = Some(count(field, limit)?).filter(|limit| *limit > 0);
+                    if query.limit.is_none() {
+                        return Err("The query limit should be at least 1".to_string());
+                    }
+                }
+                ("fields", fields) => {
+                    query.projection = Some(Projection::parse(fields)?);
+                }
+                ("deleted", Json::Bool(deleted)) => {
+                    query.deleted = *deleted;
+                }
+                ("deleted", _) => {
+                    return Err("The query field deleted should be true or false".to_string());
+                }
+                ("strict", Json::Bool(strict)) => {
+                    query.strict = *strict;
+                }
+                ("strict", _) => {
+                    return Err("The query field strict should be true or false".to_string());
+                }
+                ("cursor", _) => {}
+                (field, _) => {
+                    return Err(format!("Unknown query field {}", field));
+                }
+            }
+        }
+        if let Some(cursor) = obj
+            .get("cursor")
+            .filter(|cursor| !matches!(cursor, Json::Null))
+        {
+            query.after = Some(query.decode_cursor(cursor)?);
+            // The skip was used up by the first page, and clients send the same query again
+            // with the cursor of the next page.
+            query.skip = 0;
+        }
+        Ok(query)
+    }
+
+    fn position(&self, document: &Json) -> Position {
+        self.sort
+            .iter()
+            .map(|key| lookup(document, &key.path).cloned())
+            .chain([lookup(document, ID_FIELD).cloned()])
+            .collect()
+    }
+
+    fn compare_positions(&self, a: &Position, b: &Position) -> Ordering {
+        a.iter()
+            .zip(b.iter())
+            .enumerate()
+            .map(|(ind, (a, b))| {
+                let order = sort_compare(a.as_ref(), b.as_ref());
+                match self.sort.get(ind) {
+                    Some(key) if key.descending => order.reverse(),
+                    _ => order,
+                }
+            })
+            .find(|order| order.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+
+    // A cursor holds the sort fields and the position of the last document of a page. Each
+    // value is wrapped in a list, which is empty when the field is missing.
+    fn encode_cursor(&self, position: &Position) -> String {
+        let mut obj = JsonObject::new();
+        obj["sort".to_string()] = Json::List(self.sort.iter().map(SortKey::to_json).collect());
+        obj["after".to_string()] = Json::List(
+            position
+                .iter()
+                .map(|value| Json::List(value.iter().cloned().collect()))
+                .collect(),
+        );
+        URL_SAFE_NO_PAD.encode(Json::Object(obj).canonical())
+    }
+
+    fn decode_cursor(&self, cursor: &Json) -> Result<Position, String> {
+        let invalid = || "The cursor is invalid".to_string();
+        let Json::String(cursor) = cursor else {
+            return Err(invalid());
+        };
+        let obj = match URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|bytes| Json::parse(&bytes).ok())
+        {
+            Some(Json::Object(obj)) => obj,
+            _ => {
+                return Err(invalid());
+            }
+        };
+        let sort = Json::List(self.sort.iter().map(SortKey::to_json).collect());
+        if obj
+            .get("sort")
+            .is_none_or(|cursor_sort| cursor_sort.canonical() != sort.canonical())
+        {
+            return Err("The cursor belongs to a query with a different sort order".to_string());
+        }
+        let Some(Json::List(after)) = obj.get("after") else {
+            return Err(invalid());
+        };
+        if after.len() != self.sort.len() + 1 {
+            return Err(invalid());
+        }
+        after
+            .iter()
+            .map(|value| match value {
+                Json::List(value) if value.len() <= 1 => Ok(value.first().cloned()),
+                _ => Err(invalid()),
+            })
+            .collect()
+    }
+
+    // Without a sort order the documents come out of the scan in ID order, so the scan starts
+    // after the cursor and stops as soon as the page is full. Sorted queries keep only the
+    // documents that can still make it into the page.
+    pub fn run(&self, db: &DB, collection: &str) -> Result<Page, String> {
+        let wanted = self.limit.map(|limit| self.skip + limit);
+        let start = match &self.after {
+            Some(after) if self.sort.is_empty() => match after.last() {
+                Some(Some(Json::String(id))) => Bound::Excluded(id.clone()),
+                _ => Bound::Unbounded,
+            },
+            _ => Bound::Unbounded,
+        };
+        let mut found: Vec<(Position, Json)> = Vec::new();
+        let by_position =
+            |a: &(Position, Json), b: &(Position, Json)| self.compare_positions(&a.0, &b.0);
+        let documents: Box<dyn Iterator<Item = Result<Json, String>>> = match self.deleted {
+            true => Box::new(db.scan_including_deleted(collection, (start, Bound::Unbounded))?),
+            false => Box::new(db.scan(collection, (start, Bound::Unbounded))?),
+        };
+        for document in documents {
+            let document = document?;
+            if !self.filter.matches(&document) {
+                continue;
+            }
+            let position = self.position(&document);
+            if self
+                .after
+                .as_ref()
+                .is_some_and(|after| self.compare_positions(&position, after) != Ordering::Greater)
+            {
+                continue;
+            }
+            found.push((position, document));
+            let Some(wanted) = wanted else {
+                continue;
+            };
+            if self.sort.is_empty() && found.len() > wanted {
+                break;
+            }
+            if found.len() > 2 * (wanted + 1) {
+                found.sort_by(by_position);
+                found.truncate(wanted + 1);
+            }
+        }
+        found.sort_by(by_position);
+        let more = wanted.is_some_and(|wanted| found.len() > wanted);
+        found.truncate(wanted.unwrap_or(found.len()));
+        let next = match more {
+            true => found
+                .last()
+                .map(|(position, _)| self.encode_cursor(position)),
+            false => None,
+        };
+        Ok(Page {
+            documents: found
+                .into_iter()
+                .skip(self.skip)
+                .map(|(_, document)| match &self.projection {
+                    Some(projection) => projection.apply(&document),
+                    None => document,
+                })
+                .collect(),
+            next,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn cursors_ignore_the_skip_of_the_first_page() {
+        let dir = TempDir::new().unwrap();
+        let db = DB::create(&dir.path().to_string_lossy(), "skipped", String::new()).unwrap();
+        for id in ["a", "b", "c", "d", "e"] {
+            db.put("items", id, &Json::parse(b"{}").unwrap()).unwrap();
+        }
+        let ids = |page: &Page| {
+            page.documents
+                .iter()
+                .filter_map(|document| lookup(document, ID_FIELD).map(Json::canonical))
+                .collect::<Vec<_>>()
+        };
+        let first = Query::parse(Some(&Json::parse(br#"{"skip":1,"limit":2}"#).unwrap()))
+            .unwrap()
+            .run(&db, "items")
+            .unwrap();
+        assert_eq!(ids(&first), [r#""b""#, r#""c""#]);
+        let next = format!(
+            r#"{{"skip":1,"limit":2,"cursor":"{}"}}"#,
+            first.next.unwrap()
+        );
+        let query = Query::parse(Some(&Json::parse(next.as_bytes()).unwrap())).unwrap();
+        assert_eq!(query.skip, 0);
+        assert_eq!(ids(&query.run(&db, "items").unwrap()), [r#""d""#, r#""e""#]);
+    }
+}
@@ -0,0 +1,271 @@
+use std::{collections::HashMap, fmt::Display, str::FromStr, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    auth, db,
+    error::ApiError,
+    http::{HttpMethod, HttpStatus, Request, Response},
+    json::{Json, JsonNumber, JsonObject},
+    server::Server,
+};
+
+const SECONDS_PER_DAY: i64 = 86400;
+// Bounds the memory of the counters. Keys are verified identities and existing databases, so
+// only a very large deployment reaches it.
+pub const MAX_QUOTA_KEYS: usize = 100_000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+impl Operation {
+    pub fn of(request: &Request) -> Operation {
+        match request.method {
+            HttpMethod::GET | HttpMethod::HEAD | HttpMethod::OPTIONS => Operation::Read,
+            _ => Operation::Write,
+        }
+    }
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Read => write!(f, "read"),
+            Operation::Write => write!(f, "write"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Quota {
+    pub reads: u64,
+    pub writes: u64,
+}
+
+impl Quota {
+    pub fn limit(&self, op: Operation) -> u64 {
+        match op {
+            Operation::Read => self.reads,
+            Operation::Write => self.writes,
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        counts_json(self.reads, self.writes)
+    }
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (reads, writes) = s.split_once('/').ok_or_else(|| {
+            format!(
+                "Expected a quota of the form READS/WRITES operations per day, found {}",
+                s
+            )
+        })?;
+        let parse = |value: &str| {
+            value.trim().parse::<u64>().map_err(|_| {
+                format!(
+                    "The quota {} should contain a non-negative number of operations, found {}",
+                    s, value
+                )
+            })
+        };
+        Ok(Quota {
+            reads: parse(reads)?,
+            writes: parse(writes)?,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Usage {
+    reads: u64,
+    writes: u64,
+}
+
+impl Usage {
+    fn count(&mut self, op: Operation) -> &mut u64 {
+        match op {
+            Operation::Read => &mut self.reads,
+            Operation::Write => &mut self.writes,
+        }
+    }
+}
+
+struct Counters {
+    day: i64,
+    usage: HashMap<String, Usage>,
+}
+
+pub struct Quotas {
+    pub per_token: Option<Quota>,
+    pub per_database: Option<Quota>,
+    counters: Mutex<Counters>,
+}
+
+impl Quotas {
+    pub fn new(per_token: Option<Quota>, per_database: Option<Quota>) -> Quotas {
+        Quotas {
+            per_token,
+            per_database,
+            counters: Mutex::new(Counters {
+                day: current_day(),
+                usage: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.per_token.is_some() || self.per_database.is_some()
+    }
+
+    // The token quota only counts identities the server verified, since anyone can send a new
+    // Basic user name or token with every request. Requests without one are rejected while it
+    // is set. The database quota only counts databases that exist, for the same reason.
+    pub fn check(
+        &self,
+        request: &Request,
+        identity: Option<&str>,
+        root: &str,
+    ) -> Result<(), ApiError> {
+        let op = Operation::of(request);
+        let mut keys = Vec::<(String, Quota)>::new();
+        if let Some(quota) = self.per_token {
+            let Some(identity) = identity else {
+                return Err(ApiError::new(
+                    HttpStatus::Unauthorized,
+                    "Every client has a daily quota, so requests must authenticate with a client certificate or the admin token"
+                        .to_string(),
+                )
+                .with_code("authentication_required"));
+            };
+            keys.push((identity.to_string(), quota));
+        }
+        if let (Some(quota), Some(database)) = (self.per_database, database_key(request, root)) {
+            keys.push((database, quota));
+        }
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut counters = self.counters.lock().unwrap();
+        let today = current_day();
+        if counters.day != today {
+            counters.day = today;
+            counters.usage.clear();
+        }
+        for (key, quota) in &keys {
+            let used = counters
+                .usage
+                .get_mut(key)
+                .map(|usage| *usage.count(op))
+                .unwrap_or(0);
+            if used >= quota.limit(op) {
+                let mut details = JsonObject::new();
+                details["key".to_string()] = Json::String(key.clone());
+                details["operation".to_string()] = Json::String(op.to_string());
+                details["limit".to_string()] =
+                    Json::Number(JsonNumber::Int(quota.limit(op) as i64));
+                details["resets_at".to_string()] = Json::String(reset_time(today).to_rfc3339());
+                return Err(ApiError::new(
+                    HttpStatus::TooManyRequests,
+                    format!(
+                        "The daily {} quota of {} operations for {} has been used up",
+                        op,
+                        quota.limit(op),
+                        key
+                    ),
+                )
+                .with_code("quota_exceeded")
+                .with_details(Json::Object(details)));
+            }
+        }
+        let new_keys = keys
+            .iter()
+            .filter(|(key, _)| !counters.usage.contains_key(key))
+            .count();
+        if counters.usage.len() + new_keys > MAX_QUOTA_KEYS {
+            return Err(ApiError::new(
+                HttpStatus::ServiceUnavailable,
+                format!(
+                    "The quotas of more than {} clients and databases are counted today, so no more are counted until midnight UTC",
+                    MAX_QUOTA_KEYS
+                ),
+            )
+            .with_code("quota_capacity"));
+        }
+        for (key, _) in keys {
+            *counters.usage.entry(key).or_default().count(op) += 1;
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Json {
+        let counters = self.counters.lock().unwrap();
+        let mut limits = JsonObject::new();
+        if let Some(quota) = self.per_token {
+            limits["token".to_string()] = quota.to_json();
+        }
+        if let Some(quota) = self.per_database {
+            limits["database".to_string()] = quota.to_json();
+        }
+        let mut usage = JsonObject::new();
+        if counters.day == current_day() {
+            for (key, used) in &counters.usage {
+                usage[key.clone()] = counts_json(used.reads, used.writes);
+            }
+        }
+        let mut obj = JsonObject::new();
+        obj["limits".to_string()] = Json::Object(limits);
+        obj["usage".to_string()] = Json::Object(usage);
+        obj["resets_at".to_string()] = Json::String(reset_time(current_day()).to_rfc3339());
+        Json::Object(obj)
+    }
+}
+
+pub fn apply(server: &Server, request: &Request) -> Option<Response> {
+    let quotas = &server.quotas;
+    if !quotas.is_enabled() || request.route.starts_with("/_") {
+        return None;
+    }
+    let identity = auth::identity(server, request);
+    match quotas.check(request, identity.as_deref(), &server.root) {
+        Ok(()) => None,
+        Err(err) => {
+            let mut resp = err.to_response();
+            // Counters are only freed when they reset, so both a used up quota and full
+            // counters last until then.
+            if err.status != HttpStatus::Unauthorized {
+                let retry_after = reset_time(current_day()).timestamp() - Utc::now().timestamp();
+                resp.set_header("Retry-After", retry_after.max(1).to_string());
+            }
+            Some(resp)
+        }
+    }
+}
+
+fn counts_json(reads: u64, writes: u64) -> Json {
+    let mut obj = JsonObject::new();
+    obj["reads".to_string()] = Json::Number(JsonNumber::Int(reads as i64));
+    obj["writes".to_string()] = Json::Number(JsonNumber::Int(writes as i64));
+    Json::Object(obj)
+}
+
+fn current_day() -> i64 {
+    Utc::now().timestamp().div_euclid(SECONDS_PER_DAY)
+}
+
+fn reset_time(day: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp((day + 1) * SECONDS_PER_DAY, 0).unwrap_or_default()
+}
+
+fn database_key(request: &Request, root: &str) -> Option<String> {
+    db::route_database(&request.route)
+        .filter(|name| db::exists(root, name))
+        .map(|name| format!("db:{}", name))
+}
@@ -0,0 +1,91 @@
+use crate::{
+    etag,
+    http::{Body, HttpMethod, HttpStatus, Request, Response},
+};
+
+pub fn parse(header: &str, len: usize) -> Result<Option<(usize, usize)>, String> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) => spec.trim(),
+        None => {
+            return Ok(None);
+        }
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => {
+            return Ok(None);
+        }
+    };
+    let parse_bound = |value: &str| value.trim().parse::<usize>().ok();
+    let range = match (start.trim().is_empty(), end.trim().is_empty()) {
+        (true, true) => None,
+        (true, false) => match parse_bound(end) {
+            Some(0) => {
+                return Err(format!("The range {} does not select any bytes", header));
+            }
+            Some(suffix) => Some((len.saturating_sub(suffix), len.saturating_sub(1))),
+            None => None,
+        },
+        (false, true) => parse_bound(start).map(|start| (start, len.saturating_sub(1))),
+        (false, false) => match (parse_bound(start), parse_bound(end)) {
+            (Some(start), Some(end)) if start <= end => {
+                Some((start, end.min(len.saturating_sub(1))))
+            }
+            _ => None,
+        },
+    };
+    match range {
+        Some((start, _)) if start >= len => Err(format!(
+            "The range {} starts beyond the end of the {} byte resource",
+            header, len
+        )),
+        range => Ok(range),
+    }
+}
+
+pub fn apply(request: &Request, resp: &mut Response) {
+    if request.method != HttpMethod::GET
+        || resp.status != HttpStatus::Ok
+        || resp.header("Content-Encoding").is_some()
+    {
+        return;
+    }
+    let len = match &resp.body {
+        Body::ApplicationOctetStream(content) => content.len(),
+        _ => {
+            return;
+        }
+    };
+    resp.set_header("Accept-Ranges", "bytes".to_string());
+    let header = match &request.range {
+        Some(header) => header,
+        None => {
+            return;
+        }
+    };
+    if let Some(if_range) = &request.if_range {
+        match resp.header("ETag") {
+            Some(current) if etag::matches(if_range, current, false) => {}
+            _ => {
+                return;
+            }
+        }
+    }
+    match parse(header, len) {
+        Ok(Some((start, end))) => {
+            let part = resp.body.to_bytes()[start..=end].to_vec();
+            resp.status = HttpStatus::PartialContent;
+            resp.set_header("Content-Range", format!("bytes {}-{}/{}", start, end, len));
+            resp.body = Body::ApplicationOctetStream(part);
+        }
+        Ok(None) => {}
+        Err(err) => {
+            let mut unsatisfiable = Response::error(HttpStatus::RangeNotSatisfiable, err);
+            unsatisfiable.set_header("Content-Range", format!("bytes */{}", len));
+            *resp = unsatisfiable;
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    collections::HashMap, fmt::Display, net::IpAddr, str::FromStr, sync::Mutex, time::Instant,
+};
+
+use crate::{
+    error::ApiError,
+    http::{HttpStatus, Request, Response},
+    json::{Json, JsonNumber, JsonObject},
+    middleware,
+    quota::Operation,
+};
+
+const PRUNE_THRESHOLD: usize = 10_000;
+// Above the threshold, full buckets are dropped once every so many checks, so that a table of many
+// clients is not walked on every request.
+const PRUNE_INTERVAL: u64 = 1_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RouteClass {
+    Read,
+    Write,
+    Admin,
+}
+
+impl RouteClass {
+    pub fn of(request: &Request) -> RouteClass {
+        if middleware::is_admin_path(&request.route) {
+            return RouteClass::Admin;
+        }
+        match Operation::of(request) {
+            Operation::Read => RouteClass::Read,
+            Operation::Write => RouteClass::Write,
+        }
+    }
+}
+
+impl Display for RouteClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteClass::Read => write!(f, "read"),
+            RouteClass::Write => write!(f, "write"),
+            RouteClass::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u64,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rate, burst) = match s.split_once(':') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (s, None),
+        };
+        let rate = rate
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|rate| rate.is_finite() && *rate > 0.0)
+            .ok_or_else(|| {
+                format!(
+                    "Expected a rate limit of the form REQUESTS_PER_SECOND[:BURST] with a positive rate, found {}",
+                    s
+                )
+            })?;
+        let burst = match burst {
+            Some(burst) => burst
+                .trim()
+                .parse::<u64>()
+                .ok()
+                .filter(|burst| *burst > 0)
+                .ok_or_else(|| {
+                    format!(
+                        "The burst of the rate limit {} should be a positive number of requests",
+                        s
+                    )
+                })?,
+            None => rate.ceil() as u64,
+        };
+        Ok(RateLimit { rate, burst })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets {
+    buckets: HashMap<(IpAddr, RouteClass), Bucket>,
+    checks: u64,
+}
+
+pub struct Decision {
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset_secs: u64,
+    pub retry_after_secs: Option<u64>,
+}
+
+impl Decision {
+    fn set_headers(&self, resp: &mut Response) {
+        resp.set_header("RateLimit-Limit", self.limit.to_string());
+        resp.set_header("RateLimit-Remaining", self.remaining.to_string());
+        resp.set_header("RateLimit-Reset", self.reset_secs.to_string());
+    }
+}
+
+pub struct RateLimits {
+    pub read: Option<RateLimit>,
+    pub write: Option<RateLimit>,
+    pub admin: Option<RateLimit>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimits {
+    pub fn new(
+        read: Option<RateLimit>,
+        write: Option<RateLimit>,
+        admin: Option<RateLimit>,
+    ) -> RateLimits {
+        RateLimits {
+            read,
+            write,
+            admin,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                checks: 0,
+            }),
+        }
+    }
+
+    pub fn limit(&self, class: RouteClass) -> Option<RateLimit> {
+        match class {
+            RouteClass::Read => self.read,
+            RouteClass::Write => self.write,
+            RouteClass::Admin => self.admin,
+        }
+    }
+
+    pub fn check(&self, client: IpAddr, class: RouteClass) -> Option<Decision> {
+        let limit = self.limit(class)?;
+        let capacity = limit.burst as f64;
+        let now = Instant::now();
+        let mut state = self.buckets.lock().unwrap();
+        state.checks += 1;
+        if state.buckets.len() >= PRUNE_THRESHOLD && state.checks >= PRUNE_INTERVAL {
+            state.checks = 0;
+            state
+                .buckets
+                .retain(|(_, class), bucket| match self.limit(*class) {
+                    Some(limit) => {
+                        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                        bucket.tokens + elapsed * limit.rate < limit.burst as f64
+                    }
+                    None => false,
+                });
+        }
+        let bucket = state.buckets.entry((client, class)).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.rate).min(capacity);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        Some(Decision {
+            limit: limit.burst,
+            remaining: bucket.tokens.floor() as u64,
+            reset_secs: ((capacity - bucket.tokens) / limit.rate).ceil() as u64,
+            retry_after_secs: (!allowed)
+                .then(|| (((1.0 - bucket.tokens) / limit.rate).ceil() as u64).max(1)),
+        })
+    }
+}
+
+pub fn apply(limits: &RateLimits, request: &Request, next: impl FnOnce() -> Response) -> Response {
+    let class = RouteClass::of(request);
+    let decision = match request
+        .client
+        .and_then(|client| limits.check(client, class))
+    {
+        Some(decision) => decision,
+        None => {
+            return next();
+        }
+    };
+    let mut resp = match decision.retry_after_secs {
+        Some(retry_after) => {
+            let mut details = JsonObject::new();
+            details["class".to_string()] = Json::String(class.to_string());
+            details["retry_after".to_string()] = Json::Number(JsonNumber::Int(retry_after as i64));
+            let mut resp = ApiError::new(
+                HttpStatus::TooManyRequests,
+                format!(
+                    "Too many {} requests from this client. Please retry in {} seconds",
+                    class, retry_after
+                ),
+            )
+            .with_code("rate_limited")
+            .with_details(Json::Object(details))
+            .to_response();
+            resp.set_header("Retry-After", retry_after.to_string());
+            resp
+        }
+        None => next(),
+    };
+    decision.set_headers(&mut resp);
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn client(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(n))
+    }
+
+    fn buckets(limits: &RateLimits) -> usize {
+        limits.buckets.lock().unwrap().buckets.len()
+    }
+
+    // Buckets refill at once, so every bucket but the one being checked can be dropped.
+    fn refilling() -> RateLimits {
+        RateLimits::new(
+            Some(RateLimit {
+                rate: 1e12,
+                burst: 1,
+            }),
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn full_buckets_are_pruned_above_the_threshold() {
+        let limits = refilling();
+        for n in 0..PRUNE_THRESHOLD as u32 {
+            limits.check(client(n), RouteClass::Read).unwrap();
+        }
+        assert_eq!(buckets(&limits), PRUNE_THRESHOLD);
+        limits.check(client(u32::MAX), RouteClass::Read).unwrap();
+        assert!(buckets(&limits) < PRUNE_THRESHOLD, "{}", buckets(&limits));
+    }
+
+    #[test]
+    fn pruning_waits_for_the_interval() {
+        let limits = refilling();
+        let now = Instant::now();
+        {
+            let mut state = limits.buckets.lock().unwrap();
+            for n in 0..PRUNE_THRESHOLD as u32 {
+                let bucket = Bucket {
+                    tokens: 1.0,
+                    updated: now,
+                };
+                state.buckets.insert((client(n), RouteClass::Read), bucket);
+            }
+        }
+        for _ in 1..PRUNE_INTERVAL {
+            limits.check(client(u32::MAX), RouteClass::Read).unwrap();
+        }
+        assert_eq!(buckets(&limits), PRUNE_THRESHOLD + 1);
+        limits.check(client(u32::MAX), RouteClass::Read).unwrap();
+        assert_eq!(buckets(&limits), 1);
+    }
+
+    #[test]
+    fn buckets_of_classes_without_a_limit_are_not_kept() {
+        let limits = refilling();
+        assert!(limits.check(client(1), RouteClass::Write).is_none());
+        assert_eq!(buckets(&limits), 0);
+    }
+}
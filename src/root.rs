@@ -0,0 +1,120 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::db::{self, DB, MANIFEST_FILE};
+
+pub struct Discovered {
+    pub name: String,
+    pub db: Result<DB, String>,
+}
+
+pub struct Root {
+    path: String,
+}
+
+impl Root {
+    pub fn new(path: &str) -> Root {
+        Root {
+            path: path.to_string(),
+        }
+    }
+
+    pub fn open(path: &str) -> Result<Root, String> {
+        let dir = Path::new(path);
+        match fs::metadata(dir) {
+            Ok(meta) if meta.is_dir() => {}
+            Ok(_) => {
+                return Err(format!(
+                    "The root path {} exists but is not a directory",
+                    dir.display()
+                ));
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                fs::create_dir_all(dir).map_err(|err| {
+                    format!(
+                        "Error while creating the root directory {}: {}",
+                        dir.display(),
+                        err
+                    )
+                })?;
+            }
+            Err(err) => {
+                return Err(format!(
+                    "Error while reading the root directory {}: {}",
+                    dir.display(),
+                    err
+                ));
+            }
+        }
+        Ok(Root::new(path))
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn database_dir(&self, name: &str) -> PathBuf {
+        Path::new(&self.path).join(name)
+    }
+
+    pub fn databases(&self) -> Result<Vec<String>, String> {
+        db::list(&self.path)
+    }
+
+    pub fn discover(&self) -> Result<Vec<Discovered>, String> {
+        let entries = fs::read_dir(&self.path).map_err(|err| {
+            format!(
+                "Error while listing the databases in {}: {}",
+                self.path, err
+            )
+        })?;
+        let mut names = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| db::is_valid_name(name))
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names
+            .into_iter()
+            .map(|name| {
+                let db = match DB::open(&self.path, &name) {
+                    Ok(Some(db)) => Ok(db),
+                    Ok(None) => Err(format!(
+                        "{} is not a database, the file {} is missing",
+                        self.database_dir(&name).display(),
+                        MANIFEST_FILE
+                    )),
+                    Err(err) => Err(err),
+                };
+                Discovered { name, db }
+            })
+            .collect())
+    }
+
+    pub fn check_available(&self, name: &str) -> Result<(), String> {
+        let dir = self.database_dir(name);
+        if dir.join(MANIFEST_FILE).is_file() {
+            return Err(format!(
+                "The database {} already exists in {}",
+                name, self.path
+            ));
+        }
+        if dir.exists() {
+            return Err(format!(
+                "Cannot create the database {}, the path {} is already in use",
+                name,
+                dir.display()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn create(&self, name: &str, password: String) -> Result<DB, String> {
+        self.check_available(name)?;
+        DB::create(&self.path, name, password)
+    }
+}
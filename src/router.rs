@@ -0,0 +1,300 @@
+use std::{
+    cell::{RefCell, RefMut},
+    collections::HashMap,
+    io::{BufRead, Cursor},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use crate::{
+    http::{ContentType, HttpMethod, HttpStatus, Request, Response},
+    json::{Json, JsonNumber, JsonObject},
+    server::Server,
+};
+
+pub type Params = HashMap<String, String>;
+
+pub type Handler = Box<dyn Fn(&Context) -> Response + Send + Sync>;
+
+pub struct Context<'a> {
+    pub request: &'a Request,
+    pub params: Params,
+    pub server: &'a Server,
+    body: &'a RefCell<dyn BufRead + 'a>,
+}
+
+impl<'a> Context<'a> {
+    pub fn body(&self) -> RefMut<'_, dyn BufRead + 'a> {
+        self.body.borrow_mut()
+    }
+
+    pub fn param<T: FromStr>(&self, name: &str) -> Result<T, Response> {
+        match self.params.get(name) {
+            Some(value) => match value.parse::<T>() {
+                Ok(val) => Ok(val),
+                Err(_) => Err(Response::error(
+                    HttpStatus::BadRequest,
+                    format!(
+                        "The value {} of the path parameter {} is invalid",
+                        value, name
+                    ),
+                )),
+            },
+            None => Err(Response::error(
+                HttpStatus::InternalServerError,
+                format!("The route does not have a path parameter named {}", name),
+            )),
+        }
+    }
+
+    pub fn db_path(&self, name: &str) -> PathBuf {
+        PathBuf::from(&self.server.root).join(name)
+    }
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+pub struct Route {
+    pub method: HttpMethod,
+    pub pattern: String,
+    pub content_types: Vec<ContentType>,
+    pub query_params: Vec<String>,
+    pub limits: Vec<(String, i64)>,
+    pub streaming: bool,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+impl Route {
+    pub fn accepts(&mut self, content_type: ContentType) -> &mut Route {
+        self.content_types.push(content_type);
+        self
+    }
+
+    pub fn query_param(&mut self, name: &str) -> &mut Route {
+        self.query_params.push(name.to_string());
+        self
+    }
+
+    pub fn limit(&mut self, name: &str, value: i64) -> &mut Route {
+        self.limits.push((name.to_string(), value));
+        self
+    }
+
+    pub fn streaming(&mut self) -> &mut Route {
+        self.streaming = true;
+        self
+    }
+
+    fn matches(&self, path: &[&str]) -> Option<Params> {
+        if path.len() != self.segments.len() {
+            return None;
+        }
+        let mut params = Params::new();
+        for (segment, part) in self.segments.iter().zip(path.iter()) {
+            match segment {
+                Segment::Literal(literal) => {
+                    if literal != part {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.insert(name.clone(), part.to_string());
+                }
+            }
+        }
+        Some(params)
+    }
+}
+
+fn limits_json(limits: &[(String, i64)], obj: &mut JsonObject) {
+    for (name, value) in limits {
+        obj[name.clone()] = Json::Number(JsonNumber::Int(*value));
+    }
+}
+
+pub struct Router {
+    routes: Vec<Route>,
+    pub limits: Vec<(String, i64)>,
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|part| !part.is_empty()).collect()
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            limits: Vec::new(),
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        method: HttpMethod,
+        pattern: &str,
+        handler: impl Fn(&Context) -> Response + Send + Sync + 'static,
+    ) -> &mut Route {
+        let segments = split_path(pattern)
+            .into_iter()
+            .map(|part| match part.strip_prefix(':') {
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Literal(part.to_string()),
+            })
+            .collect();
+        self.routes.push(Route {
+            method,
+            pattern: pattern.to_string(),
+            content_types: Vec::new(),
+            query_params: Vec::new(),
+            limits: Vec::new(),
+            streaming: false,
+            segments,
+            handler: Box::new(handler),
+        });
+        self.routes.last_mut().unwrap()
+    }
+
+    pub fn allowed_methods(&self, path: &str) -> Vec<HttpMethod> {
+        let parts = split_path(path);
+        let mut methods = Vec::<HttpMethod>::new();
+        for route in &self.routes {
+            if route.matches(&parts).is_some() && !methods.contains(&route.method) {
+                methods.push(route.method.clone());
+            }
+        }
+        if methods.contains(&HttpMethod::GET) && !methods.contains(&HttpMethod::HEAD) {
+            methods.push(HttpMethod::HEAD);
+        }
+        methods
+    }
+
+    pub fn resolve(&self, method: &HttpMethod, path: &str) -> Option<(&Route, Params)> {
+        let parts = split_path(path);
+        let mut head_fallback: Option<(&Route, Params)> = None;
+        for route in &self.routes {
+            if let Some(params) = route.matches(&parts) {
+                if route.method == *method {
+                    return Some((route, params));
+                }
+                if *method == HttpMethod::HEAD
+                    && route.method == HttpMethod::GET
+                    && head_fallback.is_none()
+                {
+                    head_fallback = Some((route, params));
+                }
+            }
+        }
+        head_fallback
+    }
+
+    pub fn dispatch(&self, server: &Server, request: &Request) -> Response {
+        let body = RefCell::new(Cursor::new(request.content.as_slice()));
+        self.dispatch_with_body(server, request, &body)
+    }
+
+    pub fn dispatch_with_body<'a>(
+        &self,
+        server: &'a Server,
+        request: &'a Request,
+        body: &'a RefCell<dyn BufRead + 'a>,
+    ) -> Response {
+        if let Some((route, params)) = self.resolve(&request.method, &request.route) {
+            return (route.handler)(&Context {
+                request,
+                params,
+                server,
+                body,
+            });
+        }
+        let parts = split_path(&request.route);
+        let path_matched = self
+            .routes
+            .iter()
+            .any(|route| route.matches(&parts).is_some());
+        if path_matched && request.method == HttpMethod::OPTIONS {
+            return self.capabilities(&request.route);
+        }
+        if path_matched {
+            let allowed = self
+                .allowed_methods(&request.route)
+                .iter()
+                .map(|method| method.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let mut resp = Response::error(
+                HttpStatus::MethodNotAllowed,
+                format!(
+                    "The method {} is not allowed for {}. Allowed methods are {}",
+                    request.method, request.route, allowed
+                ),
+            );
+            resp.set_header("Allow", allowed);
+            resp
+        } else {
+            Response::error(
+                HttpStatus::NotFound,
+                format!("No route found for {}", request.route),
+            )
+        }
+    }
+
+    pub fn capabilities(&self, path: &str) -> Response {
+        let parts = split_path(path);
+        let mut methods = Vec::<Json>::new();
+        let mut allowed = vec![HttpMethod::OPTIONS.to_string()];
+        let mut limits = JsonObject::new();
+        limits_json(&self.limits, &mut limits);
+        for route in &self.routes {
+            if route.matches(&parts).is_none() {
+                continue;
+            }
+            let mut method = JsonObject::new();
+            method["method".to_string()] = Json::String(route.method.to_string());
+            method["route".to_string()] = Json::String(route.pattern.clone());
+            method["content_types".to_string()] = Json::List(
+                route
+                    .content_types
+                    .iter()
+                    .map(|content_type| Json::String(content_type.to_string()))
+                    .collect(),
+            );
+            method["query_parameters".to_string()] = Json::List(
+                route
+                    .query_params
+                    .iter()
+                    .map(|name| Json::String(name.clone()))
+                    .collect(),
+            );
+            let mut route_limits = JsonObject::new();
+            limits_json(&route.limits, &mut route_limits);
+            method["limits".to_string()] = Json::Object(route_limits);
+            methods.push(Json::Object(method));
+            if !allowed.contains(&route.method.to_string()) {
+                allowed.push(route.method.to_string());
+            }
+        }
+        if allowed.contains(&HttpMethod::GET.to_string())
+            && !allowed.contains(&HttpMethod::HEAD.to_string())
+        {
+            allowed.push(HttpMethod::HEAD.to_string());
+        }
+        let mut obj = JsonObject::new();
+        obj["path".to_string()] = Json::String(path.to_string());
+        obj["methods".to_string()] = Json::List(methods);
+        obj["limits".to_string()] = Json::Object(limits);
+        let mut resp = Response::json(HttpStatus::Ok, Json::Object(obj));
+        resp.set_header("Allow", allowed.join(", "));
+        resp
+    }
+}
@@ -0,0 +1,536 @@
+use regex::Regex;
+
+use crate::{
+    json::{Json, JsonNumber, JsonObject},
+    query::{self, equals, sorted},
+};
+
+// Keywords that describe a schema without constraining the documents it accepts.
+const ANNOTATIONS: [&str; 8] = [
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "deprecated",
+];
+const TYPES: [&str; 7] = [
+    "null", "boolean", "object", "array", "number", "integer", "string",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Validation {
+    // Writes of documents that do not match the schema are rejected.
+    Strict,
+    // Writes of documents that do not match the schema are accepted and logged.
+    Warn,
+}
+
+impl Validation {
+    pub fn parse(value: &str) -> Option<Validation> {
+        match value {
+            "strict" => Some(Validation::Strict),
+            "warn" => Some(Validation::Warn),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Validation::Strict => "strict",
+            Validation::Warn => "warn",
+        }
+    }
+}
+
+// A value of the document that does not match the schema. The path is the dotted path of the
+// value in the document, as in query filters, and is empty for the document itself.
+#[derive(Clone, Debug)]
+pub struct Violation {
+    pub path: String,
+    pub message: String,
+}
+
+impl Violation {
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["path".to_string()] = Json::String(self.path.clone());
+        obj["message".to_string()] = Json::String(self.message.clone());
+        Json::Object(obj)
+    }
+}
+
+pub fn describe(violations: &[Violation]) -> String {
+    violations
+        .iter()
+        .map(|violation| match violation.path.as_str() {
+            "" => violation.message.clone(),
+            path => format!("{}: {}", path, violation.message),
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+enum Rule {
+    Type(Vec<String>),
+    Enum(Vec<Json>),
+    Const(Json),
+    Minimum(f64, bool),
+    Maximum(f64, bool),
+    MultipleOf(f64),
+    MinLength(usize),
+    MaxLength(usize),
+    Pattern(Regex),
+    MinItems(usize),
+    MaxItems(usize),
+    UniqueItems,
+    Items(Box<Node>),
+    Properties(Vec<(String, Node)>),
+    Required(Vec<String>),
+    AdditionalProperties(Vec<String>, Box<Node>),
+    MinProperties(usize),
+    MaxProperties(usize),
+    AllOf(Vec<Node>),
+    AnyOf(Vec<Node>),
+    OneOf(Vec<Node>),
+    Not(Box<Node>),
+}
+
+enum Node {
+    Bool(bool),
+    Rules(Vec<Rule>),
+}
+
+// A schema is a subset of JSON Schema. It is compiled when it is set, so that a schema with an
+// unknown keyword or a bad value is refused instead of silently accepting every document.
+pub struct Schema {
+    source: Json,
+    root: Node,
+}
+
+impl Schema {
+    pub fn new(source: &Json) -> Result<Schema, String> {
+        Ok(Schema {
+            root: Node::parse(source, "")?,
+            source: source.clone(),
+        })
+    }
+
+    pub fn source(&self) -> &Json {
+        &self.source
+    }
+
+    pub fn validate(&self, document: &Json) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        self.root.check(document, "", &mut violations);
+        violations
+    }
+
+    // The types allowed at a dotted path, where numeric segments index into lists. Only the
+    // properties and items keywords are followed, so a path under a combinator is not known.
+    pub fn types_at(&self, path: &str) -> Option<&[String]> {
+        path.split('.')
+            .try_fold(&self.root, |node, segment| node.child(segment))?
+            .types()
+    }
+}
+
+impl Node {
+    fn rules(&self) -> &[Rule] {
+        match self {
+            Node::Rules(rules) => rules,
+            Node::Bool(_) => &[],
+        }
+    }
+
+    fn child(&self, segment: &str) -> Option<&Node> {
+        self.rules().iter().find_map(|rule| match rule {
+            Rule::Properties(properties) => properties
+                .iter()
+                .find(|(name, _)| name == segment)
+                .map(|(_, node)| node),
+            Rule::Items(node) if segment.parse::<usize>().is_ok() => Some(node),
+            _ => None,
+        })
+    }
+
+    fn types(&self) -> Option<&[String]> {
+        self.rules().iter().find_map(|rule| match rule {
+            Rule::Type(types) => Some(types.as_slice()),
+            _ => None,
+        })
+    }
+
+    fn parse(json: &Json, at: &str) -> Result<Node, String> {
+        let obj = match json {
+            Json::Bool(accept) => {
+                return Ok(Node::Bool(*accept));
+            }
+            Json::Object(obj) => obj,
+            _ => {
+                return Err(format!(
+                    "The schema{} should be a JSON object or a boolean",
+                    location(at)
+                ));
+            }
+        };
+        let invalid = |key: &str, expected: &str| {
+            format!(
+                "The keyword {} of the schema{} expects {}",
+                key,
+                location(at),
+                expected
+            )
+        };
+        let count = |key: &str, value: &Json| match value {
+            Json::Number(JsonNumber::Int(count)) if *count >= 0 => Ok(*count as usize),
+            _ => Err(invalid(key, "a non-negative integer")),
+        };
+        let bound = |key: &str, value: &Json| match value {
+            Json::Number(num) => Ok(query::number(num)),
+            _ => Err(invalid(key, "a number")),
+        };
+        let nodes = |key: &str, value: &Json| match value {
+            Json::List(items) if !items.is_empty() => items
+                .iter()
+                .enumerate()
+                .map(|(ind, item)| Node::parse(item, &format!("{}/{}/{}", at, key, ind)))
+                .collect::<Result<Vec<_>, _>>(),
+            _ => Err(invalid(key, "a non-empty list of schemas")),
+        };
+        let exclusive = |key: &str| match obj.get(key) {
+            None => Ok(None),
+            Some(Json::Bool(_)) => Ok(None),
+            Some(value) => bound(key, value).map(Some),
+        };
+        let is_exclusive = |key: &str| matches!(obj.get(key), Some(Json::Bool(true)));
+        let mut rules = Vec::new();
+        for (key, value) in sorted(obj) {
+            let rule = match key.as_str() {
+                "type" => {
+                    let names = match value {
+                        Json::String(name) => vec![name.clone()],
+                        Json::List(names) => names
+                            .iter()
+                            .map(|name| match name {
+                                Json::String(name) => Ok(name.clone()),
+                                _ => Err(invalid(key, "a type name or a list of type names")),
+                            })
+                            .collect::<Result<_, _>>()?,
+                        _ => {
+                            return Err(invalid(key, "a type name or a list of type names"));
+                        }
+                    };
+                    if let Some(name) = names.iter().find(|name| !TYPES.contains(&name.as_str())) {
+                        return Err(format!(
+                            "The schema{} has the unknown type {}. The types are {}",
+                            location(at),
+                            name,
+                            TYPES.join(", ")
+                        ));
+                    }
+                    Rule::Type(names)
+                }
+                "enum" => match value {
+                    Json::List(values) => Rule::Enum(values.clone()),
+                    _ => {
+                        return Err(invalid(key, "a list of values"));
+                    }
+                },
+                "const" => Rule::Const(value.clone()),
+                "minimum" => Rule::Minimum(bound(key, value)?, is_exclusive("exclusiveMinimum")),
+                "maximum" => Rule::Maximum(bound(key, value)?, is_exclusive("exclusiveMaximum")),
+                "exclusiveMinimum" => match exclusive(key)? {
+                    Some(minimum) => Rule::Minimum(minimum, true),
+                    None => continue,
+                },
+                "exclusiveMaximum" => match exclusive(key)? {
+                    Some(maximum) => Rule::Maximum(maximum, true),
+                    None => continue,
+                },
+                "multipleOf" => match bound(key, value)? {
+                    divisor if divisor > 0.0 => Rule::MultipleOf(divisor),
+                    _ => {
+                        return Err(invalid(key, "a positive number"));
+                    }
+                },
+                "minLength" => Rule::MinLength(count(key, value)?),
+                "maxLength" => Rule::MaxLength(count(key, value)?),
+                "pattern" => match value {
+                    Json::String(pattern) => Rule::Pattern(Regex::new(pattern).map_err(|err| {
+                        format!(
+                            "The pattern of the schema{} is invalid: {}",
+                            location(at),
+                            err
+                        )
+                    })?),
+                    _ => {
+                        return Err(invalid(key, "a regular expression"));
+                    }
+                },
+                "minItems" => Rule::MinItems(count(key, value)?),
+                "maxItems" => Rule::MaxItems(count(key, value)?),
+                "uniqueItems" => match value {
+                    Json::Bool(true) => Rule::UniqueItems,
+                    Json::Bool(false) => continue,
+                    _ => {
+                        return Err(invalid(key, "a boolean"));
+                    }
+                },
+                "items" => Rule::Items(Box::new(Node::parse(value, &format!("{}/items", at))?)),
+                "properties" => match value {
+                    Json::Object(properties) => Rule::Properties(
+                        sorted(properties)
+                            .into_iter()
+                            .map(|(name, value)| {
+                                let node =
+                                    Node::parse(value, &format!("{}/properties/{}", at, name))?;
+                                Ok((name.clone(), node))
+                            })
+                            .collect::<Result<_, String>>()?,
+                    ),
+                    _ => {
+                        return Err(invalid(key, "an object of schemas"));
+                    }
+                },
+                "required" => match value {
+                    Json::List(names) => Rule::Required(
+                        names
+                            .iter()
+                            .map(|name| match name {
+                                Json::String(name) => Ok(name.clone()),
+                                _ => Err(invalid(key, "a list of field names")),
+                            })
+                            .collect::<Result<_, _>>()?,
+                    ),
+                    _ => {
+                        return Err(invalid(key, "a list of field names"));
+                    }
+                },
+                "additionalProperties" => {
+                    let known = match obj.get("properties") {
+                        Some(Json::Object(properties)) => {
+                            properties.iter().map(|(name, _)| name.clone()).collect()
+                        }
+                        _ => Vec::new(),
+                    };
+                    let node = Node::parse(value, &format!("{}/additionalProperties", at))?;
+                    Rule::AdditionalProperties(known, Box::new(node))
+                }
+                "minProperties" => Rule::MinProperties(count(key, value)?),
+                "maxProperties" => Rule::MaxProperties(count(key, value)?),
+                "allOf" => Rule::AllOf(nodes(key, value)?),
+                "anyOf" => Rule::AnyOf(nodes(key, value)?),
+                "oneOf" => Rule::OneOf(nodes(key, value)?),
+                "not" => Rule::Not(Box::new(Node::parse(value, &format!("{}/not", at))?)),
+                key if ANNOTATIONS.contains(&key) => continue,
+                key => {
+                    return Err(format!(
+                        "The schema{} has the unsupported keyword {}",
+                        location(at),
+                        key
+                    ));
+                }
+            };
+            rules.push(rule);
+        }
+        Ok(Node::Rules(rules))
+    }
+
+    fn accepts(&self, value: &Json) -> bool {
+        let mut violations = Vec::new();
+        self.check(value, "", &mut violations);
+        violations.is_empty()
+    }
+
+    fn check(&self, value: &Json, path: &str, violations: &mut Vec<Violation>) {
+        let rules = match self {
+            Node::Bool(true) => return,
+            Node::Bool(false) => {
+                violations.push(violation(path, "is not allowed".to_string()));
+                return;
+            }
+            Node::Rules(rules) => rules,
+        };
+        for rule in rules {
+            rule.check(value, path, violations);
+        }
+    }
+}
+
+impl Rule {
+    fn check(&self, value: &Json, path: &str, violations: &mut Vec<Violation>) {
+        match (self, value) {
+            (Rule::Items(node), Json::List(items)) => {
+                for (ind, item) in items.iter().enumerate() {
+                    node.check(item, &child(path, &ind.to_string()), violations);
+                }
+            }
+            (Rule::Properties(properties), Json::Object(obj)) => {
+                for (name, node) in properties {
+                    if let Some(value) = obj.get(name) {
+                        node.check(value, &child(path, name), violations);
+                    }
+                }
+            }
+            (Rule::Required(names), Json::Object(obj)) => {
+                for name in names.iter().filter(|name| obj.get(name).is_none()) {
+                    violations.push(violation(
+                        &child(path, name),
+                        "is required but missing".to_string(),
+                    ));
+                }
+            }
+            (Rule::AdditionalProperties(known, node), Json::Object(obj)) => {
+                for (name, value) in sorted(obj) {
+                    if !known.contains(name) {
+                        node.check(value, &child(path, name), violations);
+                    }
+                }
+            }
+            (Rule::AllOf(nodes), value) => {
+                for node in nodes {
+                    node.check(value, path, violations);
+                }
+            }
+            (rule, value) => {
+                if let Some(message) = rule.failure(value) {
+                    violations.push(violation(path, message));
+                }
+            }
+        }
+    }
+
+    // The keywords other than type, enum, const and the combinators only apply to values of
+    // their own type.
+    fn failure(&self, value: &Json) -> Option<String> {
+        let number = |num: &JsonNumber| query::number(num);
+        let length = |string: &str| string.chars().count();
+        match (self, value) {
+            (Rule::Type(names), value) if !names.iter().any(|name| is_type(value, name)) => {
+                Some(format!(
+                    "expected {} but found {}",
+                    names.join(" or "),
+                    type_name(value)
+                ))
+            }
+            (Rule::Enum(options), value) if !options.iter().any(|option| equals(value, option)) => {
+                Some(format!(
+                    "expected one of {}",
+                    options
+                        .iter()
+                        .map(Json::canonical)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            }
+            (Rule::Const(expected), value) if !equals(value, expected) => {
+                Some(format!("expected {}", expected.canonical()))
+            }
+            (Rule::Minimum(minimum, false), Json::Number(num)) if number(num) < *minimum => {
+                Some(format!("expected a number at least {}", minimum))
+            }
+            (Rule::Minimum(minimum, true), Json::Number(num)) if number(num) <= *minimum => {
+                Some(format!("expected a number greater than {}", minimum))
+            }
+            (Rule::Maximum(maximum, false), Json::Number(num)) if number(num) > *maximum => {
+                Some(format!("expected a number at most {}", maximum))
+            }
+            (Rule::Maximum(maximum, true), Json::Number(num)) if number(num) >= *maximum => {
+                Some(format!("expected a number less than {}", maximum))
+            }
+            (Rule::MultipleOf(divisor), Json::Number(num))
+                if ((number(num) / divisor) - (number(num) / divisor).round()).abs() > 1e-9 =>
+            {
+                Some(format!("expected a multiple of {}", divisor))
+            }
+            (Rule::MinLength(min), Json::String(string)) if length(string) < *min => {
+                Some(format!("expected at least {} characters", min))
+            }
+            (Rule::MaxLength(max), Json::String(string)) if length(string) > *max => {
+                Some(format!("expected at most {} characters", max))
+            }
+            (Rule::Pattern(pattern), Json::String(string)) if !pattern.is_match(string) => {
+                Some(format!("expected a string matching {}", pattern.as_str()))
+            }
+            (Rule::MinItems(min), Json::List(items)) if items.len() < *min => {
+                Some(format!("expected at least {} items", min))
+            }
+            (Rule::MaxItems(max), Json::List(items)) if items.len() > *max => {
+                Some(format!("expected at most {} items", max))
+            }
+            (Rule::UniqueItems, Json::List(items))
+                if items
+                    .iter()
+                    .enumerate()
+                    .any(|(ind, item)| items[..ind].iter().any(|other| equals(item, other))) =>
+            {
+                Some("expected unique items".to_string())
+            }
+            (Rule::MinProperties(min), Json::Object(obj)) if obj.len() < *min => {
+                Some(format!("expected at least {} fields", min))
+            }
+            (Rule::MaxProperties(max), Json::Object(obj)) if obj.len() > *max => {
+                Some(format!("expected at most {} fields", max))
+            }
+            (Rule::AnyOf(nodes), value) if !nodes.iter().any(|node| node.accepts(value)) => {
+                Some("expected a value matching at least one of the anyOf schemas".to_string())
+            }
+            (Rule::OneOf(nodes), value) => {
+                match nodes.iter().filter(|node| node.accepts(value)).count() {
+                    1 => None,
+                    matched => Some(format!(
+                        "expected a value matching exactly one of the oneOf schemas, but it matched {}",
+                        matched
+                    )),
+                }
+            }
+            (Rule::Not(node), value) if node.accepts(value) => {
+                Some("expected a value not matching the not schema".to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+fn is_type(value: &Json, name: &str) -> bool {
+    match (name, value) {
+        ("integer", Json::Number(JsonNumber::Int(_))) => true,
+        ("integer", Json::Number(JsonNumber::Float(num))) => num.fract() == 0.0,
+        (name, value) => type_name(value) == name,
+    }
+}
+
+pub fn type_name(value: &Json) -> &'static str {
+    match value {
+        Json::Null | Json::None => "null",
+        Json::Bool(_) => "boolean",
+        Json::Object(_) => "object",
+        Json::List(_) => "array",
+        Json::Number(_) => "number",
+        Json::String(_) => "string",
+    }
+}
+
+fn violation(path: &str, message: String) -> Violation {
+    Violation {
+        path: path.to_string(),
+        message,
+    }
+}
+
+fn child(path: &str, name: &str) -> String {
+    match path {
+        "" => name.to_string(),
+        path => format!("{}.{}", path, name),
+    }
+}
+
+fn location(at: &str) -> String {
+    match at {
+        "" => String::new(),
+        at => format!(" at {}", at),
+    }
+}
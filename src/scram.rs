@@ -0,0 +1,277 @@
+use std::{fmt::Display, str::FromStr};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+pub const MECHANISM: &str = "SCRAM-SHA-256";
+pub const DEFAULT_ITERATIONS: u32 = 4096;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 18;
+const KEY_LENGTH: usize = 32;
+
+type Key = [u8; KEY_LENGTH];
+
+fn hmac(key: &[u8], message: &[u8]) -> Key {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![0u8; len];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|err| format!("Could not generate random bytes: {}", err))?;
+    Ok(bytes)
+}
+
+fn decode_key(value: &str, name: &str) -> Result<Key, String> {
+    STANDARD
+        .decode(value)
+        .ok()
+        .and_then(|bytes| Key::try_from(bytes.as_slice()).ok())
+        .ok_or_else(|| format!("The {} of the SCRAM verifier is invalid", name))
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct ScramVerifier {
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: Key,
+    pub server_key: Key,
+}
+
+impl ScramVerifier {
+    pub fn new(password: &str) -> Result<ScramVerifier, String> {
+        Ok(ScramVerifier::derive(
+            password,
+            &random_bytes(SALT_LENGTH)?,
+            DEFAULT_ITERATIONS,
+        ))
+    }
+
+    pub fn derive(password: &str, salt: &[u8], iterations: u32) -> ScramVerifier {
+        let mut salted_password = Key::default();
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut salted_password);
+        let client_key = hmac(&salted_password, b"Client Key");
+        ScramVerifier {
+            salt: salt.to_vec(),
+            iterations,
+            stored_key: Sha256::digest(client_key).into(),
+            server_key: hmac(&salted_password, b"Server Key"),
+        }
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        ScramVerifier::derive(password, &self.salt, self.iterations) == *self
+    }
+}
+
+impl Display for ScramVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}${}:{}${}:{}",
+            MECHANISM,
+            self.iterations,
+            STANDARD.encode(&self.salt),
+            STANDARD.encode(self.stored_key),
+            STANDARD.encode(self.server_key)
+        )
+    }
+}
+
+impl FromStr for ScramVerifier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split('$').collect::<Vec<_>>();
+        if parts.len() != 3 || parts[0] != MECHANISM {
+            return Err(format!(
+                "Expected a verifier of the form {}$ITERATIONS:SALT$STORED_KEY:SERVER_KEY",
+                MECHANISM
+            ));
+        }
+        let (iterations, salt) = parts[1]
+            .split_once(':')
+            .ok_or_else(|| "The SCRAM verifier is missing its salt".to_string())?;
+        let (stored_key, server_key) = parts[2]
+            .split_once(':')
+            .ok_or_else(|| "The SCRAM verifier is missing its server key".to_string())?;
+        Ok(ScramVerifier {
+            salt: STANDARD
+                .decode(salt)
+                .map_err(|_| "The salt of the SCRAM verifier is invalid".to_string())?,
+            iterations: iterations
+                .parse::<u32>()
+                .map_err(|_| "The iteration count of the SCRAM verifier is invalid".to_string())?,
+            stored_key: decode_key(stored_key, "stored key")?,
+            server_key: decode_key(server_key, "server key")?,
+        })
+    }
+}
+
+fn attributes(message: &str) -> Result<Vec<(char, &str)>, String> {
+    message
+        .split(',')
+        .map(|attr| {
+            let mut chars = attr.chars();
+            match (chars.next(), chars.next()) {
+                (Some(name), Some('=')) if name.is_ascii_alphabetic() => Ok((name, &attr[2..])),
+                _ => Err(format!("The SCRAM attribute {} is malformed", attr)),
+            }
+        })
+        .collect()
+}
+
+fn attribute<'a>(attrs: &[(char, &'a str)], name: char) -> Result<&'a str, String> {
+    attrs
+        .iter()
+        .find(|(attr, _)| *attr == name)
+        .map(|(_, value)| *value)
+        .ok_or_else(|| format!("The SCRAM message is missing the {} attribute", name))
+}
+
+fn decode_username(value: &str) -> Result<String, String> {
+    let mut user = String::new();
+    let mut rest = value;
+    while let Some(index) = rest.find('=') {
+        user += &rest[..index];
+        match rest.get(index..index + 3) {
+            Some("=2C") => user.push(','),
+            Some("=3D") => user.push('='),
+            _ => {
+                return Err(format!(
+                    "The SCRAM username {} is not escaped correctly",
+                    value
+                ));
+            }
+        }
+        rest = &rest[index + 3..];
+    }
+    Ok(user + rest)
+}
+
+pub struct ClientFirst {
+    pub user: String,
+    pub nonce: String,
+    bare: String,
+}
+
+impl FromStr for ClientFirst {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bare = match s.strip_prefix("n,") {
+            Some(rest) => match rest.split_once(',') {
+                Some(("", bare)) => bare,
+                Some(_) => {
+                    return Err("Authorization identities are not supported".to_string());
+                }
+                None => {
+                    return Err("The SCRAM client-first message is malformed".to_string());
+                }
+            },
+            None if s.starts_with("p=") || s.starts_with("y,") => {
+                return Err("SCRAM channel binding is not supported".to_string());
+            }
+            None => {
+                return Err("The SCRAM client-first message is malformed".to_string());
+            }
+        };
+        let attrs = attributes(bare)?;
+        if attrs.iter().any(|(name, _)| *name == 'm') {
+            return Err("SCRAM extensions are not supported".to_string());
+        }
+        let nonce = attribute(&attrs, 'r')?;
+        if nonce.is_empty() || !nonce.chars().all(|ch| ch.is_ascii_graphic() && ch != ',') {
+            return Err("The SCRAM client nonce is invalid".to_string());
+        }
+        Ok(ClientFirst {
+            user: decode_username(attribute(&attrs, 'n')?)?,
+            nonce: nonce.to_string(),
+            bare: bare.to_string(),
+        })
+    }
+}
+
+pub struct ScramExchange {
+    pub user: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+    verifier: ScramVerifier,
+}
+
+impl ScramExchange {
+    pub fn start(
+        client_first: &ClientFirst,
+        verifier: ScramVerifier,
+    ) -> Result<ScramExchange, String> {
+        let server_nonce = STANDARD.encode(random_bytes(NONCE_LENGTH)?);
+        Ok(ScramExchange::with_server_nonce(
+            client_first,
+            verifier,
+            &server_nonce,
+        ))
+    }
+
+    pub fn with_server_nonce(
+        client_first: &ClientFirst,
+        verifier: ScramVerifier,
+        server_nonce: &str,
+    ) -> ScramExchange {
+        let nonce = client_first.nonce.clone() + server_nonce;
+        ScramExchange {
+            user: client_first.user.clone(),
+            client_first_bare: client_first.bare.clone(),
+            server_first: format!(
+                "r={},s={},i={}",
+                nonce,
+                STANDARD.encode(&verifier.salt),
+                verifier.iterations
+            ),
+            nonce,
+            verifier,
+        }
+    }
+
+    pub fn server_first(&self) -> &str {
+        &self.server_first
+    }
+
+    pub fn finish(&self, client_final: &str) -> Result<String, String> {
+        let (without_proof, proof) = client_final
+            .rsplit_once(",p=")
+            .ok_or_else(|| "The SCRAM client-final message is missing its proof".to_string())?;
+        let attrs = attributes(without_proof)?;
+        if attribute(&attrs, 'c')? != "biws" {
+            return Err("SCRAM channel binding is not supported".to_string());
+        }
+        if attribute(&attrs, 'r')? != self.nonce {
+            return Err("The SCRAM nonce does not match the exchange".to_string());
+        }
+        let proof = decode_key(proof, "client proof")
+            .map_err(|_| "The SCRAM client proof is invalid".to_string())?;
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, without_proof
+        );
+        let client_signature = hmac(&self.verifier.stored_key, auth_message.as_bytes());
+        let mut client_key = Key::default();
+        for (index, byte) in client_key.iter_mut().enumerate() {
+            *byte = proof[index] ^ client_signature[index];
+        }
+        let stored_key: Key = Sha256::digest(client_key).into();
+        let matches = stored_key
+            .iter()
+            .zip(self.verifier.stored_key.iter())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+        if !matches {
+            return Err("The SCRAM credentials are invalid".to_string());
+        }
+        let server_signature = hmac(&self.verifier.server_key, auth_message.as_bytes());
+        Ok(format!("v={}", STANDARD.encode(server_signature)))
+    }
+}
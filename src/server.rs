@@ -1,111 +1,1239 @@
 use std::{
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    str,
+    cell::RefCell,
+    collections::BTreeMap,
+    io::{ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    str::FromStr,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::{cli, http, json::Json};
+use chrono::{DateTime, Utc};
+use rustls::ServerConfig;
+#[cfg(unix)]
+use signal_hook::{consts::SIGHUP, iterator::Signals};
 
-pub fn listen(cl: &cli::Cli) -> std::io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:".to_string() + &cl.port.to_string())?;
-    println!("Got listener");
-    loop {
-        match listener.accept() {
-            Ok((mut stream, addr)) => {
-                let res = handle_request(&mut stream);
-                if res.is_err() {
-                    eprintln!(
-                        "Handling of request from {} failed with error: {}",
-                        addr,
-                        res.err().unwrap()
-                    );
+use crate::{
+    admin,
+    alert::{self, AlertConfig, Alerter, DiskMonitor},
+    api,
+    auth::{self, Sessions},
+    cache, changes, cli,
+    compaction::{self, CompactionConfig, Compactor},
+    compression::Encoding,
+    cors::CorsConfig,
+    engine,
+    error::ApiError,
+    http::{
+        self, Body, BodyReader, ChunkedDecoder, ContentType, Credentials, HttpMethod, HttpStatus,
+        Request, Response, Upgrade,
+    },
+    json::{Json, JsonNumber, JsonObject},
+    logging::{AccessEntry, LogConfig, RequestLogger},
+    metrics::{self, RequestTimings},
+    middleware::{self, Middleware, Next},
+    quota::Quotas,
+    ratelimit::RateLimits,
+    router::{Context, Route, Router},
+    service::{Service, Services},
+    sse, startup, systemd,
+    telemetry::Reporter,
+    tls::{self, TlsStream},
+    ttl::Sweeper,
+    types::ID,
+    wal::{self, WalConfig},
+    warmup::Warmer,
+    websocket,
+};
+
+pub const DEFAULT_WORKERS: usize = 16;
+pub const DEFAULT_QUEUE_SIZE: usize = 128;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+// Change feeds, event streams and exports that may run at once, each on a thread of its own.
+pub const MAX_UPGRADED_CONNECTIONS: usize = 256;
+const UPGRADE_RETRY_AFTER_SECS: u64 = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BindAddress {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+}
+
+impl BindAddress {
+    pub fn socket_addr(&self, default_port: u16) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port.unwrap_or(default_port))
+    }
+}
+
+impl FromStr for BindAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(BindAddress {
+                ip: addr.ip(),
+                port: Some(addr.port()),
+            });
+        }
+        let ip = s
+            .strip_prefix('[')
+            .and_then(|ip| ip.strip_suffix(']'))
+            .unwrap_or(s);
+        match ip.parse::<IpAddr>() {
+            Ok(ip) => Ok(BindAddress { ip, port: None }),
+            Err(_) => Err(format!(
+                "The bind address {} should be an IP address, optionally followed by a port",
+                s
+            )),
+        }
+    }
+}
+
+pub struct Settings {
+    pub idle_timeout: Duration,
+    pub max_body_size: usize,
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub max_connections: usize,
+    pub cors: CorsConfig,
+    pub tls: Option<Arc<ServerConfig>>,
+    pub admin_token: Option<String>,
+}
+
+impl Settings {
+    pub fn new(cl: &cli::Cli) -> Result<Settings, String> {
+        Ok(Settings {
+            idle_timeout: Duration::from_secs(cl.idle_timeout),
+            max_body_size: cl.max_body_size,
+            read_timeout: Duration::from_secs(cl.read_timeout),
+            write_timeout: Duration::from_secs(cl.write_timeout),
+            max_connections: cl.max_connections,
+            cors: CorsConfig {
+                allowed_origins: cl.cors_origins.clone(),
+                max_age: cl.cors_max_age,
+                ..CorsConfig::new()
+            },
+            tls: match (&cl.tls_cert, &cl.tls_key) {
+                (Some(cert), Some(key)) => {
+                    Some(tls::load_config(cert, key, cl.tls_client_ca.as_deref())?)
                 }
-            }
-            Err(err) => {
-                eprintln!("Error while handling incoming request: {}", err);
-            }
+                _ => None,
+            },
+            admin_token: cl.admin_token.clone(),
+        })
+    }
+}
+
+fn log_config(cl: &cli::Cli) -> LogConfig {
+    LogConfig {
+        sample_rate: cl.log_sample_rate,
+        log_bodies: cl.log_bodies,
+        redact_fields: cl.log_redact.clone(),
+        format: cl.log_format,
+    }
+}
+
+fn wal_config(cl: &cli::Cli) -> WalConfig {
+    WalConfig {
+        durability: cl.durability,
+        segment_size: cl.wal_segment_size,
+    }
+}
+
+fn compaction_config(cl: &cli::Cli) -> CompactionConfig {
+    CompactionConfig {
+        rate: cl.compaction_rate,
+    }
+}
+
+fn addresses(cl: &cli::Cli) -> Vec<SocketAddr> {
+    if cl.bind.is_empty() {
+        vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), cl.port)]
+    } else {
+        cl.bind
+            .iter()
+            .map(|address| address.socket_addr(cl.port))
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub opened: DateTime<Utc>,
+    pub requests: u64,
+    pub upgraded: bool,
+}
+
+impl ConnectionInfo {
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["id".to_string()] = Json::Number(JsonNumber::Int(self.id as i64));
+        obj["peer".to_string()] = Json::String(self.peer.to_string());
+        obj["opened".to_string()] = Json::String(self.opened.to_rfc3339());
+        obj["requests".to_string()] = Json::Number(JsonNumber::Int(self.requests as i64));
+        obj["upgraded".to_string()] = Json::Bool(self.upgraded);
+        Json::Object(obj)
+    }
+}
+
+#[derive(Default)]
+struct Connections {
+    open: AtomicUsize,
+    next_id: AtomicU64,
+    active: Mutex<BTreeMap<u64, ConnectionInfo>>,
+    upgraded: AtomicUsize,
+}
+
+impl Connections {
+    fn upgrade(self: &Arc<Self>, max: usize) -> Option<UpgradeGuard> {
+        self.upgraded
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |upgraded| {
+                (upgraded < max).then_some(upgraded + 1)
+            })
+            .ok()?;
+        Some(UpgradeGuard {
+            connections: self.clone(),
+        })
+    }
+}
+
+// Held by the thread of an upgraded connection for as long as it runs.
+struct UpgradeGuard {
+    connections: Arc<Connections>,
+}
+
+impl Drop for UpgradeGuard {
+    fn drop(&mut self) {
+        self.connections.upgraded.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct ConnectionGuard {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl ConnectionGuard {
+    fn update(&self, f: impl FnOnce(&mut ConnectionInfo)) {
+        if let Some(info) = self.connections.active.lock().unwrap().get_mut(&self.id) {
+            f(info);
         }
     }
 }
 
-pub fn handle_request(stream: &mut TcpStream) -> Result<(), String> {
-    let header_end = "\r\n\r\n";
-    let mut buf = Vec::<u8>::new();
-    let mut req_complete = false;
-    let mut temp_buff = [0; 512];
-    let mut content_index: usize = 0;
-    let mut reading_content = false;
-    let mut pending_bytes = 0usize;
-    let mut req: Option<http::Request> = None;
-    while !req_complete {
-        match stream.read(&mut temp_buff) {
-            Ok(bytes_read) if bytes_read > 0 => {
-                buf.extend_from_slice(&temp_buff[..bytes_read]);
-                match str::from_utf8(&temp_buff) {
-                    Ok(temp_str) => {
-                        if !reading_content && temp_str.contains(header_end) {
-                            let end_index = temp_str.find(header_end).unwrap();
-                            let header_end_index = buf.len() - (bytes_read - end_index);
-                            content_index = header_end_index + header_end.len();
-                            match http::Request::from_bytes(&buf[..header_end_index]) {
-                                Ok(head) => {
-                                    if head.content_length.is_some() {
-                                        pending_bytes = head.content_length.unwrap();
-                                        pending_bytes -= bytes_read - end_index - header_end.len();
-                                        reading_content = true;
-                                    }
-                                    req = Some(head);
-                                }
-                                Err(err) => {
-                                    return Err(err);
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.active.lock().unwrap().remove(&self.id);
+        self.connections.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+type Connection = (TcpStream, SocketAddr, ConnectionGuard);
+
+pub struct Server {
+    pub root: String,
+    pub port: u16,
+    pub addresses: Vec<SocketAddr>,
+    pub router: Router,
+    pub logger: RequestLogger,
+    pub workers: usize,
+    pub queue_size: usize,
+    pub maintenance: RwLock<Option<String>>,
+    pub middleware: Vec<Middleware>,
+    pub quotas: Quotas,
+    pub rate_limits: RateLimits,
+    pub sessions: Sessions,
+    pub alerts: Arc<Alerter>,
+    pub services: Services,
+    settings: RwLock<Arc<Settings>>,
+    connections: Arc<Connections>,
+    shutdown: AtomicBool,
+}
+
+pub enum Outcome {
+    KeepAlive,
+    Close,
+    Upgrade(Upgrade),
+}
+
+pub trait Stream: Read + Write {
+    fn socket(&self) -> &TcpStream;
+
+    fn peer_identity(&self) -> Option<String> {
+        None
+    }
+
+    fn close(&mut self) {}
+}
+
+impl Stream for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+}
+
+impl Stream for TlsStream {
+    fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        tls::peer_identity(self)
+    }
+
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        let _ = self.flush();
+    }
+}
+
+impl Server {
+    pub fn new(cl: &cli::Cli) -> Result<Server, String> {
+        let settings = Settings::new(cl)?;
+        wal::configure(wal_config(cl));
+        compaction::configure(compaction_config(cl));
+        cache::configure(cl.cache_size);
+        let mut router = default_router();
+        let mut services = Services::new();
+        services.add(Box::new(DiskMonitor));
+        services.add(Box::new(Reporter));
+        services.add(Box::new(Sweeper));
+        services.add(Box::new(Compactor));
+        if cl.warm_up {
+            services.add(Box::new(Warmer::new(cl.root.clone())));
+        }
+        router
+            .limits
+            .push(("max_body_size".to_string(), cl.max_body_size as i64));
+        Ok(Server {
+            root: cl.root.clone(),
+            port: cl.port,
+            addresses: addresses(cl),
+            router,
+            logger: RequestLogger::new(log_config(cl)),
+            workers: cl.workers.max(1),
+            queue_size: cl.queue_size,
+            maintenance: RwLock::new(cl.maintenance.clone()),
+            middleware: middleware::defaults(),
+            quotas: Quotas::new(cl.token_quota, cl.db_quota),
+            rate_limits: RateLimits::new(
+                cl.rate_limit_read,
+                cl.rate_limit_write,
+                cl.rate_limit_admin,
+            ),
+            sessions: Sessions::new(),
+            alerts: Arc::new(Alerter::new(AlertConfig {
+                sinks: cl.alert_sinks.clone(),
+                throttle: Duration::from_secs(cl.alert_throttle),
+                disk_low_bytes: cl.alert_disk_low,
+                auth_failures: cl.alert_auth_failures,
+            })),
+            services,
+            settings: RwLock::new(Arc::new(settings)),
+            connections: Arc::new(Connections::default()),
+            shutdown: AtomicBool::new(false),
+        })
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.connections.open.load(Ordering::SeqCst)
+    }
+
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connections
+            .active
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    fn admit(&self, peer: SocketAddr) -> Option<ConnectionGuard> {
+        let max = self.settings().max_connections;
+        self.connections
+            .open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < max).then_some(open + 1)
+            })
+            .ok()?;
+        let id = self.connections.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.connections.active.lock().unwrap().insert(
+            id,
+            ConnectionInfo {
+                id,
+                peer,
+                opened: Utc::now(),
+                requests: 0,
+                upgraded: false,
+            },
+        );
+        Some(ConnectionGuard {
+            connections: self.connections.clone(),
+            id,
+        })
+    }
+
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    pub fn apply(&self, cl: &cli::Cli) -> Result<Vec<&'static str>, String> {
+        let settings = Settings::new(cl)?;
+        *self.settings.write().unwrap() = Arc::new(settings);
+        self.logger.reconfigure(log_config(cl));
+        wal::configure(wal_config(cl));
+        compaction::configure(compaction_config(cl));
+        cache::configure(cl.cache_size);
+        let mut restart_required = Vec::new();
+        if addresses(cl) != self.addresses {
+            restart_required.push("bind");
+        }
+        if cl.workers.max(1) != self.workers {
+            restart_required.push("workers");
+        }
+        if cl.queue_size != self.queue_size {
+            restart_required.push("queue-size");
+        }
+        if cl.token_quota != self.quotas.per_token {
+            restart_required.push("token-quota");
+        }
+        if cl.db_quota != self.quotas.per_database {
+            restart_required.push("db-quota");
+        }
+        if cl.rate_limit_read != self.rate_limits.read {
+            restart_required.push("rate-limit-read");
+        }
+        if cl.rate_limit_write != self.rate_limits.write {
+            restart_required.push("rate-limit-write");
+        }
+        if cl.rate_limit_admin != self.rate_limits.admin {
+            restart_required.push("rate-limit-admin");
+        }
+        if cl.warm_up != self.services.names().contains(&"cache-warmer") {
+            restart_required.push("warm-up");
+        }
+        Ok(restart_required)
+    }
+
+    pub fn reload(&self) -> Result<Vec<&'static str>, String> {
+        let cl = cli::Cli::new()?;
+        if !matches!(cl.command, cli::CliCommand::Run) {
+            return Err(
+                "The settings can only be reloaded by a server started with 'db6 run'".to_string(),
+            );
+        }
+        self.apply(&cl)
+    }
+
+    pub fn maintenance_message(&self) -> Option<String> {
+        self.maintenance.read().unwrap().clone()
+    }
+
+    pub fn set_maintenance(&self, message: Option<String>) {
+        *self.maintenance.write().unwrap() = message;
+    }
+
+    pub fn layer(
+        &mut self,
+        layer: impl Fn(&Request, Next) -> Response + Send + Sync + 'static,
+    ) -> &mut Server {
+        self.middleware.push(Box::new(layer));
+        self
+    }
+
+    pub fn route(
+        &mut self,
+        method: HttpMethod,
+        pattern: &str,
+        handler: impl Fn(&Context) -> Response + Send + Sync + 'static,
+    ) -> &mut Route {
+        self.router.add(method, pattern, handler)
+    }
+
+    pub fn service(&mut self, service: impl Service + 'static) -> &mut Server {
+        service.register(&mut self.router);
+        self.services.add(Box::new(service));
+        self
+    }
+
+    pub fn bind(&self) -> std::io::Result<Vec<TcpListener>> {
+        if let Some(listeners) = systemd::listeners()? {
+            return Ok(listeners);
+        }
+        self.addresses
+            .iter()
+            .map(|addr| {
+                TcpListener::bind(addr).map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!("Could not listen on {}: {}", addr, err),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    pub fn listen(&self) -> std::io::Result<()> {
+        let listeners = self.bind()?;
+        let addresses = listeners
+            .iter()
+            .map(|listener| listener.local_addr())
+            .collect::<std::io::Result<Vec<_>>>()?;
+        startup::check(self, addresses)
+            .map_err(std::io::Error::other)?
+            .print(self.logger.format());
+        self.services.start(self).map_err(std::io::Error::other)?;
+        alert::watch_ids(&self.alerts);
+        if let Err(err) = systemd::notify("READY=1") {
+            eprintln!("Could not notify systemd that the server is ready: {}", err);
+        }
+        let result = self.serve_with_reload(listeners);
+        let _ = systemd::notify("STOPPING=1");
+        self.services.stop();
+        for err in engine::flush_all() {
+            eprintln!("{}", err);
+        }
+        result
+    }
+
+    #[cfg(unix)]
+    fn serve_with_reload(&self, listeners: Vec<TcpListener>) -> std::io::Result<()> {
+        let mut signals = Signals::new([SIGHUP])?;
+        let handle = signals.handle();
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                for _ in signals.forever() {
+                    match self.reload() {
+                        Ok(restart_required) if restart_required.is_empty() => {
+                            println!("Reloaded the settings");
+                        }
+                        Ok(restart_required) => println!(
+                            "Reloaded the settings. Changes to {} take effect after a restart",
+                            restart_required.join(", ")
+                        ),
+                        Err(err) => {
+                            eprintln!("Could not reload the settings: {}", err);
+                        }
+                    }
+                }
+            });
+            let result = self.serve(listeners);
+            handle.close();
+            result
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn serve_with_reload(&self, listeners: Vec<TcpListener>) -> std::io::Result<()> {
+        self.serve(listeners)
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    pub fn serve(&self, listeners: Vec<TcpListener>) -> std::io::Result<()> {
+        let (sender, receiver) = mpsc::sync_channel::<Connection>(self.queue_size);
+        let receiver = Mutex::new(receiver);
+        let addrs = listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect::<Vec<_>>();
+        thread::scope(|scope| {
+            for _ in 0..self.workers {
+                scope.spawn(|| {
+                    loop {
+                        let next = receiver.lock().unwrap().recv();
+                        match next {
+                            Ok((stream, addr, guard)) => {
+                                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| {
+                                    self.accept(stream, addr, guard)
+                                })) {
+                                    eprintln!(
+                                        "Recovered from a panic while serving {}: {}",
+                                        addr,
+                                        panic_message(&*payload)
+                                    );
                                 }
                             }
-                        } else if reading_content && pending_bytes > 0 {
-                            if bytes_read < pending_bytes {
-                                pending_bytes -= bytes_read
-                            } else {
-                                pending_bytes = 0;
-                            }
+                            Err(_) => break,
+                        }
+                    }
+                });
+            }
+            for listener in listeners {
+                let sender = sender.clone();
+                let addrs = &addrs;
+                scope.spawn(move || {
+                    self.accept_loop(listener, sender);
+                    for addr in addrs {
+                        let _ = TcpStream::connect(addr);
+                    }
+                });
+            }
+            drop(sender);
+        });
+        Ok(())
+    }
+
+    fn accept_loop(&self, listener: TcpListener, sender: SyncSender<Connection>) {
+        while !self.shutdown.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok(_) if self.shutdown.load(Ordering::SeqCst) => {}
+                Ok((stream, addr)) => match self.admit(addr) {
+                    Some(guard) => match sender.try_send((stream, addr, guard)) {
+                        Ok(_) => {}
+                        Err(TrySendError::Full((stream, addr, _))) => {
+                            eprintln!(
+                                "Rejected a connection from {} because all {} workers are busy",
+                                addr, self.workers
+                            );
+                            self.reject(stream);
                         }
-                        if pending_bytes == 0 {
-                            reading_content = false;
-                            req_complete = true;
+                        Err(TrySendError::Disconnected(_)) => {
+                            break;
                         }
+                    },
+                    None => {
+                        eprintln!(
+                            "Rejected a connection from {} because {} connections are open",
+                            addr,
+                            self.open_connections()
+                        );
+                        self.reject(stream);
                     }
-                    Err(err) => {
-                        return Err(format!(
-                            "Error while converting the request content to a string slice: {}",
-                            err.to_string()
-                        ));
+                },
+                Err(err) => {
+                    eprintln!("Error while handling incoming request: {}", err);
+                }
+            }
+        }
+    }
+
+    fn accept(&self, stream: TcpStream, addr: SocketAddr, guard: ConnectionGuard) {
+        match &self.settings().tls {
+            Some(config) => match tls::accept(config, stream) {
+                Ok(tls_stream) => {
+                    handle_connection(tls_stream, self, addr, guard);
+                }
+                Err(err) => {
+                    eprintln!("Error while accepting a connection from {}: {}", addr, err);
+                }
+            },
+            None => {
+                handle_connection(stream, self, addr, guard);
+            }
+        }
+    }
+
+    // Runs on the accept loop, so everything, the TLS handshake included, is bounded by the
+    // reject timeout.
+    fn reject(&self, mut stream: TcpStream) {
+        let _ = stream.set_read_timeout(Some(REJECT_TIMEOUT));
+        let _ = stream.set_write_timeout(Some(REJECT_TIMEOUT));
+        let mut resp = Response::error(
+            HttpStatus::ServiceUnavailable,
+            "The server is busy. Please retry shortly".to_string(),
+        );
+        resp.set_header("Retry-After", "1".to_string());
+        resp.set_header("Connection", "close".to_string());
+        match &self.settings().tls {
+            Some(config) => {
+                if let Ok(mut tls_stream) = tls::accept(config, stream)
+                    && resp.write_to(&mut tls_stream).is_ok()
+                {
+                    tls_stream.conn.send_close_notify();
+                    let _ = tls_stream.flush();
+                }
+            }
+            None => {
+                let _ = resp.write_to(&mut stream);
+            }
+        }
+    }
+}
+
+fn default_router() -> Router {
+    let mut router = Router::new();
+    router.add(HttpMethod::GET, "/", |_| {
+        let mut resp_obj = JsonObject::new();
+        resp_obj["status".to_string()] = Json::String("success".to_string());
+        Response::json(HttpStatus::Ok, Json::Object(resp_obj))
+    });
+    router.add(HttpMethod::GET, "/_metrics", |_| {
+        let mut metrics = metrics::snapshot().to_json();
+        if let Json::Object(obj) = &mut metrics {
+            obj["cache".to_string()] = cache::stats().to_json();
+        }
+        Response::json(HttpStatus::Ok, metrics)
+    });
+    router.add(HttpMethod::GET, "/_status", |context| {
+        let mut resp_obj = JsonObject::new();
+        resp_obj["status".to_string()] = Json::String("ok".to_string());
+        resp_obj["ids".to_string()] = ID::stats().to_json();
+        resp_obj["sequences".to_string()] = sequences();
+        resp_obj["connections".to_string()] =
+            Json::Number(JsonNumber::Int(context.server.open_connections() as i64));
+        resp_obj["services".to_string()] = Json::List(
+            context
+                .server
+                .services
+                .names()
+                .into_iter()
+                .map(|name| Json::String(name.to_string()))
+                .collect(),
+        );
+        Response::json(HttpStatus::Ok, Json::Object(resp_obj))
+    });
+    router.add(HttpMethod::GET, "/_quotas", |context| {
+        Response::json(HttpStatus::Ok, context.server.quotas.to_json())
+    });
+    router.add(HttpMethod::GET, "/_maintenance", |context| {
+        maintenance_status(context.server)
+    });
+    router
+        .add(HttpMethod::PUT, "/_maintenance", |context| {
+            let message = match &context.request.body {
+                Body::ApplicationJson(Json::Object(obj)) => match obj.get("message") {
+                    Some(Json::String(message)) => message.clone(),
+                    Some(_) => {
+                        return ApiError::bad_request(
+                            "The maintenance message should be a string".to_string(),
+                        )
+                        .to_response();
                     }
+                    None => DEFAULT_MAINTENANCE_MESSAGE.to_string(),
+                },
+                Body::None => DEFAULT_MAINTENANCE_MESSAGE.to_string(),
+                _ => {
+                    return ApiError::bad_request(
+                        "Expected a JSON object with an optional message field".to_string(),
+                    )
+                    .to_response();
                 }
+            };
+            context.server.set_maintenance(Some(message));
+            maintenance_status(context.server)
+        })
+        .accepts(ContentType::ApplicationJson);
+    router.add(HttpMethod::POST, "/admin/reload", |context| {
+        match context.server.reload() {
+            Ok(restart_required) => {
+                let mut resp_obj = JsonObject::new();
+                resp_obj["status".to_string()] = Json::String("reloaded".to_string());
+                resp_obj["restart_required".to_string()] = Json::List(
+                    restart_required
+                        .into_iter()
+                        .map(|name| Json::String(name.to_string()))
+                        .collect(),
+                );
+                Response::json(HttpStatus::Ok, Json::Object(resp_obj))
+            }
+            Err(err) => ApiError::new(HttpStatus::InternalServerError, err)
+                .with_code("reload_failed")
+                .to_response(),
+        }
+    });
+    router.add(HttpMethod::DELETE, "/_maintenance", |context| {
+        context.server.set_maintenance(None);
+        maintenance_status(context.server)
+    });
+    router.add(HttpMethod::GET, "/db/:name/changes", |context| {
+        let name = match database_name(context) {
+            Ok(name) => name,
+            Err(resp) => {
+                return resp;
+            }
+        };
+        match websocket::handshake(context.request) {
+            Ok(mut resp) => {
+                let events = changes::subscribe(&name);
+                resp.upgrade = Some(Box::new(move |stream| {
+                    if let Err(err) = websocket::serve(stream, events) {
+                        eprintln!("The change feed of {} ended with error: {}", name, err);
+                    }
+                }));
+                resp
+            }
+            Err(resp) => resp,
+        }
+    });
+    router.add(
+        HttpMethod::GET,
+        "/db/:name/events",
+        |context| match database_name(context) {
+            Ok(name) => sse::response(changes::subscribe(&name)),
+            Err(resp) => resp,
+        },
+    );
+    admin::register(&mut router);
+    auth::register(&mut router);
+    api::register(&mut router);
+    router
+}
+
+const DEFAULT_MAINTENANCE_MESSAGE: &str =
+    "The database is down for maintenance. Please try again later";
+
+// The sequence high-water mark of every loaded database, and the sequence numbers missing from
+// its log when it was replayed.
+fn sequences() -> Json {
+    let mut obj = JsonObject::new();
+    let mut gaps = 0;
+    for (path, last_seq, missing) in engine::sequences() {
+        let mut database = JsonObject::new();
+        database["last_seq".to_string()] = Json::Number(JsonNumber::Int(last_seq as i64));
+        database["gaps".to_string()] = Json::Number(JsonNumber::Int(missing as i64));
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        obj[name.to_string()] = Json::Object(database);
+        gaps += missing;
+    }
+    let mut sequences = JsonObject::new();
+    sequences["gaps".to_string()] = Json::Number(JsonNumber::Int(gaps as i64));
+    sequences["databases".to_string()] = Json::Object(obj);
+    Json::Object(sequences)
+}
+
+fn maintenance_status(server: &Server) -> Response {
+    let mut obj = JsonObject::new();
+    match server.maintenance_message() {
+        Some(message) => {
+            obj["maintenance".to_string()] = Json::Bool(true);
+            obj["message".to_string()] = Json::String(message);
+        }
+        None => {
+            obj["maintenance".to_string()] = Json::Bool(false);
+        }
+    }
+    Response::json(HttpStatus::Ok, Json::Object(obj))
+}
+
+fn database_name(context: &Context) -> Result<String, Response> {
+    let name = context.param::<String>("name")?;
+    if name.starts_with('.') || !context.db_path(&name).is_dir() {
+        return Err(Response::error(
+            HttpStatus::NotFound,
+            format!("The database {} does not exist", name),
+        ));
+    }
+    Ok(name)
+}
+
+pub fn listen(cl: &cli::Cli) -> Result<(), String> {
+    Server::new(cl)?.listen().map_err(|err| err.to_string())
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+fn isolate(request: &Request, handler: impl FnOnce() -> Response) -> Response {
+    panic::catch_unwind(AssertUnwindSafe(handler)).unwrap_or_else(|payload| {
+        eprintln!(
+            "Request {} ({} {}) panicked: {}",
+            request.id,
+            request.method,
+            request.route,
+            panic_message(&*payload)
+        );
+        let mut details = JsonObject::new();
+        details["request_id".to_string()] = Json::String(request.id.clone());
+        ApiError::internal("The server failed to handle the request".to_string())
+            .with_details(Json::Object(details))
+            .to_response()
+    })
+}
+
+pub fn handle_connection<S: Stream + Send + 'static>(
+    mut stream: S,
+    server: &Server,
+    addr: SocketAddr,
+    guard: ConnectionGuard,
+) {
+    if let Err(err) = stream
+        .socket()
+        .set_write_timeout(Some(server.settings().write_timeout))
+    {
+        eprintln!("Could not set the write timeout for {}: {}", addr, err);
+    }
+    let mut buf = Vec::<u8>::new();
+    loop {
+        if let Err(err) = stream
+            .socket()
+            .set_read_timeout(Some(server.settings().idle_timeout))
+        {
+            eprintln!("Could not set the idle timeout for {}: {}", addr, err);
+        }
+        let outcome = handle_request(&mut stream, server, addr.ip(), &mut buf);
+        if outcome.is_ok() {
+            guard.update(|info| info.requests += 1);
+        }
+        match outcome {
+            Ok(Outcome::KeepAlive) => {}
+            Ok(Outcome::Close) => {
+                break;
             }
-            Ok(_) => {
-                return Err("Client disconnected".to_string());
+            Ok(Outcome::Upgrade(upgrade)) => {
+                guard.update(|info| info.upgraded = true);
+                thread::spawn(move || {
+                    upgrade(&mut stream);
+                    stream.close();
+                    drop(guard);
+                });
+                return;
             }
             Err(err) => {
-                return Err(err.to_string());
-            }
-        }
-    }
-    Json::parse(buf.as_slice());
-    match req {
-        Some(request) => {
-            println!("Handling request to {}", request.route);
-            let resp = "{ \"status\" : \"success\" }";
-            let resp_str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: "
-                .to_owned()
-                + &resp.len().to_string()
-                + "\r\n\r\n"
-                + resp;
-            match stream.write_all(resp_str.as_bytes()) {
-                Ok(_) => match stream.flush() {
-                    Ok(_) => Ok(()),
-                    Err(err) => Err(err.to_string()),
-                },
-                Err(err) => Err(err.to_string()),
+                eprintln!(
+                    "Handling of request from {} failed with error: {}",
+                    addr, err
+                );
+                break;
+            }
+        }
+    }
+    stream.close();
+}
+
+enum Framing {
+    Length(usize),
+    Chunked(ChunkedDecoder),
+}
+
+const READ_CHUNK_SIZE: usize = 4096;
+
+fn fill(
+    stream: &mut impl Stream,
+    buf: &mut Vec<u8>,
+    read_timeout: Duration,
+    started: &mut Instant,
+) -> Result<bool, String> {
+    let mut chunk = [0; READ_CHUNK_SIZE];
+    match stream.read(&mut chunk) {
+        Ok(bytes_read) if bytes_read > 0 => {
+            if buf.is_empty() {
+                *started = Instant::now();
+                let _ = stream.socket().set_read_timeout(Some(read_timeout));
+            }
+            buf.extend_from_slice(&chunk[..bytes_read]);
+            Ok(true)
+        }
+        Ok(_) if buf.is_empty() => Ok(false),
+        Ok(_) => Err("Client disconnected".to_string()),
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            if buf.is_empty() {
+                return Ok(false);
+            }
+            reject_request(
+                stream,
+                ApiError::new(
+                    HttpStatus::RequestTimeout,
+                    "The request was not received completely within the read timeout".to_string(),
+                ),
+            );
+            Err("Timed out while reading the request".to_string())
+        }
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn reject_request(stream: &mut impl Stream, err: ApiError) {
+    let mut resp = err.to_response();
+    resp.set_header("Connection", "close".to_string());
+    let _ = resp.write_to(stream);
+}
+
+fn framing(request: &Request, max_body_size: usize) -> Result<Option<Framing>, ApiError> {
+    if let Some(transfer_encoding) = &request.transfer_encoding {
+        if request.content_length.is_some() {
+            return Err(ApiError::bad_request(
+                "The request has both Transfer-Encoding and Content-Length headers".to_string(),
+            ));
+        }
+        if transfer_encoding.trim() != "chunked" {
+            return Err(ApiError::new(
+                HttpStatus::NotImplemented,
+                format!(
+                    "The transfer encoding {} is not supported. Only chunked is supported",
+                    transfer_encoding
+                ),
+            ));
+        }
+        return Ok(Some(Framing::Chunked(ChunkedDecoder::new())));
+    }
+    match request.content_length {
+        Some(content_length) if content_length > max_body_size => Err(ApiError::new(
+            HttpStatus::PayloadTooLarge,
+            format!(
+                "The request body of {} bytes exceeds the maximum allowed size of {} bytes",
+                content_length, max_body_size
+            ),
+        )),
+        Some(content_length) => Ok(Some(Framing::Length(content_length))),
+        None => Ok(None),
+    }
+}
+
+pub fn handle_request(
+    stream: &mut impl Stream,
+    server: &Server,
+    client: IpAddr,
+    buf: &mut Vec<u8>,
+) -> Result<Outcome, String> {
+    let settings = server.settings();
+    let mut timings = RequestTimings::new();
+    metrics::take_spent();
+    let mut started = Instant::now();
+    if !buf.is_empty() {
+        let _ = stream
+            .socket()
+            .set_read_timeout(Some(settings.read_timeout));
+    }
+    let mut scanned = 0;
+    let (header_end_index, content_index) = loop {
+        let blank = buf
+            .iter()
+            .take_while(|byte| matches!(byte, b'\r' | b'\n'))
+            .count();
+        if blank > 0 {
+            buf.drain(..blank);
+            scanned = 0;
+        }
+        if let Some(end) = http::find_header_end(buf, scanned) {
+            break end;
+        }
+        if buf.len() > http::MAX_HEADER_SIZE {
+            reject_request(
+                stream,
+                ApiError::new(
+                    HttpStatus::RequestHeaderFieldsTooLarge,
+                    format!(
+                        "The request header exceeds the maximum allowed size of {} bytes",
+                        http::MAX_HEADER_SIZE
+                    ),
+                ),
+            );
+            return Err("Rejected an oversized request header".to_string());
+        }
+        scanned = buf.len();
+        if !fill(stream, buf, settings.read_timeout, &mut started)? {
+            return Ok(Outcome::Close);
+        }
+    };
+    let mut request = match RequestTimings::time(&mut timings.parse, || {
+        http::Request::from_bytes(&buf[..header_end_index])
+    }) {
+        Ok(request) => request,
+        Err(err) => {
+            let message = err.message.clone();
+            reject_request(stream, err);
+            return Err(message);
+        }
+    };
+    request.client = Some(client);
+    if request.credentials.is_none() {
+        request.credentials = stream.peer_identity().map(Credentials::Certificate);
+    }
+    let streaming = server
+        .router
+        .resolve(&request.method, &request.route)
+        .is_some_and(|(route, _)| route.streaming);
+    // Streaming routes read a body with a length while it arrives, so it is not limited.
+    let max_length = match streaming {
+        true => usize::MAX,
+        false => settings.max_body_size,
+    };
+    let framing = match framing(&request, max_length) {
+        Ok(framing) => framing,
+        Err(err) => {
+            let message = format!("Rejected the request to {}: {}", request.route, err.message);
+            reject_request(stream, err);
+            return Err(message);
+        }
+    };
+    let (content, consumed, remaining) = match framing {
+        None => (Vec::new(), content_index, 0),
+        Some(Framing::Length(content_length)) if streaming => {
+            let consumed = buf.len().min(content_index + content_length);
+            (
+                buf[content_index..consumed].to_vec(),
+                consumed,
+                content_index + content_length - consumed,
+            )
+        }
+        Some(Framing::Length(content_length)) => {
+            while buf.len() < content_index + content_length {
+                fill(stream, buf, settings.read_timeout, &mut started)?;
+            }
+            (
+                buf[content_index..content_index + content_length].to_vec(),
+                content_index + content_length,
+                0,
+            )
+        }
+        Some(Framing::Chunked(mut decoder)) => loop {
+            match decoder.advance(&buf[content_index..], settings.max_body_size) {
+                Ok(Some(length)) => {
+                    break (decoder.body, content_index + length, 0);
+                }
+                Ok(None) => {
+                    fill(stream, buf, settings.read_timeout, &mut started)?;
+                }
+                Err(err) => {
+                    let message =
+                        format!("Rejected the request to {}: {}", request.route, err.message);
+                    reject_request(stream, err);
+                    return Err(message);
+                }
+            }
+        },
+    };
+    buf.drain(..consumed);
+    let streamed_bytes = streaming.then_some(remaining);
+    let mut body_error: Option<ApiError> = None;
+    let received = if streaming {
+        content
+    } else {
+        if let Err(err) = RequestTimings::time(&mut timings.parse, || {
+            request.parse_content(content, settings.max_body_size)
+        }) {
+            body_error = Some(err);
+        }
+        Vec::new()
+    };
+    let mut resp = match streamed_bytes {
+        Some(remaining) => {
+            let encoding = match &request.content_encoding {
+                Some(encoding_name) => Encoding::from_name(encoding_name).unwrap_or_else(|| {
+                    body_error = Some(ApiError::new(
+                        HttpStatus::UnsupportedMediaType,
+                        format!("The content encoding {} is not supported", encoding_name),
+                    ));
+                    Encoding::Identity
+                }),
+                None => Encoding::Identity,
+            };
+            let reader = RefCell::new(BodyReader::new(
+                received,
+                &mut *stream,
+                remaining as u64,
+                encoding,
+            ));
+            let resp = isolate(&request, || {
+                middleware::run(server, &request, &|request| match &body_error {
+                    Some(err) => err.to_response(),
+                    None => server.router.dispatch_with_body(server, request, &reader),
+                })
+            });
+            if reader.into_inner().drain().is_err() {
+                request.keep_alive = false;
+            }
+            resp
+        }
+        None => isolate(&request, || {
+            middleware::run(server, &request, &|request| match &body_error {
+                Some(err) => err.to_response(),
+                None => server.router.dispatch(server, request),
+            })
+        }),
+    };
+    if matches!(
+        resp.status,
+        HttpStatus::Unauthorized | HttpStatus::Forbidden
+    ) {
+        server.alerts.auth_failed(request.client);
+    }
+    let spent = metrics::take_spent();
+    timings.validate = spent.validate;
+    timings.storage = spent.storage;
+    // Every upgraded connection keeps a thread of its own until it ends, so only so many are
+    // taken at once.
+    let mut upgrade_guard = None;
+    if resp.upgrade.is_some() {
+        match server.connections.upgrade(MAX_UPGRADED_CONNECTIONS) {
+            Some(guard) => upgrade_guard = Some(guard),
+            None => {
+                resp = ApiError::new(
+                    HttpStatus::ServiceUnavailable,
+                    "Too many streams are open, try again later".to_string(),
+                )
+                .with_code("too_many_streams")
+                .to_response();
+                resp.set_header("Retry-After", UPGRADE_RETRY_AFTER_SECS.to_string());
             }
         }
-        None => Err("Failed to parse header data from the request".to_string()),
+    }
+    resp.set_header(http::REQUEST_ID_HEADER, request.id.clone());
+    if resp.upgrade.is_none() {
+        resp.set_header(
+            "Connection",
+            if request.keep_alive {
+                "keep-alive".to_string()
+            } else {
+                "close".to_string()
+            },
+        );
+    }
+    let bytes = RequestTimings::time(&mut timings.serialize, || {
+        if request.method == HttpMethod::HEAD {
+            resp.head_bytes()
+        } else {
+            resp.to_bytes()
+        }
+    });
+    let upgrade = resp.upgrade.take();
+    metrics::record(&request.route, &timings);
+    let written = stream.write_all(&bytes).and_then(|_| stream.flush());
+    server.logger.log(
+        &AccessEntry {
+            request: &request,
+            client,
+            status: resp.status,
+            bytes: bytes.len(),
+            latency: started.elapsed(),
+        },
+        request.json(),
+    );
+    match written {
+        Ok(_) => Ok(match upgrade {
+            Some(upgrade) => Outcome::Upgrade(Box::new(move |stream| {
+                let _guard = upgrade_guard;
+                upgrade(stream)
+            })),
+            None if request.keep_alive => Outcome::KeepAlive,
+            None => Outcome::Close,
+        }),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upgrades_are_capped_until_one_ends() {
+        let connections = Arc::new(Connections::default());
+        let first = connections.upgrade(2).unwrap();
+        let _second = connections.upgrade(2).unwrap();
+        assert!(connections.upgrade(2).is_none());
+        drop(first);
+        assert!(connections.upgrade(2).is_some());
+        assert_eq!(connections.upgraded.load(Ordering::SeqCst), 1);
     }
 }
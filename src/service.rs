@@ -0,0 +1,59 @@
+use crate::{router::Router, server::Server};
+
+pub trait Service: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn register(&self, _router: &mut Router) {}
+
+    fn start(&self, server: &Server) -> Result<(), String>;
+
+    fn stop(&self) {}
+}
+
+pub struct Services {
+    services: Vec<Box<dyn Service>>,
+}
+
+impl Default for Services {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Services {
+    pub fn new() -> Services {
+        Services {
+            services: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, service: Box<dyn Service>) {
+        self.services.push(service);
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.services.iter().map(|service| service.name()).collect()
+    }
+
+    pub fn start(&self, server: &Server) -> Result<(), String> {
+        for (index, service) in self.services.iter().enumerate() {
+            if let Err(err) = service.start(server) {
+                for started in self.services[..index].iter().rev() {
+                    started.stop();
+                }
+                return Err(format!(
+                    "Could not start the {} service: {}",
+                    service.name(),
+                    err
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        for service in self.services.iter().rev() {
+            service.stop();
+        }
+    }
+}
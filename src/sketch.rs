@@ -0,0 +1,247 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use crate::json::{Json, JsonNumber, JsonObject};
+
+pub const DEFAULT_HLL_PRECISION: u8 = 14;
+pub const DEFAULT_TOP_K_CAPACITY: usize = 1024;
+
+fn hash_key(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog {
+            precision: DEFAULT_HLL_PRECISION,
+            registers: vec![0; 1 << DEFAULT_HLL_PRECISION],
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub fn new(precision: u8) -> Result<HyperLogLog, String> {
+        if !(4..=18).contains(&precision) {
+            return Err(format!(
+                "The HyperLogLog precision should be between 4 and 18, found {}",
+                precision
+            ));
+        }
+        Ok(HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        })
+    }
+
+    pub fn insert(&mut self, value: &Json) {
+        self.insert_hash(hash_key(&value.canonical()));
+    }
+
+    pub fn insert_hash(&mut self, hash: u64) {
+        let index = (hash >> (64 - self.precision)) as usize;
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) -> Result<(), String> {
+        if self.precision != other.precision {
+            return Err(format!(
+                "Cannot merge HyperLogLog sketches with precisions {} and {}",
+                self.precision, other.precision
+            ));
+        }
+        for (mine, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            if *theirs > *mine {
+                *mine = *theirs;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let count = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / count),
+        };
+        let mut sum = 0.0f64;
+        let mut zeros = 0usize;
+        for register in &self.registers {
+            sum += 2f64.powi(-(*register as i32));
+            if *register == 0 {
+                zeros += 1;
+            }
+        }
+        let raw = alpha * count * count / sum;
+        if raw <= 2.5 * count && zeros > 0 {
+            (count * (count / zeros as f64).ln()).round() as u64
+        } else {
+            raw.round() as u64
+        }
+    }
+
+    pub fn relative_error(&self) -> f64 {
+        1.04 / (self.registers.len() as f64).sqrt()
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["estimate".to_string()] = Json::Number(JsonNumber::Int(self.estimate() as i64));
+        obj["relative_error".to_string()] = Json::Number(JsonNumber::Float(self.relative_error()));
+        Json::Object(obj)
+    }
+}
+
+struct Counter {
+    count: u64,
+    error: u64,
+}
+
+pub struct TopK {
+    capacity: usize,
+    counters: HashMap<String, Counter>,
+}
+
+impl TopK {
+    pub fn new(capacity: usize) -> Result<TopK, String> {
+        if capacity == 0 {
+            return Err("The capacity of a top-k sketch should be at least 1".to_string());
+        }
+        Ok(TopK {
+            capacity,
+            counters: HashMap::new(),
+        })
+    }
+
+    pub fn insert(&mut self, value: &Json) {
+        self.insert_key(value.canonical(), 1);
+    }
+
+    pub fn insert_key(&mut self, key: String, weight: u64) {
+        if let Some(counter) = self.counters.get_mut(&key) {
+            counter.count += weight;
+            return;
+        }
+        if self.counters.len() < self.capacity {
+            self.counters.insert(
+                key,
+                Counter {
+                    count: weight,
+                    error: 0,
+                },
+            );
+            return;
+        }
+        let min_key = self
+            .counters
+            .iter()
+            .min_by_key(|(_, counter)| counter.count)
+            .map(|(key, _)| key.clone());
+        if let Some(min_key) = min_key
+            && let Some(evicted) = self.counters.remove(&min_key)
+        {
+            self.counters.insert(
+                key,
+                Counter {
+                    count: evicted.count + weight,
+                    error: evicted.count,
+                },
+            );
+        }
+    }
+
+    pub fn top(&self, k: usize) -> Vec<(&str, u64, u64)> {
+        let mut items = self
+            .counters
+            .iter()
+            .map(|(key, counter)| (key.as_str(), counter.count, counter.error))
+            .collect::<Vec<_>>();
+        items.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        items.truncate(k);
+        items
+    }
+
+    pub fn to_json(&self, k: usize) -> Json {
+        Json::List(
+            self.top(k)
+                .into_iter()
+                .map(|(key, count, error)| {
+                    let mut obj = JsonObject::new();
+                    obj["value".to_string()] = match Json::parse(key.as_bytes()) {
+                        Ok(value) => value,
+                        Err(_) => Json::String(key.to_string()),
+                    };
+                    obj["count".to_string()] = Json::Number(JsonNumber::Int(count as i64));
+                    obj["error".to_string()] = Json::Number(JsonNumber::Int(error as i64));
+                    Json::Object(obj)
+                })
+                .collect(),
+        )
+    }
+}
+
+// A uniform random sample of a stream of unknown length, kept in one pass: the n-th item replaces
+// a random one of the kept items with probability capacity / n.
+pub struct Reservoir<T> {
+    capacity: usize,
+    items: Vec<T>,
+    seen: u64,
+    state: u64,
+}
+
+impl<T> Reservoir<T> {
+    pub fn new(capacity: usize) -> Result<Reservoir<T>, String> {
+        let mut seed = [0u8; 8];
+        getrandom::getrandom(&mut seed)
+            .map_err(|err| format!("Could not seed the sample: {}", err))?;
+        Ok(Reservoir {
+            capacity,
+            items: Vec::with_capacity(capacity),
+            seen: 0,
+            state: u64::from_le_bytes(seed),
+        })
+    }
+
+    // SplitMix64, which is plenty for picking documents and needs no system call per item.
+    fn random(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn insert(&mut self, item: T) {
+        self.seen += 1;
+        if self.items.len() < self.capacity {
+            self.items.push(item);
+            return;
+        }
+        let slot = ((self.random() as u128 * self.seen as u128) >> 64) as usize;
+        if slot < self.capacity {
+            self.items[slot] = item;
+        }
+    }
+
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    pub fn into_items(self) -> Vec<T> {
+        self.items
+    }
+}
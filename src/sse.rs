@@ -0,0 +1,101 @@
+use std::{sync::mpsc::RecvTimeoutError, time::Duration};
+
+use crate::{
+    changes::Events,
+    http::{HttpStatus, Response},
+    server::Stream,
+};
+
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+pub struct Event {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: String,
+    pub retry: Option<u64>,
+}
+
+impl Event {
+    pub fn new(data: String) -> Event {
+        Event {
+            id: None,
+            event: None,
+            data,
+            retry: None,
+        }
+    }
+
+    pub fn named(event: &str, data: String) -> Event {
+        Event {
+            event: Some(event.to_string()),
+            ..Event::new(data)
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut text = String::new();
+        if let Some(id) = &self.id {
+            text += &format!("id: {}\n", single_line(id));
+        }
+        if let Some(event) = &self.event {
+            text += &format!("event: {}\n", single_line(event));
+        }
+        if let Some(retry) = self.retry {
+            text += &format!("retry: {}\n", retry);
+        }
+        for line in self.data.split('\n') {
+            text += &format!("data: {}\n", line.trim_end_matches('\r'));
+        }
+        text += "\n";
+        text.into_bytes()
+    }
+}
+
+impl From<String> for Event {
+    fn from(data: String) -> Self {
+        Event::new(data)
+    }
+}
+
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+fn write_bytes(stream: &mut dyn Stream, bytes: &[u8]) -> Result<(), String> {
+    stream
+        .write_all(bytes)
+        .and_then(|_| stream.flush())
+        .map_err(|err| format!("Error while writing to the event stream: {}", err))
+}
+
+pub fn send<T: Into<Event>>(
+    stream: &mut dyn Stream,
+    mut events: impl Events<T>,
+) -> Result<(), String> {
+    write_bytes(stream, b": connected\n\n")?;
+    loop {
+        match events.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(event) => {
+                write_bytes(stream, &event.into().to_bytes())?;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                write_bytes(stream, b": heartbeat\n\n")?;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Ok(());
+            }
+        }
+    }
+}
+
+pub fn response<T: Into<Event>>(events: impl Events<T> + Send + 'static) -> Response {
+    let mut resp = Response::new(HttpStatus::Ok);
+    resp.set_header("Content-Type", "text/event-stream".to_string());
+    resp.set_header("Cache-Control", "no-cache".to_string());
+    resp.upgrade = Some(Box::new(move |stream| {
+        if let Err(err) = send(stream, events) {
+            eprintln!("The event stream ended with error: {}", err);
+        }
+    }));
+    resp
+}
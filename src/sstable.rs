@@ -0,0 +1,620 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, ErrorKind, Read, Write},
+    ops::{Bound, Deref},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use crate::{cache, db};
+
+pub const TABLE_EXTENSION: &str = "sst";
+const MAGIC_V1: &[u8; 8] = b"DB6SST01";
+const MAGIC: &[u8; 8] = b"DB6SST02";
+const ENTRY_HEADER_LEN: usize = 13;
+const BLOCK_HEADER_LEN: usize = 13;
+const FOOTER_LEN_V1: usize = 28;
+const FOOTER_LEN: usize = 36;
+const INDEX_INTERVAL: u64 = 16;
+const KIND_VALUE: u8 = 0;
+const KIND_TOMBSTONE: u8 = 1;
+
+// Every table that is opened gets the next serial number, which keys its blocks in the cache.
+static SERIAL: AtomicU64 = AtomicU64::new(0);
+
+// How the blocks of a table are compressed. A block that does not get smaller is stored as it is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Codec {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Lz4 => "lz4",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Codec> {
+        match name {
+            "none" => Some(Codec::None),
+            "lz4" => Some(Codec::Lz4),
+            "zstd" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Lz4),
+            2 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+// A value of None is a tombstone, which hides the key in every older table.
+pub type Value = Option<Vec<u8>>;
+
+pub type Entry = (String, Value);
+
+// The range of log records whose writes a table holds. Tables from a merge cover the ranges of
+// all their inputs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TableId {
+    pub first_seq: u64,
+    pub last_seq: u64,
+}
+
+impl TableId {
+    pub fn file_name(&self) -> String {
+        format!(
+            "{:020}-{:020}.{}",
+            self.first_seq, self.last_seq, TABLE_EXTENSION
+        )
+    }
+
+    pub fn parse(path: &Path) -> Option<TableId> {
+        if path.extension()? != TABLE_EXTENSION {
+            return None;
+        }
+        let (first, last) = path.file_stem()?.to_str()?.split_once('-')?;
+        Some(TableId {
+            first_seq: first.parse().ok()?,
+            last_seq: last.parse().ok()?,
+        })
+        .filter(|id| id.first_seq <= id.last_seq)
+    }
+
+    pub fn covers(&self, other: &TableId) -> bool {
+        self.first_seq <= other.first_seq && other.last_seq <= self.last_seq
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, offset)? {
+            0 => {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            read => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn u32_at(bytes: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap())
+}
+
+fn encode_entry(key: &str, value: Option<&[u8]>) -> Vec<u8> {
+    let body = value.unwrap_or_default();
+    let mut entry = Vec::with_capacity(ENTRY_HEADER_LEN + key.len() + body.len());
+    entry.extend_from_slice(&[0; 4]);
+    entry.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    entry.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    entry.push(match value {
+        Some(_) => KIND_VALUE,
+        None => KIND_TOMBSTONE,
+    });
+    entry.extend_from_slice(key.as_bytes());
+    entry.extend_from_slice(body);
+    let checksum = crc32fast::hash(&entry[4..]);
+    entry[..4].copy_from_slice(&checksum.to_le_bytes());
+    entry
+}
+
+// Compresses the entries of a block, and prefixes them with a header to check and decompress
+// them with.
+fn encode_block(raw: &[u8], codec: Codec) -> std::io::Result<Vec<u8>> {
+    let compressed = match codec {
+        Codec::None => None,
+        Codec::Lz4 => Some(lz4_flex::block::compress(raw)),
+        Codec::Zstd => Some(zstd::bulk::compress(raw, 0)?),
+    };
+    let (codec, stored) = match &compressed {
+        Some(compressed) if compressed.len() < raw.len() => (codec, compressed.as_slice()),
+        _ => (Codec::None, raw),
+    };
+    let mut block = Vec::with_capacity(BLOCK_HEADER_LEN + stored.len());
+    block.push(codec.id());
+    block.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+    block.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+    block.extend_from_slice(&crc32fast::hash(stored).to_le_bytes());
+    block.extend_from_slice(stored);
+    Ok(block)
+}
+
+fn decode_block(block: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if block.len() < BLOCK_HEADER_LEN {
+        return Err("is cut short");
+    }
+    let codec = Codec::from_id(block[0]).ok_or("has an unknown compression")?;
+    let stored = &block[BLOCK_HEADER_LEN..];
+    let raw_len = u32_at(block, 5) as usize;
+    if stored.len() != u32_at(block, 1) as usize {
+        return Err("has the wrong length");
+    }
+    if crc32fast::hash(stored) != u32_at(block, 9) {
+        return Err("fails its checksum");
+    }
+    let raw = match codec {
+        Codec::None => Ok(stored.to_vec()),
+        Codec::Lz4 => lz4_flex::block::decompress(stored, raw_len).map_err(|_| ()),
+        Codec::Zstd => zstd::bulk::decompress(stored, raw_len).map_err(|_| ()),
+    };
+    raw.ok()
+        .filter(|raw| raw.len() == raw_len)
+        .ok_or("cannot be decompressed")
+}
+
+// A table file holds its entries sorted by key, each with its own checksum, in blocks of 16
+// entries that are compressed on their own. A sparse index with the first key and the offset of
+// every block, and a fixed size footer, follow them:
+//
+//     entry: crc32 | key length u32 | value length u32 | kind u8 | key | value
+//     block: codec u8 | stored length u32 | raw length u32 | crc32 | entries
+//     index: key length u32 | key | offset u64
+//     footer: raw length u64 | index offset u64 | entry count u64 | index crc32 | magic
+//
+// Tables of the first version have the entries right after each other, and no raw length in
+// the footer, since they are not compressed.
+pub struct Table {
+    pub id: TableId,
+    pub path: PathBuf,
+    pub entries: u64,
+    pub bytes: u64,
+    // The size of the entries before compression.
+    pub raw_bytes: u64,
+    file: File,
+    index: Vec<(String, u64)>,
+    index_offset: u64,
+    blocks: bool,
+    serial: u64,
+}
+
+impl Table {
+    fn error(&self, message: &str) -> String {
+        format!(
+            "The table {} is corrupted: {}",
+            self.path.display(),
+            message
+        )
+    }
+
+    // Writes the entries, which are sorted by key, in the table format.
+    pub fn encode(
+        entries: impl IntoIterator<Item = (impl AsRef<str>, Option<impl AsRef<[u8]>>)>,
+        codec: Codec,
+        out: &mut impl Write,
+    ) -> std::io::Result<()> {
+        let mut offset = 0u64;
+        let mut raw_bytes = 0u64;
+        let mut count = 0u64;
+        let mut index = Vec::new();
+        let mut block = Vec::new();
+        for (key, value) in entries {
+            let key = key.as_ref();
+            if count.is_multiple_of(INDEX_INTERVAL) {
+                if !block.is_empty() {
+                    let encoded = encode_block(&block, codec)?;
+                    out.write_all(&encoded)?;
+                    offset += encoded.len() as u64;
+                    block.clear();
+                }
+                index.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                index.extend_from_slice(key.as_bytes());
+                index.extend_from_slice(&offset.to_le_bytes());
+            }
+            let entry = encode_entry(key, value.as_ref().map(|value| value.as_ref()));
+            block.extend_from_slice(&entry);
+            raw_bytes += entry.len() as u64;
+            count += 1;
+        }
+        if !block.is_empty() {
+            let encoded = encode_block(&block, codec)?;
+            out.write_all(&encoded)?;
+            offset += encoded.len() as u64;
+        }
+        out.write_all(&index)?;
+        out.write_all(&raw_bytes.to_le_bytes())?;
+        out.write_all(&offset.to_le_bytes())?;
+        out.write_all(&count.to_le_bytes())?;
+        out.write_all(&crc32fast::hash(&index).to_le_bytes())?;
+        out.write_all(MAGIC)
+    }
+
+    pub fn write(
+        dir: &Path,
+        id: TableId,
+        codec: Codec,
+        entries: impl IntoIterator<Item = (impl AsRef<str>, Option<impl AsRef<[u8]>>)>,
+    ) -> Result<Table, String> {
+        let path = dir.join(id.file_name());
+        let temp = dir.join(format!(".{}.tmp", id.file_name()));
+        let written = File::create(&temp).and_then(|file| {
+            let mut out = BufWriter::new(file);
+            Table::encode(entries, codec, &mut out)?;
+            out.into_inner()?.sync_all()?;
+            fs::rename(&temp, &path)?;
+            db::sync_dir(dir)
+        });
+        if let Err(err) = written {
+            let _ = fs::remove_file(&temp);
+            return Err(format!(
+                "Error while writing the table {}: {}",
+                path.display(),
+                err
+            ));
+        }
+        Table::open(&path)
+    }
+
+    pub fn open(path: &Path) -> Result<Table, String> {
+        let io_error = |err: std::io::Error| {
+            format!("Error while reading the table {}: {}", path.display(), err)
+        };
+        let id = TableId::parse(path)
+            .ok_or_else(|| format!("The table name {} is invalid", path.display()))?;
+        let file = File::open(path).map_err(io_error)?;
+        let bytes = file.metadata().map_err(io_error)?.len();
+        let mut table = Table {
+            id,
+            path: path.to_path_buf(),
+            entries: 0,
+            bytes,
+            raw_bytes: 0,
+            file,
+            index: Vec::new(),
+            index_offset: 0,
+            blocks: false,
+            serial: SERIAL.fetch_add(1, Ordering::Relaxed),
+        };
+        if bytes < FOOTER_LEN_V1 as u64 {
+            return Err(table.error("the footer is missing"));
+        }
+        let mut magic = [0u8; 8];
+        read_exact_at(&table.file, &mut magic, bytes - 8).map_err(io_error)?;
+        table.blocks = match &magic {
+            MAGIC => true,
+            MAGIC_V1 => false,
+            _ => {
+                return Err(table.error("the footer is invalid"));
+            }
+        };
+        if bytes < table.footer_len() {
+            return Err(table.error("the footer is missing"));
+        }
+        let mut footer = vec![0u8; table.footer_len() as usize];
+        read_exact_at(&table.file, &mut footer, bytes - table.footer_len()).map_err(io_error)?;
+        // The first version has no raw length in front of the index offset.
+        let footer = match table.blocks {
+            true => {
+                table.raw_bytes = u64_at(&footer, 0);
+                &footer[8..]
+            }
+            false => &footer[..],
+        };
+        table.index_offset = u64_at(footer, 0);
+        table.entries = u64_at(footer, 8);
+        if !table.blocks {
+            table.raw_bytes = table.index_offset;
+        }
+        let index_end = bytes - table.footer_len();
+        if table.index_offset > index_end {
+            return Err(table.error("the index offset is out of range"));
+        }
+        let mut index = vec![0u8; (index_end - table.index_offset) as usize];
+        read_exact_at(&table.file, &mut index, table.index_offset).map_err(io_error)?;
+        if crc32fast::hash(&index) != u32_at(footer, 16) {
+            return Err(table.error("the index checksum does not match"));
+        }
+        let mut pos = 0;
+        while pos < index.len() {
+            let key_end = index
+                .get(pos..pos + 4)
+                .map(|len| pos + 4 + u32_at(len, 0) as usize)
+                .filter(|key_end| key_end + 8 <= index.len())
+                .ok_or_else(|| table.error("the index is cut short"))?;
+            let key = String::from_utf8(index[pos + 4..key_end].to_vec())
+                .map_err(|_| table.error("an index key is not valid UTF-8"))?;
+            table.index.push((key, u64_at(&index, key_end)));
+            pos = key_end + 8;
+        }
+        Ok(table)
+    }
+
+    fn footer_len(&self) -> u64 {
+        match self.blocks {
+            true => FOOTER_LEN as u64,
+            false => FOOTER_LEN_V1 as u64,
+        }
+    }
+
+    // The block whose first key is at or before the given key.
+    fn seek(&self, key: &str) -> usize {
+        self.index
+            .partition_point(|(first, _)| first.as_str() <= key)
+            .saturating_sub(1)
+    }
+
+    // Reads the entries of a block, decompressed. The entries of a table of the first version
+    // are read in blocks as well, from one index offset to the next. Reads that go through the
+    // cache keep the block in it.
+    fn block(&self, block: usize, cached: bool) -> Result<Option<Arc<Vec<u8>>>, String> {
+        let Some((_, start)) = self.index.get(block) else {
+            return Ok(None);
+        };
+        if cached && let Some(data) = cache::get((self.serial, block)) {
+            return Ok(Some(data));
+        }
+        let end = self
+            .index
+            .get(block + 1)
+            .map_or(self.index_offset, |(_, end)| *end);
+        if *start > end || end > self.index_offset {
+            return Err(self.error("the index offsets are out of order"));
+        }
+        let mut data = vec![0u8; (end - start) as usize];
+        read_exact_at(&self.file, &mut data, *start).map_err(|err| {
+            format!(
+                "Error while reading the table {}: {}",
+                self.path.display(),
+                err
+            )
+        })?;
+        let data = match self.blocks {
+            true => decode_block(&data).map_err(|message| {
+                self.error(&format!("the block at offset {} {}", start, message))
+            })?,
+            false => data,
+        };
+        let data = Arc::new(data);
+        if cached {
+            cache::insert((self.serial, block), data.clone());
+        }
+        Ok(Some(data))
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Value>, String> {
+        let mut reader = Reader::new(self, self.seek(key), true);
+        while let Some((found, value)) = reader.next_entry()? {
+            match found.as_str().cmp(key) {
+                std::cmp::Ordering::Less => {}
+                std::cmp::Ordering::Equal => {
+                    return Ok(Some(value));
+                }
+                std::cmp::Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn scan(self: &Arc<Table>, start: Bound<&str>) -> TableScan {
+        let block = match start {
+            Bound::Included(key) | Bound::Excluded(key) => self.seek(key),
+            Bound::Unbounded => 0,
+        };
+        TableScan {
+            reader: Reader::new(self.clone(), block, true),
+            start: match start {
+                Bound::Included(key) => Bound::Included(key.to_string()),
+                Bound::Excluded(key) => Bound::Excluded(key.to_string()),
+                Bound::Unbounded => Bound::Unbounded,
+            },
+            failed: false,
+        }
+    }
+
+    // Reads every entry without keeping the blocks in the cache, for merges and checks that read
+    // each block once and would only push the blocks that are read often out of it.
+    pub fn scan_uncached(self: &Arc<Table>) -> TableScan {
+        TableScan {
+            reader: Reader::new(self.clone(), 0, false),
+            start: Bound::Unbounded,
+            failed: false,
+        }
+    }
+
+    // The size of the sparse index, which is kept in memory while the table is open.
+    pub fn index_bytes(&self) -> u64 {
+        self.bytes - self.footer_len() - self.index_offset
+    }
+
+    // The size of the blocks as stored, which is less than raw_bytes when they are compressed.
+    pub fn data_bytes(&self) -> u64 {
+        self.index_offset
+    }
+
+    // Reads the whole file through the handle the table was opened with, so it can be copied
+    // even after a merge removed it.
+    pub fn contents(&self) -> Contents<'_> {
+        Contents {
+            table: self,
+            offset: 0,
+        }
+    }
+
+    pub fn remove(&self) -> Result<(), String> {
+        match fs::remove_file(&self.path) {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(format!(
+                "Error while removing the table {}: {}",
+                self.path.display(),
+                err
+            )),
+        }
+    }
+}
+
+pub struct Contents<'a> {
+    table: &'a Table,
+    offset: u64,
+}
+
+impl Read for Contents<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = (buf.len() as u64).min(self.table.bytes - self.offset) as usize;
+        read_exact_at(&self.table.file, &mut buf[..len], self.offset)?;
+        self.offset += len as u64;
+        Ok(len)
+    }
+}
+
+// Reads entries sequentially, a block at a time.
+struct Reader<T: Deref<Target = Table>> {
+    table: T,
+    block: usize,
+    cached: bool,
+    data: Arc<Vec<u8>>,
+    pos: usize,
+}
+
+impl<T: Deref<Target = Table>> Reader<T> {
+    fn new(table: T, block: usize, cached: bool) -> Reader<T> {
+        Reader {
+            table,
+            block,
+            cached,
+            data: Arc::new(Vec::new()),
+            pos: 0,
+        }
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>, String> {
+        while self.pos >= self.data.len() {
+            match self.table.block(self.block, self.cached)? {
+                Some(data) => {
+                    self.data = data;
+                    self.pos = 0;
+                    self.block += 1;
+                }
+                None => {
+                    return Ok(None);
+                }
+            }
+        }
+        let (block, pos) = (self.block - 1, self.pos);
+        let corrupted =
+            |message: &str| format!("the entry at offset {} of block {} {}", pos, block, message);
+        let Some(header) = self.data.get(pos..pos + ENTRY_HEADER_LEN) else {
+            return Err(self.table.error(&corrupted("is cut short")));
+        };
+        let checksum = u32_at(header, 0);
+        let key_len = u32_at(header, 4) as usize;
+        let value_len = u32_at(header, 8) as usize;
+        let kind = header[12];
+        let len = ENTRY_HEADER_LEN + key_len + value_len;
+        let Some(entry) = self.data.get(pos..pos + len) else {
+            return Err(self
+                .table
+                .error(&corrupted("runs past the end of its block")));
+        };
+        if crc32fast::hash(&entry[4..]) != checksum {
+            return Err(self.table.error(&corrupted("fails its checksum")));
+        }
+        let key = match std::str::from_utf8(&entry[ENTRY_HEADER_LEN..ENTRY_HEADER_LEN + key_len]) {
+            Ok(key) => key.to_string(),
+            Err(_) => {
+                return Err(self
+                    .table
+                    .error(&corrupted("has a key that is not valid UTF-8")));
+            }
+        };
+        let value = match kind {
+            KIND_VALUE => Some(entry[ENTRY_HEADER_LEN + key_len..].to_vec()),
+            KIND_TOMBSTONE => None,
+            _ => {
+                return Err(self.table.error(&corrupted("has an unknown kind")));
+            }
+        };
+        self.pos += len;
+        Ok(Some((key, value)))
+    }
+}
+
+pub struct TableScan {
+    reader: Reader<Arc<Table>>,
+    start: Bound<String>,
+    failed: bool,
+}
+
+impl Iterator for TableScan {
+    type Item = Result<Entry, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            let (key, value) = match self.reader.next_entry() {
+                Ok(Some(entry)) => entry,
+                Ok(None) => {
+                    return None;
+                }
+                Err(err) => {
+                    self.failed = true;
+                    return Some(Err(err));
+                }
+            };
+            let before_start = match &self.start {
+                Bound::Included(start) => key < *start,
+                Bound::Excluded(start) => key <= *start,
+                Bound::Unbounded => false,
+            };
+            if !before_start {
+                self.start = Bound::Unbounded;
+                return Some(Ok((key, value)));
+            }
+        }
+        None
+    }
+}
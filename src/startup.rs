@@ -0,0 +1,238 @@
+use std::{fs, net::SocketAddr, path::Path, process};
+
+use chrono::Utc;
+
+use crate::{
+    json::{Json, JsonNumber, JsonObject},
+    logging::LogFormat,
+    root::{Discovered, Root},
+    server::Server,
+    wal::{self, Recovery},
+};
+
+pub enum DatabaseState {
+    Ok,
+    Warning(String),
+    Error(String),
+}
+
+impl DatabaseState {
+    fn name(&self) -> &'static str {
+        match self {
+            DatabaseState::Ok => "ok",
+            DatabaseState::Warning(_) => "warning",
+            DatabaseState::Error(_) => "error",
+        }
+    }
+
+    fn message(&self) -> Option<&str> {
+        match self {
+            DatabaseState::Ok => None,
+            DatabaseState::Warning(message) | DatabaseState::Error(message) => Some(message),
+        }
+    }
+}
+
+pub struct DatabaseReport {
+    pub name: String,
+    pub state: DatabaseState,
+    pub collections: usize,
+    pub documents: u64,
+    pub recovery: Option<Recovery>,
+}
+
+impl DatabaseReport {
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["name".to_string()] = Json::String(self.name.clone());
+        obj["state".to_string()] = Json::String(self.state.name().to_string());
+        if let Some(message) = self.state.message() {
+            obj["message".to_string()] = Json::String(message.to_string());
+        }
+        obj["collections".to_string()] = Json::Number(JsonNumber::Int(self.collections as i64));
+        obj["documents".to_string()] = Json::Number(JsonNumber::Int(self.documents as i64));
+        if let Some(recovery) = &self.recovery {
+            obj["wal".to_string()] = recovery.to_json();
+        }
+        Json::Object(obj)
+    }
+}
+
+pub struct StartupReport {
+    pub version: String,
+    pub root: String,
+    pub addresses: Vec<SocketAddr>,
+    pub tls: bool,
+    pub workers: usize,
+    pub durability: String,
+    pub databases: Vec<DatabaseReport>,
+    pub warnings: Vec<String>,
+}
+
+impl StartupReport {
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["time".to_string()] = Json::String(Utc::now().to_rfc3339());
+        obj["event".to_string()] = Json::String("startup".to_string());
+        obj["version".to_string()] = Json::String(self.version.clone());
+        obj["pid".to_string()] = Json::Number(JsonNumber::Int(process::id() as i64));
+        obj["root".to_string()] = Json::String(self.root.clone());
+        obj["addresses".to_string()] = Json::List(
+            self.addresses
+                .iter()
+                .map(|addr| Json::String(addr.to_string()))
+                .collect(),
+        );
+        obj["tls".to_string()] = Json::Bool(self.tls);
+        obj["workers".to_string()] = Json::Number(JsonNumber::Int(self.workers as i64));
+        obj["durability".to_string()] = Json::String(self.durability.clone());
+        obj["databases".to_string()] =
+            Json::List(self.databases.iter().map(|db| db.to_json()).collect());
+        obj["warnings".to_string()] = Json::List(
+            self.warnings
+                .iter()
+                .map(|warning| Json::String(warning.clone()))
+                .collect(),
+        );
+        Json::Object(obj)
+    }
+
+    pub fn to_text(&self) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        let mut text = format!("db6 {} (pid {})\n", self.version, process::id());
+        text += &format!("  root:       {}\n", self.root);
+        for addr in &self.addresses {
+            text += &format!("  listening:  {}://{}\n", scheme, addr);
+        }
+        text += &format!("  workers:    {}\n", self.workers);
+        text += &format!("  durability: {}\n", self.durability);
+        text += &format!("  databases:  {}\n", self.databases.len());
+        for db in &self.databases {
+            text += &format!(
+                "    {} [{}] {} collections, {} documents",
+                db.name,
+                db.state.name(),
+                db.collections,
+                db.documents
+            );
+            if let Some(recovery) = db
+                .recovery
+                .as_ref()
+                .filter(|recovery| recovery.last_seq > 0)
+            {
+                text += &format!(
+                    ", log at {}, {} records replayed",
+                    recovery.last_seq, recovery.replayed
+                );
+                if recovery.truncated {
+                    text += ", torn tail truncated";
+                }
+            }
+            if let Some(message) = db.state.message() {
+                text += &format!(": {}", message);
+            }
+            text += "\n";
+        }
+        for warning in &self.warnings {
+            text += &format!("  warning:    {}\n", warning);
+        }
+        text.trim_end().to_string()
+    }
+
+    pub fn print(&self, format: LogFormat) {
+        match format {
+            LogFormat::Json => println!("{}", self.to_json().canonical()),
+            LogFormat::Text | LogFormat::Common => println!("{}", self.to_text()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn permission_warning(path: &Path) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path).ok()?.permissions().mode();
+    (mode & 0o002 != 0).then(|| {
+        format!(
+            "{} is writable by all users of the host (mode {:o})",
+            path.display(),
+            mode & 0o777
+        )
+    })
+}
+
+#[cfg(not(unix))]
+fn permission_warning(_path: &Path) -> Option<String> {
+    None
+}
+
+fn check_writable(dir: &Path) -> Result<(), String> {
+    let probe = dir.join(format!(".db6-write-check-{}", process::id()));
+    fs::write(&probe, b"")
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|err| format!("{} is not writable: {}", dir.display(), err))
+}
+
+fn check_database(found: Discovered) -> DatabaseReport {
+    let mut report = DatabaseReport {
+        name: found.name,
+        state: DatabaseState::Ok,
+        collections: 0,
+        documents: 0,
+        recovery: None,
+    };
+    let db = match found.db {
+        Ok(db) => db,
+        Err(err) => {
+            report.state = DatabaseState::Error(err);
+            return report;
+        }
+    };
+    match db.recover() {
+        Ok(recovery) => {
+            report.recovery = Some(recovery);
+        }
+        Err(err) => {
+            report.state = DatabaseState::Error(err);
+            return report;
+        }
+    }
+    match db.stats() {
+        Ok(stats) => {
+            report.collections = stats.collections.len();
+            report.documents = stats.collections.iter().map(|col| col.documents).sum();
+        }
+        Err(err) => {
+            report.state = DatabaseState::Error(err);
+            return report;
+        }
+    }
+    let path = Path::new(db.path());
+    if let Err(err) = check_writable(path) {
+        report.state = DatabaseState::Error(err);
+    } else if let Some(warning) = permission_warning(path) {
+        report.state = DatabaseState::Warning(warning);
+    }
+    report
+}
+
+pub fn check(server: &Server, addresses: Vec<SocketAddr>) -> Result<StartupReport, String> {
+    let root = Root::open(&server.root)?;
+    check_writable(Path::new(root.path()))?;
+    let mut warnings = Vec::new();
+    warnings.extend(permission_warning(Path::new(root.path())));
+    let databases = root
+        .discover()?
+        .into_iter()
+        .map(check_database)
+        .collect::<Vec<_>>();
+    Ok(StartupReport {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        root: server.root.clone(),
+        addresses,
+        tls: server.settings().tls.is_some(),
+        workers: server.workers,
+        durability: wal::config().durability.to_string(),
+        databases,
+        warnings,
+    })
+}
@@ -0,0 +1,95 @@
+use std::{io, net::TcpListener};
+
+#[cfg(unix)]
+use std::{
+    env,
+    os::{fd::BorrowedFd, unix::net::UnixDatagram},
+    process,
+};
+
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+#[cfg(unix)]
+pub fn listeners() -> io::Result<Option<Vec<TcpListener>>> {
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    if pid != Some(process::id()) {
+        return Ok(None);
+    }
+    let count = match env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<i32>().ok())
+    {
+        Some(count) if count > 0 => count,
+        _ => {
+            return Ok(None);
+        }
+    };
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // systemd keeps the passed sockets open for the lifetime of the process.
+            let socket = unsafe { BorrowedFd::borrow_raw(fd) }
+                .try_clone_to_owned()
+                .map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!("The socket {} passed by systemd is not open: {}", fd, err),
+                    )
+                })?;
+            let listener = TcpListener::from(socket);
+            listener.local_addr().map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!(
+                        "The socket {} passed by systemd is not a TCP listener: {}",
+                        fd, err
+                    ),
+                )
+            })?;
+            Ok(listener)
+        })
+        .collect::<io::Result<Vec<_>>>()
+        .map(Some)
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> io::Result<Option<Vec<TcpListener>>> {
+    Ok(None)
+}
+
+#[cfg(unix)]
+pub fn notify(state: &str) -> io::Result<()> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) if !path.is_empty() => path,
+        _ => {
+            return Ok(());
+        }
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Abstract notification sockets are only supported on Linux",
+            ));
+        }
+        None => {
+            socket.send_to(state.as_bytes(), &path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> io::Result<()> {
+    Ok(())
+}
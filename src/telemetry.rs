@@ -0,0 +1,190 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    alert::{self, WebhookUrl},
+    db,
+    json::{Json, JsonObject},
+    server::Server,
+    service::Service,
+};
+
+pub const TELEMETRY_CONFIG_FILE: &str = "telemetry.json";
+const REPORT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub enum TelemetryAction {
+    Show,
+    Status,
+    Enable(String),
+    Disable,
+}
+
+pub struct TelemetryConfig {
+    pub enabled: bool,
+    pub url: Option<String>,
+}
+
+impl TelemetryConfig {
+    fn path(root: &str) -> PathBuf {
+        Path::new(root).join(TELEMETRY_CONFIG_FILE)
+    }
+
+    pub fn load(root: &str) -> Result<TelemetryConfig, String> {
+        let path = TelemetryConfig::path(root);
+        if !path.is_file() {
+            return Ok(TelemetryConfig {
+                enabled: false,
+                url: None,
+            });
+        }
+        let content = fs::read(&path).map_err(|err| {
+            format!(
+                "Error while reading the telemetry configuration at {}: {}",
+                path.display(),
+                err
+            )
+        })?;
+        let value = Json::parse(&content).map_err(|err| {
+            format!(
+                "The telemetry configuration at {} is not valid JSON: {}",
+                path.display(),
+                err
+            )
+        })?;
+        match value {
+            Json::Object(obj) => Ok(TelemetryConfig {
+                enabled: matches!(obj.get("enabled"), Some(Json::Bool(true))),
+                url: match obj.get("url") {
+                    Some(Json::String(url)) => Some(url.clone()),
+                    _ => None,
+                },
+            }),
+            _ => Err(format!(
+                "The telemetry configuration at {} should be a JSON object",
+                path.display()
+            )),
+        }
+    }
+
+    pub fn save(&self, root: &str) -> Result<(), String> {
+        let mut obj = JsonObject::new();
+        obj["enabled".to_string()] = Json::Bool(self.enabled);
+        if let Some(url) = &self.url {
+            obj["url".to_string()] = Json::String(url.clone());
+        }
+        let path = TelemetryConfig::path(root);
+        fs::write(&path, Json::Object(obj).to_string()).map_err(|err| {
+            format!(
+                "Error while writing the telemetry configuration to {}: {}",
+                path.display(),
+                err
+            )
+        })
+    }
+}
+
+fn database_count_range(root: &str) -> &'static str {
+    let count = db::list(root).map(|names| names.len()).unwrap_or(0);
+    match count {
+        0 => "0",
+        1 => "1",
+        2..=5 => "2-5",
+        6..=20 => "6-20",
+        21..=100 => "21-100",
+        _ => "100+",
+    }
+}
+
+pub fn report(root: &str) -> Json {
+    let mut obj = JsonObject::new();
+    obj["version".to_string()] = Json::String(env!("CARGO_PKG_VERSION").to_string());
+    obj["os".to_string()] = Json::String(std::env::consts::OS.to_string());
+    obj["arch".to_string()] = Json::String(std::env::consts::ARCH.to_string());
+    obj["databases".to_string()] = Json::String(database_count_range(root).to_string());
+    Json::Object(obj)
+}
+
+pub fn run(root: &str, action: &TelemetryAction) -> Result<(), String> {
+    match action {
+        TelemetryAction::Show => {
+            println!("{}", report(root));
+        }
+        TelemetryAction::Status => {
+            let config = TelemetryConfig::load(root)?;
+            match (config.enabled, config.url) {
+                (true, Some(url)) => println!("Telemetry is enabled and reports to {}", url),
+                _ => println!("Telemetry is disabled"),
+            }
+        }
+        TelemetryAction::Enable(url) => {
+            url.parse::<WebhookUrl>()?;
+            TelemetryConfig {
+                enabled: true,
+                url: Some(url.clone()),
+            }
+            .save(root)?;
+            println!(
+                "Telemetry is enabled. Once a day the running server will send the following report to {}",
+                url
+            );
+            println!("{}", report(root));
+        }
+        TelemetryAction::Disable => {
+            TelemetryConfig {
+                enabled: false,
+                url: None,
+            }
+            .save(root)?;
+            println!("Telemetry is disabled");
+        }
+    }
+    Ok(())
+}
+
+pub struct Reporter;
+
+impl Service for Reporter {
+    fn name(&self) -> &str {
+        "telemetry"
+    }
+
+    fn start(&self, server: &Server) -> Result<(), String> {
+        start(&server.root);
+        Ok(())
+    }
+}
+
+pub fn start(root: &str) {
+    let config = match TelemetryConfig::load(root) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}. Telemetry stays disabled", err);
+            return;
+        }
+    };
+    let url = match (config.enabled, config.url) {
+        (true, Some(url)) => match url.parse::<WebhookUrl>() {
+            Ok(url) => url,
+            Err(err) => {
+                eprintln!("{}. Telemetry stays disabled", err);
+                return;
+            }
+        },
+        _ => {
+            return;
+        }
+    };
+    let root = root.to_string();
+    thread::spawn(move || {
+        loop {
+            if let Err(err) = alert::post_json(&url, &report(&root).to_string()) {
+                eprintln!("Could not send the telemetry report: {}", err);
+            }
+            thread::sleep(REPORT_INTERVAL);
+        }
+    });
+}
@@ -0,0 +1,165 @@
+use std::{
+    fs,
+    net::{SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread::{self, JoinHandle},
+};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+
+use crate::{cli::Cli, server::Server};
+
+static INSTANCES: AtomicU64 = AtomicU64::new(0);
+const TEST_IDLE_TIMEOUT: u64 = 1;
+
+// A directory of its own for a test, removed with everything in it when it is dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Result<TempDir, String> {
+        let mut suffix = [0u8; 6];
+        getrandom::getrandom(&mut suffix)
+            .map_err(|err| format!("Could not name the test directory: {}", err))?;
+        let path = std::env::temp_dir().join(format!(
+            "db6-test-{}-{}-{}",
+            process::id(),
+            INSTANCES.fetch_add(1, Ordering::Relaxed),
+            URL_SAFE_NO_PAD.encode(suffix)
+        ));
+        fs::create_dir_all(&path).map_err(|err| {
+            format!(
+                "Could not create the test directory {}: {}",
+                path.display(),
+                err
+            )
+        })?;
+        Ok(TempDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+pub struct TestServer {
+    server: Arc<Server>,
+    addr: SocketAddr,
+    root: TempDir,
+    admin_token: String,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    pub fn start() -> Result<TestServer, String> {
+        TestServer::start_with(|_| {})
+    }
+
+    pub fn start_with(configure: impl FnOnce(&mut Cli)) -> Result<TestServer, String> {
+        let mut token = [0u8; 24];
+        getrandom::getrandom(&mut token)
+            .map_err(|err| format!("Could not generate the admin token: {}", err))?;
+        let admin_token = URL_SAFE_NO_PAD.encode(token);
+        let root = TempDir::new()?;
+        let mut cl = Cli::with_root(root.path().to_string_lossy().to_string());
+        cl.port = 0;
+        cl.idle_timeout = TEST_IDLE_TIMEOUT;
+        cl.admin_token = Some(admin_token.clone());
+        configure(&mut cl);
+        let started = Server::new(&cl).and_then(|server| {
+            let listeners = server.bind().map_err(|err| err.to_string())?;
+            let addr = listeners[0].local_addr().map_err(|err| err.to_string())?;
+            Ok((Arc::new(server), listeners, addr))
+        });
+        let (server, listeners, addr) = started?;
+        let serving = server.clone();
+        let handle = thread::spawn(move || {
+            if let Err(err) = serving.serve(listeners) {
+                eprintln!("The test server stopped with error: {}", err);
+            }
+        });
+        Ok(TestServer {
+            server,
+            addr,
+            root,
+            admin_token: cl.admin_token.unwrap_or_default(),
+            handle: Some(handle),
+        })
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn admin_token(&self) -> &str {
+        &self.admin_token
+    }
+
+    pub fn root(&self) -> &Path {
+        self.root.path()
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    // Returns once the server stopped serving. Its root directory is kept until it is dropped.
+    pub fn stop(&mut self) {
+        let Some(handle) = self.handle.take() else {
+            return;
+        };
+        self.server.shutdown();
+        // Wakes up the accept loop, which only sees the shutdown on its next connection.
+        let _ = TcpStream::connect(self.addr);
+        let _ = handle.join();
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stopped_servers_refuse_connections() {
+        let mut server = TestServer::start().unwrap();
+        assert!(TcpStream::connect(server.addr()).is_ok());
+        server.stop();
+        assert!(TcpStream::connect(server.addr()).is_err());
+        assert!(server.root().is_dir());
+        let root = server.root().to_path_buf();
+        drop(server);
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn temporary_directories_are_removed_when_dropped() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("file"), "data").unwrap();
+        let path = dir.path().to_path_buf();
+        assert_ne!(path, TempDir::new().unwrap().path());
+        drop(dir);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,146 @@
+use std::{net::TcpStream, sync::Arc};
+
+use rustls::{
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use webpki::EndEntityCert;
+
+const COMMON_NAME_OID: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+fn load_certs(cert_path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = match CertificateDer::pem_file_iter(cert_path) {
+        Ok(iter) => iter.collect::<Result<Vec<_>, _>>().map_err(|err| {
+            format!(
+                "Error while reading the TLS certificates from {}: {}",
+                cert_path, err
+            )
+        })?,
+        Err(err) => {
+            return Err(format!(
+                "Could not open the TLS certificate file {}: {}",
+                cert_path, err
+            ));
+        }
+    };
+    if certs.is_empty() {
+        return Err(format!(
+            "No certificates were found in the TLS certificate file {}",
+            cert_path
+        ));
+    }
+    Ok(certs)
+}
+
+pub fn load_config(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+) -> Result<Arc<ServerConfig>, String> {
+    let certs = load_certs(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|err| {
+        format!(
+            "Error while reading the TLS private key from {}: {}",
+            key_path, err
+        )
+    })?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| format!("Error while configuring the TLS protocol versions: {}", err))?;
+    let builder = match client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca_path)? {
+                roots.add(cert).map_err(|err| {
+                    format!(
+                        "The client CA certificate in {} could not be used: {}",
+                        client_ca_path, err
+                    )
+                })?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|err| {
+                    format!(
+                        "Error while configuring the client certificate verification with {}: {}",
+                        client_ca_path, err
+                    )
+                })?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(certs, key).map_err(|err| {
+        format!(
+            "The TLS certificate {} and private key {} could not be used: {}",
+            cert_path, key_path, err
+        )
+    })?;
+    Ok(Arc::new(config))
+}
+
+fn common_name(subject: &[u8]) -> Option<String> {
+    let start = subject
+        .windows(COMMON_NAME_OID.len())
+        .position(|window| window == COMMON_NAME_OID)?
+        + COMMON_NAME_OID.len();
+    let (tag, rest) = subject[start..].split_first()?;
+    if !matches!(tag, 0x0c | 0x13 | 0x16) {
+        return None;
+    }
+    let (length, rest) = match rest.split_first()? {
+        (length, rest) if *length < 0x80 => (*length as usize, rest),
+        (0x81, rest) => {
+            let (length, rest) = rest.split_first()?;
+            (*length as usize, rest)
+        }
+        _ => {
+            return None;
+        }
+    };
+    rest.get(..length)
+        .and_then(|value| String::from_utf8(value.to_vec()).ok())
+}
+
+pub fn identity(cert: &CertificateDer) -> Option<String> {
+    let cert = EndEntityCert::try_from(cert).ok()?;
+    common_name(cert.subject())
+        .or_else(|| cert.valid_dns_names().next().map(|name| name.to_string()))
+        .or_else(|| cert.valid_uri_names().next().map(|name| name.to_string()))
+}
+
+pub fn peer_identity(stream: &TlsStream) -> Option<String> {
+    stream.conn.peer_certificates()?.first().and_then(identity)
+}
+
+pub fn accept(config: &Arc<ServerConfig>, stream: TcpStream) -> Result<TlsStream, String> {
+    match ServerConnection::new(config.clone()) {
+        Ok(conn) => Ok(StreamOwned::new(conn, stream)),
+        Err(err) => Err(format!("Error while starting the TLS session: {}", err)),
+    }
+}
+
+pub type TlsClientStream = StreamOwned<ClientConnection, TcpStream>;
+
+pub fn connect(host: &str, stream: TcpStream) -> Result<TlsClientStream, String> {
+    let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|err| format!("Error while configuring the TLS protocol versions: {}", err))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|err| format!("The host {} is not a valid TLS server name: {}", host, err))?;
+    match ClientConnection::new(Arc::new(config), server_name) {
+        Ok(conn) => Ok(StreamOwned::new(conn, stream)),
+        Err(err) => Err(format!(
+            "Error while starting the TLS session with {}: {}",
+            host, err
+        )),
+    }
+}
@@ -0,0 +1,66 @@
+use std::{thread, time::Duration};
+
+use chrono::Utc;
+
+use crate::{
+    api,
+    db::{self, DB},
+    server::Server,
+    service::Service,
+};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct Sweeper;
+
+impl Service for Sweeper {
+    fn name(&self) -> &str {
+        "ttl-sweeper"
+    }
+
+    fn start(&self, server: &Server) -> Result<(), String> {
+        let root = server.root.clone();
+        thread::spawn(move || {
+            loop {
+                for name in db::list(&root).unwrap_or_default() {
+                    if let Err(err) = sweep(&root, &name) {
+                        eprintln!("Could not expire the documents of {}: {}", name, err);
+                    }
+                }
+                thread::sleep(SWEEP_INTERVAL);
+            }
+        });
+        Ok(())
+    }
+}
+
+// Deletes the expired documents of the database and reports each of them to the change
+// stream as an expire event. Tombstones older than the retention of their collection are purged
+// as well, and reported as purge events.
+pub fn sweep(root: &str, name: &str) -> Result<usize, String> {
+    let Some(db) = DB::open(root, name)?.filter(|db| !db.is_closed()) else {
+        return Ok(0);
+    };
+    let mut count = 0;
+    for collection in db.list_collections()? {
+        let now = Utc::now();
+        for id in db.expire(&collection, now)? {
+            api::publish(db.name(), &collection, "expire", Some(&id));
+            count += 1;
+        }
+        let retention = db
+            .collection(&collection)?
+            .and_then(|meta| meta.soft_delete)
+            .and_then(|soft_delete| soft_delete.retention);
+        if let Some(retention) = retention {
+            for id in db.purge(
+                &collection,
+                now - chrono::Duration::seconds(retention as i64),
+            )? {
+                api::publish(db.name(), &collection, "purge", Some(&id));
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
@@ -1,14 +1,48 @@
 use std::{
     fmt::Display,
-    sync::{Arc, LazyLock, Mutex},
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
+use crate::json::{Json, JsonNumber, JsonObject};
+
 static ID_COUNTER: LazyLock<Arc<Mutex<Vec<u64>>>> = LazyLock::new(|| Arc::new(Mutex::new(vec![0])));
+static IDS_ALLOCATED: AtomicU64 = AtomicU64::new(0);
+static ID_ROLLOVERS: AtomicU64 = AtomicU64::new(0);
+static ON_ROLLOVER: Mutex<Option<RolloverAction>> = Mutex::new(None);
+
+type RolloverAction = Box<dyn Fn(usize) + Send>;
+
+pub struct IdStats {
+    pub allocated: u64,
+    pub rollovers: u64,
+    pub segments: usize,
+    pub high_water_mark: String,
+}
+
+impl IdStats {
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["allocated".to_string()] = Json::Number(JsonNumber::Int(self.allocated as i64));
+        obj["rollovers".to_string()] = Json::Number(JsonNumber::Int(self.rollovers as i64));
+        obj["segments".to_string()] = Json::Number(JsonNumber::Int(self.segments as i64));
+        obj["high_water_mark".to_string()] = Json::String(self.high_water_mark.clone());
+        Json::Object(obj)
+    }
+}
 
 pub struct ID {
     id: Vec<u64>,
 }
 
+impl Default for ID {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ID {
     pub fn new() -> ID {
         let mut id_lock = ID_COUNTER.try_lock();
@@ -16,14 +50,44 @@ impl ID {
             id_lock = ID_COUNTER.try_lock();
         }
         let vec_val = id_lock.as_mut().unwrap();
-        if *vec_val.last().unwrap() == u64::MAX {
+        IDS_ALLOCATED.fetch_add(1, Ordering::Relaxed);
+        let rolled_over = *vec_val.last().unwrap() == u64::MAX;
+        if rolled_over {
             vec_val.push(0);
+            ID_ROLLOVERS.fetch_add(1, Ordering::Relaxed);
         } else {
             *vec_val.last_mut().unwrap() += 1;
         }
-        return ID {
+        let id = ID {
             id: vec_val.clone(),
         };
+        drop(id_lock);
+        if rolled_over && let Some(on_rollover) = ON_ROLLOVER.lock().unwrap().as_ref() {
+            on_rollover(id.id.len());
+        }
+        id
+    }
+
+    // Sets what is done when the ID space extends by a segment, which is passed the new number
+    // of segments.
+    pub fn on_rollover(action: impl Fn(usize) + Send + 'static) {
+        *ON_ROLLOVER.lock().unwrap() = Some(Box::new(action));
+    }
+
+    pub fn stats() -> IdStats {
+        let mut id_lock = ID_COUNTER.try_lock();
+        while id_lock.is_err() {
+            id_lock = ID_COUNTER.try_lock();
+        }
+        let current = ID {
+            id: id_lock.as_ref().unwrap().to_vec(),
+        };
+        IdStats {
+            allocated: IDS_ALLOCATED.load(Ordering::Relaxed),
+            rollovers: ID_ROLLOVERS.load(Ordering::Relaxed),
+            segments: current.id.len(),
+            high_water_mark: current.to_string(),
+        }
     }
 }
 
@@ -37,19 +101,7 @@ impl PartialEq for ID {
                 return false;
             }
         }
-        return true;
-    }
-
-    fn ne(&self, other: &Self) -> bool {
-        if self.id.len() != other.id.len() {
-            return true;
-        }
-        for i in 0..self.id.len() {
-            if self.id[i] != other.id[i] {
-                return true;
-            }
-        }
-        return false;
+        true
     }
 }
 
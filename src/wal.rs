@@ -0,0 +1,502 @@
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::RwLock,
+};
+
+use crate::{
+    db,
+    json::{Json, JsonNumber, JsonObject},
+};
+
+pub const SEGMENT_EXTENSION: &str = "wal";
+pub const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+pub const SEQ_FIELD: &str = "seq";
+pub const CHECKPOINT_FILE: &str = "checkpoint";
+pub const BACKUP_FILE: &str = "backup";
+const HEADER_LEN: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Durability {
+    Always,
+    None,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "always" => Ok(Durability::Always),
+            "none" => Ok(Durability::None),
+            _ => Err(format!(
+                "Expected 'always' or 'none' as the durability, found {}",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Durability::Always => write!(f, "always"),
+            Durability::None => write!(f, "none"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct WalConfig {
+    pub durability: Durability,
+    pub segment_size: u64,
+}
+
+static CONFIG: RwLock<WalConfig> = RwLock::new(WalConfig {
+    durability: Durability::Always,
+    segment_size: DEFAULT_SEGMENT_SIZE,
+});
+
+pub fn configure(config: WalConfig) {
+    *CONFIG.write().unwrap() = config;
+}
+
+pub fn config() -> WalConfig {
+    *CONFIG.read().unwrap()
+}
+
+pub struct Record {
+    pub seq: u64,
+    pub entry: Json,
+}
+
+pub struct Segment {
+    pub path: PathBuf,
+    pub first_seq: u64,
+    pub bytes: u64,
+}
+
+pub struct SegmentContents {
+    pub records: Vec<Record>,
+    pub valid_len: u64,
+    pub torn: bool,
+}
+
+#[derive(Clone)]
+pub struct Recovery {
+    pub checkpoint: u64,
+    pub replayed: u64,
+    pub last_seq: u64,
+    // The number of sequence numbers missing between the replayed records.
+    pub gaps: u64,
+    pub truncated: bool,
+}
+
+impl Recovery {
+    pub fn to_json(&self) -> Json {
+        let mut obj = JsonObject::new();
+        obj["checkpoint".to_string()] = Json::Number(JsonNumber::Int(self.checkpoint as i64));
+        obj["replayed".to_string()] = Json::Number(JsonNumber::Int(self.replayed as i64));
+        obj["last_seq".to_string()] = Json::Number(JsonNumber::Int(self.last_seq as i64));
+        obj["gaps".to_string()] = Json::Number(JsonNumber::Int(self.gaps as i64));
+        obj["truncated".to_string()] = Json::Bool(self.truncated);
+        Json::Object(obj)
+    }
+}
+
+fn read_seq(path: &Path, what: &str) -> Result<Option<u64>, String> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Ok(None);
+        }
+        Err(err) => {
+            return Err(format!(
+                "Error while reading the {} {}: {}",
+                what,
+                path.display(),
+                err
+            ));
+        }
+    };
+    match Json::parse(&content) {
+        Ok(Json::Object(obj)) => match obj.get(SEQ_FIELD) {
+            Some(Json::Number(JsonNumber::Int(seq))) => u64::try_from(*seq).ok(),
+            _ => None,
+        },
+        _ => None,
+    }
+    .map(Some)
+    .ok_or_else(|| format!("The {} {} is invalid", what, path.display()))
+}
+
+pub fn read_checkpoint(dir: &Path) -> Result<u64, String> {
+    read_seq(&dir.join(CHECKPOINT_FILE), "checkpoint").map(|seq| seq.unwrap_or(0))
+}
+
+// The last record in a backup of the database. Segments with records after it are kept, so the
+// next backup can be an incremental one holding only those records.
+pub fn read_backup(dir: &Path) -> Result<Option<u64>, String> {
+    read_seq(&dir.join(BACKUP_FILE), "backup position")
+}
+
+pub fn write_backup(dir: &Path, seq: u64) -> Result<(), String> {
+    let path = dir.join(BACKUP_FILE);
+    let temp = dir.join(format!(".{}.tmp", BACKUP_FILE));
+    db::replace_file(&temp, &path, checkpoint_content(seq).as_bytes(), true).map_err(|err| {
+        format!(
+            "Error while writing the backup position {}: {}",
+            path.display(),
+            err
+        )
+    })
+}
+
+pub fn checkpoint_content(seq: u64) -> String {
+    let mut obj = JsonObject::new();
+    obj[SEQ_FIELD.to_string()] = Json::Number(JsonNumber::Int(seq as i64));
+    Json::Object(obj).canonical()
+}
+
+fn write_checkpoint(dir: &Path, seq: u64) -> Result<(), String> {
+    let path = dir.join(CHECKPOINT_FILE);
+    let temp = dir.join(format!(".{}.tmp", CHECKPOINT_FILE));
+    db::replace_file(&temp, &path, checkpoint_content(seq).as_bytes(), true).map_err(|err| {
+        format!(
+            "Error while writing the checkpoint {}: {}",
+            path.display(),
+            err
+        )
+    })
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", first_seq, SEGMENT_EXTENSION))
+}
+
+pub fn segments(dir: &Path) -> Result<Vec<Segment>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Ok(Vec::new());
+        }
+        Err(err) => {
+            return Err(format!(
+                "Error while listing the log segments in {}: {}",
+                dir.display(),
+                err
+            ));
+        }
+    };
+    let mut segments = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.extension()? != SEGMENT_EXTENSION {
+                return None;
+            }
+            let first_seq = path.file_stem()?.to_str()?.parse::<u64>().ok()?;
+            Some(Segment {
+                bytes: entry.metadata().map(|meta| meta.len()).unwrap_or(0),
+                path,
+                first_seq,
+            })
+        })
+        .collect::<Vec<_>>();
+    segments.sort_by_key(|segment| segment.first_seq);
+    Ok(segments)
+}
+
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+fn decode(payload: &[u8]) -> Option<Record> {
+    let entry = Json::parse(payload).ok()?;
+    let seq = match &entry {
+        Json::Object(obj) => match obj.get(SEQ_FIELD) {
+            Some(Json::Number(JsonNumber::Int(seq))) => u64::try_from(*seq).ok()?,
+            _ => {
+                return None;
+            }
+        },
+        _ => {
+            return None;
+        }
+    };
+    Some(Record { seq, entry })
+}
+
+// Reading stops at the first record that is cut short or fails its checksum, since nothing
+// after it can be trusted.
+pub fn read_segment(path: &Path) -> Result<SegmentContents, String> {
+    let data = fs::read(path).map_err(|err| {
+        format!(
+            "Error while reading the log segment {}: {}",
+            path.display(),
+            err
+        )
+    })?;
+    let mut contents = SegmentContents {
+        records: Vec::new(),
+        valid_len: 0,
+        torn: false,
+    };
+    let mut pos = 0;
+    while pos < data.len() {
+        if data.len() - pos < HEADER_LEN {
+            contents.torn = true;
+            break;
+        }
+        let len = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap());
+        let end = pos + HEADER_LEN + len;
+        if end > data.len() {
+            contents.torn = true;
+            break;
+        }
+        let payload = &data[pos + HEADER_LEN..end];
+        let record = match (crc32fast::hash(payload) == checksum)
+            .then(|| decode(payload))
+            .flatten()
+        {
+            Some(record) => record,
+            None => {
+                contents.torn = true;
+                break;
+            }
+        };
+        contents.records.push(record);
+        pos = end;
+        contents.valid_len = pos as u64;
+    }
+    Ok(contents)
+}
+
+pub struct Wal {
+    dir: PathBuf,
+    file: File,
+    segment_len: u64,
+    next_seq: u64,
+    checkpoint: u64,
+    truncated: bool,
+    recovered: bool,
+}
+
+impl Wal {
+    fn create_segment(dir: &Path, first_seq: u64) -> Result<File, String> {
+        let path = segment_path(dir, first_seq);
+        File::options()
+            .append(true)
+            .create_new(true)
+            .open(&path)
+            .and_then(|file| {
+                db::sync_dir(dir)?;
+                Ok(file)
+            })
+            .map_err(|err| {
+                format!(
+                    "Error while creating the log segment {}: {}",
+                    path.display(),
+                    err
+                )
+            })
+    }
+
+    pub fn open(dir: &Path) -> Result<Wal, String> {
+        fs::create_dir_all(dir).map_err(|err| {
+            format!(
+                "Error while creating the log directory {}: {}",
+                dir.display(),
+                err
+            )
+        })?;
+        let checkpoint = read_checkpoint(dir)?;
+        let last = match segments(dir)?.pop() {
+            Some(last) => last,
+            None => {
+                return Ok(Wal {
+                    dir: dir.to_path_buf(),
+                    file: Wal::create_segment(dir, checkpoint + 1)?,
+                    segment_len: 0,
+                    next_seq: checkpoint + 1,
+                    checkpoint,
+                    truncated: false,
+                    recovered: true,
+                });
+            }
+        };
+        let contents = read_segment(&last.path)?;
+        let file = File::options()
+            .append(true)
+            .open(&last.path)
+            .and_then(|file| {
+                if contents.torn {
+                    file.set_len(contents.valid_len)?;
+                    file.sync_all()?;
+                }
+                Ok(file)
+            })
+            .map_err(|err| {
+                format!(
+                    "Error while opening the log segment {}: {}",
+                    last.path.display(),
+                    err
+                )
+            })?;
+        Ok(Wal {
+            dir: dir.to_path_buf(),
+            file,
+            segment_len: contents.valid_len,
+            next_seq: contents
+                .records
+                .last()
+                .map(|record| record.seq + 1)
+                .unwrap_or(last.first_seq),
+            checkpoint,
+            truncated: contents.torn,
+            recovered: false,
+        })
+    }
+
+    pub fn recovered(&self) -> bool {
+        self.recovered
+    }
+
+    // Replays the records after the checkpoint. Records are idempotent, so replaying one that
+    // was already applied before a crash is harmless.
+    pub fn recover(
+        &mut self,
+        mut apply: impl FnMut(&Record) -> Result<(), String>,
+    ) -> Result<Recovery, String> {
+        let checkpoint = self.checkpoint;
+        let mut recovery = Recovery {
+            checkpoint,
+            replayed: 0,
+            last_seq: self.next_seq - 1,
+            gaps: 0,
+            truncated: self.truncated,
+        };
+        if self.recovered {
+            recovery.truncated = false;
+            return Ok(recovery);
+        }
+        let segments = segments(&self.dir)?;
+        let mut expected = checkpoint + 1;
+        for (ind, segment) in segments.iter().enumerate() {
+            let next = segments.get(ind + 1);
+            if next.is_some_and(|next| next.first_seq <= checkpoint + 1) {
+                continue;
+            }
+            let contents = read_segment(&segment.path)?;
+            if contents.torn && next.is_some() {
+                return Err(format!(
+                    "The log segment {} is corrupted before its end, so the records after it cannot be trusted",
+                    segment.path.display()
+                ));
+            }
+            for record in contents
+                .records
+                .iter()
+                .filter(|record| record.seq > checkpoint)
+            {
+                apply(record).map_err(|err| {
+                    format!(
+                        "Error while replaying the log record {} in {}: {}",
+                        record.seq,
+                        segment.path.display(),
+                        err
+                    )
+                })?;
+                recovery.replayed += 1;
+                recovery.gaps += record.seq.saturating_sub(expected);
+                expected = record.seq + 1;
+            }
+        }
+        self.recovered = true;
+        Ok(recovery)
+    }
+
+    pub fn checkpoint_seq(&self) -> u64 {
+        self.checkpoint
+    }
+
+    // Records up to the checkpoint are persisted elsewhere, so the segments that only hold
+    // such records are removed, unless they hold records that are not backed up yet.
+    pub fn checkpoint(&mut self) -> Result<(), String> {
+        if !self.recovered {
+            return Ok(());
+        }
+        self.file
+            .sync_all()
+            .map_err(|err| format!("Error while syncing the log segment: {}", err))?;
+        let seq = self.next_seq - 1;
+        write_checkpoint(&self.dir, seq)?;
+        self.checkpoint = seq;
+        let keep = match read_backup(&self.dir)? {
+            Some(backup) => backup.min(seq),
+            None => seq,
+        };
+        let segments = segments(&self.dir)?;
+        for pair in segments.windows(2) {
+            if pair[1].first_seq > keep + 1 {
+                break;
+            }
+            fs::remove_file(&pair[0].path).map_err(|err| {
+                format!(
+                    "Error while removing the log segment {}: {}",
+                    pair[0].path.display(),
+                    err
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    pub fn append(&mut self, entry: &Json) -> Result<u64, String> {
+        let config = config();
+        let seq = self.next_seq;
+        let mut obj = match entry {
+            Json::Object(obj) => obj.clone(),
+            _ => JsonObject::new(),
+        };
+        obj[SEQ_FIELD.to_string()] = Json::Number(JsonNumber::Int(seq as i64));
+        let record = encode(Json::Object(obj).canonical().as_bytes());
+        if self.segment_len > 0 && self.segment_len + record.len() as u64 > config.segment_size {
+            self.file
+                .sync_all()
+                .map_err(|err| format!("Error while syncing the log segment: {}", err))?;
+            self.file = Wal::create_segment(&self.dir, seq)?;
+            self.segment_len = 0;
+        }
+        let written = self
+            .file
+            .write_all(&record)
+            .and_then(|_| match config.durability {
+                Durability::Always => self.file.sync_data(),
+                Durability::None => Ok(()),
+            });
+        if let Err(err) = written {
+            let _ = self.file.set_len(self.segment_len);
+            return Err(format!(
+                "Error while appending to the log in {}: {}",
+                self.dir.display(),
+                err
+            ));
+        }
+        self.segment_len += record.len() as u64;
+        self.next_seq += 1;
+        Ok(seq)
+    }
+}
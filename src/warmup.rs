@@ -0,0 +1,188 @@
+use std::{
+    collections::HashMap,
+    fs,
+    ops::Bound,
+    path::Path,
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cache,
+    db::DB,
+    json::{Json, JsonNumber, JsonObject},
+    server::Server,
+    service::Service,
+};
+
+// The reads of each collection, kept in the root directory across restarts.
+pub const PROFILE_FILE: &str = "access-profile.json";
+const SAVE_INTERVAL: Duration = Duration::from_secs(300);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static READS: LazyLock<Mutex<HashMap<(String, String), u64>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Counts a read of the collection for the access profile. Reads are only counted when warm-up is
+// on.
+pub fn record(database: &str, collection: &str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    *READS
+        .lock()
+        .unwrap()
+        .entry((database.to_string(), collection.to_string()))
+        .or_insert(0) += 1;
+}
+
+// The collections of the profile in the root directory, most read first.
+pub fn load(root: &str) -> Result<Vec<(String, String, u64)>, String> {
+    let path = Path::new(root).join(PROFILE_FILE);
+    let content = match fs::read(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(format!(
+                "Could not read the access profile {}: {}",
+                path.display(),
+                err
+            ));
+        }
+    };
+    let invalid = || format!("The access profile {} is invalid", path.display());
+    let Json::Object(databases) = Json::parse(&content).map_err(|_| invalid())? else {
+        return Err(invalid());
+    };
+    let mut profile = Vec::new();
+    for (database, collections) in databases.iter() {
+        let Json::Object(collections) = collections else {
+            return Err(invalid());
+        };
+        for (collection, reads) in collections.iter() {
+            let Json::Number(JsonNumber::Int(reads)) = reads else {
+                return Err(invalid());
+            };
+            profile.push((database.clone(), collection.clone(), (*reads).max(0) as u64));
+        }
+    }
+    profile.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| (&a.0, &a.1).cmp(&(&b.0, &b.1))));
+    Ok(profile)
+}
+
+pub fn save(root: &str) -> Result<(), String> {
+    let mut databases = JsonObject::new();
+    for ((database, collection), reads) in READS.lock().unwrap().iter() {
+        if databases.get(database).is_none() {
+            databases[database.clone()] = Json::Object(JsonObject::new());
+        }
+        if let Json::Object(collections) = &mut databases[database.clone()] {
+            collections[collection.clone()] = Json::Number(JsonNumber::Int(*reads as i64));
+        }
+    }
+    let path = Path::new(root).join(PROFILE_FILE);
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, Json::Object(databases).canonical())
+        .and_then(|_| fs::rename(&temp, &path))
+        .map_err(|err| {
+            format!(
+                "Error while writing the access profile {}: {}",
+                path.display(),
+                err
+            )
+        })
+}
+
+// Reads the collections of the profile, most read first, so that their blocks are in the cache
+// before the first requests for them. Stops once the cache is full, since reading more would only
+// evict the blocks of hotter collections. Returns how many collections were read.
+pub fn warm(root: &str, profile: &[(String, String, u64)]) -> usize {
+    let mut warmed = 0;
+    for (database, collection, _) in profile {
+        let stats = cache::stats();
+        if stats.bytes >= stats.capacity {
+            break;
+        }
+        let read = DB::open(root, database).and_then(|db| match db {
+            Some(db) if !db.is_closed() => {
+                for entry in db
+                    .engine()?
+                    .scan(collection, (Bound::Unbounded, Bound::Unbounded))
+                {
+                    entry?;
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        });
+        match read {
+            Ok(true) => warmed += 1,
+            Ok(false) => {}
+            Err(err) => eprintln!(
+                "Could not warm the cache with the collection {} of {}: {}",
+                collection, database, err
+            ),
+        }
+    }
+    warmed
+}
+
+// Warms the cache from the access profile when the server starts, and keeps the profile up to
+// date for the next start. The reads of the previous runs count for half at every start, so the
+// profile follows the collections that are read now.
+pub struct Warmer {
+    root: String,
+}
+
+impl Warmer {
+    pub fn new(root: String) -> Warmer {
+        Warmer { root }
+    }
+}
+
+impl Service for Warmer {
+    fn name(&self) -> &str {
+        "cache-warmer"
+    }
+
+    fn start(&self, _server: &Server) -> Result<(), String> {
+        let profile = load(&self.root)?;
+        {
+            let mut reads = READS.lock().unwrap();
+            for (database, collection, count) in &profile {
+                reads.insert((database.clone(), collection.clone()), count / 2);
+            }
+        }
+        ENABLED.store(true, Ordering::Relaxed);
+        let root = self.root.clone();
+        thread::spawn(move || {
+            let start = Instant::now();
+            let warmed = warm(&root, &profile);
+            if warmed > 0 {
+                println!(
+                    "Warmed the cache with {} collections in {} ms ({} bytes)",
+                    warmed,
+                    start.elapsed().as_millis(),
+                    cache::stats().bytes
+                );
+            }
+            loop {
+                thread::sleep(SAVE_INTERVAL);
+                if let Err(err) = save(&root) {
+                    eprintln!("{}", err);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn stop(&self) {
+        if let Err(err) = save(&self.root) {
+            eprintln!("{}", err);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    io::{ErrorKind, Read},
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, Instant},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    changes::Events,
+    http::{HttpStatus, Request, Response},
+    server::Stream,
+};
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const FRAME_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    pub fn code(&self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Opcode> {
+        match code {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    pub fn is_control(&self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(opcode: Opcode, payload: Vec<u8>) -> Frame {
+        Frame {
+            fin: true,
+            opcode,
+            payload,
+        }
+    }
+
+    pub fn text(text: String) -> Frame {
+        Frame::new(Opcode::Text, text.into_bytes())
+    }
+
+    pub fn close(code: u16, reason: &str) -> Frame {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        Frame::new(Opcode::Close, payload)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::<u8>::with_capacity(self.payload.len() + 10);
+        bytes.push(if self.fin { 0x80 } else { 0 } | self.opcode.code());
+        let len = self.payload.len();
+        if len < 126 {
+            bytes.push(len as u8);
+        } else if len <= u16::MAX as usize {
+            bytes.push(126);
+            bytes.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            bytes.push(127);
+            bytes.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn read_from(reader: &mut (impl Read + ?Sized), max_size: usize) -> Result<Frame, String> {
+        let mut head = [0u8; 2];
+        read_exact(reader, &mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        if head[0] & 0x70 != 0 {
+            return Err("The WebSocket frame uses reserved bits without an extension".to_string());
+        }
+        let opcode = match Opcode::from_code(head[0] & 0x0F) {
+            Some(opcode) => opcode,
+            None => {
+                return Err(format!(
+                    "The WebSocket frame has an unknown opcode {}",
+                    head[0] & 0x0F
+                ));
+            }
+        };
+        if head[1] & 0x80 == 0 {
+            return Err("WebSocket frames sent by clients should be masked".to_string());
+        }
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut ext = [0u8; 2];
+                read_exact(reader, &mut ext)?;
+                u16::from_be_bytes(ext) as u64
+            }
+            127 => {
+                let mut ext = [0u8; 8];
+                read_exact(reader, &mut ext)?;
+                u64::from_be_bytes(ext)
+            }
+            len => len as u64,
+        };
+        if opcode.is_control() && (len > 125 || !fin) {
+            return Err(
+                "WebSocket control frames should be unfragmented and at most 125 bytes".to_string(),
+            );
+        }
+        if len > max_size as u64 {
+            return Err(format!(
+                "The WebSocket frame of {} bytes exceeds the maximum frame size of {} bytes",
+                len, max_size
+            ));
+        }
+        let mut mask = [0u8; 4];
+        read_exact(reader, &mut mask)?;
+        let mut payload = vec![0u8; len as usize];
+        read_exact(reader, &mut payload)?;
+        for (ind, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[ind % 4];
+        }
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+}
+
+fn read_exact(reader: &mut (impl Read + ?Sized), buf: &mut [u8]) -> Result<(), String> {
+    reader
+        .read_exact(buf)
+        .map_err(|err| format!("Error while reading a WebSocket frame: {}", err))
+}
+
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+pub fn handshake(request: &Request) -> Result<Response, Response> {
+    if request.upgrade.as_deref() != Some("websocket") {
+        let mut resp = Response::error(
+            HttpStatus::BadRequest,
+            format!(
+                "{} is a WebSocket endpoint and expects an 'Upgrade: websocket' request",
+                request.route
+            ),
+        );
+        resp.set_header("Upgrade", "websocket".to_string());
+        return Err(resp);
+    }
+    if request
+        .websocket_version
+        .as_deref()
+        .map(|version| version.trim())
+        != Some("13")
+    {
+        let mut resp = Response::error(
+            HttpStatus::BadRequest,
+            "Only version 13 of the WebSocket protocol is supported".to_string(),
+        );
+        resp.set_header("Sec-WebSocket-Version", "13".to_string());
+        return Err(resp);
+    }
+    let key = match &request.websocket_key {
+        Some(key) if STANDARD.decode(key.trim()).map(|raw| raw.len()) == Ok(16) => key,
+        _ => {
+            return Err(Response::error(
+                HttpStatus::BadRequest,
+                "The Sec-WebSocket-Key header is missing or is not a base64 encoded 16 byte value"
+                    .to_string(),
+            ));
+        }
+    };
+    let mut resp = Response::new(HttpStatus::SwitchingProtocols);
+    resp.set_header("Upgrade", "websocket".to_string());
+    resp.set_header("Connection", "Upgrade".to_string());
+    resp.set_header("Sec-WebSocket-Accept", accept_key(key));
+    Ok(resp)
+}
+
+fn write_frame(stream: &mut dyn Stream, frame: &Frame) -> Result<(), String> {
+    stream
+        .write_all(&frame.to_bytes())
+        .and_then(|_| stream.flush())
+        .map_err(|err| format!("Error while writing a WebSocket frame: {}", err))
+}
+
+fn has_pending_input(stream: &mut dyn Stream) -> Result<bool, String> {
+    let socket = stream.socket();
+    let _ = socket.set_nonblocking(true);
+    let mut probe = [0u8; 1];
+    let result = socket.peek(&mut probe);
+    let _ = socket.set_nonblocking(false);
+    match result {
+        Ok(0) => Err("The WebSocket client disconnected".to_string()),
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+pub fn serve<T: Into<String>>(
+    stream: &mut dyn Stream,
+    mut events: impl Events<T>,
+) -> Result<(), String> {
+    let _ = stream.socket().set_read_timeout(Some(FRAME_READ_TIMEOUT));
+    let mut last_ping = Instant::now();
+    loop {
+        match events.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => {
+                write_frame(stream, &Frame::text(event.into()))?;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if last_ping.elapsed() >= PING_INTERVAL {
+                    write_frame(stream, &Frame::new(Opcode::Ping, Vec::new()))?;
+                    last_ping = Instant::now();
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return write_frame(stream, &Frame::close(1001, "The change feed was closed"));
+            }
+        }
+        while has_pending_input(stream)? {
+            let frame = match Frame::read_from(stream, MAX_FRAME_SIZE) {
+                Ok(frame) => frame,
+                Err(err) => {
+                    let _ = write_frame(stream, &Frame::close(1002, "Protocol error"));
+                    return Err(err);
+                }
+            };
+            match frame.opcode {
+                Opcode::Close => {
+                    let code = if frame.payload.len() >= 2 {
+                        frame.payload[..2].to_vec()
+                    } else {
+                        Vec::new()
+                    };
+                    return write_frame(stream, &Frame::new(Opcode::Close, code));
+                }
+                Opcode::Ping => {
+                    write_frame(stream, &Frame::new(Opcode::Pong, frame.payload))?;
+                }
+                _ => {}
+            }
+        }
+    }
+}
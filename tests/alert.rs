@@ -0,0 +1,84 @@
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, TcpListener},
+    time::Duration,
+};
+
+use db6::alert::{AlertConfig, AlertSink, Alerter, WebhookUrl};
+
+#[test]
+fn webhook_urls_take_bracketed_ipv6_hosts() {
+    let url = "http://[::1]:8080/hook".parse::<WebhookUrl>().unwrap();
+    assert_eq!(
+        (url.host.as_str(), url.port, url.path.as_str()),
+        ("::1", 8080, "/hook")
+    );
+    let url = "https://[2001:db8::7]".parse::<WebhookUrl>().unwrap();
+    assert_eq!(
+        (url.host.as_str(), url.port, url.path.as_str()),
+        ("2001:db8::7", 443, "/")
+    );
+    let url = "http://example.com:81/".parse::<WebhookUrl>().unwrap();
+    assert_eq!((url.host.as_str(), url.port), ("example.com", 81));
+    for invalid in [
+        "http://[::1/hook",
+        "http://[::1]8080/",
+        "http://[::1]:port/",
+        "http://:80/",
+    ] {
+        assert!(invalid.parse::<WebhookUrl>().is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn repeated_auth_failures_raise_an_alert() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+    let alerter = Alerter::new(AlertConfig {
+        sinks: vec![AlertSink::Webhook(url.parse().unwrap())],
+        auth_failures: 3,
+        ..AlertConfig::new()
+    });
+    let client = Some("10.0.0.9".parse::<IpAddr>().unwrap());
+    alerter.auth_failed(client);
+    alerter.auth_failed(client);
+    listener.set_nonblocking(true).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(
+        listener.accept().is_err(),
+        "two failures should not raise an alert"
+    );
+
+    alerter.auth_failed(client);
+    listener.set_nonblocking(false).unwrap();
+    let (mut stream, _) = listener.accept().unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !String::from_utf8_lossy(&request).contains("10.0.0.9") {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0);
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    assert!(
+        request.contains(r#""event" : "auth-failures""#),
+        "{}",
+        request
+    );
+    stream
+        .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+        .unwrap();
+}
+
+#[test]
+fn webhooks_to_ipv6_hosts_send_a_bracketed_host_header() {
+    let url = "http://[::1]:8080/hook".parse::<WebhookUrl>().unwrap();
+    assert_eq!(url.authority_host(), "[::1]");
+    assert_eq!(
+        AlertSink::Webhook(url).to_string(),
+        "webhook http://[::1]:8080/hook"
+    );
+}
@@ -0,0 +1,220 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use db6::{
+    db::DB,
+    json::{Json, JsonNumber},
+    test_util::TestServer,
+};
+
+struct Reply {
+    status: u16,
+    head: String,
+    body: String,
+}
+
+impl Reply {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().find_map(|line| {
+            let (header, value) = line.split_once(':')?;
+            header.eq_ignore_ascii_case(name).then_some(value.trim())
+        })
+    }
+}
+
+// A server with the database shop, whose password is pw.
+fn shop() -> TestServer {
+    let server = TestServer::start().unwrap();
+    DB::create(&server.root().to_string_lossy(), "shop", "pw".to_string()).unwrap();
+    server
+}
+
+fn call(server: &TestServer, method: &str, path: &str, headers: &str, body: &str) -> Reply {
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        method,
+        path,
+        STANDARD.encode("shop:pw"),
+        body.len(),
+        headers,
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    Reply {
+        status: head
+            .split(' ')
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or_default(),
+        head: head.to_string(),
+        body: body.to_string(),
+    }
+}
+
+const DOC: &str = "/dbs/shop/collections/items/docs/a";
+
+#[test]
+fn writes_with_a_stale_if_match_fail() {
+    let server = shop();
+    assert_eq!(call(&server, "PUT", DOC, "", r#"{"n":1}"#).status, 201);
+    let first = call(&server, "GET", DOC, "", "");
+    let etag = first.header("ETag").unwrap().to_string();
+    let if_match = format!("If-Match: {}\r\n", etag);
+
+    let reply = call(&server, "PUT", DOC, &if_match, r#"{"n":2}"#);
+    assert_eq!(reply.status, 200, "{}", reply.body);
+    // The ETag changed with the write, so the same condition is now stale.
+    for (method, body) in [
+        ("PUT", r#"{"n":3}"#),
+        ("PATCH", r#"{"n":3}"#),
+        ("DELETE", ""),
+    ] {
+        let reply = call(&server, method, DOC, &if_match, body);
+        assert_eq!(reply.status, 412, "{} {}", method, reply.body);
+        assert!(reply.body.contains("precondition_failed"), "{}", reply.body);
+    }
+    let current = call(&server, "GET", DOC, "", "");
+    assert!(current.body.contains(r#""n" : 2"#), "{}", current.body);
+
+    let if_match = format!("If-Match: {}\r\n", current.header("ETag").unwrap());
+    let reply = call(&server, "PATCH", DOC, &if_match, r#"{"n":4}"#);
+    assert_eq!(reply.status, 200, "{}", reply.body);
+    let if_match = format!(
+        "If-Match: \"stale\", {}\r\n",
+        call(&server, "GET", DOC, "", "").header("ETag").unwrap()
+    );
+    let reply = call(&server, "DELETE", DOC, &if_match, "");
+    assert_eq!(reply.status, 200, "{}", reply.body);
+    assert_eq!(call(&server, "GET", DOC, "", "").status, 404);
+    // A document that does not exist matches no If-Match.
+    let reply = call(&server, "PUT", DOC, "If-Match: *\r\n", r#"{"n":5}"#);
+    assert_eq!(reply.status, 412, "{}", reply.body);
+}
+
+#[test]
+fn if_none_match_star_only_creates() {
+    let server = shop();
+    let create = "If-None-Match: *\r\n";
+    let reply = call(&server, "PUT", DOC, create, r#"{"n":1}"#);
+    assert_eq!(reply.status, 201, "{}", reply.body);
+    let reply = call(&server, "PUT", DOC, create, r#"{"n":2}"#);
+    assert_eq!(reply.status, 412, "{}", reply.body);
+    let current = call(&server, "GET", DOC, "", "");
+    assert!(current.body.contains(r#""n" : 1"#), "{}", current.body);
+    let reply = call(&server, "GET", DOC, create, "");
+    assert_eq!(reply.status, 304);
+}
+
+#[test]
+fn status_counts_the_ids_of_requests() {
+    let server = shop();
+    call(&server, "GET", DOC, "", "");
+    let reply = call(&server, "GET", "/_status", "", "");
+    let id = reply.header("X-Request-Id").unwrap();
+    let status = Json::parse(reply.body.as_bytes()).unwrap();
+    let Json::Object(status) = status else {
+        panic!("{}", reply.body);
+    };
+    let Some(Json::Object(ids)) = status.get("ids") else {
+        panic!("{}", reply.body);
+    };
+    let Some(Json::Number(JsonNumber::Int(allocated))) = ids.get("allocated") else {
+        panic!("{}", reply.body);
+    };
+    assert!(*allocated >= 2, "{}", reply.body);
+    // Request IDs are allocated from the same counter, after a per-process prefix.
+    let (_, counter) = id.split_once('-').unwrap();
+    assert!(counter.parse::<u64>().unwrap() >= 2, "{}", id);
+    assert!(reply.body.contains(r#""sequences""#), "{}", reply.body);
+}
+
+#[test]
+fn exports_include_deleted_documents_when_asked() {
+    let server = shop();
+    let settings = r#"{"soft_delete":true}"#;
+    let reply = call(&server, "PUT", "/dbs/shop/collections/items", "", settings);
+    assert!(reply.status < 300, "{}", reply.body);
+    assert_eq!(call(&server, "PUT", DOC, "", r#"{"n":1}"#).status, 201);
+    let kept = "/dbs/shop/collections/items/docs/b";
+    assert_eq!(call(&server, "PUT", kept, "", r#"{"n":2}"#).status, 201);
+    assert_eq!(call(&server, "DELETE", DOC, "", "").status, 200);
+
+    let export = "/dbs/shop/collections/items/export";
+    let reply = call(&server, "GET", export, "", "");
+    assert_eq!(reply.status, 200, "{}", reply.body);
+    assert!(!reply.body.contains(r#""_id":"a""#), "{}", reply.body);
+    assert!(reply.body.contains(r#""_id":"b""#), "{}", reply.body);
+    let reply = call(
+        &server,
+        "GET",
+        &format!("{}?include_deleted=true", export),
+        "",
+        "",
+    );
+    assert_eq!(reply.status, 200, "{}", reply.body);
+    assert!(reply.body.contains(r#""_deleted":"#), "{}", reply.body);
+    assert!(reply.body.contains(r#""_id":"b""#), "{}", reply.body);
+}
+
+#[test]
+fn documents_are_created_read_queried_and_deleted() {
+    let server = shop();
+    let docs = "/dbs/shop/collections/items/docs";
+    let reply = call(&server, "POST", docs, "", r#"{"n":1}"#);
+    assert_eq!(reply.status, 201, "{}", reply.body);
+    let Ok(Json::Object(created)) = Json::parse(reply.body.as_bytes()) else {
+        panic!("{}", reply.body);
+    };
+    let Some(Json::String(id)) = created.get("_id") else {
+        panic!("{}", reply.body);
+    };
+    let doc = format!("{}/{}", docs, id);
+    let reply = call(&server, "GET", &doc, "", "");
+    assert_eq!(reply.status, 200, "{}", reply.body);
+    assert!(reply.body.contains(r#""n" : 1"#), "{}", reply.body);
+
+    let query = "/dbs/shop/collections/items/query";
+    let reply = call(&server, "POST", query, "", r#"{"filter":{"n":1}}"#);
+    assert_eq!(reply.status, 200, "{}", reply.body);
+    assert!(reply.body.contains(id.as_str()), "{}", reply.body);
+    let reply = call(&server, "GET", "/dbs", "", "");
+    assert_eq!(reply.status, 200, "{}", reply.body);
+    assert!(reply.body.contains(r#""shop""#), "{}", reply.body);
+
+    assert_eq!(call(&server, "DELETE", &doc, "", "").status, 200);
+    assert_eq!(call(&server, "GET", &doc, "", "").status, 404);
+    assert_eq!(call(&server, "DELETE", &doc, "", "").status, 404);
+}
+
+#[test]
+fn queries_report_lints_and_refuse_them_when_strict() {
+    let server = shop();
+    assert_eq!(call(&server, "PUT", DOC, "", r#"{"n":1}"#).status, 201);
+    let query = "/dbs/shop/collections/items/query";
+    let reply = call(
+        &server,
+        "POST",
+        query,
+        "",
+        r#"{"filter":{"n":{"$gtee":1}}}"#,
+    );
+    assert_eq!(reply.status, 400, "{}", reply.body);
+    assert!(reply.body.contains("$gte"), "{}", reply.body);
+
+    let mismatched = r#"{"filter":{"n":1,"name":{"$lt":null}}}"#;
+    let reply = call(&server, "POST", query, "", mismatched);
+    assert_eq!(reply.status, 200, "{}", reply.body);
+    assert!(reply.body.contains(r#""warnings""#), "{}", reply.body);
+    let strict = r#"{"filter":{"n":1,"name":{"$lt":null}},"strict":true}"#;
+    let reply = call(&server, "POST", query, "", strict);
+    assert_eq!(reply.status, 400, "{}", reply.body);
+    assert!(reply.body.contains("filter_lint"), "{}", reply.body);
+}
@@ -0,0 +1,151 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use db6::{db::DB, json::Json, quota::Quota, test_util::TestServer};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+fn post(server: &TestServer, path: &str, headers: &str, body: &str) -> (u16, Json) {
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        path,
+        body.len(),
+        headers,
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, Json::parse(body.as_bytes()).unwrap_or(Json::Null))
+}
+
+fn field(json: &Json, name: &str) -> String {
+    match json {
+        Json::Object(obj) => match obj.get(name) {
+            Some(Json::String(value)) => value.clone(),
+            _ => panic!("{} has no {}", json, name),
+        },
+        _ => panic!("{} is not an object", json),
+    }
+}
+
+fn hmac(key: &[u8], message: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(message.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+struct Login {
+    status: u16,
+    reply: Json,
+    server_signature: String,
+}
+
+// Runs the client side of a SCRAM-SHA-256 login.
+fn login(server: &TestServer, user: &str, password: &str) -> Login {
+    let client_first_bare = format!("n={},r=clientnonce", user);
+    let body = format!(r#"{{"message":"n,,{}"}}"#, client_first_bare);
+    let (status, first) = post(server, "/_auth", "", &body);
+    assert_eq!(status, 200, "{}", first);
+    let server_first = field(&first, "message");
+    let attrs = server_first
+        .split(',')
+        .map(|attr| attr.split_at(2))
+        .collect::<Vec<_>>();
+    let attr = |name: &str| attrs.iter().find(|(key, _)| *key == name).unwrap().1;
+    let salt = STANDARD.decode(attr("s=")).unwrap();
+    let iterations = attr("i=").parse::<u32>().unwrap();
+    let mut salted = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, iterations, &mut salted);
+    let client_key = hmac(&salted, "Client Key");
+    let stored_key = Sha256::digest(&client_key);
+    let without_proof = format!("c=biws,r={}", attr("r="));
+    let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+    let signature = hmac(&stored_key, &auth_message);
+    let proof = client_key
+        .iter()
+        .zip(&signature)
+        .map(|(key, sig)| key ^ sig)
+        .collect::<Vec<_>>();
+    let body = format!(
+        r#"{{"exchange":"{}","message":"{},p={}"}}"#,
+        field(&first, "exchange"),
+        without_proof,
+        STANDARD.encode(proof)
+    );
+    let (status, reply) = post(server, "/_auth", "", &body);
+    Login {
+        status,
+        reply,
+        server_signature: format!(
+            "v={}",
+            STANDARD.encode(hmac(&hmac(&salted, "Server Key"), &auth_message))
+        ),
+    }
+}
+
+#[test]
+fn logins_prove_the_password_and_open_a_session() {
+    let quota = Quota {
+        reads: 100,
+        writes: 100,
+    };
+    let server = TestServer::start_with(|cl| cl.token_quota = Some(quota)).unwrap();
+    DB::create(&server.root().to_string_lossy(), "shop", "pw".to_string()).unwrap();
+
+    let login = login(&server, "shop", "pw");
+    assert_eq!(login.status, 200, "{}", login.reply);
+    assert_eq!(field(&login.reply, "message"), login.server_signature);
+    // The session token is an identity the token quota accepts.
+    let token = field(&login.reply, "token");
+    let path = "/dbs/shop/collections/items/docs/a";
+    let (status, _) = post(&server, path, "", "{}");
+    assert_eq!(status, 401);
+    let bearer = format!("Authorization: Bearer {}\r\n", token);
+    let (status, reply) = post(&server, path, &bearer, "{}");
+    assert_ne!(status, 401, "{}", reply);
+
+    let wrong = self::login(&server, "shop", "wrong");
+    assert_eq!(wrong.status, 401, "{}", wrong.reply);
+    assert!(wrong.reply.canonical().contains("authentication_failed"));
+}
+
+#[test]
+fn exchanges_are_used_once_and_unknown_users_look_alike() {
+    let server = TestServer::start().unwrap();
+    let body = r#"{"message":"n,,n=ghost,r=abc"}"#;
+    let (_, first) = post(&server, "/_auth", "", body);
+    let (_, second) = post(&server, "/_auth", "", body);
+    let salt = |reply: &Json| {
+        field(reply, "message")
+            .split(",s=")
+            .nth(1)
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(salt(&first), salt(&second));
+
+    let finish = format!(
+        r#"{{"exchange":"{}","message":"c=biws,r=abc,p={}"}}"#,
+        field(&first, "exchange"),
+        STANDARD.encode([0u8; 32])
+    );
+    assert_eq!(post(&server, "/_auth", "", &finish).0, 401);
+    let (status, reply) = post(&server, "/_auth", "", &finish);
+    assert_eq!(status, 401);
+    assert!(reply.canonical().contains("expired"), "{}", reply);
+    let (status, _) = post(
+        &server,
+        "/_auth",
+        "",
+        r#"{"message":"p=tls-unique,,n=a,r=b"}"#,
+    );
+    assert_eq!(status, 400);
+}
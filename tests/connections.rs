@@ -0,0 +1,76 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use db6::test_util::TestServer;
+use rustls::{
+    ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+    pki_types::{CertificateDer, ServerName, pem::PemObject},
+};
+
+const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/localhost.crt");
+const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/localhost.key");
+
+// Starts a server that admits a single connection and takes it, so the next one is rejected.
+fn full_server(tls: bool) -> (TestServer, TcpStream) {
+    let server = TestServer::start_with(|cl| {
+        cl.max_connections = 1;
+        if tls {
+            cl.tls_cert = Some(CERT.to_string());
+            cl.tls_key = Some(KEY.to_string());
+        }
+    })
+    .unwrap();
+    let held = TcpStream::connect(server.addr()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    (server, held)
+}
+
+fn read_response(stream: &mut impl Read) -> String {
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    String::from_utf8_lossy(&response).to_string()
+}
+
+#[test]
+fn connections_over_the_limit_get_a_503() {
+    let (server, _held) = full_server(false);
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    let response = read_response(&mut stream);
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable"),
+        "{}",
+        response
+    );
+    assert!(response.contains("Retry-After: 1\r\n"), "{}", response);
+}
+
+#[test]
+fn tls_connections_over_the_limit_get_a_503_after_the_handshake() {
+    let (server, _held) = full_server(true);
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_file(CERT).unwrap())
+        .unwrap();
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    let conn = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap())
+        .unwrap();
+    let mut stream = StreamOwned::new(conn, TcpStream::connect(server.addr()).unwrap());
+    stream.flush().unwrap();
+    let response = read_response(&mut stream);
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable"),
+        "{}",
+        response
+    );
+    assert!(response.contains("Retry-After: 1\r\n"), "{}", response);
+}
@@ -0,0 +1,154 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+    time::Duration,
+};
+
+use db6::test_util::TestServer;
+
+fn send_in_parts(server: &TestServer, parts: &[&[u8]]) -> String {
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_nodelay(true).unwrap();
+    for part in parts {
+        stream.write_all(part).unwrap();
+        stream.flush().unwrap();
+        thread::sleep(Duration::from_millis(50));
+    }
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn status_lines(response: &str) -> Vec<&str> {
+    response
+        .match_indices("HTTP/1.1 ")
+        .filter_map(|(start, _)| response[start..].lines().next())
+        .collect()
+}
+
+fn maintenance_head(server: &TestServer, framing: &str, connection: &str) -> String {
+    format!(
+        "PUT /_maintenance HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\n{}\r\nConnection: {}\r\n\r\n",
+        server.admin_token(),
+        framing,
+        connection
+    )
+}
+
+#[test]
+fn header_terminator_split_across_reads() {
+    let server = TestServer::start().unwrap();
+    let response = send_in_parts(
+        &server,
+        &[
+            b"GET /_status HTTP/1.1\r\nHo",
+            b"st: localhost\r\nConnection: close\r\n\r",
+            b"\n",
+        ],
+    );
+    assert_eq!(status_lines(&response), ["HTTP/1.1 200 OK"], "{}", response);
+}
+
+#[test]
+fn bare_line_feeds_and_leading_blank_lines() {
+    let server = TestServer::start().unwrap();
+    let response = send_in_parts(
+        &server,
+        &[b"\r\n\nGET /_status HTTP/1.1\nHost: localhost\nConnection: close\n\n"],
+    );
+    assert_eq!(status_lines(&response), ["HTTP/1.1 200 OK"], "{}", response);
+}
+
+#[test]
+fn pipelined_requests_in_one_write() {
+    let server = TestServer::start().unwrap();
+    let response = send_in_parts(
+        &server,
+        &[concat!(
+            "GET /_status HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "HEAD /_status HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "GET /_status HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+        )
+        .as_bytes()],
+    );
+    assert_eq!(
+        status_lines(&response),
+        ["HTTP/1.1 200 OK", "HTTP/1.1 200 OK", "HTTP/1.1 200 OK"],
+        "{}",
+        response
+    );
+}
+
+#[test]
+fn pipelined_request_after_body_is_kept() {
+    let server = TestServer::start().unwrap();
+    let body = r#"{"message":"Pipelined"}"#;
+    let request = maintenance_head(
+        &server,
+        &format!("Content-Length: {}", body.len()),
+        "keep-alive",
+    ) + body
+        + &format!(
+            "GET /_maintenance HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\r\n",
+            server.admin_token()
+        );
+    let bytes = request.as_bytes();
+    let split = bytes.len() - 40;
+    let response = send_in_parts(&server, &[&bytes[..split], &bytes[split..]]);
+    assert_eq!(
+        status_lines(&response),
+        ["HTTP/1.1 200 OK", "HTTP/1.1 200 OK"],
+        "{}",
+        response
+    );
+    assert_eq!(response.matches("Pipelined").count(), 2, "{}", response);
+}
+
+#[test]
+fn chunked_body_split_across_reads() {
+    let server = TestServer::start().unwrap();
+    let head = maintenance_head(&server, "Transfer-Encoding: chunked", "close");
+    let response = send_in_parts(
+        &server,
+        &[
+            head.as_bytes(),
+            b"b\r\n{\"message\":",
+            b"\r\n11;ext=1\r\n\"Chunked upload\"}\r",
+            b"\n0\r\n",
+            b"\r\n",
+        ],
+    );
+    assert_eq!(status_lines(&response), ["HTTP/1.1 200 OK"], "{}", response);
+    assert!(response.contains("Chunked upload"), "{}", response);
+}
+
+#[test]
+fn chunked_body_with_content_length_is_rejected() {
+    let server = TestServer::start().unwrap();
+    let head = maintenance_head(
+        &server,
+        "Transfer-Encoding: chunked\r\nContent-Length: 5",
+        "keep-alive",
+    );
+    let response = send_in_parts(&server, &[head.as_bytes(), b"0\r\n\r\n"]);
+    assert_eq!(
+        status_lines(&response),
+        ["HTTP/1.1 400 Bad Request"],
+        "{}",
+        response
+    );
+}
+
+#[test]
+fn unsupported_transfer_encoding_is_rejected() {
+    let server = TestServer::start().unwrap();
+    let head = maintenance_head(&server, "Transfer-Encoding: gzip, chunked", "close");
+    let response = send_in_parts(&server, &[head.as_bytes()]);
+    assert_eq!(
+        status_lines(&response),
+        ["HTTP/1.1 501 Not Implemented"],
+        "{}",
+        response
+    );
+}
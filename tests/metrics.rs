@@ -0,0 +1,35 @@
+use std::{thread, time::Duration};
+
+use db6::metrics::{self, Phase, PhaseMetrics, RequestTimings};
+
+#[test]
+fn nested_phases_are_counted_once() {
+    metrics::take_spent();
+    metrics::spend(Phase::Storage, || {
+        thread::sleep(Duration::from_millis(20));
+        metrics::spend(Phase::Storage, || thread::sleep(Duration::from_millis(20)));
+        metrics::spend(Phase::Validate, || thread::sleep(Duration::from_millis(30)));
+    });
+    let spent = metrics::take_spent();
+    assert!(spent.validate >= Duration::from_millis(30));
+    assert!(spent.storage >= Duration::from_millis(40));
+    assert!(
+        spent.storage < Duration::from_millis(70),
+        "{:?}",
+        spent.storage
+    );
+    assert_eq!(metrics::take_spent().total(), Duration::ZERO);
+}
+
+#[test]
+fn averages_count_every_request() {
+    // More requests than fit in a u32, which wrapped around to 1 when the average was taken.
+    let mut phases = PhaseMetrics::new();
+    phases.requests = u32::MAX as u64 + 2;
+    phases.totals = RequestTimings {
+        storage: Duration::from_secs(phases.requests),
+        ..RequestTimings::new()
+    };
+    let json = phases.to_json().canonical();
+    assert!(json.contains(r#""storage":{"avg_ms":1000"#), "{}", json);
+}
@@ -0,0 +1,217 @@
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, TcpStream},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use db6::{
+    db::DB,
+    http::{HttpStatus, Request},
+    middleware,
+    ratelimit::RateLimit,
+    test_util::TestServer,
+};
+
+fn send(server: &TestServer, method: &str, path: &str, headers: &str) -> String {
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n{}Connection: close\r\n\r\n",
+        method, path, headers
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn status(response: &str) -> &str {
+    response.lines().next().unwrap_or_default()
+}
+
+#[test]
+fn admin_endpoints_require_the_token() {
+    let server = TestServer::start().unwrap();
+    for (method, path) in [
+        ("POST", "/admin/dbs/intruder"),
+        ("POST", "/admin/reload"),
+        ("PUT", "/_maintenance"),
+        ("GET", "/admin/dbs"),
+    ] {
+        for headers in ["", "Authorization: Bearer wrong\r\n"] {
+            let response = send(&server, method, path, headers);
+            assert_eq!(
+                status(&response),
+                "HTTP/1.1 401 Unauthorized",
+                "{} {}",
+                method,
+                path
+            );
+            assert!(
+                response.contains("WWW-Authenticate: Bearer"),
+                "{}",
+                response
+            );
+        }
+    }
+    let token = format!("Authorization: Bearer {}\r\n", server.admin_token());
+    let response = send(&server, "GET", "/admin/dbs", &token);
+    assert_eq!(status(&response), "HTTP/1.1 200 OK", "{}", response);
+    let response = send(&server, "GET", "/_status", "");
+    assert_eq!(status(&response), "HTTP/1.1 200 OK", "{}", response);
+}
+
+#[test]
+fn admin_endpoints_without_a_token_only_answer_local_clients() {
+    let request = |client: &str| {
+        let mut request =
+            Request::from_bytes(b"POST /admin/dbs/intruder HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+        request.client = Some(client.parse::<IpAddr>().unwrap());
+        request
+    };
+    for client in ["127.0.0.1", "::1", "::ffff:127.0.0.1"] {
+        assert!(
+            middleware::admin_access(&request(client), None).is_ok(),
+            "{}",
+            client
+        );
+    }
+    for client in ["10.0.0.7", "192.168.1.2", "::ffff:10.0.0.7", "2001:db8::1"] {
+        let err = middleware::admin_access(&request(client), None).unwrap_err();
+        assert_eq!(err.status, HttpStatus::Forbidden, "{}", client);
+    }
+    let err = middleware::admin_access(&request("127.0.0.1"), Some("secret")).unwrap_err();
+    assert_eq!(err.status, HttpStatus::Unauthorized);
+
+    let server = TestServer::start_with(|cl| cl.admin_token = None).unwrap();
+    let response = send(&server, "GET", "/admin/dbs", "");
+    assert_eq!(status(&response), "HTTP/1.1 200 OK", "{}", response);
+}
+
+#[test]
+fn explain_times_the_phases_and_only_plans_writes() {
+    let server = TestServer::start().unwrap();
+    DB::create(&server.root().to_string_lossy(), "shop", "pw".to_string()).unwrap();
+    let headers = format!(
+        "Authorization: Basic {}\r\n{}: true\r\n",
+        STANDARD.encode("shop:pw"),
+        middleware::EXPLAIN_HEADER
+    );
+    let doc = "/dbs/shop/collections/items/docs/a";
+    let response = send(&server, "PUT", doc, &headers);
+    assert_eq!(status(&response), "HTTP/1.1 200 OK", "{}", response);
+    assert!(response.contains(r#""executed" : false"#), "{}", response);
+    let response = send(&server, "GET", doc, &headers);
+    assert_eq!(status(&response), "HTTP/1.1 404 Not Found", "{}", response);
+    let trace = response
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{}: ", middleware::TRACE_HEADER)))
+        .unwrap();
+    for field in [
+        r#""executed":true"#,
+        r#""storage_ms":"#,
+        r#""validate_ms":"#,
+        r#""cache":{"hits":"#,
+    ] {
+        assert!(trace.contains(field), "{} in {}", field, trace);
+    }
+}
+
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let (header, value) = line.split_once(':')?;
+        header.eq_ignore_ascii_case(name).then_some(value.trim())
+    })
+}
+
+const DASHBOARD: &str = "https://dash.example";
+
+#[test]
+fn cors_answers_preflights_and_marks_responses_for_allowed_origins() {
+    let server =
+        TestServer::start_with(|cl| cl.cors_origins = vec![DASHBOARD.to_string()]).unwrap();
+    let preflight = format!(
+        "Origin: {}\r\nAccess-Control-Request-Method: PUT\r\n",
+        DASHBOARD
+    );
+    let response = send(&server, "OPTIONS", "/dbs", &preflight);
+    assert_eq!(status(&response), "HTTP/1.1 204 No Content", "{}", response);
+    assert!(
+        header(&response, "Access-Control-Allow-Methods").is_some(),
+        "{}",
+        response
+    );
+    let response = send(
+        &server,
+        "GET",
+        "/dbs",
+        &format!("Origin: {}\r\n", DASHBOARD),
+    );
+    assert_eq!(
+        header(&response, "Access-Control-Allow-Origin"),
+        Some(DASHBOARD),
+        "{}",
+        response
+    );
+    let response = send(&server, "GET", "/dbs", "Origin: https://evil.example\r\n");
+    assert_eq!(header(&response, "Access-Control-Allow-Origin"), None);
+    let preflight = "Origin: https://evil.example\r\nAccess-Control-Request-Method: PUT\r\n";
+    let response = send(&server, "OPTIONS", "/dbs", preflight);
+    assert_eq!(status(&response), "HTTP/1.1 403 Forbidden", "{}", response);
+}
+
+#[test]
+fn responses_of_inner_layers_go_through_the_outer_ones() {
+    let server = TestServer::start_with(|cl| {
+        cl.cors_origins = vec![DASHBOARD.to_string()];
+        cl.maintenance = Some("Back soon".to_string());
+    })
+    .unwrap();
+    let origin = format!("Origin: {}\r\n", DASHBOARD);
+    let response = send(&server, "GET", "/dbs", &origin);
+    assert_eq!(
+        status(&response),
+        "HTTP/1.1 503 Service Unavailable",
+        "{}",
+        response
+    );
+    assert!(response.contains("Back soon"), "{}", response);
+    assert_eq!(
+        header(&response, "Access-Control-Allow-Origin"),
+        Some(DASHBOARD),
+        "{}",
+        response
+    );
+    let response = send(&server, "GET", "/_status", &origin);
+    assert_eq!(status(&response), "HTTP/1.1 200 OK", "{}", response);
+}
+
+#[test]
+fn clients_over_their_rate_limit_get_a_429() {
+    let server = TestServer::start_with(|cl| {
+        cl.rate_limit_read = Some(RateLimit {
+            rate: 0.01,
+            burst: 2,
+        })
+    })
+    .unwrap();
+    for remaining in ["1", "0"] {
+        let response = send(&server, "GET", "/dbs", "");
+        assert_eq!(status(&response), "HTTP/1.1 200 OK", "{}", response);
+        assert_eq!(header(&response, "RateLimit-Limit"), Some("2"));
+        assert_eq!(header(&response, "RateLimit-Remaining"), Some(remaining));
+    }
+    let response = send(&server, "GET", "/dbs", "");
+    assert_eq!(
+        status(&response),
+        "HTTP/1.1 429 Too Many Requests",
+        "{}",
+        response
+    );
+    assert!(header(&response, "Retry-After").is_some(), "{}", response);
+    assert!(response.contains("rate_limited"), "{}", response);
+    // Health checks are never limited.
+    let response = send(&server, "GET", "/_status", "");
+    assert_eq!(status(&response), "HTTP/1.1 200 OK", "{}", response);
+}
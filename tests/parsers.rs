@@ -0,0 +1,407 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use db6::{
+    error::ApiError,
+    http::{
+        ContentType, Cookie, HttpStatus, MAX_HEADER_COUNT, MAX_HEADER_SIZE, Request, SameSite,
+        parse_multipart, parse_query, percent_decode,
+    },
+    range,
+    scram::{ClientFirst, ScramExchange, ScramVerifier},
+    websocket::{Frame, MAX_FRAME_SIZE, Opcode},
+};
+
+fn parse(head: &str) -> Result<Request, ApiError> {
+    Request::from_bytes(head.as_bytes())
+}
+
+fn request(headers: &str) -> Request {
+    parse(&format!(
+        "GET /_status HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+        headers
+    ))
+    .unwrap()
+}
+
+// A frame as a client sends it, with its payload masked.
+fn client_frame(opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut bytes = vec![0x80 | opcode.code()];
+    let len = payload.len();
+    if len < 126 {
+        bytes.push(0x80 | len as u8);
+    } else {
+        bytes.push(0x80 | 126);
+        bytes.extend_from_slice(&(len as u16).to_be_bytes());
+    }
+    bytes.extend_from_slice(&mask);
+    bytes.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(ind, byte)| byte ^ mask[ind % 4]),
+    );
+    bytes
+}
+
+#[test]
+fn websocket_frames_are_unmasked() {
+    for payload in [&b"hello"[..], &[7u8; 300][..], b""] {
+        let bytes = client_frame(Opcode::Text, payload);
+        let frame = Frame::read_from(&mut &bytes[..], MAX_FRAME_SIZE).unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, Opcode::Text);
+        assert_eq!(frame.payload, payload);
+    }
+}
+
+#[test]
+fn truncated_websocket_frames_are_rejected() {
+    for bytes in [
+        client_frame(Opcode::Text, b"hello"),
+        client_frame(Opcode::Binary, &[7u8; 300]),
+        client_frame(Opcode::Ping, b""),
+    ] {
+        // Cut in the head, the extended length, the mask and the payload.
+        for len in 0..bytes.len() {
+            assert!(
+                Frame::read_from(&mut &bytes[..len], MAX_FRAME_SIZE).is_err(),
+                "{:?}",
+                &bytes[..len]
+            );
+        }
+    }
+}
+
+#[test]
+fn invalid_websocket_frames_are_rejected() {
+    let mut unmasked = client_frame(Opcode::Text, b"hi");
+    unmasked[1] &= 0x7F;
+    let mut reserved = client_frame(Opcode::Text, b"hi");
+    reserved[0] |= 0x40;
+    let mut unknown = client_frame(Opcode::Text, b"hi");
+    unknown[0] = 0x83;
+    let mut fragmented_ping = client_frame(Opcode::Ping, b"hi");
+    fragmented_ping[0] &= 0x7F;
+    for bytes in [
+        unmasked,
+        reserved,
+        unknown,
+        fragmented_ping,
+        client_frame(Opcode::Ping, &[0u8; 126]),
+        client_frame(Opcode::Binary, &[0u8; 300]),
+    ] {
+        assert!(
+            Frame::read_from(&mut &bytes[..], 200).is_err(),
+            "{:?}",
+            bytes
+        );
+    }
+}
+
+#[test]
+fn cookies_are_parsed_leniently() {
+    let request = request(concat!(
+        "Cookie: session=abc; theme=\"dark\" ;  =orphan; flag; empty=\r\n",
+        "Cookie: session=later; lang=en=GB\r\n",
+    ));
+    assert_eq!(request.cookie("session"), Some("abc"));
+    assert_eq!(request.cookie("theme"), Some("dark"));
+    assert_eq!(request.cookie("empty"), Some(""));
+    assert_eq!(request.cookie("lang"), Some("en=GB"));
+    assert_eq!(request.cookie("flag"), None);
+    assert_eq!(request.cookie(""), None);
+    assert_eq!(request.cookies.len(), 4);
+}
+
+#[test]
+fn set_cookie_headers_are_validated() {
+    let mut cookie = Cookie::new("session", "abc.123").unwrap();
+    assert_eq!(
+        cookie.to_string(),
+        "session=abc.123; Path=/; HttpOnly; SameSite=Lax"
+    );
+    cookie.same_site = Some(SameSite::None);
+    cookie.max_age = Some(60);
+    assert_eq!(
+        cookie.to_string(),
+        "session=abc.123; Path=/; Max-Age=60; Secure; HttpOnly; SameSite=None"
+    );
+    assert_eq!(
+        Cookie::removal("session").unwrap().to_string(),
+        "session=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax"
+    );
+    for (name, value) in [
+        ("", "a"),
+        ("se ssion", "a"),
+        ("se;ssion", "a"),
+        ("session", "a b"),
+        ("session", "a;b"),
+        ("session", "\"a\""),
+        ("session", "caf\u{e9}"),
+    ] {
+        assert!(Cookie::new(name, value).is_err(), "{}={}", name, value);
+    }
+}
+
+#[test]
+fn multipart_bodies_are_split_at_the_boundary() {
+    let body = concat!(
+        "preamble\r\n--xyz\r\n",
+        "Content-Disposition: form-data; name=\"meta\"\r\n\r\n",
+        "{\"a\":1}\r\n--xyz\r\n",
+        "Content-Disposition: form-data; name=\"file\"; filename=\"a.bin\"\r\n",
+        "Content-Type: application/octet-stream\r\n\r\n",
+        "\r\n--xy\r\n--xyz--\r\nepilogue",
+    );
+    let parts = parse_multipart(body.as_bytes(), "xyz").unwrap();
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0].name.as_deref(), Some("meta"));
+    assert_eq!(parts[0].text().unwrap(), "{\"a\":1}");
+    assert_eq!(parts[1].filename.as_deref(), Some("a.bin"));
+    assert_eq!(parts[1].data, b"\r\n--xy");
+}
+
+#[test]
+fn bad_multipart_boundaries_are_rejected() {
+    for body in [
+        "--abc\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--abc--",
+        "--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--xy",
+        "--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1--xyz--",
+        "--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n",
+        "--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--xyz",
+        "--xyz",
+        "--xyzContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--xyz--",
+        "--xyz\r\nbroken header\r\n\r\n1\r\n--xyz--",
+    ] {
+        assert!(parse_multipart(body.as_bytes(), "xyz").is_err(), "{}", body);
+    }
+    assert!(matches!(
+        "multipart/form-data; boundary=\"a b\"".parse::<ContentType>(),
+        Ok(ContentType::MultipartFormData(boundary)) if boundary == "a b"
+    ));
+    for content_type in [
+        "multipart/form-data",
+        "multipart/form-data; boundary=",
+        "multipart/form-data; boundary=\"\"",
+        &format!("multipart/form-data; boundary={}", "b".repeat(71)),
+    ] {
+        assert!(
+            content_type.parse::<ContentType>().is_err(),
+            "{}",
+            content_type
+        );
+    }
+}
+
+#[test]
+fn suffix_and_open_ranges_are_clamped_to_the_resource() {
+    let cases = [
+        ("bytes=-3", Some((7, 9))),
+        ("bytes=-30", Some((0, 9))),
+        ("bytes=4-", Some((4, 9))),
+        ("bytes=2-4", Some((2, 4))),
+        ("bytes=2-400", Some((2, 9))),
+        (" bytes= 9 - 9 ", Some((9, 9))),
+        ("bytes=5-2", None),
+        ("bytes=-", None),
+        ("bytes=a-b", None),
+        ("bytes=--1", None),
+        ("items=0-1", None),
+    ];
+    for (header, expected) in cases {
+        assert_eq!(range::parse(header, 10).unwrap(), expected, "{}", header);
+    }
+}
+
+#[test]
+fn overlapping_and_unsatisfiable_ranges() {
+    // Several ranges, overlapping or not, are served as the whole resource.
+    for header in [
+        "bytes=0-4,2-6",
+        "bytes=0-1, 0-1",
+        "bytes=-2,-3",
+        "bytes=0-1,5-",
+    ] {
+        assert_eq!(range::parse(header, 10).unwrap(), None, "{}", header);
+    }
+    for (header, len) in [
+        ("bytes=-0", 10),
+        ("bytes=10-", 10),
+        ("bytes=10-12", 10),
+        ("bytes=-5", 0),
+        ("bytes=0-", 0),
+    ] {
+        assert!(range::parse(header, len).is_err(), "{} of {}", header, len);
+    }
+}
+
+#[test]
+fn header_names_are_case_insensitive() {
+    let request = request(concat!(
+        "CONTENT-type: application/json\r\n",
+        "X-Custom:\t spaced value \t\r\n",
+        "x-empty:\r\n",
+        "Accept-Encoding: gzip\r\n",
+        "accept-encoding: br\r\n",
+    ));
+    assert_eq!(request.header("x-custom"), Some("spaced value"));
+    assert_eq!(request.header("X-CUSTOM"), Some("spaced value"));
+    assert_eq!(request.header("X-Empty"), Some(""));
+    assert_eq!(request.accept_encoding.as_deref(), Some("gzip, br"));
+    assert_eq!(request.host, "localhost");
+}
+
+#[test]
+fn malformed_headers_are_rejected() {
+    for headers in [
+        "X-Folded: a\r\n  b\r\n",
+        "No colon\r\n",
+        "Bad Name: a\r\n",
+        "Host : localhost\r\n",
+        ": empty\r\n",
+        "X-Control: a\u{7}b\r\n",
+        "Content-Length: 1\r\nContent-Length: 2\r\n",
+        "Content-Length: -1\r\n",
+        "Host: other\r\n",
+    ] {
+        let head = format!(
+            "POST /_status HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+            headers
+        );
+        let err = parse(&head).err().unwrap();
+        assert_eq!(err.status, HttpStatus::BadRequest, "{}", headers);
+    }
+    for head in [
+        "GET /_status HTTP/1.1\r\n\r\n",
+        "GET /_status\r\nHost: localhost\r\n\r\n",
+        "GET /_status HTTQ/1.1\r\nHost: localhost\r\n\r\n",
+        "GET  /_status HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    ] {
+        assert!(parse(head).is_err(), "{}", head);
+    }
+    // Repeating a header with the same value is not a conflict.
+    assert!(parse("GET / HTTP/1.1\r\nHost: a\r\nHost: a\r\n\r\n").is_ok());
+
+    let many = "X-A: 1\r\n".repeat(MAX_HEADER_COUNT);
+    let err = parse(&format!("GET / HTTP/1.1\r\nHost: a\r\n{}\r\n", many))
+        .err()
+        .unwrap();
+    assert_eq!(err.status, HttpStatus::RequestHeaderFieldsTooLarge);
+    let long = format!("X-A: {}\r\n", "a".repeat(MAX_HEADER_SIZE));
+    let err = parse(&format!("GET / HTTP/1.1\r\nHost: a\r\n{}\r\n", long))
+        .err()
+        .unwrap();
+    assert_eq!(err.status, HttpStatus::RequestHeaderFieldsTooLarge);
+}
+
+// The example exchange of RFC 7677.
+const SCRAM_CLIENT_NONCE: &str = "rOprNGfwEbeRWgbNEkqO";
+const SCRAM_SERVER_NONCE: &str = "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+const SCRAM_PROOF: &str = "p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+
+fn scram_exchange() -> ScramExchange {
+    let salt = STANDARD.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+    let verifier = ScramVerifier::derive("pencil", &salt, 4096);
+    let client_first = format!("n,,n=user,r={}", SCRAM_CLIENT_NONCE)
+        .parse::<ClientFirst>()
+        .unwrap();
+    ScramExchange::with_server_nonce(&client_first, verifier, SCRAM_SERVER_NONCE)
+}
+
+#[test]
+fn scram_exchanges_follow_the_rfc_example() {
+    let exchange = scram_exchange();
+    assert_eq!(
+        exchange.server_first(),
+        "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+    );
+    let client_final = format!(
+        "c=biws,r={}{},{}",
+        SCRAM_CLIENT_NONCE, SCRAM_SERVER_NONCE, SCRAM_PROOF
+    );
+    assert_eq!(
+        exchange.finish(&client_final).unwrap(),
+        "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="
+    );
+}
+
+#[test]
+fn scram_nonce_mismatches_are_rejected() {
+    let exchange = scram_exchange();
+    let nonce = format!("{}{}", SCRAM_CLIENT_NONCE, SCRAM_SERVER_NONCE);
+    for nonce in [
+        SCRAM_CLIENT_NONCE.to_string(),
+        format!("{}x", nonce),
+        nonce[1..].to_string(),
+        nonce.to_ascii_lowercase(),
+        format!("{}{}", SCRAM_CLIENT_NONCE, "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k1"),
+        String::new(),
+    ] {
+        let err = exchange
+            .finish(&format!("c=biws,r={},{}", nonce, SCRAM_PROOF))
+            .unwrap_err();
+        assert!(err.contains("nonce"), "{}: {}", nonce, err);
+    }
+    for client_final in [
+        format!("c=biws,{}", SCRAM_PROOF),
+        format!("c=biws,r={}", nonce),
+        format!("c=eSws,r={},{}", nonce, SCRAM_PROOF),
+        format!("c=biws,r={},p=AAAA", nonce),
+        format!(
+            "c=biws,r={},p=AHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+            nonce
+        ),
+    ] {
+        assert!(exchange.finish(&client_final).is_err(), "{}", client_final);
+    }
+    for client_first in [
+        "n,,n=user",
+        "n,,n=user,r=",
+        "n,,n=user,r=a b",
+        "n,a=admin,n=user,r=abc",
+        "y,,n=user,r=abc",
+        "n,,n=us=er,r=abc",
+        "n,,n=user,r=abc,m=ext",
+        "n=user,r=abc",
+    ] {
+        assert!(
+            client_first.parse::<ClientFirst>().is_err(),
+            "{}",
+            client_first
+        );
+    }
+}
+
+#[test]
+fn percent_escapes_are_decoded() {
+    assert_eq!(percent_decode("a%20b+c%2B%2fd", false).unwrap(), "a b+c+/d");
+    assert_eq!(percent_decode("a%20b+c", true).unwrap(), "a b c");
+    assert_eq!(percent_decode("caf%C3%A9%25", false).unwrap(), "caf\u{e9}%");
+    let query = parse_query("a=1&b=x%3Dy&a=%32&&flag&c=one+two&=empty").unwrap();
+    assert_eq!(query["a"], ["1", "2"]);
+    assert_eq!(query["b"], ["x=y"]);
+    assert_eq!(query["flag"], [""]);
+    assert_eq!(query["c"], ["one two"]);
+    assert_eq!(query[""], ["empty"]);
+
+    let request = parse("GET /dbs/a%2Eb/docs?filter=%7B%7D HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    assert_eq!(request.route, "/dbs/a.b/docs");
+    assert_eq!(request.query_value("filter"), Some("{}"));
+}
+
+#[test]
+fn invalid_percent_escapes_are_rejected() {
+    for value in [
+        "%", "%2", "a%", "a%2", "%zz", "%2g", "%%20", "%+1", "%C3", "%FF", "%C3%28",
+    ] {
+        assert!(percent_decode(value, false).is_err(), "{}", value);
+        assert!(parse_query(&format!("a={}", value)).is_err(), "{}", value);
+        assert!(parse_query(&format!("{}=a", value)).is_err(), "{}", value);
+    }
+    for target in ["/dbs/a%2", "/dbs/a%ZZ", "/dbs?x=%", "/dbs?%FF=1"] {
+        let err = parse(&format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", target))
+            .err()
+            .unwrap();
+        assert_eq!(err.status, HttpStatus::BadRequest, "{}", target);
+    }
+}
@@ -0,0 +1,276 @@
+use db6::{
+    aggregate::Pipeline,
+    db::DB,
+    json::Json,
+    query::{self, Filter, Query},
+    schema::{Schema, Validation},
+    test_util::TempDir,
+};
+
+fn json(text: &str) -> Json {
+    Json::parse(text.as_bytes()).unwrap()
+}
+
+fn matches(filter: &str, document: &str) -> bool {
+    Filter::parse(&json(filter))
+        .unwrap()
+        .matches(&json(document))
+}
+
+#[test]
+fn filter_operators() {
+    let user = r#"{"name":"Ada","age":36,"tags":["math","code"],"address":{"city":"London"}}"#;
+    assert!(matches(r#"{"name":"Ada","age":36.0}"#, user));
+    assert!(matches(r#"{"address.city":{"$prefix":"Lon"}}"#, user));
+    assert!(matches(r#"{"tags.1":"code"}"#, user));
+    assert!(matches(r#"{"age":{"$gte":18,"$lt":40}}"#, user));
+    assert!(!matches(r#"{"age":{"$gt":"18"}}"#, user));
+    assert!(matches(r#"{"name":{"$in":["Ada","Grace"]}}"#, user));
+    assert!(matches(
+        r#"{"email":{"$nin":["a@b.c"],"$exists":false}}"#,
+        user
+    ));
+    assert!(matches(r#"{"name":{"$regex":"^A.a$"}}"#, user));
+    assert!(matches(
+        r#"{"$or":[{"age":{"$lt":18}},{"$not":{"name":{"$ne":"Ada"}}}]}"#,
+        user
+    ));
+    assert!(!matches(
+        r#"{"$and":[{"name":"Ada"},{"address":{"city":"Paris"}}]}"#,
+        user
+    ));
+
+    for invalid in [
+        r#"{"age":{"$between":[1,2]}}"#,
+        r#"{"age":{"$gt":1,"max":2}}"#,
+        r#"{"$or":{"age":1}}"#,
+        r#"{"name":{"$regex":"("}}"#,
+        r#"[]"#,
+    ] {
+        assert!(Filter::parse(&json(invalid)).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn queries_run_over_collections() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "people", String::new()).unwrap();
+    for (id, age) in [("a", 12), ("b", 30), ("c", 45)] {
+        db.put("users", id, &json(&format!(r#"{{"age":{}}}"#, age)))
+            .unwrap();
+    }
+    let query = Query::parse(Some(&json(r#"{"filter":{"age":{"$gt":20}}}"#))).unwrap();
+    let ids = query
+        .run(&db, "users")
+        .unwrap()
+        .documents
+        .iter()
+        .map(|document| document.canonical())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, [r#"{"_id":"b","age":30}"#, r#"{"_id":"c","age":45}"#]);
+    assert!(Query::parse(Some(&json(r#"{"filters":{}}"#))).is_err());
+}
+
+#[test]
+fn filters_are_linted_against_the_schema() {
+    let lints = |filter: &str, schema: Option<&Schema>| {
+        query::lint(&json(filter), schema)
+            .iter()
+            .map(|lint| lint.to_json().canonical())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        lints(r#"{"$or":[{"age":{"$gtee":1}},{"$nand":[]}]}"#, None),
+        [
+            r#"{"message":"Unknown operator $gtee","path":"$or.0.age.$gtee","suggestion":"$gte"}"#,
+            r#"{"message":"Unknown operator $nand","path":"$or.1.$nand","suggestion":"$and"}"#,
+        ]
+    );
+    assert_eq!(
+        lints(r#"{"age":{"gte":18},"name":{"$lt":null}}"#, None),
+        [
+            r#"{"message":"The field age is compared with the whole object, where gte is not an operator","path":"age.gte","suggestion":"$gte"}"#,
+            r#"{"message":"The operator $lt orders numbers, strings and booleans, so it never matches null","path":"name.$lt"}"#,
+        ]
+    );
+    assert!(lints(r#"{"age":{"$gte":18},"name":"Ada"}"#, None).is_empty());
+
+    let schema = Schema::new(&json(
+        r#"{"properties":{"age":{"type":"integer"},"name":{"type":"string"},
+            "tags":{"type":"array","items":{"type":"string"}}}}"#,
+    ))
+    .unwrap();
+    assert_eq!(
+        lints(
+            r#"{"age":{"$gt":"18","$lt":40.5},"name":{"$in":["Ada",7]},"tags.0":{"$regex":"^m"},"age.x":1}"#,
+            Some(&schema)
+        ),
+        [
+            r#"{"message":"The field age is integer in the schema, but it is compared with the string \"18\"","path":"age.$gt","suggestion":"18"}"#,
+            r#"{"message":"The field name is string in the schema, but it is compared with the number 7","path":"name.$in.1","suggestion":"\"7\""}"#,
+        ]
+    );
+    assert_eq!(
+        lints(r#"{"age":{"$prefix":"1"}}"#, Some(&schema)),
+        [
+            r#"{"message":"The field age is integer in the schema, and $prefix only matches strings","path":"age.$prefix"}"#
+        ]
+    );
+
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "linted", String::new()).unwrap();
+    db.create_collection("users").unwrap();
+    let filter = json(r#"{"age":"18"}"#);
+    assert!(db.lint("users", &filter).unwrap().is_empty());
+    db.set_schema("users", Some((&schema, Validation::Strict)))
+        .unwrap();
+    assert_eq!(db.lint("users", &filter).unwrap().len(), 1);
+    assert!(
+        Query::parse(Some(&json(r#"{"strict":true}"#)))
+            .unwrap()
+            .strict
+    );
+    assert!(Query::parse(Some(&json(r#"{"strict":1}"#))).is_err());
+}
+
+fn page(db: &DB, query: &str, cursor: Option<&str>) -> (Vec<String>, Option<String>) {
+    let mut query = json(query);
+    if let (Json::Object(obj), Some(cursor)) = (&mut query, cursor) {
+        obj["cursor".to_string()] = Json::String(cursor.to_string());
+    }
+    let page = Query::parse(Some(&query))
+        .unwrap()
+        .run(db, "items")
+        .unwrap();
+    let ids = page
+        .documents
+        .iter()
+        .map(|document| match document {
+            Json::Object(obj) => obj.get("_id").unwrap().canonical().replace('"', ""),
+            _ => unreachable!(),
+        })
+        .collect();
+    (ids, page.next)
+}
+
+#[test]
+fn sorted_pages_follow_cursors() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "paged", String::new()).unwrap();
+    for (id, group, rank) in [
+        ("a", 2, 1),
+        ("b", 1, 5),
+        ("c", 2, 3),
+        ("d", 1, 5),
+        ("e", 3, 0),
+    ] {
+        db.put(
+            "items",
+            id,
+            &json(&format!(r#"{{"group":{},"rank":{}}}"#, group, rank)),
+        )
+        .unwrap();
+    }
+    db.put("items", "f", &json("{}")).unwrap();
+
+    let sorted = r#"{"sort":["group","-rank"],"limit":2}"#;
+    let (ids, next) = page(&db, sorted, None);
+    assert_eq!(ids, ["f", "b"]);
+    db.delete("items", "b").unwrap();
+    db.put("items", "a0", &json(r#"{"group":0}"#)).unwrap();
+    let (ids, next) = page(&db, sorted, next.as_deref());
+    assert_eq!(ids, ["d", "c"]);
+    let (ids, next) = page(&db, sorted, next.as_deref());
+    assert_eq!(ids, ["a", "e"]);
+    assert!(next.is_none());
+
+    let (ids, next) = page(&db, r#"{"limit":2,"skip":1}"#, None);
+    assert_eq!(ids, ["a0", "c"]);
+    let (ids, next) = page(&db, r#"{"limit":2}"#, next.as_deref());
+    assert_eq!(ids, ["d", "e"]);
+    let (ids, next) = page(&db, r#"{"limit":2}"#, next.as_deref());
+    assert_eq!(ids, ["f"]);
+    assert!(next.is_none());
+
+    let (_, next) = page(&db, sorted, None);
+    let mut other = json(r#"{"sort":["rank"]}"#);
+    if let Json::Object(obj) = &mut other {
+        obj["cursor".to_string()] = Json::String(next.unwrap());
+    }
+    assert!(Query::parse(Some(&other)).is_err());
+    assert!(Query::parse(Some(&json(r#"{"cursor":"!!"}"#))).is_err());
+    assert!(Query::parse(Some(&json(r#"{"limit":0}"#))).is_err());
+}
+
+#[test]
+fn projections_keep_or_drop_dotted_paths() {
+    let document = json(
+        r#"{"_id":"x","name":"Ada","bio":"long","address":{"city":"London","zip":"N1"},"jobs":[{"title":"a","year":1},{"year":2},3]}"#,
+    );
+    let project = |fields: &str| {
+        let query = Query::parse(Some(&json(&format!(r#"{{"fields":{}}}"#, fields)))).unwrap();
+        query.projection.unwrap().apply(&document).canonical()
+    };
+    assert_eq!(
+        project(r#"["name","address.city","jobs.title","jobs.year"]"#),
+        r#"{"_id":"x","address":{"city":"London"},"jobs":[{"title":"a","year":1},{"year":2}],"name":"Ada"}"#
+    );
+    assert_eq!(project(r#"["name","-_id"]"#), r#"{"name":"Ada"}"#);
+    assert_eq!(
+        project(r#"["-bio","-address.zip","-jobs.year"]"#),
+        r#"{"_id":"x","address":{"city":"London"},"jobs":[{"title":"a"},{},3],"name":"Ada"}"#
+    );
+    assert!(Query::parse(Some(&json(r#"{"fields":["name","-bio"]}"#))).is_err());
+    assert!(Query::parse(Some(&json(r#"{"fields":["a..b"]}"#))).is_err());
+}
+
+#[test]
+fn pipelines_group_and_sort() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "sales", String::new()).unwrap();
+    for (id, region, amount) in [
+        ("1", "north", "10"),
+        ("2", "south", "5"),
+        ("3", "north", "2.5"),
+        ("4", "east", "\"n/a\""),
+        ("5", "south", "7"),
+        ("6", "west", "100"),
+    ] {
+        db.put(
+            "orders",
+            id,
+            &json(&format!(r#"{{"region":"{}","amount":{}}}"#, region, amount)),
+        )
+        .unwrap();
+    }
+    let pipeline = Pipeline::parse(Some(&json(
+        r#"{"pipeline":[
+            {"$match":{"region":{"$ne":"west"}}},
+            {"$group":{"_id":"$region","orders":{"$count":{}},"total":{"$sum":"$amount"},
+                "average":{"$avg":"$amount"},"largest":{"$max":"$amount"}}},
+            {"$sort":["-orders","_id"]},
+            {"$limit":2},
+            {"$project":["total","average","largest"]}
+        ]}"#,
+    )))
+    .unwrap();
+    let results = pipeline
+        .run(&db, "orders")
+        .unwrap()
+        .iter()
+        .map(|document| document.canonical())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        results,
+        [
+            r#"{"_id":"north","average":6.25,"largest":10,"total":12.5}"#,
+            r#"{"_id":"south","average":6,"largest":7,"total":12}"#
+        ]
+    );
+    assert!(Pipeline::parse(Some(&json(r#"{"pipeline":[{"$unwind":"$x"}]}"#))).is_err());
+    assert!(Pipeline::parse(Some(&json(r#"{"pipeline":[{"$group":{"n":1}}]}"#))).is_err());
+}
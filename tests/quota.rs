@@ -0,0 +1,98 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+use db6::{
+    db::DB,
+    http::{HttpStatus, Request},
+    quota::{MAX_QUOTA_KEYS, Quota, Quotas},
+    test_util::{TempDir, TestServer},
+};
+
+fn request(method: &str, route: &str) -> Request {
+    let head = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, route);
+    Request::from_bytes(head.as_bytes()).unwrap()
+}
+
+fn status(server: &TestServer, path: &str, authorization: &str) -> String {
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+        path, authorization
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.lines().next().unwrap_or_default().to_string()
+}
+
+const QUOTA: Quota = Quota {
+    reads: 2,
+    writes: 1,
+};
+
+#[test]
+fn token_quotas_only_count_verified_identities() {
+    let server = TestServer::start_with(|cl| cl.token_quota = Some(QUOTA)).unwrap();
+    let path = "/dbs/shop/collections/c/docs/a";
+    for authorization in [
+        "",
+        "Authorization: Basic YWxpY2U6cHc=\r\n",
+        "Authorization: Bearer made-up\r\n",
+    ] {
+        let status = status(&server, path, authorization);
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized", "{}", authorization);
+    }
+    let admin = format!("Authorization: Bearer {}\r\n", server.admin_token());
+    for _ in 0..2 {
+        assert!(!status(&server, path, &admin).contains("429"));
+    }
+    assert_eq!(
+        status(&server, path, &admin),
+        "HTTP/1.1 429 Too Many Requests"
+    );
+
+    let quotas = Quotas::new(Some(QUOTA), None);
+    let write = request("PUT", path);
+    quotas.check(&write, Some("user:alice"), "/").unwrap();
+    let err = quotas.check(&write, Some("user:alice"), "/").unwrap_err();
+    assert_eq!(err.status, HttpStatus::TooManyRequests);
+    // Another identity has a quota of its own.
+    quotas.check(&write, Some("user:bob"), "/").unwrap();
+    let err = quotas.check(&write, None, "/").unwrap_err();
+    assert_eq!(err.code, "authentication_required");
+}
+
+#[test]
+fn database_quotas_only_count_existing_databases() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    DB::create(&root, "shop", String::new()).unwrap();
+    let quotas = Quotas::new(None, Some(QUOTA));
+    let check = |route: &str| quotas.check(&request("DELETE", route), None, &root);
+    for _ in 0..3 {
+        check("/dbs/missing/collections/c/docs/a").unwrap();
+    }
+    check("/dbs/shop/collections/c/docs/a").unwrap();
+    let err = check("/dbs/shop/collections/c/docs/a").unwrap_err();
+    assert_eq!(err.status, HttpStatus::TooManyRequests);
+    let report = quotas.to_json().canonical();
+    assert!(report.contains(r#""usage":{"db:shop":"#), "{}", report);
+    assert!(!report.contains("db:missing"), "{}", report);
+}
+
+#[test]
+fn quota_counters_are_bounded() {
+    let quotas = Quotas::new(Some(QUOTA), None);
+    let request = request("GET", "/dbs/shop");
+    let check = |user: &str| quotas.check(&request, Some(user), "/");
+    for n in 0..MAX_QUOTA_KEYS {
+        check(&format!("user{}", n)).unwrap();
+    }
+    let err = check("latecomer").unwrap_err();
+    assert_eq!(err.status, HttpStatus::ServiceUnavailable);
+    assert_eq!(err.code, "quota_capacity");
+    check("user0").unwrap();
+}
@@ -0,0 +1,105 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+    time::Duration,
+};
+
+use db6::test_util::TestServer;
+use flate2::{Compression, write::GzEncoder};
+
+fn send_in_parts(server: &TestServer, parts: &[&[u8]]) -> String {
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_nodelay(true).unwrap();
+    for part in parts {
+        stream.write_all(part).unwrap();
+        stream.flush().unwrap();
+        thread::sleep(Duration::from_millis(50));
+    }
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn put_maintenance_head(server: &TestServer, content_length: usize, extra: &str) -> String {
+    format!(
+        "PUT /_maintenance HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        server.admin_token(),
+        content_length,
+        extra
+    )
+}
+
+#[test]
+fn json_body_split_across_reads() {
+    let server = TestServer::start().unwrap();
+    let body = r#"{"message":"Back in five minutes"}"#;
+    let head = put_maintenance_head(&server, body.len(), "");
+    let response = send_in_parts(
+        &server,
+        &[
+            head.as_bytes(),
+            &body.as_bytes()[..7],
+            &body.as_bytes()[7..20],
+            &body.as_bytes()[20..],
+        ],
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.contains("Back in five minutes"), "{}", response);
+}
+
+#[test]
+fn header_and_body_in_one_read() {
+    let server = TestServer::start().unwrap();
+    let body = r#"{"message":"Upgrading"}"#;
+    let request = put_maintenance_head(&server, body.len(), "") + body;
+    let response = send_in_parts(&server, &[request.as_bytes()]);
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.contains("Upgrading"), "{}", response);
+}
+
+#[test]
+fn body_is_limited_to_content_length() {
+    let server = TestServer::start().unwrap();
+    let body = r#"{"message":"Short"}"#;
+    let request = put_maintenance_head(&server, body.len(), "") + body + "trailing garbage";
+    let response = send_in_parts(&server, &[request.as_bytes()]);
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.contains("Short"), "{}", response);
+}
+
+#[test]
+fn compressed_body_split_across_reads() {
+    let server = TestServer::start().unwrap();
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(r#"{"message":"Compressed notice"}"#.as_bytes())
+        .unwrap();
+    let body = encoder.finish().unwrap();
+    let head = put_maintenance_head(&server, body.len(), "Content-Encoding: gzip\r\n");
+    let (first, second) = body.split_at(body.len() / 2);
+    let response = send_in_parts(&server, &[head.as_bytes(), first, second]);
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.contains("Compressed notice"), "{}", response);
+}
+
+#[test]
+fn invalid_json_split_across_reads() {
+    let server = TestServer::start().unwrap();
+    let body = r#"{"message": "unterminated}"#;
+    let head = put_maintenance_head(&server, body.len(), "");
+    let response = send_in_parts(
+        &server,
+        &[
+            head.as_bytes(),
+            &body.as_bytes()[..5],
+            &body.as_bytes()[5..],
+        ],
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request"),
+        "{}",
+        response
+    );
+    assert!(response.contains("invalid_json"), "{}", response);
+}
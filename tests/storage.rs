@@ -0,0 +1,1496 @@
+use std::{
+    fs,
+    ops::Bound,
+    path::Path,
+    process::Command,
+    sync::{Arc, Mutex},
+    time::Duration as Timeout,
+};
+
+use chrono::{Duration, Utc};
+use sha2::{Digest, Sha256};
+
+use db6::{
+    archive, cache,
+    changes::{self, Tail},
+    compaction,
+    copy::{self, Remote},
+    db::{Commit, DATA_DIR, DB, Mutation, SoftDelete, WriteError, WriteMode},
+    engine::{self, MAX_TABLES},
+    export::{self, Export},
+    hooks::{self, Hook, Operation, WriteEvent},
+    import::{self, ImportOptions},
+    infer,
+    json::{Json, JsonNumber},
+    patch::{FindAndModify, Image, Patch},
+    query::Query,
+    root::Root,
+    schema::{Schema, Validation},
+    sstable::Codec,
+    test_util::{TempDir, TestServer},
+    ttl, warmup,
+};
+
+fn json(text: &str) -> Json {
+    Json::parse(text.as_bytes()).unwrap()
+}
+
+fn field(document: &Json, name: &str) -> Option<String> {
+    match document {
+        Json::Object(obj) => obj.get(name).map(|value| value.canonical()),
+        _ => None,
+    }
+}
+
+fn collection_files(db_dir: &Path, collection: &str) -> Vec<String> {
+    let mut files = fs::read_dir(db_dir.join(DATA_DIR).join(collection))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+fn scan_ids(db: &DB, start: Bound<&str>, end: Bound<&str>) -> Vec<String> {
+    let range = (start.map(str::to_string), end.map(str::to_string));
+    db.scan("keys", range)
+        .unwrap()
+        .map(|document| field(&document.unwrap(), "_id").unwrap())
+        .map(|id| id.trim_matches('"').to_string())
+        .collect()
+}
+
+#[test]
+fn scans_merge_the_memtable_with_every_table() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "ordered", String::new()).unwrap();
+    for n in 0..40 {
+        db.put("keys", &format!("k{:02}", n), &json(r#"{"v":1}"#))
+            .unwrap();
+    }
+    db.flush().unwrap();
+    for n in (0..40).step_by(3) {
+        assert!(db.delete("keys", &format!("k{:02}", n)).unwrap());
+    }
+    db.put("keys", "k07", &json(r#"{"v":2}"#)).unwrap();
+    db.flush().unwrap();
+    db.put("keys", "k03", &json(r#"{"v":3}"#)).unwrap();
+    db.put("keys", "k40", &json(r#"{"v":3}"#)).unwrap();
+
+    let expected = ["k03", "k04", "k05", "k07", "k08", "k10"];
+    assert_eq!(
+        scan_ids(&db, Bound::Excluded("k02"), Bound::Included("k10")),
+        expected
+    );
+    assert_eq!(scan_ids(&db, Bound::Unbounded, Bound::Unbounded).len(), 28);
+    let k07 = db.get("keys", "k07").unwrap().unwrap();
+    assert_eq!(field(&k07, "v").as_deref(), Some("2"));
+    assert!(db.get("keys", "k06").unwrap().is_none());
+
+    engine::close(Path::new(db.path()));
+    assert_eq!(
+        scan_ids(&db, Bound::Excluded("k02"), Bound::Included("k10")),
+        expected
+    );
+
+    for round in 0..MAX_TABLES - 1 {
+        db.put("keys", &format!("r{}", round), &json("{}")).unwrap();
+        db.flush().unwrap();
+    }
+    compaction::wait_for_merges();
+    let tables = collection_files(&dir.path().join("ordered"), "keys")
+        .into_iter()
+        .filter(|file| file.ends_with(".sst"))
+        .count();
+    assert_eq!(tables, 1);
+    assert_eq!(scan_ids(&db, Bound::Unbounded, Bound::Unbounded).len(), 35);
+    assert!(db.get("keys", "k06").unwrap().is_none());
+}
+
+fn mutation(collection: &str, id: &str, document: Option<&str>) -> Mutation {
+    Mutation {
+        collection: collection.to_string(),
+        id: id.to_string(),
+        document: document.map(json),
+        mode: WriteMode::Upsert,
+    }
+}
+
+#[test]
+fn transactions_apply_all_or_nothing() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "bank", String::new()).unwrap();
+    db.put("accounts", "a", &json(r#"{"balance":10}"#)).unwrap();
+
+    let aborted = db
+        .commit(&[
+            mutation("accounts", "a", Some(r#"{"balance":0}"#)),
+            mutation("accounts", "missing", None),
+        ])
+        .unwrap();
+    assert!(matches!(aborted, Commit::Aborted(_)));
+    let a = db.get("accounts", "a").unwrap().unwrap();
+    assert_eq!(field(&a, "balance").as_deref(), Some("10"));
+
+    let committed = db
+        .commit(&[
+            mutation("accounts", "a", Some(r#"{"balance":4}"#)),
+            mutation("accounts", "b", Some(r#"{"balance":6}"#)),
+            mutation("ledger", "t1", Some(r#"{"from":"a","to":"b"}"#)),
+            mutation("accounts", "b", None),
+        ])
+        .unwrap();
+    let Commit::Committed(results) = committed else {
+        panic!("The transaction was aborted");
+    };
+    assert_eq!(results, [false, true, true, true]);
+
+    engine::close(Path::new(db.path()));
+    assert_eq!(db.recover().unwrap().replayed, 2);
+    let a = db.get("accounts", "a").unwrap().unwrap();
+    assert_eq!(field(&a, "balance").as_deref(), Some("4"));
+    assert!(db.get("accounts", "b").unwrap().is_none());
+    assert!(db.get("ledger", "t1").unwrap().is_some());
+}
+
+#[test]
+fn expired_documents_are_swept() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "cache", String::new()).unwrap();
+    db.create_collection("sessions").unwrap();
+    assert!(db.set_ttl("sessions", Some(3600)).unwrap());
+    assert!(!db.set_ttl("missing", Some(3600)).unwrap());
+    assert_eq!(db.collection("sessions").unwrap().unwrap().ttl, Some(3600));
+
+    db.put("sessions", "live", &json("{}")).unwrap();
+    let expires = format!(r#"{{"_expires":"{}"}}"#, Utc::now().to_rfc3339());
+    db.put("sessions", "stale", &json(&expires)).unwrap();
+    db.put("pages", "kept", &json("{}")).unwrap();
+    let live = db.get("sessions", "live").unwrap().unwrap();
+    assert!(field(&live, "_expires").is_some());
+
+    // Expired documents are gone for reads and writes before the sweeper reclaims them.
+    assert!(db.get("sessions", "stale").unwrap().is_none());
+    assert_eq!(db.documents("sessions").unwrap().len(), 1);
+    let query = Query::parse(Some(&json("{}"))).unwrap();
+    assert_eq!(query.run(&db, "sessions").unwrap().documents.len(), 1);
+    assert!(matches!(
+        db.put("sessions", "bad", &json(r#"{"_expires":"tomorrow"}"#)),
+        Err(WriteError::Rejected(_))
+    ));
+
+    let events = changes::subscribe("cache");
+    assert_eq!(ttl::sweep(&root, "cache").unwrap(), 1);
+    assert!(db.get("sessions", "stale").unwrap().is_none());
+    assert_eq!(
+        json(&events.try_recv().unwrap()).canonical(),
+        r#"{"_id":"stale","collection":"sessions","type":"expire"}"#
+    );
+
+    let later = Utc::now() + Duration::hours(2);
+    assert_eq!(db.expire("sessions", later).unwrap(), ["live"]);
+    assert!(db.expire("pages", later).unwrap().is_empty());
+    assert!(db.get("pages", "kept").unwrap().is_some());
+}
+
+fn feed(db: &DB, collection: &str, since: u64, limit: usize) -> Vec<String> {
+    db.changes(collection, since, limit)
+        .unwrap()
+        .iter()
+        .map(|change| change.canonical())
+        .collect()
+}
+
+#[test]
+fn change_feeds_record_every_write_in_order() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "feeds", String::new()).unwrap();
+    db.put("users", "ada", &json(r#"{"name":"Ada","age":36}"#))
+        .unwrap();
+    db.put("users", "ada", &json(r#"{"name":"Ada","city":"London"}"#))
+        .unwrap();
+    db.put("other", "x", &json("{}")).unwrap();
+    db.commit(&[
+        mutation("users", "grace", Some("{}")),
+        mutation("users", "ada", None),
+    ])
+    .unwrap();
+    assert!(!db.delete("users", "ada").unwrap());
+
+    let changes = [
+        r#"{"_id":"ada","delta":{"_id":"ada","age":36,"name":"Ada"},"seq":1,"type":"insert"}"#,
+        r#"{"_id":"ada","delta":{"age":null,"city":"London"},"seq":2,"type":"update"}"#,
+        r#"{"_id":"grace","delta":{"_id":"grace"},"seq":4,"type":"insert"}"#,
+        r#"{"_id":"ada","seq":4,"type":"delete"}"#,
+    ];
+    assert_eq!(feed(&db, "users", 0, 100), changes);
+    assert_eq!(feed(&db, "users", 2, 100), changes[2..]);
+    assert_eq!(feed(&db, "users", 1, 2), changes[1..]);
+    assert_eq!(feed(&db, "other", 0, 100).len(), 1);
+
+    engine::close(Path::new(db.path()));
+    db.recover().unwrap();
+    assert_eq!(feed(&db, "users", 0, 100), changes);
+    db.flush().unwrap();
+    assert_eq!(feed(&db, "users", 3, 100), changes[2..]);
+
+    let mut tail = Tail::new(db, "users", 2, false);
+    let read = tail.wait(10, Timeout::ZERO).unwrap();
+    assert_eq!(read.len(), 2);
+    assert_eq!(tail.since(), 4);
+    assert!(tail.wait(10, Timeout::from_millis(20)).unwrap().is_empty());
+}
+
+struct Audit {
+    log: Arc<Mutex<Vec<String>>>,
+}
+
+impl Hook for Audit {
+    fn before(&self, write: &mut WriteEvent) -> Result<(), String> {
+        let Some(Json::Object(document)) = &mut write.document else {
+            return match &write.previous {
+                Some(Json::Object(previous)) if previous.get("locked").is_some() => {
+                    Err("the document is locked".to_string())
+                }
+                _ => Ok(()),
+            };
+        };
+        if document.get("name").is_none() {
+            return Err("a name is required".to_string());
+        }
+        document["audited".to_string()] = Json::Bool(true);
+        Ok(())
+    }
+
+    fn after(&self, write: &WriteEvent) {
+        let operation = match write.operation {
+            Operation::Insert => "insert",
+            Operation::Update => "update",
+            Operation::Delete => "delete",
+        };
+        self.log
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", operation, write.id));
+    }
+}
+
+#[test]
+fn hooks_validate_and_change_writes() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "hooked", String::new()).unwrap();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    hooks::register("hooked", "users", Audit { log: log.clone() });
+
+    assert!(matches!(
+        db.put("users", "ada", &json("{}")),
+        Err(WriteError::Rejected(_))
+    ));
+    assert!(db.get("users", "ada").unwrap().is_none());
+    db.put("users", "ada", &json(r#"{"name":"Ada"}"#)).unwrap();
+    db.put("users", "ada", &json(r#"{"name":"Ada","locked":1}"#))
+        .unwrap();
+    let ada = db.get("users", "ada").unwrap().unwrap();
+    assert_eq!(field(&ada, "audited").as_deref(), Some("true"));
+    assert!(matches!(
+        db.delete("users", "ada"),
+        Err(WriteError::Rejected(_))
+    ));
+    let id = db.insert("users", &json(r#"{"name":"Grace"}"#)).unwrap();
+    assert!(db.delete("users", &id).unwrap());
+    db.put("other", "x", &json("{}")).unwrap();
+
+    let aborted = db
+        .commit(&[
+            mutation("users", "alan", Some(r#"{"name":"Alan"}"#)),
+            mutation("users", "bob", Some("{}")),
+        ])
+        .unwrap();
+    assert!(matches!(aborted, Commit::Aborted(_)));
+    assert!(db.get("users", "alan").unwrap().is_none());
+
+    hooks::clear("hooked", "users");
+    assert!(db.delete("users", "ada").unwrap());
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            "insert ada".to_string(),
+            "update ada".to_string(),
+            format!("insert {}", id),
+            format!("delete {}", id),
+        ]
+    );
+}
+
+fn extract(archive: &Path, root: &Path, name: &str) -> DB {
+    let file = fs::File::open(archive).unwrap();
+    tar::Archive::new(flate2::read::GzDecoder::new(file))
+        .unpack(root.join(name))
+        .unwrap();
+    DB::open(&root.to_string_lossy(), name).unwrap().unwrap()
+}
+
+#[test]
+fn backups_hold_every_write_up_to_the_snapshot() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "live", String::new()).unwrap();
+    db.put("keys", "ada", &json(r#"{"name":"Ada"}"#)).unwrap();
+    db.put("keys", "alan", &json(r#"{"name":"Alan"}"#)).unwrap();
+    db.flush().unwrap();
+    db.put("keys", "grace", &json(r#"{"name":"Grace"}"#))
+        .unwrap();
+    db.delete("keys", "alan").unwrap();
+    let loaded = dir.path().join("loaded.tar.gz");
+    let stats = archive::write(&db, &loaded).unwrap();
+    assert_eq!(stats.path, loaded.to_string_lossy());
+    // Without the engine loaded in this process, the backup is read from the files.
+    engine::close(Path::new(db.path()));
+    let unloaded = dir.path().join("unloaded.tar.gz");
+    archive::write(&db, &unloaded).unwrap();
+    db.put("keys", "later", &json(r#"{"name":"Later"}"#))
+        .unwrap();
+
+    for archive in [loaded, unloaded] {
+        let copy = archive.with_extension("");
+        let restored = extract(&archive, &copy, "live");
+        let ids = scan_ids(&restored, Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(ids, ["ada", "grace"]);
+        let changes = feed(&restored, "keys", 0, 10);
+        assert_eq!(changes.len(), 4);
+        assert_eq!(changes[3], r#"{"_id":"alan","seq":4,"type":"delete"}"#);
+        restored.insert("keys", &json("{}")).unwrap();
+        assert_eq!(feed(&restored, "keys", 4, 10).len(), 1);
+    }
+}
+
+#[test]
+fn restores_check_the_archive_before_adding_the_database() {
+    let dir = TempDir::new().unwrap();
+    let root = Root::new(&dir.path().to_string_lossy());
+    let db = root.create("origin", String::new()).unwrap();
+    db.put("keys", "ada", &json(r#"{"name":"Ada"}"#)).unwrap();
+    let backup = dir.path().join("origin.tar.gz");
+    archive::write(&db, &backup).unwrap();
+
+    let err = archive::restore(&root, &backup, &[], None).err().unwrap();
+    assert!(err.contains("already exists"), "{}", err);
+    let copy = archive::restore(&root, &backup, &[], Some("copy")).unwrap();
+    assert_eq!(copy.manifest().name, "copy");
+    let ada = copy.get("keys", "ada").unwrap().unwrap();
+    assert_eq!(field(&ada, "name").as_deref(), Some(r#""Ada""#));
+
+    let unpacked = dir.path().join("unpacked");
+    extract(&backup, &unpacked, "origin");
+    let table = fs::read_dir(unpacked.join("origin").join(DATA_DIR).join("keys"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "sst"))
+        .unwrap();
+    let mut content = fs::read(&table).unwrap();
+    content[20] ^= 1;
+    fs::write(&table, content).unwrap();
+    let damaged = dir.path().join("damaged.tar.gz");
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        fs::File::create(&damaged).unwrap(),
+        flate2::Compression::default(),
+    ));
+    builder
+        .append_dir_all(".", unpacked.join("origin"))
+        .unwrap();
+    builder.into_inner().unwrap().finish().unwrap();
+    let err = archive::restore(&root, &damaged, &[], Some("damaged"))
+        .err()
+        .unwrap();
+    assert!(err.contains("checksum"), "{}", err);
+    assert!(!dir.path().join("damaged").exists());
+    assert!(!dir.path().join(archive::RESTORE_DIR).exists());
+}
+
+#[test]
+fn backup_verification_restores_to_a_temporary_root() {
+    let dir = TempDir::new().unwrap();
+    let root = Root::new(&dir.path().to_string_lossy());
+    let db = root.create("checked", String::new()).unwrap();
+    db.put("keys", "ada", &json(r#"{"name":"Ada"}"#)).unwrap();
+    db.put("keys", "alan", &json(r#"{"name":"Alan"}"#)).unwrap();
+    db.flush().unwrap();
+    db.delete("keys", "alan").unwrap();
+    let backup = dir.path().join("checked.tar.gz");
+    archive::write(&db, &backup).unwrap();
+
+    let query = Query::parse(Some(&json(r#"{"filter":{"name":{"$prefix":"A"}}}"#))).unwrap();
+    let verified = archive::verify_backup(&backup, &[], &[("keys".to_string(), query)]).unwrap();
+    assert!(verified.passed());
+    assert_eq!(verified.name, "checked");
+    assert_eq!(verified.collections.len(), 1);
+    assert_eq!(verified.collections[0].documents, 1);
+    assert!(verified.collections[0].problems.is_empty());
+    assert_eq!(verified.queries, [Ok(1)]);
+    assert_eq!(root.databases().unwrap(), ["checked"]);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_db6"))
+        .arg("verify-backup")
+        .arg(&backup)
+        .arg(r#"--query=keys:{"filter":{"name":"Ada"}}"#)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(
+        stdout.contains("Collection keys: 1 documents, 0 problems"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("returned 1 documents"), "{}", stdout);
+    assert!(stdout.ends_with("PASS\n"), "{}", stdout);
+
+    let damaged = dir.path().join("damaged.tar.gz");
+    let content = fs::read(&backup).unwrap();
+    fs::write(&damaged, &content[..content.len() / 2]).unwrap();
+    assert!(archive::verify_backup(&damaged, &[], &[]).is_err());
+    let output = Command::new(env!("CARGO_BIN_EXE_db6"))
+        .arg("verify-backup")
+        .arg(&damaged)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with("FAIL\n"));
+}
+
+#[test]
+fn incremental_backups_restore_on_top_of_a_full_backup() {
+    let dir = TempDir::new().unwrap();
+    let root = Root::new(&dir.path().to_string_lossy());
+    let db = root.create("chain", String::new()).unwrap();
+    let first = dir.path().join("first.tar.gz");
+    assert!(archive::write_increment(&db, &first).is_err());
+    db.put("keys", "ada", &json(r#"{"name":"Ada"}"#)).unwrap();
+    let full = dir.path().join("full.tar.gz");
+    archive::write(&db, &full).unwrap();
+    db.put("keys", "alan", &json(r#"{"name":"Alan"}"#)).unwrap();
+    db.flush().unwrap();
+    db.delete("keys", "ada").unwrap();
+    archive::write_increment(&db, &first).unwrap();
+    let second = dir.path().join("second.tar.gz");
+    db.put("keys", "grace", &json(r#"{"name":"Grace"}"#))
+        .unwrap();
+    archive::write_increment(&db, &second).unwrap();
+    let empty = dir.path().join("empty.tar.gz");
+    archive::write_increment(&db, &empty).unwrap();
+
+    let err = archive::restore(&root, &full, std::slice::from_ref(&second), Some("gap"))
+        .err()
+        .unwrap();
+    assert!(err.contains("starts after the log record 3"), "{}", err);
+    assert!(!dir.path().join("gap").exists());
+    let restored = archive::restore(&root, &full, &[first, second, empty], Some("copy")).unwrap();
+    let ids = scan_ids(&restored, Bound::Unbounded, Bound::Unbounded);
+    assert_eq!(ids, ["alan", "grace"]);
+    assert_eq!(feed(&restored, "keys", 0, 10).len(), 4);
+    restored.put("keys", "later", &json("{}")).unwrap();
+    assert_eq!(feed(&restored, "keys", 4, 10).len(), 1);
+}
+
+#[test]
+fn compaction_drops_overwritten_and_deleted_documents() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "vacuum", String::new()).unwrap();
+    for round in 0..3 {
+        for id in ["ada", "alan", "grace"] {
+            let document = format!(r#"{{"round":{},"padding":"{}"}}"#, round, "x".repeat(512));
+            db.put("keys", id, &json(&document)).unwrap();
+        }
+        db.flush().unwrap();
+    }
+    db.delete("keys", "alan").unwrap();
+    db.flush().unwrap();
+    let tables = || {
+        collection_files(&dir.path().join("vacuum"), "keys")
+            .into_iter()
+            .filter(|file| file.ends_with(".sst"))
+            .count()
+    };
+    assert_eq!(tables(), 4);
+    assert_eq!(db.compact(5).unwrap().files, 0);
+
+    let compacted = db.compact(2).unwrap();
+    assert_eq!(compacted.files, 3 + 3 + 3);
+    assert!(compacted.bytes > 3 * 512, "{}", compacted.bytes);
+    assert_eq!(tables(), 1);
+    assert_eq!(
+        scan_ids(&db, Bound::Unbounded, Bound::Unbounded),
+        ["ada", "grace"]
+    );
+    let grace = db.get("keys", "grace").unwrap().unwrap();
+    assert_eq!(field(&grace, "round").as_deref(), Some("2"));
+    assert_eq!(feed(&db, "keys", 0, 100).len(), 10);
+}
+
+#[test]
+fn password_changes_replace_the_key_and_keep_the_data() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "secret", "old".to_string()).unwrap();
+    db.put("keys", "ada", &json(r#"{"name":"Ada"}"#)).unwrap();
+    let before = db.manifest().encryption.clone().unwrap();
+
+    assert!(!db.change_password("wrong", "new").unwrap());
+    assert!(db.change_password("old", "").is_err());
+    assert!(db.change_password("old", "new").unwrap());
+    let db = DB::open(&root, "secret").unwrap().unwrap();
+    let after = db.manifest().encryption.clone().unwrap();
+    assert!(after.verify("new"));
+    assert!(!after.verify("old"));
+    assert_ne!(after.salt, before.salt);
+    // The data key stays the same, wrapped under the new password instead of the old one.
+    let data_key = before.data_key("old").unwrap();
+    assert_eq!(after.data_key("new"), Some(data_key));
+    assert!(after.data_key("old").is_none());
+    assert_ne!(after.wrapped_key, before.wrapped_key);
+    assert!(db.get("keys", "ada").unwrap().is_some());
+    assert!(!db.change_password("old", "newer").unwrap());
+
+    let open = DB::create(&root, "open", String::new()).unwrap();
+    assert!(open.change_password("", "new").is_err());
+}
+
+#[test]
+fn schemas_reject_or_flag_documents_that_do_not_match() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "typed", String::new()).unwrap();
+    db.create_collection("people").unwrap();
+    let schema = Schema::new(&json(
+        r#"{"type":"object","required":["name"],"additionalProperties":false,
+            "properties":{"name":{"type":"string","minLength":1},
+            "age":{"type":"integer","minimum":0},"tags":{"type":"array","items":{"enum":["a","b"]}}}}"#,
+    ))
+    .unwrap();
+    assert!(Schema::new(&json(r#"{"type":"text"}"#)).is_err());
+    assert!(Schema::new(&json(r#"{"format":"email"}"#)).is_err());
+    assert!(
+        db.set_schema("people", Some((&schema, Validation::Strict)))
+            .unwrap()
+    );
+    assert!(
+        !db.set_schema("missing", Some((&schema, Validation::Strict)))
+            .unwrap()
+    );
+
+    db.put(
+        "people",
+        "ada",
+        &json(r#"{"name":"Ada","age":36,"tags":["a"]}"#),
+    )
+    .unwrap();
+    let err = db
+        .put(
+            "people",
+            "bob",
+            &json(r#"{"age":-1,"tags":["a","c"],"nick":"b"}"#),
+        )
+        .unwrap_err();
+    let WriteError::Invalid(_, violations) = err else {
+        panic!("expected a schema violation, got {:?}", err);
+    };
+    let mut paths = violations
+        .iter()
+        .map(|violation| violation.path.as_str())
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(paths, ["age", "name", "nick", "tags.1"]);
+    assert!(db.get("people", "bob").unwrap().is_none());
+    let mutations = [mutation("people", "eve", Some(r#"{"name":""}"#))];
+    assert!(matches!(db.commit(&mutations).unwrap(), Commit::Aborted(_)));
+
+    assert!(
+        db.set_schema("people", Some((&schema, Validation::Warn)))
+            .unwrap()
+    );
+    let meta = db.collection("people").unwrap().unwrap();
+    assert_eq!(meta.validation, Validation::Warn);
+    db.put("people", "bob", &json(r#"{"age":-1}"#)).unwrap();
+    assert!(db.get("people", "bob").unwrap().is_some());
+
+    // Writes cache the compiled schema, and every change of it replaces the cached one.
+    let carl = json(r#"{"nick":"carl"}"#);
+    assert!(
+        db.set_schema("people", Some((&schema, Validation::Strict)))
+            .unwrap()
+    );
+    assert!(db.put("people", "carl", &carl).is_err());
+    assert!(db.set_schema("people", None).unwrap());
+    assert!(db.collection("people").unwrap().unwrap().schema.is_none());
+    db.put("people", "carl", &carl).unwrap();
+
+    assert!(
+        db.set_schema("people", Some((&schema, Validation::Strict)))
+            .unwrap()
+    );
+    assert!(db.put("people", "dan", &carl).is_err());
+    assert!(db.drop_collection("people").unwrap());
+    db.create_collection("people").unwrap();
+    db.put("people", "dan", &carl).unwrap();
+}
+
+#[test]
+fn imports_load_batches_and_report_bad_lines() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "bulk", String::new()).unwrap();
+    db.put("items", "a", &json(r#"{"n":0}"#)).unwrap();
+    let input = [
+        r#"{"_id":"a","n":1}"#,
+        r#"{"n":2}"#,
+        "",
+        r#"{"n":3"#,
+        r#"[1,2]"#,
+        r#"{"_id":"b","note":"a line longer than the limit"}"#,
+        r#"{"_id":"c","n":4}"#,
+    ]
+    .join("\n");
+    let options = ImportOptions {
+        batch_size: 2,
+        max_line: 32,
+    };
+    let mut batches = Vec::new();
+    let report = import::import(
+        &db,
+        "items",
+        &mut input.as_bytes(),
+        &options,
+        |_, written| batches.push(written.len()),
+    )
+    .unwrap();
+    assert_eq!(report.lines, 7);
+    assert_eq!((report.inserted, report.updated, report.failed), (2, 1, 3));
+    assert_eq!(batches, [2, 0, 1]);
+    let lines = report
+        .errors
+        .iter()
+        .map(|error| error.line)
+        .collect::<Vec<_>>();
+    assert_eq!(lines, [4, 5, 6]);
+    let a = db.get("items", "a").unwrap().unwrap();
+    assert_eq!(field(&a, "n").as_deref(), Some("1"));
+    assert!(db.get("items", "b").unwrap().is_none());
+    assert!(db.get("items", "c").unwrap().is_some());
+    assert_eq!(db.collection_stats("items").unwrap().documents, 3);
+}
+
+#[test]
+fn imports_refuse_databases_open_in_another_process() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "served", String::new()).unwrap();
+    db.put("items", "a", &json(r#"{"n":1}"#)).unwrap();
+    let file = dir.path().join("items.ndjson");
+    fs::write(&file, "{\"_id\":\"b\",\"n\":2}\n").unwrap();
+
+    let import = |root: &str| {
+        Command::new(env!("CARGO_BIN_EXE_db6"))
+            .args(["import", "served", "items"])
+            .arg(&file)
+            .args(["--root", root])
+            .output()
+            .unwrap()
+    };
+    let output = import(&root);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("is in use by another process"),
+        "{}",
+        stderr
+    );
+    assert!(db.get("items", "b").unwrap().is_none());
+
+    assert!(db.close());
+    let output = import(&root);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(db.reopen().unwrap());
+    assert!(db.get("items", "b").unwrap().is_some());
+}
+
+#[test]
+fn samples_pick_distinct_live_documents() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "sampled", String::new()).unwrap();
+    for n in 0..100 {
+        db.put(
+            "items",
+            &format!("{:03}", n),
+            &json(&format!(r#"{{"n":{}}}"#, n)),
+        )
+        .unwrap();
+    }
+    db.delete("items", "007").unwrap();
+
+    let (documents, seen) = db.sample("items", 10).unwrap();
+    assert_eq!(seen, 99);
+    let mut ids = documents
+        .iter()
+        .map(|document| field(document, "_id").unwrap())
+        .collect::<Vec<_>>();
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), 10);
+    assert!(!ids.contains(&r#""007""#.to_string()));
+
+    // Every document has the same chance to be picked, the last ones included.
+    let mut picked = [0; 100];
+    for _ in 0..200 {
+        for document in db.sample("items", 10).unwrap().0 {
+            let n = field(&document, "n").unwrap().parse::<usize>().unwrap();
+            picked[n] += 1;
+        }
+    }
+    assert_eq!(picked[7], 0);
+    assert!(picked[..50].iter().sum::<usize>() > 700);
+    assert!(picked[50..].iter().sum::<usize>() > 700);
+
+    assert_eq!(db.sample("items", 500).unwrap().0.len(), 99);
+    assert!(db.sample("missing", 5).unwrap().0.is_empty());
+}
+
+#[test]
+fn inferred_schemas_describe_and_match_the_documents() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "inferred", String::new()).unwrap();
+    db.put(
+        "people",
+        "a",
+        &json(r#"{"name":"Ada","age":36,"tags":["math"],"address":{"city":"London"}}"#),
+    )
+    .unwrap();
+    db.put(
+        "people",
+        "b",
+        &json(r#"{"name":"Bob","age":41.5,"tags":[],"address":null}"#),
+    )
+    .unwrap();
+    db.put("people", "c", &json(r#"{"name":"Cy","tags":["art",1]}"#))
+        .unwrap();
+
+    let inferred = infer::infer(&db, "people", None).unwrap().to_json();
+    assert_eq!(field(&inferred, "documents").unwrap(), "3");
+    let Json::Object(obj) = &inferred else {
+        panic!("expected an object, got {}", inferred);
+    };
+    let Some(Json::List(fields)) = obj.get("fields") else {
+        panic!("expected fields, got {}", inferred);
+    };
+    let find = |path: &str| {
+        let path = Json::String(path.to_string()).canonical();
+        fields
+            .iter()
+            .find(|document| field(document, "path") == Some(path.clone()))
+            .unwrap_or_else(|| panic!("no field {} in {}", path, inferred))
+    };
+    assert_eq!(
+        field(find("age"), "types").unwrap(),
+        r#"{"integer":1,"number":1}"#
+    );
+    assert_eq!(field(find("age"), "present").unwrap(), "2");
+    assert_eq!(
+        field(find("name"), "examples").unwrap(),
+        r#"["Ada","Bob","Cy"]"#
+    );
+    // Missing fields count as nulls, over the objects that could have had them.
+    let null_rate = |path| {
+        field(find(path), "null_rate")
+            .unwrap()
+            .parse::<f64>()
+            .unwrap()
+    };
+    assert_eq!(null_rate("name"), 0.0);
+    assert!((null_rate("address") - 2.0 / 3.0).abs() < 1e-9);
+    assert_eq!(null_rate("address.city"), 0.0);
+    assert_eq!(
+        field(find("tags[]"), "types").unwrap(),
+        r#"{"integer":1,"string":2}"#
+    );
+    assert!(
+        fields
+            .iter()
+            .all(|document| field(document, "path").unwrap() != r#""_id""#)
+    );
+
+    // The suggested schema can be set as it is and the documents pass it.
+    let schema = Schema::new(obj.get("schema").unwrap()).unwrap();
+    assert!(
+        db.set_schema("people", Some((&schema, Validation::Strict)))
+            .unwrap()
+    );
+    db.put(
+        "people",
+        "d",
+        &json(r#"{"name":"Di","tags":[],"address":{"city":"Oslo"}}"#),
+    )
+    .unwrap();
+    assert!(db.put("people", "e", &json(r#"{"tags":[]}"#)).is_err());
+    assert_eq!(
+        field(
+            &infer::infer(&db, "people", Some(2)).unwrap().to_json(),
+            "documents"
+        )
+        .unwrap(),
+        "2"
+    );
+}
+
+#[test]
+fn exports_write_ndjson_and_csv_columns() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "out", String::new()).unwrap();
+    db.put(
+        "people",
+        "a",
+        &json(r#"{"name":"Ada, Countess","born":1815,"tags":["math"]}"#),
+    )
+    .unwrap();
+    db.put(
+        "people",
+        "b",
+        &json(r#"{"name":"Bob \"B\"","address":{"city":"Oslo"}}"#),
+    )
+    .unwrap();
+    db.put("people", "c", &json(r#"{"name":"Cy","born":1990}"#))
+        .unwrap();
+    let run = |format, filter, fields| {
+        let export = Export::parse(format, filter, fields).unwrap();
+        let mut out = Vec::new();
+        let count = export::write(&db, "people", &export, &mut out).unwrap();
+        (count, String::from_utf8(out).unwrap())
+    };
+
+    let (count, ndjson) = run(None, Some(r#"{"born":{"$lt":1900}}"#), None);
+    assert_eq!(count, 1);
+    assert_eq!(
+        field(&json(ndjson.trim_end()), "_id").as_deref(),
+        Some("\"a\"")
+    );
+
+    let (_, csv) = run(Some("csv"), None, None);
+    assert_eq!(
+        csv.lines().collect::<Vec<_>>(),
+        [
+            "_id,address,born,name,tags",
+            r#"a,,1815,"Ada, Countess","[""math""]""#,
+            r#"b,"{""city"":""Oslo""}",,"Bob ""B""","#,
+            "c,,1990,Cy,",
+        ]
+    );
+    let (_, csv) = run(
+        Some("csv"),
+        Some(r#"{"name":{"$prefix":"B"}}"#),
+        Some("_id, address.city"),
+    );
+    assert_eq!(csv, "_id,address.city\nb,Oslo\n");
+
+    assert!(Export::parse(Some("xml"), None, None).is_err());
+    assert!(Export::parse(None, None, Some("name")).is_err());
+    assert!(Export::parse(Some("csv"), None, Some("name,,born")).is_err());
+}
+
+#[test]
+fn exports_and_hashes_do_not_depend_on_the_write_order() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let first = DB::create(&root, "first", String::new()).unwrap();
+    first
+        .put("people", "b", &json(r#"{"name":"Bob","age":41}"#))
+        .unwrap();
+    first
+        .put("people", "a", &json(r#"{"name":"Ada","tags":["x"]}"#))
+        .unwrap();
+    first.flush().unwrap();
+    first.put("people", "c", &json(r#"{"name":"Cy"}"#)).unwrap();
+    let second = DB::create(&root, "second", String::new()).unwrap();
+    second
+        .put("people", "c", &json(r#"{"name":"Cy"}"#))
+        .unwrap();
+    second
+        .put("people", "a", &json(r#"{"tags":["x"],"name":"Ada"}"#))
+        .unwrap();
+    second
+        .put("people", "b", &json(r#"{"age":41,"name":"Bob"}"#))
+        .unwrap();
+
+    let export = |db: &DB| {
+        let mut out = Vec::new();
+        export::write(
+            db,
+            "people",
+            &Export::parse(None, None, None).unwrap(),
+            &mut out,
+        )
+        .unwrap();
+        out
+    };
+    let exported = export(&first);
+    assert_eq!(exported, export(&second));
+    assert!(
+        String::from_utf8_lossy(&exported).starts_with(r#"{"_id":"a","name":"Ada","tags":["x"]}"#)
+    );
+
+    let (documents, hash) = export::collection_hash(&first, "people").unwrap();
+    assert_eq!(documents, 3);
+    assert_eq!(hash.len(), 64);
+    let digest = Sha256::digest(&exported)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    assert_eq!(hash, digest);
+    assert_eq!(export::collection_hash(&second, "people").unwrap().1, hash);
+
+    let ada = first.get("people", "a").unwrap().unwrap();
+    let same = second.get("people", "a").unwrap().unwrap();
+    assert_eq!(export::document_hash(&ada), export::document_hash(&same));
+    second
+        .put("people", "a", &json(r#"{"name":"Ada","tags":["y"]}"#))
+        .unwrap();
+    let changed = second.get("people", "a").unwrap().unwrap();
+    assert_ne!(export::document_hash(&ada), export::document_hash(&changed));
+    assert_ne!(export::collection_hash(&second, "people").unwrap().1, hash);
+}
+
+#[test]
+fn write_modes_create_replace_or_upsert_documents() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "modes", String::new()).unwrap();
+    let doc = json(r#"{"n":1}"#);
+
+    assert!(matches!(
+        db.write("items", "a", &doc, WriteMode::Replace),
+        Err(WriteError::Missing(_))
+    ));
+    assert!(db.get("items", "a").unwrap().is_none());
+    assert!(db.write("items", "a", &doc, WriteMode::Create).unwrap());
+    assert!(matches!(
+        db.write("items", "a", &doc, WriteMode::Create),
+        Err(WriteError::Exists(_))
+    ));
+    assert!(!db.write("items", "a", &doc, WriteMode::Replace).unwrap());
+    assert!(!db.write("items", "a", &doc, WriteMode::Upsert).unwrap());
+    assert!(db.write("items", "b", &doc, WriteMode::Upsert).unwrap());
+
+    let create = |id: &str| Mutation {
+        mode: WriteMode::Create,
+        ..mutation("items", id, Some(r#"{"n":2}"#))
+    };
+    assert!(matches!(
+        db.commit(&[create("c"), create("a")]).unwrap(),
+        Commit::Aborted(_)
+    ));
+    assert!(db.get("items", "c").unwrap().is_none());
+    let Commit::Committed(created) = db.commit(&[create("c"), create("d")]).unwrap() else {
+        panic!("expected the transaction to commit");
+    };
+    assert_eq!(created, [true, true]);
+}
+
+#[test]
+fn patches_merge_fields_or_apply_operators() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "patches", String::new()).unwrap();
+    let patch = |text: &str| Patch::parse(&json(text)).unwrap();
+    db.put(
+        "items",
+        "a",
+        &json(r#"{"name":"a","visits":1,"tags":["x","y","x"],"address":{"city":"Oslo","zip":"0150"}}"#),
+    )
+    .unwrap();
+
+    let merged = db
+        .patch(
+            "items",
+            "a",
+            &patch(r#"{"name":"b","address":{"zip":null,"country":"NO"}}"#),
+        )
+        .unwrap();
+    assert_eq!(
+        merged.canonical(),
+        json(r#"{"_id":"a","name":"b","visits":1,"tags":["x","y","x"],"address":{"city":"Oslo","country":"NO"}}"#)
+            .canonical()
+    );
+
+    let updated = db
+        .patch(
+            "items",
+            "a",
+            &patch(
+                r#"{"$inc":{"visits":2,"score":0.5},"$pull":{"tags":"x"},"$push":{"log":1},"$set":{"stats.last":"now"},"$unset":["name"]}"#,
+            ),
+        )
+        .unwrap();
+    assert_eq!(
+        updated.canonical(),
+        json(r#"{"_id":"a","visits":3,"score":0.5,"tags":["y"],"log":[1],"stats":{"last":"now"},"address":{"city":"Oslo","country":"NO"}}"#)
+            .canonical()
+    );
+    assert_eq!(
+        db.get("items", "a").unwrap().unwrap().canonical(),
+        updated.canonical()
+    );
+
+    assert!(Patch::parse(&json(r#"{"$set":{"a":1},"b":2}"#)).is_err());
+    assert!(Patch::parse(&json(r#"{"$set":{"_id":"b"}}"#)).is_err());
+    assert!(Patch::parse(&json(r#"{"$rename":{"a":"b"}}"#)).is_err());
+    assert!(matches!(
+        db.patch("items", "a", &patch(r#"{"$inc":{"tags":1}}"#)),
+        Err(WriteError::Rejected(_))
+    ));
+    assert!(matches!(
+        db.patch("items", "a", &patch(r#"{"_id":"b"}"#)),
+        Err(WriteError::Rejected(_))
+    ));
+    assert!(matches!(
+        db.patch("items", "missing", &patch(r#"{"n":1}"#)),
+        Err(WriteError::Missing(_))
+    ));
+}
+
+#[test]
+fn increments_and_find_and_modify_update_documents_atomically() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = Arc::new(DB::create(&root, "counters", String::new()).unwrap());
+
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let db = Arc::clone(&db);
+        handles.push(std::thread::spawn(move || {
+            (0..25)
+                .map(|_| {
+                    let (value, _) = db
+                        .increment("seqs", "orders", "next", &JsonNumber::Int(1))
+                        .unwrap();
+                    value.canonical()
+                })
+                .collect::<Vec<_>>()
+        }));
+    }
+    let mut values = handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect::<Vec<_>>();
+    values.sort_by_key(|value| value.parse::<i64>().unwrap());
+    values.dedup();
+    assert_eq!(values.len(), 100);
+    assert_eq!(values.last().unwrap(), "100");
+
+    db.put("stock", "a", &json(r#"{"qty":0,"rank":1}"#))
+        .unwrap();
+    db.put("stock", "b", &json(r#"{"qty":2,"rank":3}"#))
+        .unwrap();
+    db.put("stock", "c", &json(r#"{"qty":5,"rank":2}"#))
+        .unwrap();
+    let take = |image: &str| {
+        FindAndModify::parse(&json(&format!(
+            r#"{{"filter":{{"qty":{{"$gt":0}}}},"sort":"rank","update":{{"$inc":{{"qty":-1}}}},"return":"{}"}}"#,
+            image
+        )))
+        .unwrap()
+    };
+    let before = take("before").run(&db, "stock").unwrap().unwrap();
+    assert_eq!(before.id, "c");
+    assert_eq!(
+        field(&before.document.unwrap(), "qty").as_deref(),
+        Some("5")
+    );
+    let after = take("after").run(&db, "stock").unwrap().unwrap();
+    assert_eq!(field(&after.document.unwrap(), "qty").as_deref(), Some("3"));
+
+    let none = FindAndModify::parse(&json(r#"{"filter":{"qty":99},"update":{"qty":1}}"#)).unwrap();
+    assert!(none.run(&db, "stock").unwrap().is_none());
+    let upsert = FindAndModify {
+        upsert: true,
+        image: Image::After,
+        ..none
+    };
+    let inserted = upsert.run(&db, "stock").unwrap().unwrap();
+    assert!(inserted.inserted);
+    assert_eq!(
+        field(&inserted.document.unwrap(), "qty").as_deref(),
+        Some("1")
+    );
+    assert!(FindAndModify::parse(&json(r#"{"filter":{}}"#)).is_err());
+}
+
+#[test]
+fn soft_deletes_leave_tombstones_until_they_are_purged() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "tombstones", String::new()).unwrap();
+    db.create_collection("notes").unwrap();
+    assert!(
+        db.set_soft_delete("notes", Some(SoftDelete { retention: None }))
+            .unwrap()
+    );
+    for id in ["a", "b", "c"] {
+        db.put("notes", id, &json(r#"{"text":"hi"}"#)).unwrap();
+    }
+
+    assert!(db.delete("notes", "a").unwrap());
+    assert!(!db.delete("notes", "a").unwrap());
+    assert!(db.get("notes", "a").unwrap().is_none());
+    let tombstone = db.get_including_deleted("notes", "a").unwrap().unwrap();
+    assert!(field(&tombstone, "_deleted").is_some());
+    assert_eq!(db.documents("notes").unwrap().len(), 2);
+    let all = Query::parse(Some(&json(r#"{"deleted":true}"#))).unwrap();
+    assert_eq!(all.run(&db, "notes").unwrap().documents.len(), 3);
+    assert!(
+        feed(&db, "notes", 0, 10)
+            .last()
+            .unwrap()
+            .contains(r#""type":"delete""#)
+    );
+
+    assert!(db.restore("notes", "a").unwrap());
+    assert!(!db.restore("notes", "b").unwrap());
+    let restored = db.get("notes", "a").unwrap().unwrap();
+    assert_eq!(
+        restored.canonical(),
+        json(r#"{"_id":"a","text":"hi"}"#).canonical()
+    );
+
+    db.delete("notes", "a").unwrap();
+    assert!(matches!(
+        db.write("notes", "a", &json("{}"), WriteMode::Replace),
+        Err(WriteError::Missing(_))
+    ));
+    db.delete("notes", "b").unwrap();
+    assert!(
+        db.purge("notes", Utc::now() - Duration::hours(1))
+            .unwrap()
+            .is_empty()
+    );
+    let mut purged = db.purge("notes", Utc::now()).unwrap();
+    purged.sort();
+    assert_eq!(purged, ["a", "b"]);
+    assert!(db.get_including_deleted("notes", "a").unwrap().is_none());
+
+    assert!(db.purge_document("notes", "c").unwrap());
+    assert!(db.get_including_deleted("notes", "c").unwrap().is_none());
+    db.put(
+        "notes",
+        "d",
+        &json(r#"{"_deleted":"2020-01-01T00:00:00Z"}"#),
+    )
+    .unwrap();
+    assert!(db.get("notes", "d").unwrap().is_some());
+}
+
+#[test]
+fn closed_databases_reject_requests_until_they_are_opened() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "closing", String::new()).unwrap();
+    db.put("items", "a", &json(r#"{"n":1}"#)).unwrap();
+    let engine = db.engine().unwrap();
+
+    assert!(db.close());
+    assert!(!db.close());
+    assert!(db.is_closed());
+    assert!(db.get("items", "a").is_err());
+    assert!(db.put("items", "b", &json(r#"{"n":2}"#)).is_err());
+    // Requests that got the engine before the close cannot write either.
+    let entry = json(r#"{"op":"create_collection","collection":"late"}"#);
+    assert!(engine.lock().write(&entry, |_, _| Ok(())).is_err());
+
+    assert!(db.reopen().unwrap());
+    assert!(!db.reopen().unwrap());
+    assert!(!db.is_closed());
+    assert!(db.get("items", "a").unwrap().is_some());
+    db.put("items", "b", &json(r#"{"n":2}"#)).unwrap();
+
+    db.close();
+    DB::open(&root, "closing")
+        .unwrap()
+        .unwrap()
+        .destroy()
+        .unwrap();
+    let db = DB::create(&root, "closing", String::new()).unwrap();
+    assert!(!db.is_closed());
+    assert!(db.get("items", "a").unwrap().is_none());
+}
+
+#[test]
+fn records_that_fail_to_apply_reload_the_engine_from_the_log() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "poisoned", String::new()).unwrap();
+    db.put("items", "a", &json(r#"{"n":1}"#)).unwrap();
+    let engine = db.engine().unwrap();
+    let entry = json(r#"{"op":"put","collection":"items","_id":"b","document":{"_id":"b","n":2}}"#);
+    let err = engine
+        .lock()
+        .write(&entry, |_, _| Err("the disk is gone".to_string()))
+        .unwrap_err();
+    assert!(err.contains("loaded again"), "{}", err);
+    assert!(engine.lock().write(&entry, |_, _| Ok(())).is_err());
+
+    // The record is in the log, so loading the engine again applies it.
+    assert!(!Arc::ptr_eq(&engine, &db.engine().unwrap()));
+    assert!(db.get("items", "b").unwrap().is_some());
+    db.put("items", "c", &json(r#"{"n":3}"#)).unwrap();
+    assert_eq!(db.documents("items").unwrap().len(), 3);
+}
+
+#[test]
+fn loaded_databases_are_locked_until_they_are_closed() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "locked", String::new()).unwrap();
+    db.put("items", "a", &json(r#"{"n":1}"#)).unwrap();
+    let err = db.lock().unwrap_err();
+    assert!(err.contains("in use"), "{}", err);
+    // Everything else in this process shares the lock the engine holds.
+    assert!(engine::lock(&db).is_ok());
+
+    assert!(db.close());
+    drop(db.lock().unwrap());
+    assert!(db.reopen().unwrap());
+    assert!(db.lock().is_err());
+}
+
+#[test]
+fn stats_count_documents_without_scanning_them() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "counted", String::new()).unwrap();
+    db.create_collection("trash").unwrap();
+    db.set_soft_delete("trash", Some(SoftDelete { retention: None }))
+        .unwrap();
+    for id in ["ada", "alan", "grace"] {
+        db.put("users", id, &json(r#"{"name":"x"}"#)).unwrap();
+        db.put("trash", id, &json(r#"{"name":"x"}"#)).unwrap();
+    }
+    db.put("users", "ada", &json(r#"{"name":"Ada Lovelace"}"#))
+        .unwrap();
+    db.delete("users", "alan").unwrap();
+    db.delete("trash", "alan").unwrap();
+    db.flush().unwrap();
+    db.put("users", "linus", &json(r#"{"name":"x"}"#)).unwrap();
+
+    let check = |db: &DB| {
+        let stats = db.stats().unwrap();
+        let bytes = |col: &str| {
+            db.documents(col)
+                .unwrap()
+                .iter()
+                .map(|document| document.canonical().len() as u64)
+                .sum::<u64>()
+        };
+        assert_eq!(stats.collections.len(), 2);
+        let trash = &stats.collections[0];
+        assert_eq!((trash.documents, trash.deleted), (2, 1));
+        assert_eq!(trash.bytes, bytes("trash"));
+        let users = &stats.collections[1];
+        assert_eq!((users.documents, users.deleted), (3, 0));
+        assert_eq!(users.bytes, bytes("users"));
+        assert!(users.disk_bytes > users.index_bytes && users.index_bytes > 0);
+        assert!(stats.wal_bytes > 0);
+    };
+    check(&db);
+    engine::close(Path::new(db.path()));
+    check(&db);
+
+    // Collections written before counters were kept are counted once when the engine loads.
+    db.flush().unwrap();
+    engine::close(Path::new(db.path()));
+    fs::remove_dir_all(dir.path().join("counted/data/users/.stats")).unwrap();
+    check(&db);
+    assert!(db.stats().unwrap().collections[1].compacted.is_none());
+    db.compact(2).unwrap();
+    assert!(db.stats().unwrap().collections[1].compacted.is_some());
+    check(&db);
+}
+
+#[test]
+fn compressed_tables_read_back_what_was_written() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "packed", String::new()).unwrap();
+    db.create_collection("logs").unwrap();
+    assert!(db.set_compression("logs", Codec::Zstd).unwrap());
+    let text = "the same line over and over ".repeat(20);
+    for round in 0..3 {
+        for id in 0..40 {
+            let document = format!(r#"{{"round":{},"text":"{}"}}"#, round, text);
+            db.put("logs", &format!("{:03}", id), &json(&document))
+                .unwrap();
+        }
+        db.flush().unwrap();
+    }
+    let logs = &db.stats().unwrap().collections[0];
+    assert_eq!(logs.compression, Codec::Zstd);
+    assert!(
+        logs.stored_bytes * 4 < logs.raw_bytes,
+        "{}",
+        logs.stored_bytes
+    );
+
+    db.set_compression("logs", Codec::Lz4).unwrap();
+    db.compact(2).unwrap();
+    engine::close(Path::new(db.path()));
+    let logs = &db.stats().unwrap().collections[0];
+    assert_eq!(logs.compression, Codec::Lz4);
+    assert!(
+        logs.stored_bytes * 4 < logs.raw_bytes,
+        "{}",
+        logs.stored_bytes
+    );
+    assert_eq!(logs.documents, 40);
+    let documents = db.documents("logs").unwrap();
+    assert_eq!(documents.len(), 40);
+    assert!(
+        documents
+            .iter()
+            .all(|document| field(document, "round").as_deref() == Some("2"))
+    );
+    let last = db.get("logs", "039").unwrap().unwrap();
+    assert_eq!(field(&last, "text"), Some(format!("\"{}\"", text)));
+}
+
+#[test]
+fn repeated_reads_are_served_from_the_block_cache() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "cached", String::new()).unwrap();
+    for id in 0..100 {
+        db.put("items", &format!("{:03}", id), &json(r#"{"size":1}"#))
+            .unwrap();
+    }
+    db.flush().unwrap();
+    let before = cache::stats();
+    for _ in 0..10 {
+        assert!(db.get("items", "050").unwrap().is_some());
+    }
+    let after = cache::stats();
+    assert!(
+        after.hits >= before.hits + 9,
+        "{} {}",
+        before.hits,
+        after.hits
+    );
+    assert!(after.bytes <= after.capacity);
+}
+
+#[test]
+fn warm_up_reads_the_collections_of_the_access_profile() {
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().to_string_lossy().to_string();
+    let db = DB::create(&root, "warm", String::new()).unwrap();
+    for id in 0..100 {
+        db.put("hot", &format!("{:03}", id), &json(r#"{"size":1}"#))
+            .unwrap();
+    }
+    db.flush().unwrap();
+    fs::write(
+        Path::new(&root).join(warmup::PROFILE_FILE),
+        r#"{"warm":{"hot":7,"missing":9},"gone":{"hot":1}}"#,
+    )
+    .unwrap();
+    let profile = warmup::load(&root).unwrap();
+    assert_eq!(profile[0].1, "missing");
+    assert_eq!(warmup::warm(&root, &profile), 2);
+    let (hits, misses) = cache::thread_lookups();
+    assert!(db.get("hot", "050").unwrap().is_some());
+    assert_eq!(cache::thread_lookups(), (hits + 1, misses));
+}
+
+#[test]
+fn copies_resume_and_catch_up_with_writes_to_the_source() {
+    let server = TestServer::start().unwrap();
+    let root = server.root().to_string_lossy().to_string();
+    let source = DB::create(&root, "staging", "pw".to_string()).unwrap();
+    let target = DB::create(&root, "production", "pw".to_string()).unwrap();
+    for n in 0..12 {
+        source
+            .put(
+                "users",
+                &format!("u{:02}", n),
+                &json(&format!(r#"{{"n":{}}}"#, n)),
+            )
+            .unwrap();
+    }
+    source.put("users", "a-b", &json(r#"{"n":20}"#)).unwrap();
+    source.put("users", "u99", &json(r#"{"n":99.5}"#)).unwrap();
+    target.create_collection("users").unwrap();
+    let schema = Schema::new(&json(r#"{"properties":{"n":{"type":"integer"}}}"#)).unwrap();
+    target
+        .set_schema("users", Some((&schema, Validation::Strict)))
+        .unwrap();
+
+    let remote = |db: &str| {
+        Remote::parse(&format!(
+            "http://{}:pw@{}/dbs/{}/collections/users",
+            db,
+            server.addr(),
+            db
+        ))
+        .unwrap()
+    };
+    let (from, to) = (remote("staging"), remote("production"));
+    let filter = json(r#"{"n":{"$gte":2}}"#);
+    let state = server.root().join("users.copy.json");
+    let err = copy::copy(&from, &to, Some(&filter), 5, Some(&state), |_| {})
+        .err()
+        .unwrap();
+    assert!(err.contains("refused 1 documents"), "{}", err);
+    assert!(state.exists());
+    assert_eq!(target.documents("users").unwrap().len(), 11);
+
+    // The copy resumes after the pages it imported, and catches up with the writes made since it
+    // began, including those made while it runs.
+    source.delete("users", "u99").unwrap();
+    source.delete("users", "u05").unwrap();
+    source.put("users", "u03", &json(r#"{"n":1}"#)).unwrap();
+    let mut written = false;
+    let report = copy::copy(&from, &to, Some(&filter), 5, Some(&state), |report| {
+        if !written {
+            source.put("users", "u30", &json(r#"{"n":30}"#)).unwrap();
+            written = true;
+        }
+        assert!(report.resumed);
+    })
+    .unwrap();
+    assert!(report.resumed);
+    assert!(!state.exists());
+    let ids = target
+        .documents("users")
+        .unwrap()
+        .iter()
+        .map(|document| field(document, "_id").unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        ids.join(","),
+        r#""a-b","u02","u04","u06","u07","u08","u09","u10","u11","u30""#
+    );
+
+    let err = copy::copy(&from, &remote("missing"), None, 5, Some(&state), |_| {})
+        .err()
+        .unwrap();
+    assert!(!err.is_empty());
+    assert!(Remote::parse("ftp://host/dbs/a/collections/b").is_err());
+    assert!(Remote::parse("http://host:port/dbs/a/collections/b").is_err());
+    assert!(Remote::parse("http://host/dbs/a").is_err());
+}
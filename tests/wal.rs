@@ -0,0 +1,119 @@
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+use db6::{
+    db::DB,
+    engine,
+    json::Json,
+    test_util::TestServer,
+    wal::{self, Wal},
+};
+
+// The log settings are process wide, so every test in this file starts its server with the
+// same segment size.
+const SEGMENT_SIZE: u64 = 200;
+
+fn start() -> TestServer {
+    TestServer::start_with(|cl| cl.wal_segment_size = SEGMENT_SIZE).unwrap()
+}
+
+fn entry(n: usize) -> Json {
+    Json::parse(format!(r#"{{"op":"put","collection":"c","_id":"d{}"}}"#, n).as_bytes()).unwrap()
+}
+
+#[test]
+fn segments_rotate_and_torn_tails_are_dropped() {
+    let server = start();
+    let dir = server.root().join("wal");
+    let mut log = Wal::open(&dir).unwrap();
+    let seqs = (0..6)
+        .map(|n| log.append(&entry(n)).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(seqs, [1, 2, 3, 4, 5, 6]);
+    drop(log);
+
+    let segments = wal::segments(&dir).unwrap();
+    assert!(segments.len() > 1);
+    assert!(segments.iter().all(|segment| segment.bytes <= SEGMENT_SIZE));
+    let last = segments.last().unwrap();
+    let records = wal::read_segment(&last.path).unwrap().records;
+    assert_eq!(records.last().unwrap().seq, 6);
+
+    let mut file = OpenOptions::new().append(true).open(&last.path).unwrap();
+    file.write_all(&wal::encode(b"{\"seq\":7}")[..10]).unwrap();
+    drop(file);
+    assert!(wal::read_segment(&last.path).unwrap().torn);
+
+    let mut log = Wal::open(&dir).unwrap();
+    assert_eq!(log.append(&entry(7)).unwrap(), 7);
+    assert!(!wal::read_segment(&last.path).unwrap().torn);
+    let newest = wal::segments(&dir).unwrap().pop().unwrap();
+    let contents = wal::read_segment(&newest.path).unwrap();
+    assert!(!contents.torn);
+    assert_eq!(contents.records.last().unwrap().seq, 7);
+}
+
+#[test]
+fn recovery_replays_records_missing_from_the_data_files() {
+    let server = start();
+    let root = server.root().to_string_lossy().to_string();
+    let db = DB::create(&root, "crashed", String::new()).unwrap();
+    db.put("items", "a", &Json::parse(br#"{"v":1}"#).unwrap())
+        .unwrap();
+    engine::close(Path::new(db.path()));
+
+    let segment = wal::segments(&db.wal_dir()).unwrap().pop().unwrap();
+    let mut file = OpenOptions::new().append(true).open(&segment.path).unwrap();
+    file.write_all(&wal::encode(
+        br#"{"_id":"b","collection":"items","document":{"_id":"b","v":2},"op":"put","seq":2}"#,
+    ))
+    .unwrap();
+    file.write_all(&wal::encode(
+        br#"{"_id":"a","collection":"items","op":"delete","seq":3}"#,
+    ))
+    .unwrap();
+    drop(file);
+
+    let db = DB::open(&root, "crashed").unwrap().unwrap();
+    let recovery = db.recover().unwrap();
+    assert_eq!(recovery.last_seq, 3);
+    assert_eq!(recovery.replayed, 3);
+    assert_eq!(recovery.gaps, 0);
+    assert!(db.get("items", "a").unwrap().is_none());
+    assert!(db.get("items", "b").unwrap().is_some());
+    assert_eq!(wal::read_checkpoint(&db.wal_dir()).unwrap(), 0);
+
+    db.flush().unwrap();
+    assert_eq!(wal::read_checkpoint(&db.wal_dir()).unwrap(), 3);
+    engine::close(Path::new(db.path()));
+    assert_eq!(db.recover().unwrap().replayed, 0);
+    assert!(db.get("items", "b").unwrap().is_some());
+}
+
+#[test]
+fn recovery_counts_missing_sequence_numbers() {
+    let server = start();
+    let root = server.root().to_string_lossy().to_string();
+    let db = DB::create(&root, "gappy", String::new()).unwrap();
+    db.put("items", "a", &Json::parse(br#"{"v":1}"#).unwrap())
+        .unwrap();
+    engine::close(Path::new(db.path()));
+
+    let segment = wal::segments(&db.wal_dir()).unwrap().pop().unwrap();
+    let mut file = OpenOptions::new().append(true).open(&segment.path).unwrap();
+    file.write_all(&wal::encode(
+        br#"{"_id":"b","collection":"items","document":{"_id":"b","v":2},"op":"put","seq":5}"#,
+    ))
+    .unwrap();
+    drop(file);
+
+    let db = DB::open(&root, "gappy").unwrap().unwrap();
+    let recovery = db.recover().unwrap();
+    assert_eq!(recovery.last_seq, 5);
+    assert_eq!(recovery.gaps, 3);
+    let sequences = engine::sequences();
+    let (_, last_seq, gaps) = sequences
+        .iter()
+        .find(|(path, ..)| path == Path::new(db.path()))
+        .unwrap();
+    assert_eq!((*last_seq, *gaps), (5, 3));
+}